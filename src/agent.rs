@@ -0,0 +1,212 @@
+//! The HTTP API a coordinator drives each cloner through: its status, and batches to flash.
+
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+use crate::batch::Batch;
+use crate::config::{AgentConfig, ProfileConfig};
+use crate::events::{Event, EventBus};
+use crate::history::{self, FlashRecord, FlashResult, History};
+use crate::logging;
+use crate::monitoring::Health;
+use crate::peer;
+
+pub const SERVICE_TYPE: &str = "_sd-cloner-unit._tcp.local.";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UnitStatus {
+    /// The state machine's state, e.g. `Flashing`
+    pub state: String,
+    pub image: PathBuf,
+    pub batch: Option<Batch>,
+    /// Cards flashed since the cloner started
+    pub succeeded: u64,
+    pub failed: u64,
+    pub last_serial: Option<String>,
+    /// Version of a newer image on the sync source, not flashed until it is synced
+    #[serde(default)]
+    pub update_available: Option<String>,
+}
+
+impl UnitStatus {
+    pub fn record(&mut self, record: &FlashRecord) {
+        match record.result {
+            FlashResult::Succeeded => self.succeeded += 1,
+            FlashResult::Failed | FlashResult::Removed => self.failed += 1,
+        }
+        self.last_serial = record.serial.clone();
+    }
+}
+
+/// Keeps `status` up to date with the state machine's state, the flashes it finishes and
+/// whether an update is available.
+pub async fn track(status: Arc<Mutex<UnitStatus>>, events: EventBus) {
+    let mut receiver = events.subscribe();
+    loop {
+        let event = receiver.recv().await;
+        let mut status = status.lock().unwrap();
+        match event {
+            Ok(Event::StateChanged(state)) => status.state = format!("{state:?}"),
+            Ok(Event::FlashFinished(record)) => status.record(&record),
+            Ok(Event::UpdateAvailable(version)) => status.update_available = version,
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => {
+                println!("Unit status fell behind, missed {missed} events");
+                status.state = format!("{:?}", events.state());
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+fn json(value: &impl Serialize) -> Response<io::Cursor<Vec<u8>>> {
+    Response::from_data(serde_json::to_vec(value).unwrap_or_default())
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+}
+
+/// The value of `key` in a query string, with `%XX` escapes and `+` decoded.
+fn query_value(query: &str, key: &str) -> Option<String> {
+    let (_, value) = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)?;
+    let mut decoded = vec![];
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// The flash history as CSV, filtered like the `export` subcommand by the `since`, `until` and
+/// `image` parameters of `query`.
+fn history_csv(history: &History, query: &str) -> Response<io::Cursor<Vec<u8>>> {
+    let day = |key| match query_value(query, key) {
+        Some(date) => history::parse_date(&date)
+            .map(Some)
+            .ok_or(format!("{key} isn't a YYYY-MM-DD date")),
+        None => Ok(None),
+    };
+    let filter = match (day("since"), day("until")) {
+        (Ok(since), Ok(until)) => history::Filter {
+            since,
+            until: until.map(|until| until + 24 * 60 * 60),
+            image: query_value(query, "image"),
+        },
+        (Err(error), _) | (_, Err(error)) => {
+            return Response::from_string(error).with_status_code(400)
+        }
+    };
+    let mut csv = vec![];
+    let written = history.records().and_then(|records| {
+        history::write_csv(
+            records.iter().filter(|record| filter.matches(record)),
+            &mut csv,
+        )
+    });
+    if let Err(error) = written {
+        return Response::from_string(error.to_string()).with_status_code(500);
+    }
+    Response::from_data(csv)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/csv"[..]).unwrap())
+}
+
+struct Agent {
+    status: Arc<Mutex<UnitStatus>>,
+    history: History,
+    health: Health,
+    profiles: Vec<ProfileConfig>,
+    /// Work orders for the state machine, the same as scanned ones
+    orders: mpsc::UnboundedSender<String>,
+}
+
+impl Agent {
+    fn handle(&self, request: &mut Request) -> Response<io::Cursor<Vec<u8>>> {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        match (request.method(), path) {
+            (Method::Get, "/status") => json(&*self.status.lock().unwrap()),
+            // The body is a work order, `<profile>*<quantity>`.
+            (Method::Post, "/batch") => {
+                let mut order = String::new();
+                if let Err(error) = request.as_reader().read_to_string(&mut order) {
+                    return Response::from_string(error.to_string()).with_status_code(400);
+                }
+                if let Err(error) = Batch::from_work_order(&order, &self.profiles) {
+                    return Response::from_string(error).with_status_code(400);
+                }
+                if self.orders.send(order).is_err() {
+                    return Response::from_string("Shutting down").with_status_code(503);
+                }
+                Response::from_string("Queued")
+            }
+            // Unhealthy is a 503, so monitoring that only looks at the status code notices too.
+            (Method::Get, "/healthz") => {
+                let image = self.status.lock().unwrap().image.clone();
+                let report = self.health.check(&image);
+                let code = if report.healthy { 200 } else { 503 };
+                json(&report).with_status_code(code)
+            }
+            (Method::Get, "/history.csv") => history_csv(&self.history, query),
+            (Method::Get, "/log-level") => json(&logging::level()),
+            // The body is `"info"` or `"debug"`, until the next restart or config reload.
+            (Method::Put, "/log-level") => {
+                let level = serde_json::from_reader(request.as_reader());
+                match level {
+                    Ok(level) => {
+                        logging::set_level(level);
+                        json(&level)
+                    }
+                    Err(error) => Response::from_string(error.to_string()).with_status_code(400),
+                }
+            }
+            _ => Response::from_string("Not found").with_status_code(StatusCode(404)),
+        }
+    }
+}
+
+/// Serves the API on a background thread, and announces it over mDNS for coordinators.
+pub fn serve(
+    config: &AgentConfig,
+    status: Arc<Mutex<UnitStatus>>,
+    history: History,
+    health: Health,
+    profiles: Vec<ProfileConfig>,
+    orders: mpsc::UnboundedSender<String>,
+) -> Result<(), Box<dyn Error>> {
+    let server = Server::http(("0.0.0.0", config.port))
+        .map_err(|error| format!("Listening on port {} failed: {error}", config.port))?;
+    let mdns = peer::announce(SERVICE_TYPE, config.port, "")?;
+    let agent = Agent {
+        status,
+        history,
+        health,
+        profiles,
+        orders,
+    };
+    thread::spawn(move || {
+        let _mdns = mdns;
+        for mut request in server.incoming_requests() {
+            let response = agent.handle(&mut request);
+            if let Err(error) = request.respond(response) {
+                println!("Got error when sending the response: {error:?}");
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,609 @@
+//! Dumping a card back into an image file. The backup gets an `<image>.info.json` with the size
+//! and digest of what was read, so it can be flashed as it is.
+//!
+//! What is read is hashed in chunks on the way, like a flash hashes what it writes, and the card
+//! is read again afterwards and compared with them. A reader that returns different data every
+//! now and then would otherwise leave a backup that looks fine until it is restored.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::devices;
+use crate::hashing::{hex, HashAlgorithm};
+use crate::history::{self, BackupRecord, History};
+use crate::image::{self, digest, info::ImageInfo};
+use crate::partition_table;
+use crate::peer;
+
+pub mod retention;
+pub mod store;
+
+use store::{BaselineImage, Manifest, Store};
+
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BackupOptions {
+    /// Only read up to the end of the last partition, and drop trailing zeros
+    pub shrink: bool,
+    /// Compress the output with zstd at this level, 0 being its default
+    pub compression_level: Option<i32>,
+    /// Read the card again afterwards, comparing chunks hashed with this
+    pub verify: Option<HashAlgorithm>,
+}
+
+/// The card a backup is of and the cloner that made it, kept in its manifest or info file and in
+/// the history, to find it by months later.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Source {
+    pub device: PathBuf,
+    /// CID of cards on the MMC bus, which no other card has
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cid: Option<String>,
+    /// Serial of the card itself, only known along with its CID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// Serial of the USB reader the card was in, which every card going through it shares
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reader_serial: Option<String>,
+    /// Bytes the card holds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<u64>,
+    /// Host name of the cloner
+    #[serde(default)]
+    pub host: String,
+}
+
+impl Source {
+    pub fn of(device: &Path) -> Self {
+        let cid = devices::card_cid(device);
+        // Without a CID the card isn't on the MMC bus, and the serial is the reader's.
+        let (serial, reader_serial) = match cid {
+            Some(_) => (devices::card_serial(device), None),
+            None => (None, devices::card_serial(device)),
+        };
+        Self {
+            device: device.to_path_buf(),
+            cid,
+            serial,
+            reader_serial,
+            capacity: devices::device_size(device),
+            host: peer::hostname(),
+        }
+    }
+
+    /// The card, by its CID. Cards without one are only known by the reader they were in, or
+    /// else the device.
+    pub fn card(&self) -> String {
+        let reader = || Some(format!("reader-{}", self.reader_serial.as_ref()?));
+        self.cid
+            .clone()
+            .or_else(|| self.serial.clone())
+            .or_else(reader)
+            .unwrap_or_else(|| {
+                let name = self.device.file_name().unwrap_or_default();
+                name.to_string_lossy().to_string()
+            })
+    }
+
+    /// Whether the host, a serial or the CID contains `query`.
+    pub fn matches(&self, query: &str) -> bool {
+        let fields = [&self.cid, &self.serial, &self.reader_serial];
+        [Some(&self.host)]
+            .into_iter()
+            .chain(fields.map(Option::as_ref))
+            .flatten()
+            .any(|field| field.contains(query))
+    }
+}
+
+/// Hashes what is read through it in chunks of [`CHUNK_SIZE`], for [`verify`] to compare the
+/// card with.
+struct Hashing<R> {
+    reader: R,
+    algorithm: HashAlgorithm,
+    chunk: Vec<u8>,
+    hashes: Vec<Vec<u8>>,
+}
+
+impl<R: Read> Hashing<R> {
+    fn new(reader: R, algorithm: HashAlgorithm) -> Self {
+        Self {
+            reader,
+            algorithm,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+            hashes: vec![],
+        }
+    }
+
+    fn finish(mut self) -> Vec<Vec<u8>> {
+        if !self.chunk.is_empty() {
+            self.hashes.push(self.algorithm.hash(&self.chunk));
+        }
+        self.hashes
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buffer)?;
+        let mut data = &buffer[..read];
+        while !data.is_empty() {
+            let taken = data.len().min(CHUNK_SIZE - self.chunk.len());
+            self.chunk.extend_from_slice(&data[..taken]);
+            data = &data[taken..];
+            if self.chunk.len() == CHUNK_SIZE {
+                self.hashes.push(self.algorithm.hash(&self.chunk));
+                self.chunk.clear();
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// Reads the first `end` bytes of `device` again, bypassing the cache, and compares them with
+/// the `hashes` of what the backup read.
+fn verify(device: &Path, end: u64, algorithm: HashAlgorithm, hashes: &[Vec<u8>]) -> io::Result<()> {
+    let input = File::open(device)?;
+    devices::drop_cache(&input)?;
+    let mut reader = BufReader::new(input.take(end));
+    let mut buffer = Vec::with_capacity(CHUNK_SIZE);
+    for (index, expected) in hashes.iter().enumerate() {
+        buffer.clear();
+        (&mut reader)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut buffer)?;
+        if algorithm.hash(&buffer) != *expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{device:?} read differently at {} the second time, the backup can't be \
+                     trusted",
+                    index * CHUNK_SIZE
+                ),
+            ));
+        }
+    }
+    println!("Read {end} bytes of {device:?} again, and they matched the backup");
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct BackupSummary {
+    /// The image file, named in the directory it was given if it was given one
+    pub output: PathBuf,
+    pub device_size: u64,
+    /// Bytes of the card stored in the backup, before compression
+    pub image_size: u64,
+    /// SHA-256 of those bytes
+    pub sha256: String,
+}
+
+fn hash_zeros(digest: &mut Sha256, mut count: u64) {
+    let zeros = [0; 4096];
+    while count > 0 {
+        let length = count.min(zeros.len() as u64) as usize;
+        digest.update(&zeros[..length]);
+        count -= length as u64;
+    }
+}
+
+enum Output {
+    /// Zeros in the middle of the image become holes in the file
+    Raw(File),
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl Output {
+    fn write_zeros(&mut self, mut count: u64) -> io::Result<()> {
+        match self {
+            Output::Raw(file) => {
+                file.seek(SeekFrom::Current(count as i64))?;
+            }
+            Output::Zstd(encoder) => {
+                let zeros = [0; 4096];
+                while count > 0 {
+                    let length = count.min(zeros.len() as u64) as usize;
+                    encoder.write_all(&zeros[..length])?;
+                    count -= length as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Output::Raw(file) => file.write_all(data),
+            Output::Zstd(encoder) => encoder.write_all(data),
+        }
+    }
+
+    fn finish(self, length: u64) -> io::Result<()> {
+        let file = match self {
+            Output::Raw(file) => {
+                // Pending zeros that were kept still have to exist in the file.
+                file.set_len(length)?;
+                file
+            }
+            Output::Zstd(encoder) => encoder.finish()?,
+        };
+        file.sync_all()
+    }
+}
+
+/// Opens `device` and finds how much of it to back up: the whole of it, or with `shrink` up to
+/// the end of the last partition. Returns the device positioned at its start, its size and that
+/// end.
+fn open_device(device: &Path, shrink: bool) -> io::Result<(File, u64, u64)> {
+    let mut input = File::open(device)?;
+    let device_size = input.seek(SeekFrom::End(0))?;
+    let sector_size = devices::block_sizes(device).logical;
+    let end = if shrink {
+        match partition_table::used_end(&mut input, sector_size)? {
+            Some(end) => {
+                println!("Partitions end at {end} of {device_size} bytes");
+                end.min(device_size)
+            }
+            None => {
+                println!("No partition table found, backing up the whole card");
+                device_size
+            }
+        }
+    } else {
+        device_size
+    };
+    input.seek(SeekFrom::Start(0))?;
+    Ok((input, device_size, end))
+}
+
+/// Indexes a backup in the history. It was made by then, so failing to is only logged.
+fn record(config: &Config, record: &BackupRecord) {
+    if let Err(error) = History::new(&config.history).append_backup(record) {
+        println!("Got error when adding the backup to the history: {error:?}");
+    }
+}
+
+/// Backs `device` up into the image file `output`, or into one with its [`default_name`] if
+/// `output` is a directory.
+pub fn backup(
+    config: &Config,
+    device: &Path,
+    output: &Path,
+    options: BackupOptions,
+) -> io::Result<BackupSummary> {
+    let source = Source::of(device);
+    let created_at = history::unix_time();
+    let output = match output.is_dir() {
+        true => output.join(format!(
+            "{}.img{}",
+            default_name(&source, created_at),
+            if options.compression_level.is_some() {
+                ".zst"
+            } else {
+                ""
+            }
+        )),
+        false => output.to_path_buf(),
+    };
+    let (input, device_size, end) = open_device(device, options.shrink)?;
+    // Trailing zeros are trimmed in whole sectors, and the partition table counts in them.
+    let sector_size = devices::block_sizes(device).logical;
+
+    let file = File::create(&output)?;
+    let mut writer = match options.compression_level {
+        Some(level) => Output::Zstd(zstd::Encoder::new(file, level)?),
+        None => Output::Raw(file),
+    };
+
+    let algorithm = options.verify.unwrap_or_default();
+    let mut reader = BufReader::new(Hashing::new(input.take(end), algorithm));
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut position = 0u64;
+    // Zeros read but not written yet, dropped if nothing but zeros follows them.
+    let mut pending_zeros = 0u64;
+    let mut written = 0u64;
+    let mut digest = Sha256::new();
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        let chunk = &buffer[..read];
+        position += read as u64;
+
+        let data_end = if options.shrink {
+            chunk.iter().rposition(|byte| *byte != 0).map_or(0, |last| {
+                (last + 1).next_multiple_of(sector_size as usize).min(read)
+            })
+        } else {
+            read
+        };
+        if data_end > 0 {
+            writer.write_zeros(pending_zeros)?;
+            writer.write_all(&chunk[..data_end])?;
+            hash_zeros(&mut digest, pending_zeros);
+            digest.update(&chunk[..data_end]);
+            written += pending_zeros + data_end as u64;
+            pending_zeros = 0;
+        }
+        pending_zeros += (read - data_end) as u64;
+        if position.is_multiple_of(256 * CHUNK_SIZE as u64) {
+            println!("Backed up {position}/{end}");
+        }
+    }
+    if !options.shrink {
+        hash_zeros(&mut digest, pending_zeros);
+        written += pending_zeros;
+    }
+    writer.finish(written)?;
+    if options.verify.is_some() {
+        verify(device, end, algorithm, &reader.into_inner().finish())?;
+    }
+
+    let info = ImageInfo {
+        size: written,
+        sha256: hex(&digest.finalize()),
+        source: Some(source.clone()),
+    };
+    info.write(&output)?;
+    record(
+        config,
+        &BackupRecord {
+            backup: output.display().to_string(),
+            created_at,
+            source,
+            size: info.size,
+            sha256: info.sha256.clone(),
+        },
+    );
+    Ok(BackupSummary {
+        output,
+        device_size,
+        image_size: info.size,
+        sha256: info.sha256,
+    })
+}
+
+/// What a backup is called unless it is given a name: the cloner, the card by its CID (see
+/// [`Source::card`]), its capacity and when it was made, e.g.
+/// `unit-42-1b534d4542333247...-32GB-20261016T142501`.
+pub fn default_name(source: &Source, created_at: u64) -> String {
+    let capacity = source
+        .capacity
+        .map(|bytes| format!("-{}GB", (bytes + 500_000_000) / 1_000_000_000))
+        .unwrap_or_default();
+    let time = history::format_time_with_seconds(created_at)
+        .replace(['-', ':'], "")
+        .replace(' ', "T");
+    format!("{}-{}{capacity}-{time}", source.host, source.card())
+}
+
+/// The config to flash the image at `path` with.
+fn image_config(config: &Config, path: &Path) -> Config {
+    Config {
+        image: path.to_path_buf(),
+        ..config.clone()
+    }
+}
+
+/// Opens the image at `path` decompressed, as a flash would.
+fn open_image(config: &Config, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    Ok(image::open(&image_config(config, path))?.reader)
+}
+
+/// The image the card in `device` was last flashed with, going by the flash history. It has to
+/// still be what was flashed, sync may have replaced the file since.
+fn baseline(config: &Config, device: &Path) -> io::Result<BaselineImage> {
+    // A serial may be the reader's, and the last flash in it of another card.
+    let cid = devices::card_cid(device).ok_or_else(|| {
+        io::Error::other(format!(
+            "{device:?} has no CID, its flashes can't be told apart from other cards in its reader"
+        ))
+    })?;
+    let record = History::new(&config.history)
+        .last_flash(&cid)?
+        .ok_or_else(|| io::Error::other(format!("Card {cid} was never flashed here")))?;
+    let flashed = record.image_sha256.unwrap_or_default();
+    println!("Card {cid} was flashed with {:?}", record.image);
+
+    let image = image_config(config, &record.image);
+    let sha256 = match digest::lookup(&image).and_then(|known| known.sha256) {
+        Some(sha256) => sha256,
+        None => {
+            println!("Hashing {:?} to check it is what was flashed", record.image);
+            digest::hash(&image)?
+        }
+    };
+    if sha256 != flashed {
+        return Err(io::Error::other(format!(
+            "{:?} has SHA-256 {sha256} now, but card {cid} was flashed with {flashed}",
+            record.image
+        )));
+    }
+    Ok(BaselineImage {
+        reader: open_image(config, &record.image)?,
+        image: record.image,
+        sha256,
+    })
+}
+
+/// Backs `device` up into `store` as the backup `name`, or its [`default_name`]. Compression is
+/// up to the store. With `incremental` only what differs from the image it was flashed with is
+/// stored.
+pub fn backup_to_store(
+    config: &Config,
+    device: &Path,
+    store: &Store,
+    name: Option<&str>,
+    options: BackupOptions,
+    incremental: bool,
+) -> io::Result<Manifest> {
+    let source = Source::of(device);
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => default_name(&source, history::unix_time()),
+    };
+    let baseline = match incremental {
+        true => Some(baseline(config, device)?),
+        false => None,
+    };
+    retention::make_room(store)?;
+    let (input, _, end) = open_device(device, options.shrink)?;
+    let algorithm = options.verify.unwrap_or_default();
+    let mut reader = Hashing::new(input.take(end), algorithm);
+    // A backup that doesn't verify is removed again, its chunks go with the next gc.
+    let manifest = store.add(&name, &source, &mut reader, baseline)?;
+    if options.verify.is_some() {
+        if let Err(error) = verify(device, end, algorithm, &reader.finish()) {
+            store.remove(&name)?;
+            return Err(error);
+        }
+    }
+    record(
+        config,
+        &BackupRecord {
+            backup: name,
+            created_at: manifest.created_at,
+            source,
+            size: manifest.size,
+            sha256: manifest.sha256.clone(),
+        },
+    );
+    if let Err(error) = retention::prune(store) {
+        println!("Got error when pruning backups: {error:?}");
+    }
+    Ok(manifest)
+}
+
+/// Restores the backup `name` from `store` into the image file `output`. Incremental backups
+/// read what they didn't store from their image, or from `image` if it moved since.
+pub fn restore(
+    config: &Config,
+    store: &Store,
+    name: &str,
+    output: &Path,
+    image: Option<&Path>,
+) -> io::Result<Manifest> {
+    let baseline = match (&store.manifest(name)?.baseline, image) {
+        (Some(_), Some(image)) => Some(open_image(config, image)?),
+        (Some(baseline), None) => Some(open_image(config, &baseline.image)?),
+        (None, _) => None,
+    };
+    let mut file = BufWriter::new(File::create(output)?);
+    let manifest = store.restore(name, &mut file, baseline)?;
+    file.into_inner()?.sync_all()?;
+    let info = ImageInfo {
+        size: manifest.size,
+        sha256: manifest.sha256.clone(),
+        source: Some(manifest.source.clone()),
+    };
+    info.write(output)?;
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_backups_describe_what_they_hold() {
+        let scratch =
+            |name: &str| std::env::temp_dir().join(format!("backup-{}-{name}", std::process::id()));
+        let (card, output) = (scratch("card"), scratch("output.img.zst"));
+        let mut contents = vec![0; 3 * 512];
+        contents[..4].copy_from_slice(b"boot");
+        contents[1024..1028].copy_from_slice(b"root");
+        std::fs::write(&card, &contents).unwrap();
+
+        let options = BackupOptions {
+            shrink: false,
+            compression_level: Some(3),
+            verify: Some(HashAlgorithm::Xxh3),
+        };
+        let config = Config {
+            history: scratch("history.jsonl"),
+            ..Config::load(None).unwrap()
+        };
+        let summary = backup(&config, &card, &output, options).unwrap();
+        let info = ImageInfo::read(&output).unwrap().unwrap();
+        let decompressed = zstd::decode_all(File::open(&output).unwrap()).unwrap();
+        let history = History::new(&config.history);
+        let (backups, flashes) = (history.backups().unwrap(), history.records().unwrap());
+        std::fs::remove_file(&card).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        std::fs::remove_file(scratch("output.img.zst.info.json")).unwrap();
+        std::fs::remove_file(&config.history).unwrap();
+
+        assert!(decompressed == contents);
+        assert_eq!(info.size, contents.len() as u64);
+        assert_eq!(info.sha256, hex(&Sha256::digest(&contents)));
+        assert_eq!(summary.sha256, info.sha256);
+        assert_eq!(info.source.unwrap().device, card);
+        assert!(flashes.is_empty());
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].backup, output.display().to_string());
+        assert_eq!(backups[0].sha256, info.sha256);
+    }
+
+    #[test]
+    fn default_names_say_where_a_backup_is_from() {
+        let source = Source {
+            device: PathBuf::from("/dev/mmcblk0"),
+            cid: Some("1b534d454233324710c6b6a9a9013d00".to_string()),
+            serial: Some("0xc6b6a9a9".to_string()),
+            reader_serial: None,
+            capacity: Some(31_914_983_424),
+            host: "unit-42".to_string(),
+        };
+        let in_reader = Source {
+            device: PathBuf::from("/dev/sda"),
+            cid: None,
+            serial: None,
+            reader_serial: Some("000000001206".to_string()),
+            capacity: None,
+            host: "unit-42".to_string(),
+        };
+
+        assert_eq!(
+            default_name(&source, 1_791_995_101),
+            "unit-42-1b534d454233324710c6b6a9a9013d00-32GB-20261014T162501"
+        );
+        assert_eq!(
+            default_name(&in_reader, 1_791_995_101),
+            "unit-42-reader-000000001206-20261014T162501"
+        );
+        assert!(source.matches("unit-42"));
+        assert!(source.matches("0xc6b6a9a9"));
+        assert!(!source.matches("unit-43"));
+    }
+
+    #[test]
+    fn notices_a_card_reading_differently() {
+        let card = std::env::temp_dir().join(format!("backup-{}-flaky", std::process::id()));
+        let contents: Vec<u8> = (0..CHUNK_SIZE + 1000).map(|index| index as u8).collect();
+        std::fs::write(&card, &contents).unwrap();
+        let mut reader = Hashing::new(&contents[..], HashAlgorithm::Crc32c);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        let hashes = reader.finish();
+
+        let intact = verify(&card, contents.len() as u64, HashAlgorithm::Crc32c, &hashes);
+        let mut flipped = contents.clone();
+        flipped[CHUNK_SIZE + 10] ^= 1;
+        std::fs::write(&card, &flipped).unwrap();
+        let flaky = verify(&card, contents.len() as u64, HashAlgorithm::Crc32c, &hashes);
+        std::fs::remove_file(&card).unwrap();
+
+        assert_eq!(hashes.len(), 2);
+        assert!(intact.is_ok());
+        assert!(flaky
+            .unwrap_err()
+            .to_string()
+            .contains(&format!("at {CHUNK_SIZE}")));
+    }
+}
@@ -0,0 +1,194 @@
+//! Pruning the backup store, so a station making backups unattended doesn't fill its disk. Old
+//! backups go by the configured policy after every backup, and before one when the disk is
+//! short of space. The newest backup of every card is only ever removed by hand.
+//!
+//! Cards are told apart by their CID. Cards in USB readers have none, the serial they report is
+//! the reader's, so their backups can't be counted per card and are never pruned.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use nix::sys::statvfs::statvfs;
+
+use super::store::{Manifest, Store};
+use crate::config::RetentionConfig;
+use crate::history::unix_time;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Bytes free for the store in `directory`.
+fn free_space(directory: &Path) -> io::Result<u64> {
+    let stat = statvfs(directory)?;
+    // The counts are 32 bits on 32-bit Pis.
+    #[allow(clippy::unnecessary_cast)]
+    let free = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+    Ok(free)
+}
+
+/// The backups of every card with a CID, the newest first.
+fn by_card(manifests: &[Manifest]) -> HashMap<&str, Vec<&Manifest>> {
+    let mut by_card: HashMap<&str, Vec<&Manifest>> = HashMap::new();
+    for manifest in manifests {
+        if let Some(cid) = &manifest.source.cid {
+            by_card.entry(cid).or_default().push(manifest);
+        }
+    }
+    for backups in by_card.values_mut() {
+        backups.sort_by_key(|manifest| std::cmp::Reverse(manifest.created_at));
+    }
+    by_card
+}
+
+/// Backups that may be pruned, the oldest first: all but the newest of each card.
+fn prunable(manifests: &[Manifest]) -> Vec<&Manifest> {
+    let mut prunable: Vec<&Manifest> = by_card(manifests)
+        .into_values()
+        .flat_map(|backups| backups.into_iter().skip(1))
+        .collect();
+    prunable.sort_by_key(|manifest| (manifest.created_at, &manifest.name));
+    prunable
+}
+
+/// The backups the policy says go: beyond the newest `keep_last` of a card, or older than
+/// `max_age_days`.
+fn expired<'a>(manifests: &'a [Manifest], config: &RetentionConfig, now: u64) -> Vec<&'a str> {
+    let mut expired = vec![];
+    for backups in by_card(manifests).values() {
+        for (index, manifest) in backups.iter().enumerate() {
+            let too_many = config.keep_last.is_some_and(|keep| index >= keep.max(1));
+            let too_old = config
+                .max_age_days
+                .is_some_and(|days| now.saturating_sub(manifest.created_at) > days * DAY);
+            if index > 0 && (too_many || too_old) {
+                expired.push(manifest.name.as_str());
+            }
+        }
+    }
+    expired.sort();
+    expired
+}
+
+/// Removes the backups the policy says go, and frees their chunks. Returns their names.
+pub fn prune(store: &Store) -> io::Result<Vec<String>> {
+    let manifests = store.list()?;
+    let expired = expired(&manifests, &store.config().retention, unix_time());
+    for name in &expired {
+        store.remove(name)?;
+        println!("Pruned backup {name:?}");
+    }
+    if !expired.is_empty() {
+        let garbage = store.collect_garbage()?;
+        println!("Freed {} bytes of pruned backups", garbage.bytes);
+    }
+    Ok(expired.into_iter().map(str::to_string).collect())
+}
+
+/// With less than `min_free` bytes free, removes the oldest backups until `target_free` are.
+/// Fails if that isn't enough, rather than starting a backup that runs out of space halfway.
+pub fn make_room(store: &Store) -> io::Result<()> {
+    let config = &store.config().retention;
+    let Some(min_free) = config.min_free else {
+        return Ok(());
+    };
+    let target = config.target_free.unwrap_or(min_free).max(min_free);
+    let directory = &store.config().directory;
+    std::fs::create_dir_all(directory)?;
+    let mut free = free_space(directory)?;
+    if free >= min_free {
+        return Ok(());
+    }
+    println!("Only {free} bytes are free for the backup store, pruning until {target} are");
+    let manifests = store.list()?;
+    for manifest in prunable(&manifests) {
+        if free >= target {
+            break;
+        }
+        store.remove(&manifest.name)?;
+        store.collect_garbage()?;
+        free = free_space(directory)?;
+        println!("Pruned backup {:?}, {free} bytes free now", manifest.name);
+    }
+    if free < min_free {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "Only {free} bytes are free for the backup store with all backups that may be \
+                 pruned gone, {min_free} are needed"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::backup::Source;
+
+    fn manifest(name: &str, cid: Option<&str>, days_old: u64) -> Manifest {
+        Manifest {
+            name: name.to_string(),
+            created_at: 100 * DAY - days_old * DAY,
+            source: Source {
+                device: PathBuf::from("/dev/sda"),
+                cid: cid.map(str::to_string),
+                serial: None,
+                reader_serial: Some("reader".to_string()),
+                capacity: None,
+                host: "cloner".to_string(),
+            },
+            size: 0,
+            sha256: String::new(),
+            chunks: vec![],
+            baseline: None,
+        }
+    }
+
+    #[test]
+    fn keeps_the_newest_backups_of_every_card() {
+        let manifests = [
+            manifest("a-1", Some("a"), 30),
+            manifest("a-2", Some("a"), 20),
+            manifest("a-3", Some("a"), 1),
+            manifest("b-1", Some("b"), 40),
+        ];
+        let keep_two = RetentionConfig {
+            keep_last: Some(2),
+            ..RetentionConfig::default()
+        };
+        let week = RetentionConfig {
+            max_age_days: Some(7),
+            ..RetentionConfig::default()
+        };
+
+        assert_eq!(expired(&manifests, &keep_two, 100 * DAY), ["a-1"]);
+        // b-1 is old, but the only backup of its card.
+        assert_eq!(expired(&manifests, &week, 100 * DAY), ["a-1", "a-2"]);
+        let prunable: Vec<&str> = prunable(&manifests)
+            .iter()
+            .map(|manifest| manifest.name.as_str())
+            .collect();
+        assert_eq!(prunable, ["a-1", "a-2"]);
+    }
+
+    #[test]
+    fn never_prunes_cards_told_apart_by_nothing_but_their_reader() {
+        // Different cards that went through the same USB reader, all reporting its serial.
+        let manifests = [
+            manifest("first", None, 30),
+            manifest("second", None, 20),
+            manifest("third", None, 1),
+        ];
+        let config = RetentionConfig {
+            keep_last: Some(1),
+            max_age_days: Some(7),
+            ..RetentionConfig::default()
+        };
+
+        assert!(expired(&manifests, &config, 100 * DAY).is_empty());
+        assert!(prunable(&manifests).is_empty());
+    }
+}
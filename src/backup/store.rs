@@ -0,0 +1,586 @@
+//! Backups kept as content-addressed chunks, so dumps of dozens of cards made from the same image
+//! share almost all of their storage. Every chunk is stored once, zstd compressed, as
+//! `chunks/<2 hex digits>/<SHA-256>.zst`, and every backup is a manifest in `backups/` listing
+//! the chunks it is made of. Chunks no manifest lists anymore are only deleted by
+//! [`Store::collect_garbage`].
+//!
+//! Chunks are either of a fixed size, which suits cards written from the same image block for
+//! block, or cut where the content says (a gear hash, as in FastCDC), which also finds data that
+//! moved by a few bytes.
+//!
+//! An incremental backup is made against the image the card was flashed with, and only stores
+//! the chunks that differ from the image at the same offset. Restoring it reads the rest from the
+//! image again. Its chunks are always of a fixed size, so they line up with the image.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::Source;
+use crate::config::{BackupStoreConfig, Chunking};
+use crate::hashing::hex;
+use crate::history::unix_time;
+
+/// Random numbers every byte value adds to the gear hash, the same on every run so chunks of
+/// earlier backups are cut at the same places
+const GEAR: [u64; 256] = {
+    // splitmix64
+    let mut table = [0; 256];
+    let mut state = 0u64;
+    let mut index = 0;
+    while index < table.len() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut value = state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[index] = value ^ (value >> 31);
+        index += 1;
+    }
+    table
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub name: String,
+    pub created_at: u64,
+    #[serde(flatten)]
+    pub source: Source,
+    /// Bytes of the card in the backup
+    pub size: u64,
+    /// SHA-256 of those bytes
+    pub sha256: String,
+    /// SHA-256 of every chunk, in order
+    pub chunks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<Baseline>,
+}
+
+impl Manifest {
+    /// The chunks that are in the store, rather than read from the baseline.
+    fn stored_chunks(&self) -> impl Iterator<Item = &String> {
+        let from_baseline: HashSet<usize> = self
+            .baseline
+            .iter()
+            .flat_map(|baseline| baseline.chunks.iter().copied())
+            .collect();
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter(move |(index, _)| !from_baseline.contains(index))
+            .map(|(_, hash)| hash)
+    }
+}
+
+/// The image an incremental backup only stores the differences to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Baseline {
+    /// Where the image was when the backup was made
+    pub image: PathBuf,
+    /// SHA-256 of the decompressed image, which it is known by
+    pub sha256: String,
+    /// Size of every chunk, but possibly the last
+    pub chunk_size: usize,
+    /// Indices of the chunks that are the same as in the image, and aren't stored
+    pub chunks: Vec<usize>,
+}
+
+/// The image to make an incremental backup against, decompressed.
+pub struct BaselineImage {
+    pub image: PathBuf,
+    pub sha256: String,
+    pub reader: Box<dyn Read + Send>,
+}
+
+/// Reads the next `length` bytes of `reader` into `buffer`, fewer at its end.
+fn read_chunk(reader: &mut dyn Read, length: usize, buffer: &mut Vec<u8>) -> io::Result<()> {
+    buffer.clear();
+    reader.take(length as u64).read_to_end(buffer)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GarbageSummary {
+    pub chunks: usize,
+    pub bytes: u64,
+}
+
+/// Splits what `reader` reads into chunks.
+struct Chunker<R> {
+    reader: BufReader<R>,
+    chunking: Chunking,
+    /// Size of fixed chunks, the average one of content-defined ones
+    size: usize,
+}
+
+impl<R: Read> Chunker<R> {
+    /// Fills `chunk` with the next chunk, leaving it empty at the end.
+    fn next(&mut self, chunk: &mut Vec<u8>) -> io::Result<()> {
+        chunk.clear();
+        match self.chunking {
+            Chunking::Fixed => {
+                (&mut self.reader)
+                    .take(self.size as u64)
+                    .read_to_end(chunk)?;
+            }
+            Chunking::Cdc => self.next_cdc(chunk)?,
+        }
+        Ok(())
+    }
+
+    /// Cuts where the top bits of the gear hash of the last bytes are all zero, which happens
+    /// every `size` bytes on average, but never before a quarter of it or after four times it.
+    fn next_cdc(&mut self, chunk: &mut Vec<u8>) -> io::Result<()> {
+        let (min, max) = (self.size / 4, self.size * 4);
+        let bits = self.size.max(2).ilog2();
+        let mut hash = 0u64;
+        loop {
+            let buffer = self.reader.fill_buf()?;
+            if buffer.is_empty() {
+                return Ok(());
+            }
+            let mut cut = None;
+            for (index, byte) in buffer.iter().enumerate() {
+                hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+                let length = chunk.len() + index + 1;
+                if (length >= min && hash >> (64 - bits) == 0) || length >= max {
+                    cut = Some(index + 1);
+                    break;
+                }
+            }
+            let taken = cut.unwrap_or(buffer.len());
+            chunk.extend_from_slice(&buffer[..taken]);
+            self.reader.consume(taken);
+            if cut.is_some() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+pub struct Store<'a> {
+    config: &'a BackupStoreConfig,
+}
+
+impl<'a> Store<'a> {
+    pub fn new(config: &'a BackupStoreConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &'a BackupStoreConfig {
+        self.config
+    }
+
+    /// Backups share the store, collecting garbage has it to itself. Otherwise it would delete
+    /// the chunks of a backup whose manifest isn't written yet.
+    fn lock(&self, exclusive: bool) -> io::Result<Flock<File>> {
+        fs::create_dir_all(&self.config.directory)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.config.directory.join("lock"))?;
+        let argument = match exclusive {
+            true => FlockArg::LockExclusive,
+            false => FlockArg::LockShared,
+        };
+        Flock::lock(file, argument).map_err(|(_, errno)| io::Error::from(errno))
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.config
+            .directory
+            .join("chunks")
+            .join(&hash[..2])
+            .join(format!("{hash}.zst"))
+    }
+
+    fn manifest_path(&self, name: &str) -> io::Result<PathBuf> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{name:?} can't name a backup"),
+            ));
+        }
+        Ok(self
+            .config
+            .directory
+            .join("backups")
+            .join(format!("{name}.json")))
+    }
+
+    /// Stores what `reader` reads as the backup `name` of the card `source` describes, only what differs from
+    /// `baseline` if there is one. Returns its manifest.
+    pub fn add(
+        &self,
+        name: &str,
+        source: &Source,
+        reader: impl Read,
+        baseline: Option<BaselineImage>,
+    ) -> io::Result<Manifest> {
+        let manifest_path = self.manifest_path(name)?;
+        if manifest_path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("There already is a backup named {name:?}"),
+            ));
+        }
+        let _lock = self.lock(false)?;
+        let mut chunker = Chunker {
+            reader: BufReader::new(reader),
+            chunking: match baseline {
+                Some(_) => Chunking::Fixed,
+                None => self.config.chunking,
+            },
+            size: self.config.chunk_size,
+        };
+        let mut manifest = Manifest {
+            name: name.to_string(),
+            created_at: unix_time(),
+            source: source.clone(),
+            size: 0,
+            sha256: String::new(),
+            chunks: vec![],
+            baseline: None,
+        };
+        let mut baseline_reader = baseline.map(|baseline| {
+            manifest.baseline = Some(Baseline {
+                image: baseline.image,
+                sha256: baseline.sha256,
+                chunk_size: self.config.chunk_size,
+                chunks: vec![],
+            });
+            baseline.reader
+        });
+        let (mut digest, mut chunk, mut original, mut stored) = (Sha256::new(), vec![], vec![], 0);
+        loop {
+            chunker.next(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            digest.update(&chunk);
+            let hash = hex(&Sha256::digest(&chunk));
+            let index = manifest.chunks.len();
+            manifest.size += chunk.len() as u64;
+            let unchanged = match (&mut baseline_reader, &mut manifest.baseline) {
+                (Some(reader), Some(baseline)) => {
+                    read_chunk(reader, chunk.len(), &mut original)?;
+                    if original == chunk {
+                        baseline.chunks.push(index);
+                    }
+                    original == chunk
+                }
+                _ => false,
+            };
+            if !unchanged && self.store_chunk(&hash, &chunk)? {
+                stored += 1;
+            }
+            manifest.chunks.push(hash);
+        }
+        manifest.sha256 = hex(&digest.finalize());
+        let (size, count) = (manifest.size, manifest.chunks.len());
+        match &manifest.baseline {
+            Some(baseline) => println!(
+                "Backed up {size} bytes in {count} chunks, {} of them as in the image and {stored} \
+                 new",
+                baseline.chunks.len()
+            ),
+            None => println!("Backed up {size} bytes in {count} chunks, {stored} of them new"),
+        }
+        fs::create_dir_all(manifest_path.parent().unwrap())?;
+        let temporary = manifest_path.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(&manifest)?)?;
+        fs::rename(&temporary, &manifest_path)?;
+        Ok(manifest)
+    }
+
+    /// Stores `chunk` unless it is stored already. Returns whether it was new.
+    fn store_chunk(&self, hash: &str, chunk: &[u8]) -> io::Result<bool> {
+        let path = self.chunk_path(hash);
+        if path.exists() {
+            return Ok(false);
+        }
+        fs::create_dir_all(path.parent().unwrap())?;
+        // Written under a name of its own, a chunk cut short by a crash would otherwise be taken
+        // for stored.
+        let temporary = path.with_extension(format!("tmp.{}", std::process::id()));
+        let compressed = zstd::encode_all(chunk, self.config.compression_level)?;
+        fs::write(&temporary, compressed)?;
+        fs::rename(&temporary, &path)?;
+        Ok(true)
+    }
+
+    /// Every backup, the oldest first.
+    pub fn list(&self) -> io::Result<Vec<Manifest>> {
+        let directory = self.config.directory.join("backups");
+        let entries = match fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => return Err(error),
+        };
+        let mut manifests = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                manifests.push(serde_json::from_slice::<Manifest>(&fs::read(&path)?)?);
+            }
+        }
+        manifests.sort_by(|a, b| (a.created_at, &a.name).cmp(&(b.created_at, &b.name)));
+        Ok(manifests)
+    }
+
+    pub fn manifest(&self, name: &str) -> io::Result<Manifest> {
+        let path = self.manifest_path(name)?;
+        match fs::read(&path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("There is no backup named {name:?}"),
+            )),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Writes the backup `name` to `output`, checking every chunk on the way. Incremental
+    /// backups need their image, decompressed, as `baseline`.
+    pub fn restore(
+        &self,
+        name: &str,
+        output: &mut impl Write,
+        mut baseline: Option<Box<dyn Read + Send>>,
+    ) -> io::Result<Manifest> {
+        let manifest = self.manifest(name)?;
+        let from_baseline: HashSet<usize> = match (&manifest.baseline, &baseline) {
+            (Some(baseline), Some(_)) => baseline.chunks.iter().copied().collect(),
+            (Some(baseline), None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Backup {name:?} only has what differs from image {:?}, which is needed \
+                         to restore it",
+                        baseline.image
+                    ),
+                ))
+            }
+            (None, _) => HashSet::new(),
+        };
+        let mut digest = Sha256::new();
+        let (mut chunk, mut position) = (vec![], 0);
+        for (index, hash) in manifest.chunks.iter().enumerate() {
+            // The image is read along, for the chunks of it that come later.
+            if let (Some(reader), Some(info)) = (&mut baseline, &manifest.baseline) {
+                let length = (manifest.size - position).min(info.chunk_size as u64);
+                read_chunk(reader, length as usize, &mut chunk)?;
+            }
+            if !from_baseline.contains(&index) {
+                chunk = zstd::decode_all(File::open(self.chunk_path(hash))?)?;
+            }
+            if hex(&Sha256::digest(&chunk)) != *hash {
+                let message = match from_baseline.contains(&index) {
+                    true => {
+                        format!("The image differs from the one backup {name:?} was made against")
+                    }
+                    false => format!("Chunk {hash} of the store is corrupt"),
+                };
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            position += chunk.len() as u64;
+            digest.update(&chunk);
+            output.write_all(&chunk)?;
+        }
+        if hex(&digest.finalize()) != manifest.sha256 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Backup {name:?} doesn't restore to what was backed up"),
+            ));
+        }
+        output.flush()?;
+        Ok(manifest)
+    }
+
+    /// Removes the backup `name`. Its chunks stay until garbage is collected.
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        let path = self.manifest_path(name)?;
+        fs::remove_file(&path).map_err(|error| match error.kind() {
+            io::ErrorKind::NotFound => io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("There is no backup named {name:?}"),
+            ),
+            _ => error,
+        })
+    }
+
+    /// Deletes the chunks no backup is made of, and what crashed backups left behind.
+    pub fn collect_garbage(&self) -> io::Result<GarbageSummary> {
+        let _lock = self.lock(true)?;
+        let referenced: HashSet<String> = self
+            .list()?
+            .into_iter()
+            .flat_map(|manifest| manifest.stored_chunks().cloned().collect::<Vec<_>>())
+            .collect();
+        let mut summary = GarbageSummary::default();
+        let directories = match fs::read_dir(self.config.directory.join("chunks")) {
+            Ok(directories) => directories,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(summary),
+            Err(error) => return Err(error),
+        };
+        for directory in directories {
+            for entry in fs::read_dir(directory?.path())? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                let used = name
+                    .strip_suffix(".zst")
+                    .is_some_and(|hash| referenced.contains(hash));
+                if !used {
+                    summary.bytes += entry.metadata()?.len();
+                    summary.chunks += 1;
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::Path;
+
+    use super::*;
+    use crate::config::RetentionConfig;
+
+    fn config(name: &str, chunking: Chunking) -> BackupStoreConfig {
+        BackupStoreConfig {
+            directory: std::env::temp_dir().join(format!("store-{}-{name}", std::process::id())),
+            chunking,
+            chunk_size: 4096,
+            compression_level: 1,
+            retention: RetentionConfig::default(),
+        }
+    }
+
+    /// Pseudo-random bytes, which don't compress or repeat.
+    fn noise(length: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn shares_chunks_between_backups_and_collects_the_rest() {
+        let config = config("fixed", Chunking::Fixed);
+        let store = Store::new(&config);
+        let source = Source::of(Path::new("/dev/sda"));
+        let first = noise(64 * 1024, 1);
+        let mut second = first.clone();
+        second[100] ^= 0xFF;
+        store.add("first", &source, &first[..], None).unwrap();
+        store.add("second", &source, &second[..], None).unwrap();
+        let chunks = || {
+            fs::read_dir(config.directory.join("chunks"))
+                .unwrap()
+                .map(|directory| fs::read_dir(directory.unwrap().path()).unwrap().count())
+                .sum::<usize>()
+        };
+        let shared = chunks();
+
+        let mut restored = vec![];
+        store.restore("second", &mut restored, None).unwrap();
+        let names: Vec<String> = store.list().unwrap().into_iter().map(|m| m.name).collect();
+        store.remove("first").unwrap();
+        let garbage = store.collect_garbage().unwrap();
+        let remaining = chunks();
+        fs::remove_dir_all(&config.directory).unwrap();
+
+        // 16 chunks each, all but the first one shared.
+        assert_eq!(shared, 17);
+        assert!(restored == second);
+        assert_eq!(names, ["first", "second"]);
+        assert_eq!(garbage.chunks, 1);
+        assert_eq!(remaining, 16);
+    }
+
+    #[test]
+    fn incremental_backups_only_store_what_changed() {
+        let config = config("incremental", Chunking::Cdc);
+        let store = Store::new(&config);
+        let source = Source::of(Path::new("/dev/sda"));
+        let image = noise(32 * 1024, 3);
+        let mut card = image.clone();
+        card[3 * 4096 + 10] ^= 0xFF;
+        card.extend(noise(1000, 4));
+        let baseline =
+            |image: &[u8]| -> Box<dyn Read + Send> { Box::new(Cursor::new(image.to_vec())) };
+        let manifest = store
+            .add(
+                "card",
+                &source,
+                &card[..],
+                Some(BaselineImage {
+                    image: PathBuf::from("image.img"),
+                    sha256: hex(&Sha256::digest(&image)),
+                    reader: baseline(&image),
+                }),
+            )
+            .unwrap();
+
+        let mut restored = vec![];
+        store
+            .restore("card", &mut restored, Some(baseline(&image)))
+            .unwrap();
+        let without_image = store.restore("card", &mut vec![], None);
+        let other_image = store.restore("card", &mut vec![], Some(baseline(&noise(32 * 1024, 5))));
+        fs::remove_dir_all(&config.directory).unwrap();
+
+        // The changed chunk and the one past the end of the image.
+        assert_eq!(manifest.stored_chunks().count(), 2);
+        assert_eq!(manifest.baseline.unwrap().chunks, [0, 1, 2, 4, 5, 6, 7]);
+        assert!(restored == card);
+        assert!(without_image.is_err());
+        assert!(other_image.is_err());
+    }
+
+    #[test]
+    fn content_defined_chunks_survive_a_shift() {
+        let config = config("cdc", Chunking::Cdc);
+        let data = noise(256 * 1024, 2);
+        let cut = |data: &[u8]| {
+            let mut chunker = Chunker {
+                reader: BufReader::new(data),
+                chunking: config.chunking,
+                size: config.chunk_size,
+            };
+            let (mut chunks, mut chunk) = (vec![], vec![]);
+            loop {
+                chunker.next(&mut chunk).unwrap();
+                if chunk.is_empty() {
+                    return chunks;
+                }
+                assert!(chunk.len() <= 4 * config.chunk_size);
+                chunks.push(hex(&Sha256::digest(&chunk)));
+            }
+        };
+        let original = cut(&data);
+        let mut shifted = b"inserted".to_vec();
+        shifted.extend(&data);
+        let shifted: HashSet<String> = cut(&shifted).into_iter().collect();
+
+        assert!(original.len() > 16);
+        let kept = original.iter().filter(|hash| shifted.contains(*hash));
+        assert!(kept.count() >= original.len() - 2);
+    }
+}
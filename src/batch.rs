@@ -0,0 +1,47 @@
+//! Duplication batches: a number of cards to flash with one profile's image.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ProfileConfig;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Batch {
+    pub profile: String,
+    pub image: PathBuf,
+    /// Cards still to be flashed successfully
+    pub remaining: u32,
+}
+
+impl Batch {
+    pub fn new(profile: &ProfileConfig, quantity: u32) -> Self {
+        Self {
+            profile: profile.name.clone(),
+            image: profile.image.clone(),
+            remaining: quantity,
+        }
+    }
+
+    /// Parses a work order barcode, `<profile>` or `<profile>*<quantity>`. Profile names are
+    /// matched ignoring case, as scanners may be set to either.
+    pub fn from_work_order(code: &str, profiles: &[ProfileConfig]) -> Result<Self, String> {
+        let (name, quantity) = match code.trim().split_once('*') {
+            Some((name, quantity)) => (
+                name,
+                quantity
+                    .parse()
+                    .map_err(|_| format!("Invalid quantity {quantity:?} in work order {code:?}"))?,
+            ),
+            None => (code.trim(), 1),
+        };
+        if quantity == 0 {
+            return Err(format!("Work order {code:?} is for no cards"));
+        }
+        let profile = profiles
+            .iter()
+            .find(|profile| profile.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("No profile named {name:?}"))?;
+        Ok(Self::new(profile, quantity))
+    }
+}
@@ -0,0 +1,199 @@
+//! Block maps in the bmaptool format, listing which blocks of a raw image hold data so writers
+//! can skip the rest.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::catalog::Catalog;
+use crate::ext4;
+use crate::hashing::hex;
+use crate::image::Compression;
+use crate::partition_table;
+
+pub const BLOCK_SIZE: u64 = 4096;
+/// Stands in for the file's own digest while computing it, as bmaptool does.
+const CHECKSUM_PLACEHOLDER: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// `<image>.bmap` next to the image.
+pub fn bmap_file(image: &Path) -> PathBuf {
+    let mut name = image.file_name().unwrap_or_default().to_os_string();
+    name.push(".bmap");
+    image.with_file_name(name)
+}
+
+/// Byte ranges of `image` that have to be written. Everything up to the first partition is kept,
+/// ext filesystems only contribute their allocated blocks and any other partition is kept whole.
+fn mapped_bytes(image: &mut (impl Read + Seek), size: u64) -> io::Result<Vec<Range<u64>>> {
+    let Some(table) = partition_table::read(image, partition_table::IMAGE_SECTOR_SIZE)? else {
+        // Maybe a bare filesystem, otherwise we can't tell what is used.
+        return Ok(ext4::allocated_ranges(image, 0)
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| {
+                let everything = 0..size;
+                vec![everything]
+            }));
+    };
+    let mut partitions = table.partitions;
+    partitions.sort_by_key(|partition| partition.start);
+
+    let first_start = partitions
+        .first()
+        .map_or(table.table_end, |partition| partition.start);
+    let before_partitions = 0..first_start.max(table.table_end);
+    let mut ranges = vec![before_partitions];
+    for partition in partitions {
+        // A partition table from a bigger card, nothing of it is in the image.
+        if partition.start >= size {
+            continue;
+        }
+        // Truncated images and corrupt or unsupported filesystems fail, keep the partition then.
+        match ext4::allocated_ranges(image, partition.start) {
+            Ok(Some(allocated)) => ranges.extend(
+                allocated
+                    .into_iter()
+                    .map(|range| partition.start + range.start..partition.start + range.end),
+            ),
+            _ => ranges.push(partition.start..partition.end),
+        }
+    }
+    Ok(ranges)
+}
+
+/// Turns byte ranges into sorted, merged ranges of whole blocks within an image of `size` bytes.
+fn to_blocks(mut ranges: Vec<Range<u64>>, size: u64) -> Vec<Range<u64>> {
+    let blocks_count = size.div_ceil(BLOCK_SIZE);
+    ranges.sort_by_key(|range| range.start);
+    let mut blocks: Vec<Range<u64>> = vec![];
+    for range in ranges {
+        let first = range.start / BLOCK_SIZE;
+        let end = range.end.div_ceil(BLOCK_SIZE).min(blocks_count);
+        if first >= end {
+            continue;
+        }
+        match blocks.last_mut() {
+            Some(last) if last.end >= first => last.end = last.end.max(end),
+            _ => blocks.push(first..end),
+        }
+    }
+    blocks
+}
+
+fn range_xml(image: &mut impl Read, blocks: &Range<u64>, remaining: u64) -> io::Result<String> {
+    let length = ((blocks.end - blocks.start) * BLOCK_SIZE).min(remaining);
+    let mut digest = Sha256::new();
+    let mut buffer = [0; BLOCK_SIZE as usize];
+    let mut remaining = length;
+    while remaining > 0 {
+        let chunk = &mut buffer[..remaining.min(BLOCK_SIZE) as usize];
+        image.read_exact(chunk)?;
+        digest.update(&*chunk);
+        remaining -= chunk.len() as u64;
+    }
+    let range = if blocks.end - blocks.start == 1 {
+        blocks.start.to_string()
+    } else {
+        format!("{}-{}", blocks.start, blocks.end - 1)
+    };
+    Ok(format!(
+        "        <Range chksum=\"{}\"> {range} </Range>\n",
+        hex(&digest.finalize())
+    ))
+}
+
+/// Builds the bmap of a raw image.
+pub fn generate(image: &Path) -> io::Result<String> {
+    let mut file = File::open(image)?;
+    let size = file.metadata()?.len();
+    let mut header = [0; 6];
+    let read = file.read(&mut header)?;
+    if Compression::detect(&header[..read]) != Compression::Raw {
+        return Err(io::Error::other(format!(
+            "{image:?} is compressed, a bmap can only describe a raw image"
+        )));
+    }
+
+    let blocks = to_blocks(mapped_bytes(&mut file, size)?, size);
+    let mapped_count: u64 = blocks.iter().map(|range| range.end - range.start).sum();
+    let mut ranges = String::new();
+    let mut file = BufReader::new(file);
+    for range in &blocks {
+        let offset = range.start * BLOCK_SIZE;
+        file.seek(SeekFrom::Start(offset))?;
+        ranges.push_str(&range_xml(&mut file, range, size - offset)?);
+    }
+
+    let bmap = format!(
+        "<?xml version=\"1.0\" ?>\n\
+         <bmap version=\"2.0\">\n    \
+             <ImageSize> {size} </ImageSize>\n    \
+             <BlockSize> {BLOCK_SIZE} </BlockSize>\n    \
+             <BlocksCount> {} </BlocksCount>\n    \
+             <MappedBlocksCount> {mapped_count} </MappedBlocksCount>\n    \
+             <ChecksumType> sha256 </ChecksumType>\n    \
+             <BmapFileChecksum> {CHECKSUM_PLACEHOLDER} </BmapFileChecksum>\n    \
+             <BlockMap>\n\
+         {ranges}    \
+             </BlockMap>\n\
+         </bmap>\n",
+        size.div_ceil(BLOCK_SIZE),
+    );
+    let checksum = hex(&Sha256::digest(bmap.as_bytes()));
+    Ok(bmap.replacen(CHECKSUM_PLACEHOLDER, &checksum, 1))
+}
+
+/// Writes `<image>.bmap`, returning its path.
+pub fn write_bmap(image: &Path) -> io::Result<PathBuf> {
+    let bmap = generate(image)?;
+    let path = bmap_file(image);
+    let temporary = path.with_extension("bmap.tmp");
+    fs::write(&temporary, bmap)?;
+    fs::rename(temporary, &path)?;
+    Ok(path)
+}
+
+/// Writes the bmap of every raw image in the catalog at `catalog_path`. Images that can't be
+/// mapped are reported and skipped.
+pub fn write_catalog_bmaps(catalog_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let catalog = Catalog::load(catalog_path)?;
+    let mut written = vec![];
+    for entry in &catalog.images {
+        match write_bmap(&entry.image) {
+            Ok(path) => written.push(path),
+            Err(error) => println!("Got error when writing bmap for {:?}: {error}", entry.image),
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn merges_ranges_into_whole_blocks() {
+        let ranges = vec![5000..9000, 0..1024, 8192..8193, 20000..30000, 12000..12000];
+        assert_eq!(to_blocks(ranges, 20001), [0..3, 4..5]);
+    }
+
+    #[test]
+    fn keeps_filesystems_it_cant_read_whole() {
+        let mut image = vec![0; 64 * 1024];
+        // An ext superblock with a block size of 1024 << 40.
+        image[1024 + 0x18..1024 + 0x1C].copy_from_slice(&40u32.to_le_bytes());
+        image[1024 + 0x38..1024 + 0x3A].copy_from_slice(&0xEF53u16.to_le_bytes());
+        let size = image.len() as u64;
+        let everything = 0..size;
+        assert_eq!(
+            mapped_bytes(&mut Cursor::new(image), size).unwrap(),
+            vec![everything]
+        );
+    }
+}
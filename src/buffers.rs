@@ -0,0 +1,156 @@
+//! The few large buffers a flash copies through, allocated once and handed around rather than
+//! allocated per chunk. Peak memory is their count times their size, which a Pi Zero has to be
+//! able to spare next to everything else.
+
+use std::alloc::{self, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::slice;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+
+use crate::config::BufferConfig;
+
+/// Buffers start on a page as direct I/O needs, and configured sizes are whole pages.
+pub const ALIGNMENT: usize = 4096;
+
+/// A zeroed, page aligned allocation.
+struct Allocation {
+    pointer: NonNull<u8>,
+    layout: Layout,
+}
+
+// Owned memory, nothing else points into it.
+unsafe impl Send for Allocation {}
+unsafe impl Sync for Allocation {}
+
+impl Allocation {
+    fn new(size: usize) -> Self {
+        let layout = Layout::from_size_align(size, ALIGNMENT).expect("buffer size overflows");
+        // SAFETY: `size` is at least one, so the layout isn't zero-sized.
+        let pointer = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(pointer) = NonNull::new(pointer) else {
+            alloc::handle_alloc_error(layout);
+        };
+        Self { pointer, layout }
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `new` with the same layout.
+        unsafe { alloc::dealloc(self.pointer.as_ptr(), self.layout) }
+    }
+}
+
+/// A buffer taken from a [`BufferPool`], going back to it when dropped.
+pub struct Buffer {
+    allocation: Option<Allocation>,
+    pool: SyncSender<Allocation>,
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let allocation = self.allocation.as_ref().unwrap();
+        // SAFETY: the allocation is initialised (zeroed) and only reachable through `self`.
+        unsafe { slice::from_raw_parts(allocation.pointer.as_ptr(), allocation.layout.size()) }
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let allocation = self.allocation.as_mut().unwrap();
+        // SAFETY: as in `deref`, and `self` is borrowed mutably.
+        unsafe { slice::from_raw_parts_mut(allocation.pointer.as_ptr(), allocation.layout.size()) }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(allocation) = self.allocation.take() {
+            // There is room for every buffer, and a pool that is gone frees it instead.
+            let _ = self.pool.try_send(allocation);
+        }
+    }
+}
+
+/// A fixed number of equally sized buffers. Taking one waits until another is given back, so
+/// the pool bounds how far reading the image gets ahead of writing the card.
+#[derive(Clone)]
+pub struct BufferPool {
+    sender: SyncSender<Allocation>,
+    receiver: Arc<Mutex<Receiver<Allocation>>>,
+    size: usize,
+}
+
+impl BufferPool {
+    /// Allocates `count` buffers of `size` bytes, at least one of at least one byte.
+    pub fn new(count: usize, size: usize) -> Self {
+        let (count, size) = (count.max(1), size.max(1));
+        let (sender, receiver) = mpsc::sync_channel(count);
+        for _ in 0..count {
+            sender.send(Allocation::new(size)).unwrap();
+        }
+        Self {
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+            size,
+        }
+    }
+
+    /// The configured buffers, their size rounded up to whole pages.
+    pub fn from_config(config: &BufferConfig) -> Self {
+        Self::new(config.count, config.size.next_multiple_of(ALIGNMENT))
+    }
+
+    /// Size of every buffer in the pool.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Takes a buffer, waiting for one to be given back if all are in use.
+    pub fn take(&self) -> Buffer {
+        // The pool holds a sender itself, so receiving never fails.
+        let allocation = self.receiver.lock().unwrap().recv().unwrap();
+        Buffer {
+            allocation: Some(allocation),
+            pool: self.sender.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn hands_out_aligned_buffers_and_takes_them_back() {
+        let pool = BufferPool::from_config(&BufferConfig {
+            count: 2,
+            size: 5000,
+            adaptive: false,
+        });
+        assert_eq!(pool.size(), 2 * ALIGNMENT);
+        let first = pool.take();
+        let second = pool.take();
+        assert_eq!(first.len(), pool.size());
+        assert_eq!(first.as_ptr() as usize % ALIGNMENT, 0);
+        let address = first.as_ptr() as usize;
+
+        // Both are taken, the next one waits until one is dropped.
+        let waiting = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.take().as_ptr() as usize)
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+        drop(first);
+        assert_eq!(waiting.join().unwrap(), address);
+        drop(second);
+    }
+}
@@ -0,0 +1,174 @@
+//! Detecting fake-capacity cards, which report more space than they have and silently wrap
+//! writes past their real size back onto the start.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::devices;
+
+const MARKER_SIZE: u64 = 4096;
+const MARKER_COUNT: u64 = 32;
+const MARKER_MAGIC: &[u8] = b"rpi-sd-cloner capacity marker";
+
+/// Marker for `offset`, unique to this check so stale markers from earlier runs never match.
+fn marker(nonce: u128, offset: u64) -> Vec<u8> {
+    let mut entry = MARKER_MAGIC.to_vec();
+    entry.extend(nonce.to_le_bytes());
+    entry.extend(offset.to_le_bytes());
+    entry
+        .iter()
+        .copied()
+        .cycle()
+        .take(MARKER_SIZE as usize)
+        .collect()
+}
+
+/// Offsets from the start of the device on a grid of a power of two, plus the last block. A card
+/// that drops address bits, or wraps at a multiple of the grid, maps a marker onto another one.
+fn marker_offsets(device_size: u64) -> Vec<u64> {
+    let blocks = device_size / MARKER_SIZE;
+    if blocks == 0 {
+        return vec![];
+    }
+    let step = 1 << (blocks / MARKER_COUNT).max(1).ilog2();
+    let mut offsets: Vec<u64> = (0..blocks)
+        .step_by(step)
+        .chain([blocks - 1])
+        .map(|block| block * MARKER_SIZE)
+        .collect();
+    offsets.dedup();
+    offsets
+}
+
+/// Writes all markers, then reads them back. Fake cards wrap reads like writes, so a marker only
+/// reads back wrong when a later write landed on it.
+fn markers_survive<D: Read + Write + Seek>(
+    device: &mut D,
+    device_size: u64,
+    flush: impl FnOnce(&mut D) -> io::Result<()>,
+) -> io::Result<bool> {
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        ^ std::process::id() as u128;
+    let offsets = marker_offsets(device_size);
+
+    for offset in &offsets {
+        device.seek(SeekFrom::Start(*offset))?;
+        device.write_all(&marker(nonce, *offset))?;
+    }
+    flush(device)?;
+
+    let mut buffer = vec![0; MARKER_SIZE as usize];
+    for offset in &offsets {
+        device.seek(SeekFrom::Start(*offset))?;
+        device.read_exact(&mut buffer)?;
+        if buffer != marker(nonce, *offset) {
+            println!("Capacity marker at {offset} was overwritten or lost");
+            return Ok(false);
+        }
+    }
+    device.seek(SeekFrom::Start(0))?;
+    Ok(true)
+}
+
+/// Writes markers all over `device`, the start included (the image overwrites it anyway), then
+/// reads them back past the page cache. Returns whether all of them survived, i.e. the card
+/// really has the capacity it claims.
+pub fn check(device: &mut File, device_size: u64) -> io::Result<bool> {
+    markers_survive(device, device_size, |device| {
+        device.sync_all()?;
+        devices::flush_buffers(device)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    /// A card that claims more than `real` bytes and wraps every address past them back onto
+    /// the start. Only blocks written to take memory.
+    struct WrappingCard {
+        real: u64,
+        advertised: u64,
+        blocks: HashMap<u64, Vec<u8>>,
+        position: u64,
+    }
+
+    impl WrappingCard {
+        fn new(real: u64, advertised: u64) -> Self {
+            Self {
+                real,
+                advertised,
+                blocks: HashMap::new(),
+                position: 0,
+            }
+        }
+
+        /// The block the current position ends up in and the offset in it.
+        fn physical(&self) -> (u64, usize) {
+            let physical = self.position % self.real;
+            (physical / MARKER_SIZE, (physical % MARKER_SIZE) as usize)
+        }
+    }
+
+    impl Read for WrappingCard {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            for byte in buffer.iter_mut() {
+                let (block, offset) = self.physical();
+                *byte = self.blocks.get(&block).map_or(0, |block| block[offset]);
+                self.position += 1;
+            }
+            Ok(buffer.len())
+        }
+    }
+
+    impl Write for WrappingCard {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            for byte in buffer {
+                let (block, offset) = self.physical();
+                self.blocks
+                    .entry(block)
+                    .or_insert_with(|| vec![0; MARKER_SIZE as usize])[offset] = *byte;
+                self.position += 1;
+            }
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for WrappingCard {
+        fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+            self.position = match position {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::End(offset) => self.advertised.saturating_add_signed(offset),
+                SeekFrom::Current(offset) => self.position.saturating_add_signed(offset),
+            };
+            Ok(self.position)
+        }
+    }
+
+    fn check(card: &mut WrappingCard) -> io::Result<bool> {
+        let advertised = card.advertised;
+        markers_survive(card, advertised, |card| card.flush())
+    }
+
+    #[test]
+    fn catches_cards_that_wrap_around_their_real_size() {
+        assert!(!check(&mut WrappingCard::new(8 * GIB, 64 * GIB)).unwrap());
+        // Not a power of two, wrapping at a multiple of the grid.
+        assert!(!check(&mut WrappingCard::new(6 * GIB, 60 * GIB)).unwrap());
+        assert!(!check(&mut WrappingCard::new(GIB, 64 * GIB)).unwrap());
+        assert!(check(&mut WrappingCard::new(64 * GIB, 64 * GIB)).unwrap());
+        assert!(check(&mut WrappingCard::new(60 * GIB, 60 * GIB)).unwrap());
+    }
+}
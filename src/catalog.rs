@@ -0,0 +1,128 @@
+//! Versions of the images we know about, and whether the source has a newer one than we have.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub image: PathBuf,
+    /// Version of the local copy
+    pub version: Option<String>,
+    /// Latest version seen on the configured source
+    pub available_version: Option<String>,
+}
+
+impl CatalogEntry {
+    /// The available version, if it is newer than the local one.
+    pub fn update_available(&self) -> Option<&str> {
+        let available = self.available_version.as_deref()?;
+        match &self.version {
+            Some(local) if compare_versions(available, local) != Ordering::Greater => None,
+            _ => Some(available),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Catalog {
+    pub images: Vec<CatalogEntry>,
+}
+
+impl Catalog {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(temporary, path)
+    }
+
+    pub fn entry(&self, image: &Path) -> Option<&CatalogEntry> {
+        self.images.iter().find(|entry| entry.image == image)
+    }
+
+    pub fn entry_mut(&mut self, image: &Path) -> &mut CatalogEntry {
+        let index = match self.images.iter().position(|entry| entry.image == image) {
+            Some(index) => index,
+            None => {
+                self.images.push(CatalogEntry {
+                    image: image.to_path_buf(),
+                    ..Default::default()
+                });
+                self.images.len() - 1
+            }
+        };
+        &mut self.images[index]
+    }
+
+    /// Loads the catalog at `path`, applies `update` and saves it again.
+    pub fn update(path: &Path, update: impl FnOnce(&mut Catalog)) -> io::Result<Catalog> {
+        let mut catalog = Self::load(path)?;
+        update(&mut catalog);
+        catalog.save(path)?;
+        Ok(catalog)
+    }
+}
+
+/// `<image>.version` next to the image, written by image sync.
+pub fn version_file(image: &Path) -> PathBuf {
+    let mut name = image.file_name().unwrap_or_default().to_os_string();
+    name.push(".version");
+    image.with_file_name(name)
+}
+
+/// The version of a local image, from its `.version` file or else its file name.
+pub fn image_version(image: &Path) -> Option<String> {
+    match fs::read_to_string(version_file(image)) {
+        Ok(version) if !version.trim().is_empty() => Some(version.trim().to_string()),
+        _ => version_from_filename(image),
+    }
+}
+
+/// Picks the version out of names like `appliance-1.4.2.img.xz` or
+/// `2024-07-04-raspios-bookworm-arm64.img.xz`: the first dash separated run of numbers.
+pub fn version_from_filename(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_string_lossy();
+    let name = name.split(".img").next().unwrap_or(&name);
+
+    let parts: Vec<&str> = name.split(['-', '_']).collect();
+    // Dates are spread over three parts.
+    for window in parts.windows(3) {
+        if window[0].len() == 4 && window.iter().all(|part| is_number(part)) {
+            return Some(window.join("-"));
+        }
+    }
+    parts
+        .iter()
+        .map(|part| part.trim_start_matches('v'))
+        .find(|part| {
+            part.split('.').count() > 1 && part.split('.').all(is_number)
+                || is_number(part) && part.len() < 4
+        })
+        .map(str::to_string)
+}
+
+fn is_number(part: &str) -> bool {
+    !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// Compares versions numerically component by component, so `1.10` is newer than `1.9`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let components = |version: &str| -> Vec<u64> {
+        version
+            .split(['.', '-'])
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    };
+    components(a).cmp(&components(b))
+}
@@ -0,0 +1,1329 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hashing::HashAlgorithm;
+
+/// Config file that is picked up from the working directory when no path is given.
+pub const DEFAULT_CONFIG_PATH: &str = "rpi-sd-cloner.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Image that gets written to every card
+    pub image: PathBuf,
+    /// How cards are found and opened
+    pub device_backend: DeviceBackend,
+    pub simulation: SimulationConfig,
+    /// Devices smaller than this are never considered as a target
+    pub min_device_size: u64,
+    /// Only the device this resolves to is ever flashed, like a `/dev/disk/by-path/` link naming
+    /// the USB port or reader slot the cards go into. Other devices are left alone whatever their
+    /// size.
+    pub target: Option<PathBuf>,
+    /// Devices larger than this, or that look like hard drives, need a double press or a long
+    /// hold of the button before they are written
+    pub confirm_larger_than: u64,
+    /// Lock file that keeps a second instance from starting
+    pub lock_file: PathBuf,
+    /// JSON lines file every flash attempt is appended to
+    pub history: PathBuf,
+    /// Directory a standalone JSON report of every flash is written to, as its manufacturing
+    /// record
+    pub reports: Option<PathBuf>,
+    /// Also write everything printed to a rotating file, for images without journald
+    pub log_file: Option<LogFileConfig>,
+    /// Also send everything printed to a syslog server
+    pub syslog: Option<SyslogConfig>,
+    /// `debug` adds detail for chasing problems. Changed at runtime with `SIGUSR2` or through the
+    /// agent's `/log-level`.
+    pub log_level: LogLevel,
+    /// Cards that failed verification, and which of them we refuse to flash
+    pub quarantine: PathBuf,
+    /// Quarantine a card after this many verify failures in a row, 0 never does. Only cards with
+    /// a CID are, those in USB readers can't be told apart from the reader.
+    pub quarantine_after_failures: u32,
+    /// Known image versions, and whether newer ones are available
+    pub catalog: PathBuf,
+    /// SHA-256 of each image, hashed once per version of it instead of on every flash
+    pub image_digests: PathBuf,
+    /// Threads used to decode xz images, defaults to one per CPU. Lower it on small Pis.
+    pub decompression_threads: Option<u32>,
+    /// Buffers cards are written through, which take most of the memory a flash needs
+    pub buffers: BufferConfig,
+    /// CPU and I/O priority of flashing, to share a Pi with other services or to have it to
+    /// itself
+    pub priority: PriorityConfig,
+    /// How raw local images, and cached decompressed ones, get to the card. Others are always
+    /// read through the buffers.
+    pub copy_method: CopyMethod,
+    /// Hash used to compare each written chunk with what is read back
+    pub verify_hash: HashAlgorithm,
+    /// How much of the card is read back after writing
+    pub verify_mode: VerifyMode,
+    /// Share of chunks read back in `sampled` mode, in percent
+    pub verify_sample_percent: u8,
+    /// How often a chunk that fails verification is written again before the flash fails
+    pub verify_rewrite_attempts: u32,
+    /// Discard the whole card before writing, on devices that support it
+    pub discard: bool,
+    /// Securely erase the card before writing, refusing to flash if it can't be. Only cards on
+    /// the MMC bus (`mmcblk*`) support this.
+    pub secure_erase: bool,
+    /// Check cards we haven't seen before for fake capacity, by writing markers all over them
+    pub capacity_check: bool,
+    /// Keep decompressed copies of compressed images, so later flashes skip decompression
+    pub cache: Option<CacheConfig>,
+    /// Deduplicating store `backup` writes to when it isn't given a file
+    pub backup_store: Option<BackupStoreConfig>,
+    /// How images given as `http(s)://` or `s3://` URLs are fetched
+    pub image_source: ImageSourceConfig,
+    /// Network share the image lives on
+    pub share: Option<ShareConfig>,
+    /// Periodically pull the image from a server
+    pub sync: Option<SyncConfig>,
+    /// Update the cloner itself from a release server
+    pub update: Option<UpdateConfig>,
+    /// Act as the primary of a lab, serving the image to the other cloners
+    pub peer_server: Option<PeerServerConfig>,
+    /// Let a coordinator see our status and send us batches
+    pub agent: Option<AgentConfig>,
+    /// Settings for the `coordinator` subcommand
+    pub coordinator: CoordinatorConfig,
+    pub timeouts: TimeoutConfig,
+    /// Where to report flashes to
+    pub notify: NotifyConfig,
+    /// Print a label for every card that was flashed successfully
+    pub label: Option<LabelConfig>,
+    /// Panel showing the state and the last flash record
+    pub display: DisplayConfig,
+    /// PN532 writing the image version and card serial to an NFC sticker tapped after a flash
+    pub nfc: Option<NfcConfig>,
+    /// Images a work order or the keypad can select
+    pub profiles: Vec<ProfileConfig>,
+    /// USB barcode scanner reading work orders, which start a batch
+    pub barcode: Option<InputDeviceConfig>,
+    /// USB numpad: digits and Enter select a profile by its position (from 1) in `profiles`, Enter
+    /// also starts a flash, Esc cancels
+    pub keypad: Option<InputDeviceConfig>,
+    /// Drop root once the hardware is set up
+    pub privileges: Option<PrivilegesConfig>,
+    pub hardware: HardwareConfig,
+    /// Run to power off when the button is held for five seconds without a card, or when the
+    /// UPS runs out. Empty leaves the Pi on.
+    pub shutdown_command: Vec<String>,
+    /// Power-fail line of a UPS HAT
+    pub power_fail: Option<PowerFailConfig>,
+    /// DS3231 real-time clock, setting the system clock at startup when it wasn't synchronized
+    pub rtc: Option<RtcConfig>,
+    /// Hands the card over to a device under test after flashing
+    pub sd_mux: Option<SdMuxConfig>,
+    /// Switches the power of USB hub ports, for hubs uhubctl supports
+    pub usb_power: Option<UsbPowerConfig>,
+    pub post_flash: PostFlashConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            image: PathBuf::from("disk_image.img"),
+            device_backend: DeviceBackend::default(),
+            simulation: SimulationConfig::default(),
+            min_device_size: 128 * 1000 * 1000 * 1000,
+            target: None,
+            confirm_larger_than: 1000 * 1000 * 1000 * 1000,
+            lock_file: PathBuf::from("/run/lock/rpi-sd-cloner.lock"),
+            history: PathBuf::from("flash-history.jsonl"),
+            reports: None,
+            log_file: None,
+            syslog: None,
+            log_level: LogLevel::default(),
+            quarantine: PathBuf::from("quarantine.json"),
+            quarantine_after_failures: 3,
+            catalog: PathBuf::from("catalog.json"),
+            image_digests: PathBuf::from("image-digests.json"),
+            decompression_threads: None,
+            buffers: BufferConfig::default(),
+            priority: PriorityConfig::default(),
+            copy_method: CopyMethod::default(),
+            verify_hash: HashAlgorithm::default(),
+            verify_mode: VerifyMode::default(),
+            verify_sample_percent: 10,
+            verify_rewrite_attempts: 2,
+            discard: false,
+            secure_erase: false,
+            capacity_check: false,
+            cache: None,
+            backup_store: None,
+            image_source: ImageSourceConfig::default(),
+            share: None,
+            sync: None,
+            update: None,
+            peer_server: None,
+            agent: None,
+            coordinator: CoordinatorConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            notify: NotifyConfig::default(),
+            label: None,
+            display: DisplayConfig::default(),
+            nfc: None,
+            profiles: Vec::new(),
+            barcode: None,
+            keypad: None,
+            privileges: None,
+            hardware: HardwareConfig::default(),
+            shutdown_command: vec!["systemctl".to_string(), "poweroff".to_string()],
+            power_fail: None,
+            rtc: None,
+            sd_mux: None,
+            usb_power: None,
+            post_flash: PostFlashConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogFileConfig {
+    /// Rotated files are kept next to it, as `<path>.1` (the newest) to `<path>.<keep>`
+    pub path: PathBuf,
+    /// Rotate once the file grows beyond this many bytes
+    pub max_size: u64,
+    /// Rotate once the file is this old, even if it is small
+    pub max_age_secs: Option<u64>,
+    /// Rotated files kept, older ones are deleted
+    pub keep: u32,
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("rpi-sd-cloner.log"),
+            max_size: 10 * 1024 * 1024,
+            max_age_secs: Some(24 * 60 * 60),
+            keep: 5,
+        }
+    }
+}
+
+impl LogFileConfig {
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age_secs.map(Duration::from_secs)
+    }
+}
+
+/// Messages follow RFC 5424, over TCP they are framed by their length (RFC 6587).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogConfig {
+    pub host: String,
+    #[serde(default = "SyslogConfig::default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub protocol: SyslogProtocol,
+    /// Facility code, `local0` by default
+    #[serde(default = "SyslogConfig::default_facility")]
+    pub facility: u8,
+    /// Defaults to the host name, set it if the Pis of a fleet all have the same one
+    pub hostname: Option<String>,
+}
+
+impl SyslogConfig {
+    fn default_port() -> u16 {
+        514
+    }
+
+    fn default_facility() -> u8 {
+        16
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogProtocol {
+    /// Lost messages are lost, but a server that is down never holds anything up
+    #[default]
+    Udp,
+    Tcp,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    pub directory: PathBuf,
+    /// Least recently used images are evicted once the cache grows beyond this many bytes
+    pub max_size: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupStoreConfig {
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub chunking: Chunking,
+    /// Size of fixed chunks, the average size of content-defined ones. Smaller ones find more
+    /// to share, but take more files.
+    #[serde(default = "BackupStoreConfig::default_chunk_size")]
+    pub chunk_size: usize,
+    /// zstd level new chunks are compressed with
+    #[serde(default = "BackupStoreConfig::default_compression_level")]
+    pub compression_level: i32,
+    /// Which backups are pruned automatically
+    #[serde(default)]
+    pub retention: RetentionConfig,
+}
+
+impl BackupStoreConfig {
+    fn default_chunk_size() -> usize {
+        1024 * 1024
+    }
+
+    fn default_compression_level() -> i32 {
+        3
+    }
+}
+
+/// Every limit is off unless set. The newest backup of a card is kept whatever they say, and
+/// backups of cards without a CID, those in USB readers, aren't pruned at all.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Backups kept of every card, the newest ones
+    pub keep_last: Option<usize>,
+    /// Backups older than this are pruned after every backup
+    pub max_age_days: Option<u64>,
+    /// A backup doesn't start with fewer bytes free, the oldest backups are pruned first
+    pub min_free: Option<u64>,
+    /// Bytes pruning for `min_free` goes on until, so it doesn't happen before every backup.
+    /// Defaults to `min_free`.
+    pub target_free: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Chunking {
+    /// Cards flashed from the same image differ in whole blocks
+    #[default]
+    Fixed,
+    /// Cut by content, which also shares data that moved within the card
+    Cdc,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BufferConfig {
+    /// Two keep reading the image going while the card is written, more only help with sources
+    /// that stall now and then
+    pub count: usize,
+    /// Size of each buffer, rounded up to whole pages. It is also the size of the largest chunks
+    /// that are verified and written again when they don't match. 4 MiB suits a Pi Zero.
+    pub size: usize,
+    /// Start with 1 MiB chunks and double them while the card gets faster, up to `size`. Without
+    /// it every chunk is `size` bytes.
+    pub adaptive: bool,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self {
+            count: 2,
+            size: 16 * 1024 * 1024,
+            adaptive: true,
+        }
+    }
+}
+
+/// Unset values leave the priority the cloner was started with.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PriorityConfig {
+    /// From -20, the most CPU time, to 19, only what nothing else wants. Below 0 needs
+    /// `CAP_SYS_NICE`.
+    pub nice: Option<i32>,
+    /// I/O scheduling class, as `ionice -c` sets it
+    pub io_class: Option<IoClass>,
+    /// From 0, the highest, to 7 within `realtime` and `best_effort`
+    pub io_level: u8,
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        Self {
+            nice: None,
+            io_class: None,
+            io_level: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoClass {
+    /// Ahead of everything else, needs `CAP_SYS_ADMIN`
+    Realtime,
+    BestEffort,
+    /// Only when no one else uses the disk. The card has a bus of its own usually, but a
+    /// network share or a USB hub may not.
+    Idle,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImageSourceConfig {
+    /// Timeout for connecting and for each read while downloading
+    pub timeout_secs: u64,
+    pub s3: S3Config,
+}
+
+impl Default for ImageSourceConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 30,
+            s3: S3Config::default(),
+        }
+    }
+}
+
+/// Credentials fall back to the usual `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+/// `AWS_SESSION_TOKEN` environment variables. Without any, buckets are read anonymously.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    /// Defaults to AWS in `region`, set it for MinIO and other S3 compatible stores
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            region: "us-east-1".to_string(),
+            access_key_id: None,
+            secret_access_key: None,
+        }
+    }
+}
+
+impl S3Config {
+    pub fn endpoint(&self) -> String {
+        self.endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", self.region))
+    }
+
+    pub fn credentials(&self) -> Option<(String, String)> {
+        let access_key_id = self
+            .access_key_id
+            .clone()
+            .or_else(|| std::env::var("AWS_ACCESS_KEY_ID").ok())?;
+        let secret_access_key = self
+            .secret_access_key
+            .clone()
+            .or_else(|| std::env::var("AWS_SECRET_ACCESS_KEY").ok())?;
+        Some((access_key_id, secret_access_key))
+    }
+
+    /// Only taken from the environment, as session tokens expire too soon for a config file.
+    pub fn session_token(&self) -> Option<String> {
+        std::env::var("AWS_SESSION_TOKEN").ok()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelConfig {
+    /// Device node like `/dev/usb/lp0`, or `tcp://host:9100` for network printers
+    pub printer: String,
+    pub protocol: LabelProtocol,
+    /// Label text with `{{image}}`, `{{version}}`, `{{date}}`, `{{serial}}`, `{{sha256}}` (the
+    /// first 12 digits) and `{{device}}`
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LabelProtocol {
+    /// Receipt and label printers speaking ESC/POS
+    EscPos,
+    /// Brother QL printers, switched to their ESC/P text mode
+    BrotherQl,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    #[default]
+    Info,
+    /// Also every chunk, timer and uevent
+    Debug,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CopyMethod {
+    /// Read into the buffers and written from there
+    #[default]
+    Buffered,
+    /// Mapped into memory a chunk at a time and written straight from the page cache. The image
+    /// must only ever be replaced, never changed in place, while a card is written.
+    Mmap,
+    /// Moved to the card within the kernel by sendfile(2). Chunks are still mapped to be hashed,
+    /// so the same goes for changing the image as with `mmap`.
+    Sendfile,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyMode {
+    /// Every chunk
+    #[default]
+    Full,
+    /// A random sample of chunks, plus always the first and the last one
+    Sampled,
+    /// Nothing, for trusted cards when time matters more than certainty
+    Skip,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// How long a card has to be gone before the state about it is left, so a wobbly reader
+    /// doesn't reset everything
+    pub card_removed_secs: u64,
+    /// Go back to waiting for a card after showing a result for this long, even if the card is
+    /// still in
+    pub result_reset_secs: Option<u64>,
+    /// Without a card for this long, stop polling for devices and wait for hotplug events
+    pub idle_secs: Option<u64>,
+    /// Time after the first press in which the second press (or the long hold) has to happen
+    pub confirmation_secs: u64,
+    /// A card that disappears mid-flash and comes back within this long, under the same or
+    /// another device name, is flashed again from the start. Off unless set
+    pub reenumeration_secs: Option<u64>,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            card_removed_secs: 1,
+            result_reset_secs: None,
+            idle_secs: Some(10 * 60),
+            confirmation_secs: 2,
+            reenumeration_secs: None,
+        }
+    }
+}
+
+impl TimeoutConfig {
+    pub fn card_removed(&self) -> Duration {
+        Duration::from_secs(self.card_removed_secs)
+    }
+
+    pub fn result_reset(&self) -> Option<Duration> {
+        self.result_reset_secs.map(Duration::from_secs)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        self.idle_secs.map(Duration::from_secs)
+    }
+
+    pub fn confirmation(&self) -> Duration {
+        Duration::from_secs(self.confirmation_secs)
+    }
+
+    pub fn reenumeration(&self) -> Option<Duration> {
+        self.reenumeration_secs.map(Duration::from_secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceBackend {
+    /// Straight from sysfs and `/dev`
+    #[default]
+    Sysfs,
+    /// Through udisks2 over D-Bus, which also unmounts anything a desktop automounted
+    Udisks2,
+    /// Files standing in for cards, see `[simulation]`
+    Simulated,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationConfig {
+    /// Simulated cards are files in here, present while they exist
+    pub directory: PathBuf,
+    pub cards: Vec<SimulatedCard>,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("simulated-cards"),
+            cards: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimulatedCard {
+    /// File name in the simulation directory
+    pub name: String,
+    /// Size the card claims. The file is sparse, so only what is written to it takes space
+    pub size: u64,
+    /// Seconds after start
+    pub insert_after_secs: u64,
+    /// Seconds after start, if the card is pulled again
+    #[serde(default)]
+    pub remove_after_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    pub webhooks: Vec<WebhookConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub email: Option<EmailConfig>,
+    pub buzzer: Option<BuzzerConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Also post progress while writing, every few seconds
+    #[serde(default)]
+    pub progress: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "MqttConfig::default_port")]
+    pub port: u16,
+    /// Events go to `<topic>/started`, `<topic>/progress` and `<topic>/finished`
+    #[serde(default = "MqttConfig::default_topic")]
+    pub topic: String,
+    #[serde(default = "MqttConfig::default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl MqttConfig {
+    fn default_port() -> u16 {
+        1883
+    }
+
+    fn default_topic() -> String {
+        "rpi-sd-cloner".to_string()
+    }
+
+    fn default_client_id() -> String {
+        "rpi-sd-cloner".to_string()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    pub to: Vec<String>,
+    pub from: Option<String>,
+    #[serde(default = "EmailConfig::default_sendmail")]
+    pub sendmail: PathBuf,
+    /// Only mail about cards that failed
+    #[serde(default)]
+    pub failures_only: bool,
+}
+
+impl EmailConfig {
+    fn default_sendmail() -> PathBuf {
+        PathBuf::from("/usr/sbin/sendmail")
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuzzerConfig {
+    /// BCM number of the pin driving an active buzzer
+    pub pin: u8,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    pub backend: DisplayBackend,
+    pub oled: OledConfig,
+    pub lcd: LcdConfig,
+    pub eink: EinkConfig,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayBackend {
+    #[default]
+    None,
+    /// 128x64 SSD1306 on I2C, showing a QR code of the last flash
+    Oled,
+    /// HD44780 character LCD with a PCF8574 I2C backpack, showing the state in text
+    Lcd,
+    /// Waveshare 2.13" e-paper HAT on SPI, keeping the last flash's QR code up without power
+    Eink,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OledConfig {
+    pub bus: u8,
+    /// 0x3C, or 0x3D with the address jumper bridged
+    pub address: u16,
+}
+
+impl Default for OledConfig {
+    fn default() -> Self {
+        Self {
+            bus: 1,
+            address: 0x3C,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LcdConfig {
+    pub bus: u8,
+    /// 0x27 for PCF8574 backpacks, 0x3F for PCF8574A ones
+    pub address: u16,
+    pub columns: u8,
+    pub rows: u8,
+}
+
+impl Default for LcdConfig {
+    fn default() -> Self {
+        Self {
+            bus: 1,
+            address: 0x27,
+            columns: 16,
+            rows: 2,
+        }
+    }
+}
+
+/// The defaults are how the Waveshare HAT is wired.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EinkConfig {
+    pub spi_bus: u8,
+    pub chip_select: u8,
+    /// BCM numbers of the control pins
+    pub data_command_pin: u8,
+    pub reset_pin: u8,
+    pub busy_pin: u8,
+}
+
+impl Default for EinkConfig {
+    fn default() -> Self {
+        Self {
+            spi_bus: 0,
+            chip_select: 0,
+            data_command_pin: 25,
+            reset_pin: 17,
+            busy_pin: 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NfcConfig {
+    pub bus: u8,
+    pub address: u16,
+    /// How long to wait for a sticker to be tapped before giving up on it
+    pub tap_timeout_secs: u64,
+}
+
+impl Default for NfcConfig {
+    fn default() -> Self {
+        Self {
+            bus: 1,
+            address: 0x24,
+            tap_timeout_secs: 30,
+        }
+    }
+}
+
+impl NfcConfig {
+    pub fn tap_timeout(&self) -> Duration {
+        Duration::from_secs(self.tap_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub name: String,
+    pub image: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InputDeviceConfig {
+    /// evdev node of the device, preferably the stable `/dev/input/by-id/...-event-kbd` link
+    pub device: PathBuf,
+}
+
+/// The user has to be able to open the cards, e.g. through membership of the `disk` group or a
+/// udev rule handing them the device nodes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrivilegesConfig {
+    pub user: String,
+    /// Defaults to the user's primary group
+    pub group: Option<String>,
+    /// Capabilities kept after switching user. Mounting and block device ioctls need
+    /// `CAP_SYS_ADMIN`, SMART queries `CAP_SYS_RAWIO`, and editing root-owned files in the flashed
+    /// image the ownership and DAC ones.
+    #[serde(default = "PrivilegesConfig::default_capabilities")]
+    pub capabilities: Vec<String>,
+}
+
+impl PrivilegesConfig {
+    fn default_capabilities() -> Vec<String> {
+        [
+            "CAP_SYS_ADMIN",
+            "CAP_SYS_RAWIO",
+            "CAP_DAC_OVERRIDE",
+            "CAP_FOWNER",
+            "CAP_CHOWN",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncConfig {
+    /// URL of the raw golden image, `<url>.manifest.json` must exist next to it. Leave it out to
+    /// pull from a primary cloner found over mDNS.
+    pub url: Option<String>,
+    #[serde(default = "SyncConfig::default_interval_secs")]
+    pub interval_secs: u64,
+    /// Timeout for each range request
+    #[serde(default = "SyncConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Swap in newer images automatically, otherwise only report that one is available
+    #[serde(default = "SyncConfig::default_auto_update")]
+    pub auto_update: bool,
+}
+
+impl SyncConfig {
+    fn default_interval_secs() -> u64 {
+        60 * 60
+    }
+
+    fn default_timeout_secs() -> u64 {
+        10 * 60
+    }
+
+    fn default_auto_update() -> bool {
+        true
+    }
+}
+
+/// The binary has to be writable by the user the cloner runs as.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateConfig {
+    /// URL of the release binary, with `<url>.version` and `<url>.sig` next to it
+    pub url: String,
+    /// Hex encoded Ed25519 key releases are signed with
+    pub public_key: String,
+    #[serde(default = "UpdateConfig::default_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "UpdateConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl UpdateConfig {
+    fn default_interval_secs() -> u64 {
+        6 * 60 * 60
+    }
+
+    fn default_timeout_secs() -> u64 {
+        5 * 60
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeerServerConfig {
+    pub port: u16,
+}
+
+impl Default for PeerServerConfig {
+    fn default() -> Self {
+        Self { port: 8480 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentConfig {
+    pub port: u16,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self { port: 8481 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoordinatorConfig {
+    /// Port of the dashboard
+    pub port: u16,
+    /// Agents that can't be found over mDNS, like `http://10.0.0.12:8481`
+    pub units: Vec<String>,
+    /// Find agents on the local network
+    pub discover: bool,
+}
+
+impl Default for CoordinatorConfig {
+    fn default() -> Self {
+        Self {
+            port: 8490,
+            units: Vec::new(),
+            discover: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShareKind {
+    Nfs,
+    Smb,
+    /// Mounted by the system (e.g. fstab or autofs), only health checked
+    Premounted,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShareConfig {
+    pub kind: ShareKind,
+    /// `server:/export` for NFS, `//server/share` for SMB
+    pub source: Option<String>,
+    pub mountpoint: PathBuf,
+    /// Extra mount options, e.g. `vers=4` or `credentials=/etc/smb-credentials`
+    pub options: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HardwareBackend {
+    /// LEDs and button wired straight to the Pi header
+    Gpio,
+    /// LEDs and button wired to an MCP23017 on the I2C bus
+    Mcp23017,
+    /// LEDs and button on the terminal, to go with the simulated device backend
+    Simulated,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HardwareConfig {
+    pub backend: HardwareBackend,
+    /// Pin numbers are BCM numbers for `gpio`, and 0-15 (GPA0-GPA7, GPB0-GPB7) for `mcp23017`
+    pub led_red: u8,
+    pub led_yellow: u8,
+    pub button: u8,
+    pub mcp23017: Mcp23017Config,
+    pub leds: LedPatternConfig,
+}
+
+impl Default for HardwareConfig {
+    fn default() -> Self {
+        // BCM GPIO 23 is tied to physical pin 16.
+        Self {
+            backend: HardwareBackend::Gpio,
+            led_red: 27,
+            led_yellow: 23,
+            button: 26,
+            mcp23017: Mcp23017Config::default(),
+            leds: LedPatternConfig::default(),
+        }
+    }
+}
+
+/// What the LEDs show while the cloner waits for a card, with nothing wrong
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LedPatternConfig {
+    pub no_card: IdlePattern,
+    /// After no card came for `timeouts.idle_secs`. Only the slow patterns let the Pi sleep in
+    /// between.
+    pub idle: IdlePattern,
+}
+
+impl Default for LedPatternConfig {
+    fn default() -> Self {
+        Self {
+            no_card: IdlePattern::Heartbeat,
+            idle: IdlePattern::SlowFlashingRed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdlePattern {
+    /// Two short blinks of the green LED every second and a half
+    Heartbeat,
+    FlashingRed,
+    SlowFlashingRed,
+    Off,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PowerFailConfig {
+    /// BCM number of the pin the UPS signals on
+    pub pin: u8,
+    /// The line goes low when the supply fails, rather than high
+    #[serde(default)]
+    pub active_low: bool,
+    /// Pause a running flash while on battery, so it can carry on if power comes back
+    #[serde(default)]
+    pub pause_flash: bool,
+    /// Power off after running on battery for this long. Set it well below what the battery
+    /// lasts.
+    #[serde(default = "PowerFailConfig::default_shutdown_after_secs")]
+    pub shutdown_after_secs: u64,
+}
+
+impl PowerFailConfig {
+    fn default_shutdown_after_secs() -> u64 {
+        30
+    }
+
+    pub fn shutdown_after(&self) -> Duration {
+        Duration::from_secs(self.shutdown_after_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RtcConfig {
+    pub bus: u8,
+    pub address: u16,
+}
+
+impl Default for RtcConfig {
+    fn default() -> Self {
+        Self {
+            bus: 1,
+            address: 0x68,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SdMuxConfig {
+    pub kind: SdMuxKind,
+    /// Serial number of an SDWire as `sd-mux-ctrl --list` shows it, or the SCSI generic device
+    /// of a usbsdmux, e.g. `/dev/sg1`
+    pub device: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UsbPowerConfig {
+    /// Location of the hub as uhubctl lists it, e.g. `1-1.3`
+    pub hub: String,
+    /// Port of the card reader, power-cycled after a flash failed on I/O errors
+    pub reader_port: Option<u32>,
+    /// Port powering the device under test, off while its card is flashed and on once the card
+    /// was handed over to it
+    pub target_port: Option<u32>,
+    /// How long a power-cycled port stays off
+    #[serde(default = "UsbPowerConfig::default_cycle_off_secs")]
+    pub cycle_off_secs: u64,
+}
+
+impl UsbPowerConfig {
+    fn default_cycle_off_secs() -> u64 {
+        3
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SdMuxKind {
+    /// Tizen SDWire and its clones
+    Sdwire,
+    /// Linux Automation USB-SD-Mux
+    Usbsdmux,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mcp23017Config {
+    pub bus: u8,
+    /// 0x20-0x27 depending on the A0-A2 straps
+    pub address: u16,
+}
+
+impl Default for Mcp23017Config {
+    fn default() -> Self {
+        Self {
+            bus: 1,
+            address: 0x20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostFlashConfig {
+    /// Partition numbers on the flashed card, the defaults match Raspberry Pi OS
+    pub boot_partition: u32,
+    pub rootfs_partition: u32,
+    /// Empty `/etc/machine-id` and remove the SSH host keys, so clones don't share identities
+    pub reset_identity: bool,
+    /// Give the card a new disk identifier and new PARTUUIDs, and point `cmdline.txt` and
+    /// `/etc/fstab` to them, so clones attached to one host can be told apart
+    pub randomize_disk_ids: bool,
+    /// Give every ext filesystem a new UUID and every FAT one a new volume serial, and point
+    /// `UUID=` references in `cmdline.txt` and `/etc/fstab` to them
+    pub randomize_filesystem_ids: bool,
+    /// Run a read-only fsck on every partition after flashing, failing the flash on problems
+    pub fsck: bool,
+    pub firstboot: Option<FirstbootConfig>,
+    pub provision: Option<ProvisionConfig>,
+    pub cloud_init: Option<CloudInitConfig>,
+    pub data_partition: Option<DataPartitionConfig>,
+    pub overlay: Option<OverlayConfig>,
+    pub wireguard: Option<WireGuardConfig>,
+    pub boot_files: Option<BootFilesConfig>,
+}
+
+impl Default for PostFlashConfig {
+    fn default() -> Self {
+        Self {
+            boot_partition: 1,
+            rootfs_partition: 2,
+            reset_identity: false,
+            randomize_disk_ids: false,
+            randomize_filesystem_ids: false,
+            fsck: false,
+            firstboot: None,
+            provision: None,
+            cloud_init: None,
+            data_partition: None,
+            overlay: None,
+            wireguard: None,
+            boot_files: None,
+        }
+    }
+}
+
+/// A partition created in the free space behind the last one of the image. Images that grow
+/// their root partition on first boot can't do so anymore.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DataPartitionConfig {
+    /// Bytes, the rest of the card if not set
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub filesystem: DataFilesystem,
+    /// MBR partition type as hex (`83`) or GPT type GUID, by default the usual one for the
+    /// filesystem
+    #[serde(rename = "type")]
+    pub partition_type: Option<String>,
+    #[serde(default = "DataPartitionConfig::default_label")]
+    pub label: String,
+    /// Directory whose contents are copied onto the new filesystem
+    pub seed: Option<PathBuf>,
+}
+
+impl DataPartitionConfig {
+    fn default_label() -> String {
+        "data".to_string()
+    }
+}
+
+/// Directories whose contents are copied onto the partitions of every card, replacing files that
+/// are there. Permissions and symbolic links are kept on the root partition.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverlayConfig {
+    pub boot: Option<PathBuf>,
+    pub rootfs: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFilesystem {
+    #[default]
+    Ext4,
+    Vfat,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FirstbootConfig {
+    /// Script run once on the first boot of the card. `{{image}}`, `{{device}}`, `{{serial}}` and
+    /// `{{flashed_at}}` are substituted before it is installed.
+    pub script: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProvisionConfig {
+    /// Endpoint returning the files for one card, e.g. `https://factory/cards/{{cid}}`
+    pub url: String,
+    /// Sent as a bearer token
+    pub token: Option<String>,
+    #[serde(default = "ProvisionConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl ProvisionConfig {
+    fn default_timeout_secs() -> u64 {
+        30
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+/// Templates for the cloud-init NoCloud seed files, written to the boot partition.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CloudInitConfig {
+    pub user_data: PathBuf,
+    /// Defaults to an `instance-id` that is unique per card
+    pub meta_data: Option<PathBuf>,
+    pub network_config: Option<PathBuf>,
+}
+
+/// Edits to the boot files, e.g. `config_txt = ["gpu_mem=128", "-dtparam=audio=on"]` and
+/// `cmdline = ["-quiet", "cgroup_enable=memory"]`. `post_flash/boot_files.rs` has the syntax.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootFilesConfig {
+    pub config_txt: Vec<String>,
+    pub cmdline: Vec<String>,
+}
+
+/// A WireGuard key pair generated for every card. The public key is recorded in the flash history
+/// and sent with the notifications, for the server to add the card as a peer.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WireGuardConfig {
+    /// Template of the interface config, with `{{wireguard_private_key}}` and
+    /// `{{wireguard_public_key}}` substituted besides the usual variables
+    pub template: PathBuf,
+    /// Where the config is written on the root partition
+    #[serde(default = "WireGuardConfig::default_config_path")]
+    pub config_path: PathBuf,
+    /// Where the private key is written on its own, for tools that read it from a file
+    #[serde(default = "WireGuardConfig::default_private_key_path")]
+    pub private_key_path: PathBuf,
+}
+
+impl WireGuardConfig {
+    fn default_config_path() -> PathBuf {
+        PathBuf::from("/etc/wireguard/wg0.conf")
+    }
+
+    fn default_private_key_path() -> PathBuf {
+        PathBuf::from("/etc/wireguard/private.key")
+    }
+}
+
+impl Config {
+    pub fn decompression_threads(&self) -> u32 {
+        self.decompression_threads.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, |threads| threads.get() as u32)
+        })
+    }
+
+    /// The file the config is loaded from: `path`, or [`DEFAULT_CONFIG_PATH`] if it exists.
+    pub fn path(path: Option<&Path>) -> Option<&Path> {
+        match path {
+            Some(path) => Some(path),
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Some(Path::new(DEFAULT_CONFIG_PATH)),
+            None => None,
+        }
+    }
+
+    /// Loads the config from [`Config::path`], or falls back to the defaults.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let Some(path) = Self::path(path) else {
+            return Ok(Self::default());
+        };
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| format!("Could not read config {path:?}: {error}"))?;
+        let config = toml::from_str(&contents)
+            .map_err(|error| format!("Could not parse config {path:?}: {error}"))?;
+        Ok(config)
+    }
+    /// Takes the settings from `new` that are read per card: the image, which devices are
+    /// considered, how cards are written and verified, and where flashes are reported to. A flash
+    /// that is running keeps the settings it started with. The rest, like the hardware or the
+    /// servers, only changes with a restart.
+    pub fn reload(&mut self, new: Config) {
+        let Config {
+            image,
+            min_device_size,
+            target,
+            confirm_larger_than,
+            decompression_threads,
+            copy_method,
+            verify_hash,
+            verify_mode,
+            verify_sample_percent,
+            verify_rewrite_attempts,
+            discard,
+            secure_erase,
+            capacity_check,
+            notify,
+            label,
+            post_flash,
+            log_level,
+            ..
+        } = new;
+        *self = Config {
+            image,
+            min_device_size,
+            target,
+            confirm_larger_than,
+            decompression_threads,
+            copy_method,
+            verify_hash,
+            verify_mode,
+            verify_sample_percent,
+            verify_rewrite_attempts,
+            discard,
+            secure_erase,
+            capacity_check,
+            notify,
+            label,
+            post_flash,
+            log_level,
+            ..std::mem::take(self)
+        };
+    }
+}
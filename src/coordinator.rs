@@ -0,0 +1,234 @@
+//! Coordinator for a lab of cloners: polls every unit's agent, shows them on one dashboard, and
+//! splits batch jobs across the units that have nothing to do.
+
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::agent::{self, UnitStatus};
+use crate::batch::Batch;
+use crate::config::CoordinatorConfig;
+use crate::peer;
+
+/// How often units are discovered and polled again
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+const UNIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize)]
+struct Unit {
+    url: String,
+    status: Option<UnitStatus>,
+    error: Option<String>,
+}
+
+fn poll_units(config: &CoordinatorConfig) -> Vec<Unit> {
+    let mut urls = config.units.clone();
+    if config.discover {
+        match peer::browse(agent::SERVICE_TYPE, UNIT_TIMEOUT, false) {
+            Ok(found) => urls.extend(found.into_iter().filter(|url| !config.units.contains(url))),
+            Err(error) => println!("Got error when discovering units: {error:?}"),
+        }
+    }
+    urls.into_iter()
+        .map(|url| {
+            let status = ureq::get(&format!("{url}/status"))
+                .timeout(UNIT_TIMEOUT)
+                .call()
+                .map_err(|error| error.to_string())
+                .and_then(|response| {
+                    response
+                        .into_json::<UnitStatus>()
+                        .map_err(|error| error.to_string())
+                });
+            Unit {
+                url,
+                error: status.as_ref().err().cloned(),
+                status: status.ok(),
+            }
+        })
+        .collect()
+}
+
+/// Sends `quantity` cards of `profile` to the units without a batch, as evenly as possible. Units
+/// that took their share are marked busy, so a second job doesn't land on them before the next
+/// poll.
+fn dispatch(units: &mut [Unit], profile: &str, quantity: u32) -> Result<Vec<String>, String> {
+    let mut idle: Vec<&mut Unit> = units
+        .iter_mut()
+        .filter(|unit| {
+            unit.status
+                .as_ref()
+                .is_some_and(|status| status.batch.is_none())
+        })
+        .collect();
+    if idle.is_empty() {
+        return Err("No unit is free for a batch".to_string());
+    }
+    let share = quantity / idle.len() as u32;
+    let extra = quantity as usize % idle.len();
+    let mut sent = vec![];
+    for (index, unit) in idle.iter_mut().enumerate() {
+        let count = share + u32::from(index < extra);
+        if count == 0 {
+            continue;
+        }
+        let result = ureq::post(&format!("{}/batch", unit.url))
+            .timeout(UNIT_TIMEOUT)
+            .send_string(&format!("{profile}*{count}"));
+        match result {
+            Ok(_) => {
+                sent.push(format!("{count} cards to {}", unit.url));
+                if let Some(status) = &mut unit.status {
+                    status.batch = Some(Batch {
+                        profile: profile.to_string(),
+                        image: PathBuf::new(),
+                        remaining: count,
+                    });
+                }
+            }
+            Err(error) => sent.push(format!(
+                "Failed to send {count} cards to {}: {error}",
+                unit.url
+            )),
+        }
+    }
+    Ok(sent)
+}
+
+/// Decodes one field of an `application/x-www-form-urlencoded` body.
+fn form_value(body: &str, key: &str) -> Option<String> {
+    let value = body
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)?
+        .1
+        .replace('+', " ");
+    let mut decoded = vec![];
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex: String = bytes.by_ref().take(2).map(char::from).collect();
+            decoded.push(u8::from_str_radix(&hex, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn dashboard(units: &[Unit]) -> String {
+    let mut rows = String::new();
+    for unit in units {
+        let cells = match &unit.status {
+            Some(status) => {
+                let batch = status
+                    .batch
+                    .as_ref()
+                    .map(|batch| format!("{} ({} left)", batch.profile, batch.remaining))
+                    .unwrap_or_default();
+                let mut image = status.image.to_string_lossy().to_string();
+                if let Some(version) = &status.update_available {
+                    image.push_str(&format!(" ({version} available)"));
+                }
+                [
+                    status.state.clone(),
+                    image,
+                    batch,
+                    status.succeeded.to_string(),
+                    status.failed.to_string(),
+                ]
+            }
+            None => [
+                format!("unreachable: {}", unit.error.as_deref().unwrap_or_default()),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ],
+        };
+        rows.push_str(&format!("<tr><td>{}</td>", escape(&unit.url)));
+        for cell in cells {
+            rows.push_str(&format!("<td>{}</td>", escape(&cell)));
+        }
+        rows.push_str("</tr>\n");
+    }
+    format!(
+        r#"<!DOCTYPE html>
+<html><head><title>SD cloners</title><meta http-equiv="refresh" content="5"></head>
+<body>
+<table border="1">
+<tr><th>Unit</th><th>State</th><th>Image</th><th>Batch</th><th>Flashed</th><th>Failed</th></tr>
+{rows}</table>
+<form method="post" action="/jobs">
+Flash <input name="quantity" type="number" min="1" value="10"> cards of profile <input name="profile">
+<button>Dispatch</button>
+</form>
+</body></html>
+"#
+    )
+}
+
+fn handle(request: &mut Request, units: &Mutex<Vec<Unit>>) -> Response<io::Cursor<Vec<u8>>> {
+    let content_type =
+        |value: &str| Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).unwrap();
+    match (request.method(), request.url()) {
+        (Method::Get, "/") => Response::from_string(dashboard(&units.lock().unwrap()))
+            .with_header(content_type("text/html; charset=utf-8")),
+        (Method::Get, "/status") => {
+            Response::from_data(serde_json::to_vec(&*units.lock().unwrap()).unwrap_or_default())
+                .with_header(content_type("application/json"))
+        }
+        // Form encoded `profile` and `quantity`, as the dashboard sends them.
+        (Method::Post, "/jobs") => {
+            let mut body = String::new();
+            let _ = request.as_reader().read_to_string(&mut body);
+            let profile = form_value(&body, "profile").filter(|profile| !profile.is_empty());
+            let quantity = form_value(&body, "quantity").and_then(|quantity| quantity.parse().ok());
+            let (Some(profile), Some(quantity)) = (profile, quantity) else {
+                return Response::from_string("Need a profile and a quantity")
+                    .with_status_code(400);
+            };
+            match dispatch(&mut units.lock().unwrap(), &profile, quantity) {
+                Ok(sent) => Response::from_string(sent.join("\n")),
+                Err(error) => Response::from_string(error).with_status_code(503),
+            }
+        }
+        _ => Response::from_string("Not found").with_status_code(404),
+    }
+}
+
+/// Runs the coordinator until the process is stopped.
+pub fn run(config: &CoordinatorConfig) -> Result<(), Box<dyn Error>> {
+    let server = Server::http(("0.0.0.0", config.port))
+        .map_err(|error| format!("Listening on port {} failed: {error}", config.port))?;
+    println!("Coordinator listening on port {}", config.port);
+
+    let units = Arc::new(Mutex::new(vec![]));
+    let (poller_units, poller_config) = (units.clone(), config.clone());
+    thread::spawn(move || loop {
+        let polled = poll_units(&poller_config);
+        *poller_units.lock().unwrap() = polled;
+        thread::sleep(REFRESH_INTERVAL);
+    });
+
+    for mut request in server.incoming_requests() {
+        let response = handle(&mut request, &units);
+        if let Err(error) = request.respond(response) {
+            println!("Got error when sending the response: {error:?}");
+        }
+    }
+    Ok(())
+}
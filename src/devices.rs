@@ -0,0 +1,389 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, DeviceBackend};
+use crate::simulation;
+use crate::udisks;
+
+/// What `/sys/block/*/size` counts in, whatever the device's own sector size is
+const SYSFS_SECTOR_SIZE: u64 = 512;
+
+/// Devices already reported as held, so polling doesn't repeat it. Forgotten once they aren't.
+static REPORTED_HELD: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+// From linux/fs.h
+nix::ioctl_none_bad!(blkflsbuf, nix::request_code_none!(0x12, 97));
+nix::ioctl_none_bad!(blkrrpart, nix::request_code_none!(0x12, 95));
+
+pub fn block_device_valid(path: String) -> bool {
+    let mut path = path.replace("/dev/", "/sys/block/");
+    path.push_str("/size");
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|string| string.trim().parse::<u64>().ok())
+        .is_some_and(|sectors| sectors > 0)
+}
+
+/// Whether the card in `device` is still there.
+pub fn card_present(backend: DeviceBackend, device: &Path) -> bool {
+    match backend {
+        DeviceBackend::Sysfs | DeviceBackend::Udisks2 => {
+            block_device_valid(device.to_string_lossy().to_string())
+        }
+        DeviceBackend::Simulated => simulation::card_size(device).is_some(),
+    }
+}
+
+pub fn get_block_devices_with_size(min_size_bytes: u64) -> io::Result<Vec<PathBuf>> {
+    let block_path = Path::new("/sys/block");
+
+    Ok(fs::read_dir(block_path)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path().join("size");
+            if path.exists() {
+                let size = fs::read_to_string(&path).ok()?.trim().to_string();
+                match size.parse::<u64>() {
+                    Ok(sectors) => Some((entry, sectors * SYSFS_SECTOR_SIZE)),
+                    Err(error) => {
+                        println!("Got error when parsing path: {entry:?}. Error={error:?}");
+                        None
+                    }
+                }
+            } else {
+                None
+            }
+        })
+        .filter_map(|(entry, size)| {
+            if size < min_size_bytes {
+                None
+            } else {
+                Some(entry.path())
+            }
+        })
+        .collect())
+}
+
+/// Device nodes of everything the configured backend finds that passes the size filter, the
+/// first of which is taken as the card. With a `target` configured, only the device it resolves
+/// to.
+pub fn candidates(config: &Config) -> io::Result<Vec<PathBuf>> {
+    // Links like the ones in `/dev/disk/by-path/` only exist while their device does.
+    let target = match &config.target {
+        Some(target) => match fs::canonicalize(target) {
+            Ok(target) => Some(target),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => return Err(error),
+        },
+        None => None,
+    };
+    let devices = match config.device_backend {
+        DeviceBackend::Sysfs => get_block_devices_with_size(config.min_device_size)?,
+        DeviceBackend::Udisks2 => udisks::get_block_devices_with_size(config.min_device_size)?,
+        DeviceBackend::Simulated => {
+            simulation::get_cards_with_size(&config.simulation, config.min_device_size)?
+        }
+    };
+    let mut reported = REPORTED_HELD.lock().unwrap();
+    let mut held = BTreeSet::new();
+    let candidates = devices
+        .iter()
+        .filter_map(|path| path.to_str())
+        .map(|path| PathBuf::from(path.replace("/sys/block/", "/dev/")))
+        .filter(|device| target.as_ref().is_none_or(|target| device == target))
+        // Never a card, and if one came first it would hide the card behind it.
+        .filter(|device| {
+            let holders = holders(device);
+            if holders.is_empty() {
+                return true;
+            }
+            if !reported.contains(device) {
+                println!(
+                    "Refusing {device:?}, it is in use by {} (LVM, RAID or device-mapper)",
+                    holders.join(", ")
+                );
+            }
+            held.insert(device.clone());
+            false
+        })
+        .collect();
+    *reported = held;
+    Ok(candidates)
+}
+
+/// Serial number of the card in `device` (e.g. `/dev/sda`). For cards on the MMC bus this is the
+/// card's own serial, for USB readers the best we can do is the serial of the reader.
+pub fn card_serial(device: &Path) -> Option<String> {
+    let name = device.file_name()?;
+    let sys_device = Path::new("/sys/block").join(name).join("device");
+    let sys_device = fs::canonicalize(sys_device).ok()?;
+    sys_device
+        .ancestors()
+        .map(|path| path.join("serial"))
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|serial| serial.trim().to_string())
+        .filter(|serial| !serial.is_empty())
+}
+
+/// Identifies the card in `device` across a reader dropping off the bus and coming back under
+/// another name. Cards on the MMC bus have their CID, which is unique. In USB readers it is the
+/// serial of the reader with the size of the card, telling apart the cards that go through it.
+pub fn stable_id(device: &Path) -> Option<String> {
+    if let Some(cid) = card_cid(device) {
+        return Some(cid);
+    }
+    Some(format!("{}-{}", card_serial(device)?, device_size(device)?))
+}
+
+/// CID of the card in `device`, only known for cards on the MMC bus.
+pub fn card_cid(device: &Path) -> Option<String> {
+    sys_block_attribute(device, "device/cid").filter(|cid| !cid.is_empty())
+}
+
+/// Partition device nodes of `device`, e.g. `/dev/sda1` and `/dev/sda2` for `/dev/sda`.
+pub fn partitions(device: &Path) -> io::Result<Vec<PathBuf>> {
+    let Some(name) = device.file_name() else {
+        return Ok(vec![]);
+    };
+    let mut partitions: Vec<PathBuf> = fs::read_dir(Path::new("/sys/block").join(name))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("partition").exists())
+        .map(|entry| Path::new("/dev").join(entry.file_name()))
+        .collect();
+    partitions.sort();
+    Ok(partitions)
+}
+
+/// Whether `device` is a card on the MMC bus, as opposed to one in a USB reader.
+pub fn is_mmc(device: &Path) -> bool {
+    device
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("mmcblk"))
+}
+
+fn sys_block_attribute(device: &Path, attribute: &str) -> Option<String> {
+    let path = Path::new("/sys/block")
+        .join(device.file_name()?)
+        .join(attribute);
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+/// Size of `device` in bytes, as the kernel reports it.
+pub fn device_size(device: &Path) -> Option<u64> {
+    sys_block_attribute(device, "size")?
+        .parse::<u64>()
+        .ok()
+        .map(|sectors| sectors * SYSFS_SECTOR_SIZE)
+}
+
+/// Block sizes the kernel reports for a device, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSizes {
+    /// The smallest unit the device can be addressed in
+    pub logical: u64,
+    /// The smallest unit it writes without reading and writing back around it
+    pub physical: u64,
+    /// What it prefers to be written in, 0 when it doesn't say
+    pub optimal_io: u64,
+}
+
+impl Default for BlockSizes {
+    fn default() -> Self {
+        Self {
+            logical: 512,
+            physical: 512,
+            optimal_io: 0,
+        }
+    }
+}
+
+impl BlockSizes {
+    /// The largest chunk size up to `limit` that keeps every chunk aligned: a multiple of the
+    /// optimal I/O size if one fits, of the physical block size otherwise. `limit` as it is if
+    /// not even a physical block fits.
+    pub fn chunk_size(&self, limit: usize) -> usize {
+        let limit = limit as u64;
+        let unit = [self.optimal_io, self.physical, self.logical]
+            .into_iter()
+            .find(|unit| *unit > 0 && *unit <= limit);
+        match unit {
+            Some(unit) => (limit / unit * unit) as usize,
+            None => limit as usize,
+        }
+    }
+}
+
+/// Block sizes of `device`, the defaults of a plain 512 byte sector device where the kernel
+/// doesn't tell.
+pub fn block_sizes(device: &Path) -> BlockSizes {
+    let defaults = BlockSizes::default();
+    let size = |attribute, default| {
+        sys_block_attribute(device, attribute)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    };
+    BlockSizes {
+        logical: size("queue/logical_block_size", defaults.logical),
+        physical: size("queue/physical_block_size", defaults.physical),
+        optimal_io: size("queue/optimal_io_size", defaults.optimal_io),
+    }
+}
+
+/// What the kernel tells about the card in a device, for records that outlive the card.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub size: Option<u64>,
+    /// Of the card on the MMC bus, of the reader over USB
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    /// Card identification register, only readable on the MMC bus
+    pub cid: Option<String>,
+}
+
+pub fn device_info(device: &Path) -> DeviceInfo {
+    let attribute = |name| sys_block_attribute(device, name).filter(|value| !value.is_empty());
+    DeviceInfo {
+        size: device_size(device),
+        vendor: attribute("device/vendor").or_else(|| attribute("device/manfid")),
+        model: attribute("device/model").or_else(|| attribute("device/name")),
+        cid: attribute("device/cid"),
+    }
+}
+
+/// What the kernel stacked on `device` or its partitions, like LVM volumes, RAID arrays and
+/// dm-crypt mappings. They hold it without mounting it, so nothing else tells it is in use.
+/// Device-mapper holders are shown with their name, e.g. `dm-0 (vg0-data)`.
+pub fn holders(device: &Path) -> Vec<String> {
+    let Some(name) = device.file_name() else {
+        return vec![];
+    };
+    let sys_block = Path::new("/sys/block").join(name);
+    let partitions = fs::read_dir(&sys_block)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.join("partition").exists());
+    let mut holders: Vec<String> = std::iter::once(sys_block.clone())
+        .chain(partitions)
+        .filter_map(|path| fs::read_dir(path.join("holders")).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let holder = entry.file_name().to_string_lossy().into_owned();
+            match fs::read_to_string(Path::new("/sys/block").join(&holder).join("dm/name")) {
+                Ok(dm_name) => format!("{holder} ({})", dm_name.trim()),
+                Err(_) => holder,
+            }
+        })
+        .collect();
+    holders.sort();
+    holders.dedup();
+    holders
+}
+
+/// Card readers show up as removable, an internal or USB hard drive usually doesn't.
+pub fn looks_like_hard_drive(device: &Path) -> bool {
+    !is_mmc(device)
+        && (sys_block_attribute(device, "removable").as_deref() != Some("1")
+            || sys_block_attribute(device, "queue/rotational").as_deref() == Some("1"))
+}
+
+/// Whether the kernel can pass discard (TRIM) requests on to `device`. Many USB readers can't.
+pub fn discard_supported(device: &Path) -> bool {
+    let Some(name) = device.file_name() else {
+        return false;
+    };
+    fs::read_to_string(
+        Path::new("/sys/block")
+            .join(name)
+            .join("queue/discard_max_bytes"),
+    )
+    .ok()
+    .and_then(|bytes| bytes.trim().parse::<u64>().ok())
+    .is_some_and(|bytes| bytes > 0)
+}
+
+/// Writes back and drops the kernel's cached blocks of `device`, so the next reads come from the
+/// card itself.
+pub fn flush_buffers(device: &File) -> io::Result<()> {
+    // SAFETY: the ioctl takes no argument and the fd stays open for the call.
+    unsafe { blkflsbuf(device.as_raw_fd()) }?;
+    Ok(())
+}
+
+/// Has the kernel read the partition table of `device` again, so the partitions that were just
+/// written show up right away. Fails with `EBUSY` while one of the old ones is in use.
+pub fn reread_partitions(device: &File) -> io::Result<()> {
+    // SAFETY: as for `flush_buffers`.
+    match unsafe { blkrrpart(device.as_raw_fd()) } {
+        // Not a block device, e.g. an image file as the target.
+        Err(nix::errno::Errno::ENOTTY) => Ok(()),
+        result => result.map(drop).map_err(io::Error::from),
+    }
+}
+
+/// Waits until udev has handled the events of new partitions, giving up after `timeout`. Does
+/// nothing where udev isn't installed.
+pub fn settle(timeout: Duration) {
+    let status = Command::new("udevadm")
+        .arg("settle")
+        .arg(format!("--timeout={}", timeout.as_secs()))
+        .env("PATH", "/usr/sbin:/sbin:/usr/bin:/bin")
+        .status();
+    match status {
+        Ok(status) if !status.success() => println!("udev didn't settle: {status}"),
+        Ok(_) => {}
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => println!("Got error when waiting for udev to settle: {error:?}"),
+    }
+}
+
+/// Makes sure the next reads of `file` come from the card rather than the page cache: writes
+/// everything back, then drops the cached pages, and for block devices the buffer cache too.
+pub fn drop_cache(file: &File) -> io::Result<()> {
+    file.sync_all()?;
+    posix_fadvise(file, 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED)?;
+    match flush_buffers(file) {
+        // Not a block device, e.g. an image file as the target.
+        Err(error) if error.raw_os_error() == Some(nix::libc::ENOTTY) => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_stay_aligned() {
+        let sizes = |logical, physical, optimal_io| BlockSizes {
+            logical,
+            physical,
+            optimal_io,
+        };
+        let limit = 16 * 1024 * 1024;
+        assert_eq!(BlockSizes::default().chunk_size(limit), limit);
+        assert_eq!(sizes(512, 4096, 0).chunk_size(limit), limit);
+        // USB bridges report odd optimal sizes.
+        assert_eq!(
+            sizes(512, 512, 3 * 1024 * 1024).chunk_size(limit),
+            15 * 1024 * 1024
+        );
+        // Too large to fit, the physical block size still keeps chunks aligned.
+        assert_eq!(sizes(512, 4096, 32 * 1024 * 1024).chunk_size(limit), limit);
+        assert_eq!(sizes(512, 4096, 0).chunk_size(1000), 512);
+        assert_eq!(sizes(4096, 4096, 0).chunk_size(1000), 1000);
+    }
+}
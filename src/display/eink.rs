@@ -0,0 +1,175 @@
+//! Waveshare 2.13" e-paper HAT (V3/V4, SSD1680 controller) on SPI. It keeps the last card's QR
+//! code up without power, so a batch can be labeled long after it was flashed. A full refresh
+//! takes a couple of seconds, so the panel is only touched when a flash starts and ends, and when
+//! the network comes up.
+
+use std::net::IpAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rppal::gpio::{Gpio, InputPin, OutputPin};
+use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+
+use super::StatusDisplay;
+use crate::config::EinkConfig;
+use crate::error::HardwareError;
+use crate::history::FlashRecord;
+
+/// Visible pixels across the short side. RAM rows are byte aligned, so they hold 128.
+const WIDTH: usize = 122;
+const HEIGHT: usize = 250;
+const ROW_BYTES: usize = WIDTH.div_ceil(8);
+
+const SPI_CLOCK: u32 = 4_000_000;
+/// A full refresh takes about 2 s, anything much longer means the panel isn't there.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DRIVER_OUTPUT: u8 = 0x01;
+const DEEP_SLEEP: u8 = 0x10;
+const DATA_ENTRY_MODE: u8 = 0x11;
+const SOFT_RESET: u8 = 0x12;
+const TEMPERATURE_SENSOR: u8 = 0x18;
+const ACTIVATE: u8 = 0x20;
+const UPDATE_CONTROL_1: u8 = 0x21;
+const UPDATE_CONTROL_2: u8 = 0x22;
+const WRITE_RAM: u8 = 0x24;
+const BORDER: u8 = 0x3C;
+const RAM_X_RANGE: u8 = 0x44;
+const RAM_Y_RANGE: u8 = 0x45;
+const RAM_X_COUNTER: u8 = 0x4E;
+const RAM_Y_COUNTER: u8 = 0x4F;
+
+pub struct Eink {
+    spi: Spi,
+    data_command: OutputPin,
+    reset: OutputPin,
+    busy: InputPin,
+}
+
+impl Eink {
+    pub fn new(config: &EinkConfig) -> Result<Self, HardwareError> {
+        let bus = match config.spi_bus {
+            0 => Bus::Spi0,
+            1 => Bus::Spi1,
+            bus => {
+                return Err(HardwareError::Unsupported(format!(
+                    "SPI bus {bus} is not supported"
+                )))
+            }
+        };
+        let slave = match config.chip_select {
+            0 => SlaveSelect::Ss0,
+            1 => SlaveSelect::Ss1,
+            2 => SlaveSelect::Ss2,
+            chip_select => {
+                return Err(HardwareError::Unsupported(format!(
+                    "Chip select {chip_select} is not supported"
+                )))
+            }
+        };
+        let gpio = Gpio::new()?;
+        Ok(Self {
+            spi: Spi::new(bus, slave, SPI_CLOCK, Mode::Mode0)?,
+            data_command: gpio.get(config.data_command_pin)?.into_output_low(),
+            reset: gpio.get(config.reset_pin)?.into_output_high(),
+            busy: gpio.get(config.busy_pin)?.into_input(),
+        })
+    }
+
+    fn command(&mut self, command: u8, data: &[u8]) -> Result<(), HardwareError> {
+        self.data_command.set_low();
+        self.spi.write(&[command])?;
+        if !data.is_empty() {
+            self.data_command.set_high();
+            // spidev caps transfers at 4 KiB by default.
+            for chunk in data.chunks(4096) {
+                self.spi.write(chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn wait_until_idle(&mut self) -> Result<(), HardwareError> {
+        let started = Instant::now();
+        while self.busy.is_high() {
+            if started.elapsed() > BUSY_TIMEOUT {
+                return Err(HardwareError::Protocol {
+                    chip: "SSD1680",
+                    message: "Panel stayed busy".to_string(),
+                });
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    /// Wakes the panel from deep sleep, which only a hardware reset does, and sets it up again.
+    fn wake(&mut self) -> Result<(), HardwareError> {
+        self.reset.set_low();
+        thread::sleep(Duration::from_millis(10));
+        self.reset.set_high();
+        thread::sleep(Duration::from_millis(10));
+        self.wait_until_idle()?;
+        self.command(SOFT_RESET, &[])?;
+        self.wait_until_idle()?;
+
+        let last_row = (HEIGHT - 1) as u16;
+        self.command(
+            DRIVER_OUTPUT,
+            &[last_row as u8, (last_row >> 8) as u8, 0x00],
+        )?;
+        // X and Y increment, X first.
+        self.command(DATA_ENTRY_MODE, &[0x03])?;
+        self.command(RAM_X_RANGE, &[0, ROW_BYTES as u8 - 1])?;
+        self.command(RAM_Y_RANGE, &[0, 0, last_row as u8, (last_row >> 8) as u8])?;
+        self.command(BORDER, &[0x05])?;
+        self.command(UPDATE_CONTROL_1, &[0x00, 0x80])?;
+        self.command(TEMPERATURE_SENSOR, &[0x80])?;
+        self.command(RAM_X_COUNTER, &[0])?;
+        self.command(RAM_Y_COUNTER, &[0, 0])?;
+        self.wait_until_idle()
+    }
+
+    /// Shows `dark` (row by row, `WIDTH` by `HEIGHT`) with a full refresh, then sends the panel
+    /// to deep sleep, where the picture stays and the panel draws nothing.
+    fn show(&mut self, dark: &[bool]) -> Result<(), HardwareError> {
+        self.wake()?;
+        // Set bits are white.
+        let mut frame = vec![0xFF; ROW_BYTES * HEIGHT];
+        for (index, _) in dark.iter().enumerate().filter(|(_, dark)| **dark) {
+            let (x, y) = (index % WIDTH, index / WIDTH);
+            frame[y * ROW_BYTES + x / 8] &= !(0x80 >> (x % 8));
+        }
+        self.command(WRITE_RAM, &frame)?;
+        self.command(UPDATE_CONTROL_2, &[0xF7])?;
+        self.command(ACTIVATE, &[])?;
+        self.wait_until_idle()?;
+        self.command(DEEP_SLEEP, &[0x01])
+    }
+}
+
+impl StatusDisplay for Eink {
+    fn flash_started(&mut self, _record: &FlashRecord) -> Result<(), HardwareError> {
+        self.show(&[false; WIDTH * HEIGHT])
+    }
+
+    fn flash_finished(&mut self, record: &FlashRecord) -> Result<(), HardwareError> {
+        self.show(&super::qr_code(
+            &super::record_payload(record),
+            WIDTH,
+            HEIGHT,
+        )?)
+    }
+
+    fn network_changed(
+        &mut self,
+        hostname: &str,
+        addresses: &[IpAddr],
+    ) -> Result<(), HardwareError> {
+        self.show(&super::qr_code(
+            &super::network_payload(hostname, addresses),
+            WIDTH,
+            HEIGHT,
+        )?)
+    }
+}
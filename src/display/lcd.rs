@@ -0,0 +1,169 @@
+//! HD44780 character LCD behind a PCF8574 I2C backpack, the common 16x2 and 20x4 modules. The
+//! first line shows the state, the second the image being flashed or the last card's result, or
+//! the address of the unit or a newer image until the first card comes.
+
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+
+use rppal::i2c::I2c;
+
+use super::StatusDisplay;
+use crate::config::LcdConfig;
+use crate::error::HardwareError;
+use crate::history::{FlashRecord, FlashResult};
+use crate::SystemState;
+
+// How the backpack wires its port to the LCD: the data nibble sits in the upper four bits.
+const REGISTER_SELECT: u8 = 0x01;
+const ENABLE: u8 = 0x04;
+const BACKLIGHT: u8 = 0x08;
+
+const CLEAR: u8 = 0x01;
+const ENTRY_MODE_INCREMENT: u8 = 0x06;
+const DISPLAY_ON: u8 = 0x0C;
+const FUNCTION_4BIT_2LINES: u8 = 0x28;
+const SET_DDRAM_ADDRESS: u8 = 0x80;
+/// Where each line starts in display RAM
+const LINE_OFFSETS: [u8; 4] = [0x00, 0x40, 0x14, 0x54];
+
+pub struct Lcd {
+    i2c: I2c,
+    columns: usize,
+    rows: usize,
+}
+
+impl Lcd {
+    pub fn new(config: &LcdConfig) -> Result<Self, HardwareError> {
+        let mut i2c = I2c::with_bus(config.bus)?;
+        i2c.set_slave_address(config.address)?;
+        let mut lcd = Self {
+            i2c,
+            columns: config.columns.into(),
+            rows: config.rows.clamp(1, 4).into(),
+        };
+        // The controller may be in 8 or 4 bit mode, or halfway through a byte, from a previous
+        // run. Three 8 bit function sets bring it to 8 bit mode, from where 4 bit mode is set.
+        thread::sleep(Duration::from_millis(50));
+        for _ in 0..3 {
+            lcd.write_nibble(0x30, 0)?;
+            thread::sleep(Duration::from_millis(5));
+        }
+        lcd.write_nibble(0x20, 0)?;
+        for command in [FUNCTION_4BIT_2LINES, DISPLAY_ON, ENTRY_MODE_INCREMENT] {
+            lcd.command(command)?;
+        }
+        lcd.clear()?;
+        Ok(lcd)
+    }
+
+    fn write_nibble(&mut self, nibble: u8, mode: u8) -> rppal::i2c::Result<()> {
+        let byte = nibble & 0xF0 | mode | BACKLIGHT;
+        // Data is latched on the falling edge of enable.
+        self.i2c.write(&[byte | ENABLE])?;
+        self.i2c.write(&[byte])?;
+        thread::sleep(Duration::from_micros(50));
+        Ok(())
+    }
+
+    fn write_byte(&mut self, byte: u8, mode: u8) -> rppal::i2c::Result<()> {
+        self.write_nibble(byte, mode)?;
+        self.write_nibble(byte << 4, mode)
+    }
+
+    fn command(&mut self, command: u8) -> rppal::i2c::Result<()> {
+        self.write_byte(command, 0)
+    }
+
+    fn clear(&mut self) -> rppal::i2c::Result<()> {
+        self.command(CLEAR)?;
+        // Clearing is by far the slowest command.
+        thread::sleep(Duration::from_millis(2));
+        Ok(())
+    }
+
+    /// Replaces line `row` with `text`, cut or padded to the width of the display. The character
+    /// ROM only matches ASCII from space to `}`, anything else is shown as `?`.
+    fn show_line(&mut self, row: usize, text: &str) -> rppal::i2c::Result<()> {
+        if row >= self.rows {
+            return Ok(());
+        }
+        self.command(SET_DDRAM_ADDRESS | LINE_OFFSETS[row])?;
+        let characters = text
+            .chars()
+            .map(|character| match character {
+                ' '..='}' => character as u8,
+                _ => b'?',
+            })
+            .chain(std::iter::repeat(b' '))
+            .take(self.columns);
+        for character in characters.collect::<Vec<_>>() {
+            self.write_byte(character, REGISTER_SELECT)?;
+        }
+        Ok(())
+    }
+}
+
+fn state_text(state: SystemState) -> &'static str {
+    match state {
+        SystemState::Initializing => "Starting",
+        SystemState::NoSdCard | SystemState::Idle => "Insert card",
+        SystemState::SdCardFound => "Press to flash",
+        SystemState::AwaitingConfirmation => "Press to confirm",
+        SystemState::Flashing => "Flashing",
+        SystemState::Paused => "Paused",
+        SystemState::FlashingSuceeded => "Done",
+        SystemState::FlashedUnverified => "Done, unverified",
+        SystemState::FlashingFailed => "FAILED",
+        SystemState::CardTooSmall => "Card too small",
+        SystemState::DeviceBusy => "Card is busy",
+        SystemState::BadCard => "Bad card",
+        SystemState::CardRemoved => "Card removed",
+        SystemState::ShuttingDown => "Shutting down",
+        SystemState::ConfigError(fault) => fault.text(),
+    }
+}
+
+impl StatusDisplay for Lcd {
+    fn state_changed(&mut self, state: SystemState) -> Result<(), HardwareError> {
+        Ok(self.show_line(0, state_text(state))?)
+    }
+
+    fn flash_started(&mut self, record: &FlashRecord) -> Result<(), HardwareError> {
+        let image = record.image.file_name().unwrap_or_default();
+        Ok(self.show_line(1, &image.to_string_lossy())?)
+    }
+
+    /// The address on the second line, and with four lines the hostname above it.
+    fn network_changed(
+        &mut self,
+        hostname: &str,
+        addresses: &[IpAddr],
+    ) -> Result<(), HardwareError> {
+        let address = match addresses.first() {
+            Some(address) => address.to_string(),
+            None => "No network".to_string(),
+        };
+        if self.rows >= 4 {
+            self.show_line(2, hostname)?;
+            self.show_line(3, &address)?;
+        } else {
+            self.show_line(1, &address)?;
+        }
+        Ok(())
+    }
+
+    fn update_available(&mut self, version: &str) -> Result<(), HardwareError> {
+        Ok(self.show_line(1, &format!("New image {version}"))?)
+    }
+
+    fn flash_finished(&mut self, record: &FlashRecord) -> Result<(), HardwareError> {
+        let text = match (record.result, &record.serial) {
+            (FlashResult::Succeeded, Some(serial)) => format!("SN {serial}"),
+            (FlashResult::Succeeded, None) => "OK".to_string(),
+            (FlashResult::Failed, _) => "Check the log".to_string(),
+            (FlashResult::Removed, _) => "Flash again".to_string(),
+        };
+        Ok(self.show_line(1, &text)?)
+    }
+}
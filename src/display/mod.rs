@@ -0,0 +1,150 @@
+//! Status displays, independent of the panel. Each runs on its own thread, as some panels take
+//! seconds to refresh.
+
+use std::net::IpAddr;
+use std::thread;
+
+use qrcode::{Color, EcLevel, QrCode};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::{DisplayBackend, DisplayConfig};
+use crate::error::HardwareError;
+use crate::events::{Event, EventBus};
+use crate::history::{FlashRecord, FlashResult};
+use crate::network;
+use crate::SystemState;
+
+mod eink;
+mod lcd;
+mod oled;
+
+pub trait StatusDisplay: Send {
+    fn state_changed(&mut self, _state: SystemState) -> Result<(), HardwareError> {
+        Ok(())
+    }
+
+    /// The previous card's result has to go, it could be mistaken for this card's.
+    fn flash_started(&mut self, _record: &FlashRecord) -> Result<(), HardwareError> {
+        Ok(())
+    }
+
+    fn flash_finished(&mut self, _record: &FlashRecord) -> Result<(), HardwareError> {
+        Ok(())
+    }
+
+    /// A newer image on the sync source than the one flashed, until a card replaces it. QR code
+    /// panels leave it to the agent's status.
+    fn update_available(&mut self, _version: &str) -> Result<(), HardwareError> {
+        Ok(())
+    }
+
+    /// Where the unit can be reached, shown when the network comes up until a card replaces it.
+    fn network_changed(
+        &mut self,
+        _hostname: &str,
+        _addresses: &[IpAddr],
+    ) -> Result<(), HardwareError> {
+        Ok(())
+    }
+}
+
+/// For rigs without a display.
+struct NoDisplay;
+
+impl StatusDisplay for NoDisplay {}
+
+/// Opens the configured display and keeps it up to date from `events` on a background thread.
+pub fn start(config: &DisplayConfig, events: &EventBus) -> Result<(), HardwareError> {
+    let mut display: Box<dyn StatusDisplay> = match config.backend {
+        DisplayBackend::None => Box::new(NoDisplay),
+        DisplayBackend::Oled => Box::new(oled::Oled::new(&config.oled)?),
+        DisplayBackend::Lcd => Box::new(lcd::Lcd::new(&config.lcd)?),
+        DisplayBackend::Eink => Box::new(eink::Eink::new(&config.eink)?),
+    };
+    let (mut receiver, events) = (events.subscribe(), events.clone());
+    if config.backend != DisplayBackend::None {
+        network::watch(&events);
+    }
+    thread::spawn(move || loop {
+        let result = match receiver.blocking_recv() {
+            Ok(Event::StateChanged(state)) => display.state_changed(state),
+            Ok(Event::FlashStarted(record)) => display.flash_started(&record),
+            Ok(Event::FlashFinished(record)) => display.flash_finished(&record),
+            Ok(Event::NetworkChanged {
+                hostname,
+                addresses,
+            }) => display.network_changed(&hostname, &addresses),
+            Ok(Event::UpdateAvailable(Some(version))) => display.update_available(&version),
+            Ok(_) => continue,
+            // A slow panel may miss states in between, but has to end up on the current one.
+            Err(RecvError::Lagged(_)) => display.state_changed(events.state()),
+            Err(RecvError::Closed) => return,
+        };
+        if let Err(error) = result {
+            println!("Got error when updating the display: {error:?}");
+        }
+    });
+    Ok(())
+}
+
+/// Renders `text` as a QR code in the middle of a `width` by `height` panel, as large as it fits.
+/// Returns whether each pixel is dark, row by row.
+fn qr_code(text: &str, width: usize, height: usize) -> Result<Vec<bool>, HardwareError> {
+    let code = QrCode::with_error_correction_level(text, EcLevel::L)?;
+    let modules = code.width();
+    // Scanners want a quiet zone, keep at least one module of it on each side.
+    let scale = width.min(height) / (modules + 2);
+    if scale == 0 {
+        return Err(HardwareError::Unsupported(format!(
+            "QR code with {modules} modules doesn't fit the display"
+        )));
+    }
+    let left = (width - modules * scale) / 2;
+    let top = (height - modules * scale) / 2;
+
+    let mut pixels = vec![false; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let module = (x.checked_sub(left), y.checked_sub(top));
+            if let (Some(mx), Some(my)) = module {
+                if mx < modules * scale && my < modules * scale {
+                    pixels[y * width + x] = code[(mx / scale, my / scale)] == Color::Dark;
+                }
+            }
+        }
+    }
+    Ok(pixels)
+}
+
+/// The hostname and the addresses, one per line.
+fn network_payload(hostname: &str, addresses: &[IpAddr]) -> String {
+    let mut payload = hostname.to_string();
+    for address in addresses {
+        payload.push_str(&format!("\n{address}"));
+    }
+    payload
+}
+
+/// One CSV line, so scanning it into a spreadsheet fills a row: serial, image version, result
+/// and the first 12 digits of the digest read back from the card.
+fn record_payload(record: &FlashRecord) -> String {
+    let result = match record.result {
+        FlashResult::Succeeded => "succeeded",
+        FlashResult::Failed => "failed",
+        FlashResult::Removed => "removed",
+    };
+    let digest = record
+        .device_sha256
+        .as_deref()
+        .or(record.image_sha256.as_deref())
+        .map(|digest| &digest[..digest.len().min(12)])
+        .unwrap_or_default();
+    [
+        record.serial.as_deref().unwrap_or_default(),
+        record.image_version.as_deref().unwrap_or_default(),
+        result,
+        digest,
+    ]
+    .map(|field| field.replace(',', " "))
+    .join(",")
+}
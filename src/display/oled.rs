@@ -0,0 +1,120 @@
+//! 128x64 SSD1306 OLED on I2C, showing the last flash record as a QR code.
+
+use std::net::IpAddr;
+
+use rppal::i2c::I2c;
+
+use super::StatusDisplay;
+use crate::config::OledConfig;
+use crate::error::HardwareError;
+use crate::history::FlashRecord;
+
+const WIDTH: usize = 128;
+const HEIGHT: usize = 64;
+const PAGES: usize = HEIGHT / 8;
+
+// The control byte in front of every transfer tells commands and pixel data apart.
+const COMMAND: u8 = 0x00;
+const DATA: u8 = 0x40;
+
+/// Init sequence for the common 128x64 modules with the charge pump on board.
+const INIT: &[u8] = &[
+    0xAE, // display off
+    0xD5, 0x80, // clock divide ratio
+    0xA8, 0x3F, // multiplex ratio, 64 lines
+    0xD3, 0x00, // no display offset
+    0x40, // start line 0
+    0x8D, 0x14, // enable the charge pump
+    0x20, 0x00, // horizontal addressing
+    0xA1, // mirror columns, so column 0 is on the left
+    0xC8, // scan rows top to bottom
+    0xDA, 0x12, // COM pins layout
+    0x81, 0xCF, // contrast
+    0xD9, 0xF1, // pre-charge period
+    0xDB, 0x40, // VCOMH level
+    0xA4, // show the RAM contents
+    0xA6, // not inverted
+    0xAF, // display on
+];
+
+pub struct Oled {
+    i2c: I2c,
+    /// One bit per pixel, in the controller's layout: each byte is a column of 8 rows
+    buffer: [u8; WIDTH * PAGES],
+}
+
+impl Oled {
+    pub fn new(config: &OledConfig) -> Result<Self, HardwareError> {
+        let mut i2c = I2c::with_bus(config.bus)?;
+        i2c.set_slave_address(config.address)?;
+        let mut oled = Self {
+            i2c,
+            buffer: [0; WIDTH * PAGES],
+        };
+        oled.command(INIT)?;
+        oled.flush()?;
+        Ok(oled)
+    }
+
+    fn command(&mut self, bytes: &[u8]) -> rppal::i2c::Result<()> {
+        for byte in bytes {
+            self.i2c.write(&[COMMAND, *byte])?;
+        }
+        Ok(())
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize) {
+        self.buffer[y / 8 * WIDTH + x] |= 1 << (y % 8);
+    }
+
+    fn flush(&mut self) -> rppal::i2c::Result<()> {
+        // Column and page range covering the whole panel, the address wraps after the last byte.
+        self.command(&[0x21, 0, WIDTH as u8 - 1, 0x22, 0, PAGES as u8 - 1])?;
+        // Most I2C adapters cap transfers well below the 1 KiB frame.
+        for chunk in self.buffer.chunks(32) {
+            let mut transfer = Vec::with_capacity(chunk.len() + 1);
+            transfer.push(DATA);
+            transfer.extend_from_slice(chunk);
+            self.i2c.write(&transfer)?;
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> rppal::i2c::Result<()> {
+        self.buffer.fill(0);
+        self.flush()
+    }
+
+    fn show_qr(&mut self, text: &str) -> Result<(), HardwareError> {
+        let dark = super::qr_code(text, WIDTH, HEIGHT)?;
+        self.buffer.fill(0);
+        // The panel lights set pixels, light the rest so the code reads dark on light.
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                if !dark[y * WIDTH + x] {
+                    self.set_pixel(x, y);
+                }
+            }
+        }
+        self.flush()?;
+        Ok(())
+    }
+}
+
+impl StatusDisplay for Oled {
+    fn flash_started(&mut self, _record: &FlashRecord) -> Result<(), HardwareError> {
+        Ok(self.clear()?)
+    }
+
+    fn flash_finished(&mut self, record: &FlashRecord) -> Result<(), HardwareError> {
+        self.show_qr(&super::record_payload(record))
+    }
+
+    fn network_changed(
+        &mut self,
+        hostname: &str,
+        addresses: &[IpAddr],
+    ) -> Result<(), HardwareError> {
+        self.show_qr(&super::network_payload(hostname, addresses))
+    }
+}
@@ -0,0 +1,27 @@
+//! Telling the card which blocks no longer hold data, before they are written again.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+
+// From linux/fs.h
+nix::ioctl_write_ptr_bad!(blkdiscard, nix::request_code_none!(0x12, 119), [u64; 2]);
+nix::ioctl_write_ptr_bad!(blksecdiscard, nix::request_code_none!(0x12, 125), [u64; 2]);
+
+/// Discards (TRIMs) the first `length` bytes of the block device `device`.
+pub fn discard(device: &File, length: u64) -> io::Result<()> {
+    let range = [0, length];
+    // SAFETY: the fd is open for writing for the duration of the call, and the kernel only reads
+    // the two u64s of `range`.
+    unsafe { blkdiscard(device.as_raw_fd(), &range) }?;
+    Ok(())
+}
+
+/// Securely erases the first `length` bytes of `device`, so the old data is gone from the flash
+/// cells too and not just unmapped. Fails with `Unsupported` when the card can't.
+pub fn secure_discard(device: &File, length: u64) -> io::Result<()> {
+    let range = [0, length];
+    // SAFETY: as for `discard`.
+    unsafe { blksecdiscard(device.as_raw_fd(), &range) }?;
+    Ok(())
+}
@@ -0,0 +1,192 @@
+//! Errors of the flashing pipeline and of the hardware around it, with enough context to tell
+//! failures apart: the state machine shows a card that is too small differently from one that is
+//! busy or broken.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DeviceError {
+    #[error("{device:?} is in use (mounted or opened by another process)")]
+    Busy { device: PathBuf },
+    /// Part of an LVM volume group, a RAID array or another device-mapper device
+    #[error("{device:?} is held by {holders}, refusing to overwrite a member of an LVM, RAID or device-mapper device")]
+    Held { device: PathBuf, holders: String },
+    #[error("Opening {device:?} failed: {source}")]
+    Open {
+        device: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Image is {image_size} bytes, but the card in {device:?} only has {device_size}")]
+    TooSmall {
+        device: PathBuf,
+        image_size: u64,
+        device_size: u64,
+    },
+    /// Writing ran past the end of the card, for images whose size isn't known up-front
+    #[error("Card in {device:?} is full at {offset}")]
+    Full { device: PathBuf, offset: u64 },
+    #[error("Card in {device:?} claims {device_size} bytes, but doesn't hold data written near its end. It is probably counterfeit")]
+    Counterfeit { device: PathBuf, device_size: u64 },
+    /// Partition tables of images count in 512 byte sectors, which a card with larger ones reads
+    /// wrong
+    #[error("{device:?} has {logical} byte sectors, images are laid out for 512 byte ones")]
+    SectorSize { device: PathBuf, logical: u64 },
+    #[error("Secure erase was requested, but {device:?} isn't an MMC device")]
+    NotMmc { device: PathBuf },
+    #[error("Card in {device:?} doesn't support secure erase")]
+    SecureEraseUnsupported { device: PathBuf },
+    /// The card was pulled out, or its reader went away with it
+    #[error("Card in {device:?} was removed while flashing")]
+    Removed { device: PathBuf },
+    #[error("{operation} {device:?} failed: {source}")]
+    Io {
+        device: PathBuf,
+        /// What was being done, e.g. `Securely erasing`
+        operation: &'static str,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl DeviceError {
+    /// Opening a card that something else holds fails with `EBUSY`.
+    pub fn open(device: &Path, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::ResourceBusy => DeviceError::Busy {
+                device: device.to_path_buf(),
+            },
+            _ => DeviceError::Open {
+                device: device.to_path_buf(),
+                source,
+            },
+        }
+    }
+
+    pub fn io<'a>(
+        device: &'a Path,
+        operation: &'static str,
+    ) -> impl FnOnce(io::Error) -> Self + 'a {
+        move |source| DeviceError::Io {
+            device: device.to_path_buf(),
+            operation,
+            source,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error("Card in {device:?} ended at {offset}, before all bytes were verified")]
+    Truncated { device: PathBuf, offset: u64 },
+    /// The card doesn't hold what was written, and writing it again didn't help
+    #[error("Chunk at {offset} of {device:?} doesn't match, even after writing it again")]
+    Mismatch { device: PathBuf, offset: u64 },
+    #[error("Image changed while flashing, chunk at {offset} is different now")]
+    ImageChanged { offset: u64 },
+    /// The image read differently than when it was hashed, its source changed or misread
+    #[error("Image was read with SHA-256 {actual}, but it hashed to {expected} before")]
+    ImageDigestMismatch { expected: String, actual: String },
+    #[error("{device:?} reads back with SHA-256 {actual}, but the image has {expected}")]
+    DigestMismatch {
+        device: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    #[error("Reading back {device:?} at {offset} failed: {source}")]
+    Read {
+        device: PathBuf,
+        offset: u64,
+        #[source]
+        source: io::Error,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum FlashError {
+    #[error("Image share is unavailable: {0}")]
+    Share(#[source] io::Error),
+    #[error("Reading the flash history failed: {0}")]
+    History(#[source] io::Error),
+    #[error("Opening the image failed: {0}")]
+    OpenImage(#[source] io::Error),
+    #[error("Reading the image at {offset} failed: {source}")]
+    ReadImage {
+        offset: u64,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Writing {device:?} at {offset} failed: {source}")]
+    Write {
+        device: PathBuf,
+        offset: u64,
+        #[source]
+        source: io::Error,
+    },
+    /// A bit flip in RAM, caught between reading the chunk from the image and writing it
+    #[error("Buffer at {offset} changed in memory before it was written")]
+    Corrupted { offset: u64 },
+    #[error("Post-flash steps failed: {0}")]
+    PostFlash(#[source] io::Error),
+    /// Shut down, card removed or cancelled by the user, at a chunk boundary
+    #[error("Flash was cancelled at {offset}")]
+    Cancelled { offset: u64 },
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+    #[error(transparent)]
+    Verify(#[from] VerifyError),
+}
+
+impl FlashError {
+    /// Writing past the end of the card fails with `ENOSPC`, writing to a card that is gone with
+    /// `ENODEV` or `ENXIO`.
+    pub fn write(device: &Path, offset: u64, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::StorageFull => DeviceError::Full {
+                device: device.to_path_buf(),
+                offset,
+            }
+            .into(),
+            _ if matches!(
+                source.raw_os_error(),
+                Some(nix::libc::ENODEV | nix::libc::ENXIO)
+            ) =>
+            {
+                DeviceError::Removed {
+                    device: device.to_path_buf(),
+                }
+                .into()
+            }
+            _ => FlashError::Write {
+                device: device.to_path_buf(),
+                offset,
+                source,
+            },
+        }
+    }
+
+    pub fn card_removed(&self) -> bool {
+        matches!(self, FlashError::Device(DeviceError::Removed { .. }))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum HardwareError {
+    #[error("GPIO: {0}")]
+    Gpio(#[from] rppal::gpio::Error),
+    #[error("I2C: {0}")]
+    I2c(#[from] rppal::i2c::Error),
+    #[error("SPI: {0}")]
+    Spi(#[from] rppal::spi::Error),
+    /// The config asks for something the hardware doesn't have
+    #[error("{0}")]
+    Unsupported(String),
+    /// A chip answered with something unexpected, or not at all
+    #[error("{chip}: {message}")]
+    Protocol { chip: &'static str, message: String },
+    #[error("QR code: {0}")]
+    Qr(#[from] qrcode::types::QrError),
+}
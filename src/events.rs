@@ -0,0 +1,122 @@
+//! The bus connecting inputs, the state machine and outputs. Inputs publish what happened, the
+//! state machine publishes where it is and how a flash goes, and every output (LEDs, display,
+//! notifications, the agent, the log) subscribes on its own and only sees what it asks for.
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+
+use crate::history::FlashRecord;
+use crate::SystemState;
+
+/// Events a subscriber may fall behind by before it misses some. Progress comes once per written
+/// buffer, so even a whole flash fits.
+const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ButtonEvent {
+    Press,
+    LongPress,
+    /// Held for much longer, after the `LongPress`
+    ShutdownHold,
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    ButtonPressed(ButtonEvent),
+    /// A block device appeared or changed, e.g. media was inserted into a reader
+    DeviceAdded,
+    /// A card was inserted that passes the filters, before the state machine settles on it
+    CardDetected,
+    /// Hotplug events stopped coming, cards have to be polled for again
+    HotplugStopped,
+    /// A new release is in place, the cloner should exit between cards
+    UpdateInstalled,
+    /// The UPS reports the supply failing, or back again
+    PowerFailing(bool),
+    /// The network came up or its addresses changed, empty once it is down
+    NetworkChanged {
+        hostname: String,
+        addresses: Vec<IpAddr>,
+    },
+    StateChanged(SystemState),
+    FlashStarted(FlashRecord),
+    ProgressTick {
+        device: PathBuf,
+        written: u64,
+        /// Unknown for compressed images without a size in their header
+        total: Option<u64>,
+    },
+    FlashFinished(FlashRecord),
+    /// The version of a newer image on the sync source than the local one, `None` while the
+    /// local one is up to date
+    UpdateAvailable(Option<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+    state: Arc<Mutex<SystemState>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            state: Arc::new(Mutex::new(SystemState::Initializing)),
+        }
+    }
+
+    /// Nobody listening is fine, e.g. no display and no notifications are configured.
+    pub fn publish(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Only events published from now on are received.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn state(&self) -> SystemState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn set_state(&self, state: SystemState) {
+        self.update_state(|_| Some(state));
+    }
+
+    /// Moves to the state `update` returns for the current one, if it returns one. Subscribers
+    /// hear about actual changes only.
+    pub fn update_state(&self, update: impl FnOnce(SystemState) -> Option<SystemState>) {
+        let mut current = self.state.lock().unwrap();
+        let Some(next) = update(*current) else {
+            return;
+        };
+        if next != *current {
+            *current = next;
+            // Still under the lock, so subscribers see changes in the order they happened.
+            self.publish(Event::StateChanged(next));
+        }
+    }
+}
+
+/// Logs button presses and state changes.
+pub async fn log(mut events: broadcast::Receiver<Event>) {
+    loop {
+        match events.recv().await {
+            Ok(Event::ButtonPressed(ButtonEvent::Press)) => println!("Button is pressed"),
+            Ok(Event::ButtonPressed(ButtonEvent::LongPress)) => println!("Button is held"),
+            Ok(Event::ButtonPressed(ButtonEvent::ShutdownHold)) => {
+                println!("Button is held for shutdown")
+            }
+            Ok(Event::StateChanged(state)) => println!("State changed to {state:?}"),
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                println!("Log fell behind, missed {missed} events")
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! Reads which blocks of an ext2/3/4 filesystem are allocated, from its block bitmaps, and which
+//! UUID it has.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xEF53;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_64BIT: u32 = 0x80;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const BG_BLOCK_UNINIT: u16 = 0x2;
+/// Blocks are at most 64 KiB.
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+fn u16_at(buffer: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buffer[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap())
+}
+
+/// Whether a group holds a backup of the superblock and group descriptors.
+fn has_superblock(group: u64, sparse_super: bool) -> bool {
+    let is_power_of = |base: u64| {
+        let mut value = 1;
+        while value < group {
+            value *= base;
+        }
+        value == group
+    };
+    !sparse_super || group <= 1 || is_power_of(3) || is_power_of(5) || is_power_of(7)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// What [`allocated_ranges`] needs of the superblock, checked against itself and the size of
+/// what it is on. A corrupt one mustn't lead to a bmap leaving out blocks, or to a panic.
+#[derive(Debug)]
+struct Layout {
+    block_size: u64,
+    blocks_count: u64,
+    first_data_block: u64,
+    blocks_per_group: u64,
+    reserved_gdt_blocks: u64,
+    is_64bit: bool,
+    sparse_super: bool,
+    descriptor_size: u64,
+    groups: u64,
+    gdt_blocks: u64,
+}
+
+impl Layout {
+    /// `available` is how many bytes there are from the start of the filesystem on.
+    fn parse(superblock: &[u8], available: u64) -> io::Result<Self> {
+        let incompat = u32_at(superblock, 0x60);
+        let ro_compat = u32_at(superblock, 0x64);
+        // With meta_bg the group descriptors are spread over the filesystem.
+        if incompat & INCOMPAT_META_BG != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Filesystems with meta_bg aren't supported",
+            ));
+        }
+        let log_block_size = u32_at(superblock, 0x18);
+        if log_block_size > MAX_LOG_BLOCK_SIZE {
+            return Err(invalid(format!("Block size 1024 << {log_block_size}")));
+        }
+        let block_size = 1024u64 << log_block_size;
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+        let mut blocks_count = u64::from(u32_at(superblock, 0x04));
+        let descriptor_size = if is_64bit {
+            blocks_count |= u64::from(u32_at(superblock, 0x150)) << 32;
+            u64::from(u16_at(superblock, 0xFE))
+        } else {
+            32
+        };
+        let first_data_block = u64::from(u32_at(superblock, 0x14));
+        let blocks_per_group = u64::from(u32_at(superblock, 0x20));
+        // Superblock and descriptors are in block 1 with 1k blocks, in block 0 otherwise.
+        if first_data_block != u64::from(block_size == 1024) {
+            return Err(invalid(format!("First data block {first_data_block}")));
+        }
+        if blocks_count <= first_data_block
+            || blocks_count
+                .checked_mul(block_size)
+                .is_none_or(|size| size > available)
+        {
+            return Err(invalid(format!(
+                "{blocks_count} blocks of {block_size} bytes, with {available} bytes there"
+            )));
+        }
+        // A group's bitmap is a single block.
+        if blocks_per_group == 0 || blocks_per_group > 8 * block_size {
+            return Err(invalid(format!("{blocks_per_group} blocks per group")));
+        }
+        if !descriptor_size.is_power_of_two() || !(32..=block_size).contains(&descriptor_size) {
+            return Err(invalid(format!(
+                "Group descriptors of {descriptor_size} bytes"
+            )));
+        }
+        let groups = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+        let gdt_blocks = (groups * descriptor_size).div_ceil(block_size);
+        // Without meta_bg they all follow the superblock in the first group.
+        if 1 + gdt_blocks > blocks_per_group {
+            return Err(invalid(format!(
+                "{groups} group descriptors don't fit in a group"
+            )));
+        }
+        Ok(Self {
+            block_size,
+            blocks_count,
+            first_data_block,
+            blocks_per_group,
+            reserved_gdt_blocks: u64::from(u16_at(superblock, 0xCE)),
+            is_64bit,
+            sparse_super: ro_compat & RO_COMPAT_SPARSE_SUPER != 0,
+            descriptor_size,
+            groups,
+            gdt_blocks,
+        })
+    }
+}
+
+/// Byte ranges, relative to `offset`, of the allocated blocks of the filesystem starting at
+/// `offset`. `None` if there is no ext filesystem there, an error if it is one that can't be
+/// read, so everything has to be kept.
+pub fn allocated_ranges(
+    device: &mut (impl Read + Seek),
+    offset: u64,
+) -> io::Result<Option<Vec<Range<u64>>>> {
+    let mut superblock = [0; 1024];
+    device.seek(SeekFrom::Start(offset + SUPERBLOCK_OFFSET))?;
+    device.read_exact(&mut superblock)?;
+    if u16_at(&superblock, 0x38) != MAGIC {
+        return Ok(None);
+    }
+    let available = device.seek(SeekFrom::End(0))?.saturating_sub(offset);
+    let layout = Layout::parse(&superblock, available)?;
+    let Layout {
+        block_size,
+        blocks_count,
+        first_data_block,
+        blocks_per_group,
+        descriptor_size,
+        groups,
+        ..
+    } = layout;
+
+    let mut descriptors = vec![0; (groups * descriptor_size) as usize];
+    device.seek(SeekFrom::Start(
+        offset + (first_data_block + 1) * block_size,
+    ))?;
+    device.read_exact(&mut descriptors)?;
+
+    let mut ranges: Vec<Range<u64>> = vec![];
+    let mut push_blocks = |first: u64, count: u64| {
+        let range = first * block_size..(first + count).min(blocks_count) * block_size;
+        match ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => ranges.push(range),
+        }
+    };
+    // The boot block before the superblock on 1k block filesystems.
+    if first_data_block > 0 {
+        push_blocks(0, first_data_block);
+    }
+
+    let mut bitmap = vec![0; block_size as usize];
+    for group in 0..groups {
+        let descriptor = &descriptors[(group * descriptor_size) as usize..];
+        let mut bitmap_block = u64::from(u32_at(descriptor, 0x00));
+        if layout.is_64bit && descriptor_size >= 64 {
+            bitmap_block |= u64::from(u32_at(descriptor, 0x20)) << 32;
+        }
+        let flags = u16_at(descriptor, 0x12);
+        let group_start = first_data_block + group * blocks_per_group;
+        let group_blocks = blocks_per_group.min(blocks_count - group_start);
+
+        if flags & BG_BLOCK_UNINIT != 0 {
+            // The bitmap was never written, only the superblock backup is in use.
+            if has_superblock(group, layout.sparse_super) {
+                push_blocks(
+                    group_start,
+                    1 + layout.gdt_blocks + layout.reserved_gdt_blocks,
+                );
+            }
+            continue;
+        }
+
+        if bitmap_block >= blocks_count {
+            return Err(invalid(format!(
+                "Block bitmap of group {group} at block {bitmap_block}, past the end"
+            )));
+        }
+        device.seek(SeekFrom::Start(offset + bitmap_block * block_size))?;
+        device.read_exact(&mut bitmap)?;
+        for block in 0..group_blocks {
+            if bitmap[(block / 8) as usize] & (1 << (block % 8)) != 0 {
+                push_blocks(group_start + block, 1);
+            }
+        }
+    }
+    Ok(Some(ranges))
+}
+
+/// UUID of the filesystem starting at `offset`, as blkid shows it. `None` if there is no ext
+/// filesystem there.
+pub fn uuid(device: &mut (impl Read + Seek), offset: u64) -> io::Result<Option<String>> {
+    let mut superblock = [0; 1024];
+    device.seek(SeekFrom::Start(offset + SUPERBLOCK_OFFSET))?;
+    device.read_exact(&mut superblock)?;
+    if u16_at(&superblock, 0x38) != MAGIC {
+        return Ok(None);
+    }
+    let hex: Vec<String> = superblock[0x68..0x78]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    Ok(Some(format!(
+        "{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Cursor;
+    use std::process::Command;
+
+    use super::*;
+
+    /// Bytes filled with the contents of `data`, to find its blocks by.
+    const DATA: u8 = 0xA5;
+
+    /// An 8 MiB filesystem made by mkfs.ext4 with `options`, holding a file of 64 KiB of
+    /// `DATA`. `None` if mkfs.ext4 isn't available.
+    fn filesystem(name: &str, options: &[&str]) -> Option<Vec<u8>> {
+        let scratch = std::env::temp_dir().join(format!("ext4-{name}-{}", std::process::id()));
+        let (contents, path) = (scratch.join("contents"), scratch.join("image"));
+        fs::create_dir_all(&contents).unwrap();
+        fs::write(contents.join("data"), [DATA; 64 * 1024]).unwrap();
+        File::create(&path)
+            .unwrap()
+            .set_len(8 * 1024 * 1024)
+            .unwrap();
+        let created = Command::new("mkfs.ext4")
+            .arg("-q")
+            .args(options)
+            .arg("-d")
+            .arg(&contents)
+            .arg(&path)
+            .env("PATH", "/usr/sbin:/sbin:/usr/bin:/bin")
+            .status();
+        let image = fs::read(&path).unwrap();
+        fs::remove_dir_all(&scratch).unwrap();
+        if !created.is_ok_and(|status| status.success()) {
+            println!("mkfs.ext4 isn't available, skipping");
+            return None;
+        }
+        Some(image)
+    }
+
+    fn ranges(image: Vec<u8>) -> io::Result<Option<Vec<Range<u64>>>> {
+        allocated_ranges(&mut Cursor::new(image), 0)
+    }
+
+    fn set_u32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn finds_the_allocated_blocks() {
+        for block_size in ["1024", "4096"] {
+            let Some(image) = filesystem(block_size, &["-b", block_size]) else {
+                return;
+            };
+            let size = image.len() as u64;
+            let block_size: usize = block_size.parse().unwrap();
+            let free_blocks = u32_at(&image, SUPERBLOCK_OFFSET as usize + 0x0C);
+            let data_blocks: Vec<u64> = image
+                .chunks(block_size)
+                .enumerate()
+                .filter(|(_, block)| block.iter().all(|&byte| byte == DATA))
+                .map(|(index, _)| (index * block_size) as u64)
+                .collect();
+            let ranges = ranges(image).unwrap().unwrap();
+
+            assert_eq!(data_blocks.len(), 64 * 1024 / block_size);
+            for start in data_blocks {
+                assert!(ranges.iter().any(|range| range.contains(&start)));
+            }
+            assert!(ranges[0].contains(&SUPERBLOCK_OFFSET));
+            assert!(ranges.windows(2).all(|pair| pair[0].end < pair[1].start));
+            assert!(ranges.last().unwrap().end <= size);
+            // Every block the superblock doesn't count as free.
+            let allocated: u64 = ranges.iter().map(|range| range.end - range.start).sum();
+            assert_eq!(allocated, size - u64::from(free_blocks) * block_size as u64);
+        }
+    }
+
+    #[test]
+    fn refuses_corrupt_and_unsupported_filesystems() {
+        let Some(image) = filesystem("corrupt", &["-b", "4096", "-O", "64bit"]) else {
+            return;
+        };
+        let superblock = SUPERBLOCK_OFFSET as usize;
+        let corrupted = |offset: usize, value: u32| {
+            let mut image = image.clone();
+            set_u32(&mut image, superblock + offset, value);
+            ranges(image)
+        };
+
+        assert!(ranges(vec![0; 8192]).unwrap().is_none());
+        assert!(ranges(image[..image.len() / 2].to_vec()).is_err());
+        assert!(ranges(image[..2048].to_vec()).is_err());
+        // Block size, blocks count, first data block and blocks per group.
+        assert!(corrupted(0x18, 40).is_err());
+        assert!(corrupted(0x04, 0).is_err());
+        assert!(corrupted(0x150, u32::MAX).is_err());
+        assert!(corrupted(0x14, 5).is_err());
+        assert!(corrupted(0x20, 0).is_err());
+        assert!(corrupted(0x20, u32::MAX).is_err());
+        // Group descriptor size, in the same word as the default mount options.
+        assert!(corrupted(0xFC, 3 << 16).is_err());
+        // The block bitmap of the first group, in the descriptors right after the superblock.
+        let mut past_the_end = image.clone();
+        set_u32(&mut past_the_end, 4096, 0x0100_0000);
+        assert!(ranges(past_the_end).is_err());
+
+        let meta_bg = filesystem("meta-bg", &["-b", "4096", "-O", "meta_bg,^resize_inode"]);
+        let error = ranges(meta_bg.unwrap()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,1281 @@
+//! Writing the image to the card and reading it back to verify.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::os::unix::fs::FileExt;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use nix::fcntl::PosixFadviseAdvice;
+use nix::sys::sendfile::sendfile64;
+use sha2::{Digest, Sha256};
+use tokio_util::sync::CancellationToken;
+
+use crate::buffers::BufferPool;
+use crate::capacity;
+use crate::config::{Config, CopyMethod, DeviceBackend, VerifyMode};
+use crate::devices;
+use crate::erase;
+use crate::error::{DeviceError, FlashError, VerifyError};
+use crate::events::{Event, EventBus};
+use crate::hashing::{hex, HashAlgorithm};
+use crate::health;
+use crate::history::{ChunkRewrite, FlashRecord, History};
+use crate::image::mapped::Mapping;
+use crate::image::readahead;
+use crate::image::{self, Image};
+use crate::lock;
+use crate::logging::debug;
+use crate::partition_table::IMAGE_SECTOR_SIZE;
+use crate::pause::PauseControl;
+use crate::post_flash;
+use crate::priority;
+use crate::share::Share;
+use crate::tuning::ChunkTuner;
+use crate::udisks;
+
+/// Re-reading the partition table is tried this often while the old partitions are busy
+const REREAD_ATTEMPTS: u32 = 5;
+const REREAD_INTERVAL: Duration = Duration::from_millis(200);
+/// Chunks start out this small when their size adapts to the card
+const SMALLEST_CHUNK: usize = 1024 * 1024;
+/// How long udev gets to create the new partitions' device nodes before post-flash steps run
+pub(crate) const UDEV_SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The thread flashes run from. Capabilities are per thread and only passed on to the threads a
+/// thread starts, so it is started right after dropping privileges by the thread that dropped
+/// them, see [`crate::privileges::drop_privileges`]. A thread of the blocking pool may have been
+/// started before and have none.
+#[derive(Clone)]
+pub struct Flasher {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Flasher {
+    pub fn start() -> io::Result<Self> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name("flasher".to_string())
+            .spawn(move || {
+                for job in receiver {
+                    job();
+                }
+            })?;
+        Ok(Self { jobs })
+    }
+}
+
+/// Runs [`flash`] on `flasher`, so a flash taking minutes doesn't hold up the tasks driving the
+/// LEDs and reading the button. Progress comes through `events` meanwhile. It runs on a thread
+/// of its own with the configured priority, which then ends with it.
+#[allow(clippy::too_many_arguments)]
+pub async fn spawn(
+    flasher: &Flasher,
+    config: Config,
+    device: PathBuf,
+    buffers: BufferPool,
+    mut record: FlashRecord,
+    pause: PauseControl,
+    events: EventBus,
+    cancel: CancellationToken,
+) -> (FlashRecord, Result<(), FlashError>) {
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    let job = move || {
+        let result = thread::scope(|scope| {
+            let flashing = scope.spawn(|| {
+                if let Err(error) = priority::apply(&config.priority) {
+                    println!("Got error when setting the priority of the flash: {error:?}");
+                }
+                flash(
+                    &config,
+                    &device,
+                    &buffers,
+                    &mut record,
+                    &pause,
+                    &events,
+                    &cancel,
+                )
+            });
+            // Passed on below rather than taking the flasher down with it.
+            flashing.join()
+        });
+        let _ = sender.send(result.map(|result| (record, result)));
+    };
+    flasher
+        .jobs
+        .send(Box::new(job))
+        .expect("the flasher thread runs as long as the cloner");
+    match receiver.await.expect("flash jobs always send a result") {
+        Ok(finished) => finished,
+        Err(panic) => panic::resume_unwind(panic),
+    }
+}
+
+/// Writes the configured image to `device` through `buffers`, verifies it and runs the
+/// post-flash steps. `cancel` is checked between chunks.
+pub fn flash(
+    config: &Config,
+    device: &Path,
+    buffers: &BufferPool,
+    record: &mut FlashRecord,
+    pause: &PauseControl,
+    events: &EventBus,
+    cancel: &CancellationToken,
+) -> Result<(), FlashError> {
+    let result = flash_card(config, device, buffers, record, pause, events, cancel);
+    // Whatever step ran when the card was pulled fails with an error of its own, which only
+    // hides what happened.
+    match result {
+        Err(error)
+            if !error.card_removed() && !devices::card_present(config.device_backend, device) =>
+        {
+            debug!("Flashing {device:?} failed with {error:?} once the card was gone");
+            Err(DeviceError::Removed {
+                device: device.to_path_buf(),
+            }
+            .into())
+        }
+        result => result,
+    }
+}
+
+fn flash_card(
+    config: &Config,
+    device: &Path,
+    buffers: &BufferPool,
+    record: &mut FlashRecord,
+    pause: &PauseControl,
+    events: &EventBus,
+    cancel: &CancellationToken,
+) -> Result<(), FlashError> {
+    if let Some(share) = &config.share {
+        Share::new(share)
+            .ensure_healthy()
+            .map_err(FlashError::Share)?;
+    }
+    record.health = health::read(device);
+    match &record.health {
+        Some(health) if health.near_end_of_life() => {
+            println!("Warning: card in {device:?} is near the end of its life: {health:?}")
+        }
+        Some(health) => println!("Card health: {health:?}"),
+        None => {}
+    }
+    let known = image::digest::lookup(config);
+    record.image_sha256 = known.as_ref().and_then(|known| known.sha256.clone());
+    let image = image::open(config).map_err(FlashError::OpenImage)?;
+    // Opening it exclusively fails for these as well, but only says that something has it.
+    let holders = devices::holders(device);
+    if !holders.is_empty() {
+        return Err(DeviceError::Held {
+            device: device.to_path_buf(),
+            holders: holders.join(", "),
+        }
+        .into());
+    }
+    let mut destination = match config.device_backend {
+        DeviceBackend::Sysfs => lock::open_device_exclusive(device)?,
+        DeviceBackend::Udisks2 => {
+            udisks::unmount_all(device).map_err(DeviceError::io(device, "Unmounting"))?;
+            udisks::open_device(device).map_err(|error| DeviceError::open(device, error))?
+        }
+        DeviceBackend::Simulated => OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(device)
+            .map_err(|error| DeviceError::open(device, error))?,
+    };
+    let device_size = destination
+        .seek(SeekFrom::End(0))
+        .and_then(|size| destination.seek(SeekFrom::Start(0)).map(|_| size))
+        .map_err(DeviceError::io(device, "Finding the size of"))?;
+    let logical = devices::block_sizes(device).logical;
+    if logical != IMAGE_SECTOR_SIZE {
+        return Err(DeviceError::SectorSize {
+            device: device.to_path_buf(),
+            logical,
+        }
+        .into());
+    }
+    if let Some(image_size) = image.size.filter(|image_size| *image_size > device_size) {
+        return Err(DeviceError::TooSmall {
+            device: device.to_path_buf(),
+            image_size,
+            device_size,
+        }
+        .into());
+    }
+    if config.capacity_check {
+        let started = Instant::now();
+        check_capacity(config, device, &mut destination, device_size, record)?;
+        record.timings.capacity_check = Some(started.elapsed().as_secs_f64());
+    }
+    // A secure erase discards everything as well.
+    let started = Instant::now();
+    if config.secure_erase {
+        secure_erase(device, &destination, device_size, record)?;
+    } else if config.discard {
+        discard(device, &destination, device_size);
+    }
+    if config.secure_erase || config.discard {
+        record.timings.erase = Some(started.elapsed().as_secs_f64());
+    }
+    let expected = record.image_sha256.clone();
+    let control = Control {
+        pause,
+        cancel,
+        events,
+    };
+    write_and_verify(config, image, &destination, buffers, record, &control)?;
+    // Having written all of it, we know its digest now.
+    if let (Some(known), None) = (known, expected) {
+        if let Some(sha256) = &record.image_sha256 {
+            if let Err(error) = known.remember(config, sha256) {
+                println!("Got error when storing the image digest: {error:?}");
+            }
+        }
+    }
+    if let Err(error) = reread_partitions(device, &destination) {
+        println!("Got error when re-reading the partition table of {device:?}: {error:?}");
+    }
+    // Closing the device lets udev pick up the new partition table, if re-reading it failed.
+    drop(destination);
+    devices::settle(UDEV_SETTLE_TIMEOUT);
+    let started = Instant::now();
+    let post_flash = post_flash::run(config, device, record).map_err(FlashError::PostFlash);
+    record.timings.post_flash = Some(started.elapsed().as_secs_f64());
+    post_flash
+}
+
+/// Re-reads the partition table, giving whatever probes the card after writing a moment to let go
+/// of the old partitions.
+pub(crate) fn reread_partitions(device: &Path, destination: &File) -> io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        match devices::reread_partitions(destination) {
+            Err(error)
+                if error.kind() == io::ErrorKind::ResourceBusy && attempt < REREAD_ATTEMPTS =>
+            {
+                debug!("Partitions of {device:?} are busy, re-reading them again");
+                thread::sleep(REREAD_INTERVAL);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Discarding is only an optimization, so failing to is logged rather than fatal.
+fn discard(device: &Path, destination: &File, device_size: u64) {
+    if !devices::discard_supported(device) {
+        println!("{device:?} doesn't support discard, skipping it");
+        return;
+    }
+    println!("Discarding {device_size} bytes of {device:?}");
+    if let Err(error) = erase::discard(destination, device_size) {
+        println!("Got error when discarding {device:?}: {error:?}");
+    }
+}
+
+/// Skipped for cards that passed before, the check writes all over the card. Only cards with a
+/// CID are recognized again, cards in USB readers are checked every time.
+fn check_capacity(
+    config: &Config,
+    device: &Path,
+    destination: &mut File,
+    device_size: u64,
+    record: &mut FlashRecord,
+) -> Result<(), FlashError> {
+    if let Some(cid) = &record.cid {
+        let history = History::new(&config.history);
+        if history
+            .capacity_verified(cid)
+            .map_err(FlashError::History)?
+        {
+            println!("Card {cid} passed the capacity check before");
+            return Ok(());
+        }
+    }
+    println!("Checking the capacity of the card");
+    let genuine = capacity::check(destination, device_size)
+        .map_err(DeviceError::io(device, "Checking the capacity of"))?;
+    record.capacity_genuine = Some(genuine);
+    if !genuine {
+        return Err(DeviceError::Counterfeit {
+            device: device.to_path_buf(),
+            device_size,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn secure_erase(
+    device: &Path,
+    destination: &File,
+    device_size: u64,
+    record: &mut FlashRecord,
+) -> Result<(), DeviceError> {
+    record.secure_erased = Some(false);
+    if !devices::is_mmc(device) {
+        return Err(DeviceError::NotMmc {
+            device: device.to_path_buf(),
+        });
+    }
+    println!("Securely erasing {device_size} bytes of {device:?}");
+    match erase::secure_discard(destination, device_size) {
+        Ok(()) => {
+            println!("Card honored the secure erase");
+            record.secure_erased = Some(true);
+            Ok(())
+        }
+        Err(error) if error.kind() == io::ErrorKind::Unsupported => {
+            Err(DeviceError::SecureEraseUnsupported {
+                device: device.to_path_buf(),
+            })
+        }
+        Err(error) => Err(DeviceError::io(device, "Securely erasing")(error)),
+    }
+}
+
+/// Like `read_exact`, but a short read at the end of the stream is not an error. Decompressors
+/// return data in small pieces, chunks must still line up between writing and verifying.
+fn read_full(reader: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
+
+/// Fills in the sizes and digests of `record` as they become known, so they are kept even when
+/// verification fails.
+fn write_and_verify(
+    config: &Config,
+    mut image: Image,
+    destination: &File,
+    buffers: &BufferPool,
+    record: &mut FlashRecord,
+    control: &Control,
+) -> Result<(), FlashError> {
+    let device = record.device.clone();
+    // Only the last chunk can end inside a block, where the image does. Writes go through the
+    // page cache, which reads and writes back the rest of that block.
+    let block_sizes = devices::block_sizes(&device);
+    let largest = block_sizes.chunk_size(buffers.size());
+    let tuner = match config.buffers.adaptive {
+        true => ChunkTuner::new(block_sizes.chunk_size(SMALLEST_CHUNK.min(largest)), largest),
+        false => ChunkTuner::fixed(largest),
+    };
+    debug!("{device:?} has {block_sizes:?}, writing it in chunks of up to {largest} bytes");
+    let started = Instant::now();
+    // Chunks go to the card as they are, they are far larger than anything buffering would gather.
+    let copied = match &image.file {
+        Some(file) if config.copy_method != CopyMethod::Buffered => copy_mapped(
+            file,
+            config.copy_method,
+            destination,
+            &device,
+            tuner,
+            config.verify_hash,
+            control,
+        ),
+        _ => copy_chunks(
+            &mut image,
+            destination,
+            &device,
+            buffers,
+            tuner,
+            config.verify_hash,
+            control,
+        ),
+    };
+    let written = match copied {
+        Ok(written) => written,
+        Err(error @ FlashError::Cancelled { .. }) => {
+            // Leave the card with what was written so far rather than dirty pages for it.
+            let _ = destination.sync_all();
+            return Err(error);
+        }
+        Err(error) => return Err(error),
+    };
+    record.timings.write = Some(started.elapsed().as_secs_f64());
+    record.bytes_written = Some(written.length);
+    record.chunk_size = Some(written.chunk_size as u64);
+    if let Some(expected) = record.image_sha256.replace(written.sha256.clone()) {
+        if expected != written.sha256 {
+            return Err(VerifyError::ImageDigestMismatch {
+                expected,
+                actual: written.sha256,
+            }
+            .into());
+        }
+    }
+    println!(
+        "Written bytes, reading back to verify. Bytes written = {}, image SHA-256 = {}",
+        written.length, written.sha256
+    );
+
+    if config.verify_mode == VerifyMode::Skip {
+        destination
+            .sync_all()
+            .map_err(|error| FlashError::write(&device, written.length, error))?;
+        println!("WARNING: verification is disabled, nothing was read back from the card");
+        return Ok(());
+    }
+    let selected = chunks_to_verify(config, written.hashes.len());
+    // Otherwise we would mostly verify our own page cache, not what the card stored.
+    devices::drop_cache(destination).map_err(DeviceError::io(&device, "Dropping the cache of"))?;
+    let rewrite = |offset, chunk: &mut [u8], expected: &[u8]| {
+        println!("Chunk at {offset} doesn't match, writing it again");
+        let rewrite = rewrite_chunk(
+            config,
+            control.cancel,
+            &device,
+            destination,
+            offset,
+            chunk,
+            expected,
+        )?;
+        let matched = rewrite.matched;
+        record.rewrites.push(rewrite);
+        Ok(matched)
+    };
+    let started = Instant::now();
+    let mut reader = destination;
+    let readback = read_back(
+        &mut reader,
+        &device,
+        &mut buffers.take()[..largest],
+        &written,
+        &selected,
+        control,
+        rewrite,
+    );
+    record.timings.verify = Some(started.elapsed().as_secs_f64());
+    if let Err(FlashError::Verify(VerifyError::Mismatch { .. })) = readback {
+        record.verify_failed = true;
+    }
+    let device_sha256 = readback?;
+    if config.verify_mode == VerifyMode::Sampled {
+        let verified = selected.iter().filter(|verify| **verify).count();
+        println!(
+            "Sampled {verified} of {} chunks, and they matched",
+            selected.len()
+        );
+        return Ok(());
+    }
+    println!(
+        "SHA-256 of the {} bytes read back from the card = {device_sha256}",
+        written.length
+    );
+    record.device_sha256 = Some(device_sha256.clone());
+    if device_sha256 != written.sha256 {
+        record.verify_failed = true;
+        return Err(VerifyError::DigestMismatch {
+            device,
+            expected: written.sha256,
+            actual: device_sha256,
+        }
+        .into());
+    }
+    println!("All hashes checked, and matched");
+    Ok(())
+}
+
+/// Pausing, cancelling and progress, shared by the passes over the card.
+struct Control<'a> {
+    pause: &'a PauseControl,
+    cancel: &'a CancellationToken,
+    events: &'a EventBus,
+}
+
+impl Control<'_> {
+    /// Waits out a pause, then fails if the flash was cancelled meanwhile. Runs between chunks.
+    fn checkpoint(&self, offset: u64) -> Result<(), FlashError> {
+        self.pause.wait_while_paused(self.cancel);
+        if self.cancel.is_cancelled() {
+            return Err(FlashError::Cancelled { offset });
+        }
+        Ok(())
+    }
+}
+
+/// What the copy pass wrote, for the verify pass to check.
+struct Written {
+    length: u64,
+    algorithm: HashAlgorithm,
+    /// One per chunk. Every chunk is full, except possibly the last.
+    hashes: Vec<Vec<u8>>,
+    /// Where each chunk starts, they are as large as the chunk size was when they were written
+    offsets: Vec<u64>,
+    /// The size chunks ended up with
+    chunk_size: usize,
+    sha256: String,
+}
+
+/// The first `length` bytes of a buffer or mapping, shared with the hash workers while they are
+/// written.
+#[derive(Clone)]
+struct Chunk {
+    data: Arc<dyn Deref<Target = [u8]> + Send + Sync>,
+    length: usize,
+}
+
+impl Chunk {
+    fn new(data: impl Deref<Target = [u8]> + Send + Sync + 'static, length: usize) -> Self {
+        Self {
+            data: Arc::new(data),
+            length,
+        }
+    }
+}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.length]
+    }
+}
+
+/// Hashing takes about as long as writing on a Pi, so both hashes are worked out on threads of
+/// their own, which run for the whole copy. Each chunk goes to both while it is written, and is
+/// waited for before the next.
+struct Hashers {
+    verify: mpsc::Sender<Chunk>,
+    sha256: mpsc::Sender<Chunk>,
+    /// The verify hash of every chunk
+    hashes: mpsc::Receiver<Vec<u8>>,
+    /// Every chunk added to the SHA-256
+    hashed: mpsc::Receiver<()>,
+    verify_worker: Option<JoinHandle<()>>,
+    sha256_worker: Option<JoinHandle<Sha256>>,
+}
+
+impl Hashers {
+    fn start(algorithm: HashAlgorithm) -> Self {
+        // Chunks are dropped before they are answered for, the last one to go frees the buffer.
+        let (verify, verify_chunks) = mpsc::channel::<Chunk>();
+        let (hash_sender, hashes) = mpsc::channel();
+        let verify_worker = thread::spawn(move || {
+            for chunk in verify_chunks {
+                let hash = algorithm.hash(&chunk);
+                drop(chunk);
+                if hash_sender.send(hash).is_err() {
+                    return;
+                }
+            }
+        });
+        let (sha256, sha256_chunks) = mpsc::channel::<Chunk>();
+        let (hashed_sender, hashed) = mpsc::channel();
+        let sha256_worker = thread::spawn(move || {
+            let mut digest = Sha256::new();
+            for chunk in sha256_chunks {
+                digest.update(&*chunk);
+                drop(chunk);
+                if hashed_sender.send(()).is_err() {
+                    break;
+                }
+            }
+            digest
+        });
+        Self {
+            verify,
+            sha256,
+            hashes,
+            hashed,
+            verify_worker: Some(verify_worker),
+            sha256_worker: Some(sha256_worker),
+        }
+    }
+
+    fn send(&self, chunk: &Chunk) {
+        // A worker that is gone panicked, which `wait` passes on.
+        let _ = self.verify.send(chunk.clone());
+        let _ = self.sha256.send(chunk.clone());
+    }
+
+    /// Waits until both workers are done with the last chunk, returning its verify hash.
+    fn wait(&mut self) -> Vec<u8> {
+        let hash = match self.hashes.recv() {
+            Ok(hash) => hash,
+            Err(_) => rethrow(self.verify_worker.take()),
+        };
+        if self.hashed.recv().is_err() {
+            rethrow(self.sha256_worker.take());
+        }
+        hash
+    }
+
+    /// Stops the workers, returning the SHA-256 of all chunks.
+    fn finish(mut self) -> Sha256 {
+        let (verify_worker, sha256_worker) = (self.verify_worker.take(), self.sha256_worker.take());
+        // Without the senders the workers run out of chunks.
+        drop(self);
+        if let Some(worker) = verify_worker {
+            joined(worker.join());
+        }
+        joined(sha256_worker.expect("finished once").join())
+    }
+}
+
+/// Passes on the panic of a hash worker that stopped answering, it only stops when it panics.
+fn rethrow<T>(worker: Option<JoinHandle<T>>) -> ! {
+    if let Some(Err(panic)) = worker.map(JoinHandle::join) {
+        panic::resume_unwind(panic);
+    }
+    unreachable!("hash worker stopped without panicking")
+}
+
+/// The copy pass so far.
+struct Copy<'a, W> {
+    writer: W,
+    device: &'a Path,
+    algorithm: HashAlgorithm,
+    control: &'a Control<'a>,
+    total: Option<u64>,
+    tuner: ChunkTuner,
+    /// When the last chunk was written, the time between chunks is what the tuner goes by
+    last_written: Instant,
+    hashes: Vec<Vec<u8>>,
+    offsets: Vec<u64>,
+    hashers: Hashers,
+    length: u64,
+}
+
+impl<'a, W: Write> Copy<'a, W> {
+    fn new(
+        writer: W,
+        device: &'a Path,
+        algorithm: HashAlgorithm,
+        control: &'a Control<'a>,
+        total: Option<u64>,
+        tuner: ChunkTuner,
+    ) -> Self {
+        Self {
+            writer,
+            device,
+            algorithm,
+            control,
+            total,
+            tuner,
+            last_written: Instant::now(),
+            hashes: vec![],
+            offsets: vec![],
+            hashers: Hashers::start(algorithm),
+            length: 0,
+        }
+    }
+
+    /// Writes the next chunk, which had `checksum` when it was read. It is checked before anything
+    /// is written: a bit flip in RAM would otherwise go unnoticed, if it came before hashing the
+    /// readback would match it.
+    fn write(&mut self, chunk: &Chunk, checksum: u32) -> Result<(), FlashError> {
+        self.transfer(chunk, checksum, |writer| {
+            writer.write_all(chunk).and_then(|()| writer.flush())
+        })
+    }
+
+    /// Like [`Copy::write`], with `transfer` getting the chunk to the card some other way.
+    fn transfer(
+        &mut self,
+        chunk: &Chunk,
+        checksum: u32,
+        transfer: impl FnOnce(&mut W) -> io::Result<()>,
+    ) -> Result<(), FlashError> {
+        if crc32c::crc32c(chunk) != checksum {
+            return Err(FlashError::Corrupted {
+                offset: self.length,
+            });
+        }
+        self.hashers.send(chunk);
+        let written = transfer(&mut self.writer);
+        let hash = self.hashers.wait();
+        written.map_err(|error| FlashError::write(self.device, self.length, error))?;
+        self.hashes.push(hash);
+        self.offsets.push(self.length);
+        self.length += chunk.len() as u64;
+        self.tuner.record(chunk.len(), self.last_written.elapsed());
+        self.last_written = Instant::now();
+        match self.total {
+            Some(size) => debug!("Wrote {}/{size}", self.length),
+            None => debug!("Wrote {}", self.length),
+        }
+        self.control.events.publish(Event::ProgressTick {
+            device: self.device.to_path_buf(),
+            written: self.length,
+            total: self.total,
+        });
+        Ok(())
+    }
+
+    fn finish(self) -> Written {
+        Written {
+            length: self.length,
+            algorithm: self.algorithm,
+            hashes: self.hashes,
+            offsets: self.offsets,
+            chunk_size: self.tuner.size(),
+            sha256: hex(&self.hashers.finish().finalize()),
+        }
+    }
+}
+
+/// Copies `image` to `writer` in chunks of the size `tuner` settles on, at most the size of the
+/// buffers in `buffers`, hashing every chunk on the way. The image is read into the next buffer
+/// on a thread of its own while the last one is written, so decompressing overlaps with writing.
+fn copy_chunks(
+    image: &mut Image,
+    writer: impl Write,
+    device: &Path,
+    buffers: &BufferPool,
+    tuner: ChunkTuner,
+    algorithm: HashAlgorithm,
+    control: &Control,
+) -> Result<Written, FlashError> {
+    let chunk_size = AtomicUsize::new(tuner.size());
+    let mut copy = Copy::new(writer, device, algorithm, control, image.size, tuner);
+    let reader = &mut image.reader;
+    thread::scope(|scope| -> Result<(), FlashError> {
+        // Unbounded, taking a buffer from the pool is what holds the reader back.
+        let (sender, receiver) = mpsc::channel();
+        let chunk_size = &chunk_size;
+        scope.spawn(move || loop {
+            let mut buffer = buffers.take();
+            // The buffer read ahead keeps the size of before the last change, any size will do.
+            let size = chunk_size.load(Ordering::Relaxed);
+            let message = match read_full(reader, &mut buffer[..size]) {
+                Ok(0) => break,
+                Ok(read) => Ok((crc32c::crc32c(&buffer[..read]), buffer, read)),
+                Err(error) => Err(error),
+            };
+            let failed = message.is_err();
+            // The receiver is gone when writing failed, nothing left to do.
+            if sender.send(message).is_err() || failed {
+                break;
+            }
+        });
+        for message in receiver {
+            control.checkpoint(copy.length)?;
+            let (checksum, buffer, read) = message.map_err(|source| FlashError::ReadImage {
+                offset: copy.length,
+                source,
+            })?;
+            copy.write(&Chunk::new(buffer, read), checksum)?;
+            chunk_size.store(copy.tuner.size(), Ordering::Relaxed);
+        }
+        Ok(())
+    })?;
+    Ok(copy.finish())
+}
+
+/// Copies the raw image in `file` to `writer` like [`copy_chunks`], but maps each chunk of it
+/// into memory instead of reading it into a buffer. With [`CopyMethod::Sendfile`] the kernel
+/// moves the chunk, the mapping is only hashed.
+fn copy_mapped(
+    file: &File,
+    method: CopyMethod,
+    writer: &File,
+    device: &Path,
+    tuner: ChunkTuner,
+    algorithm: HashAlgorithm,
+    control: &Control,
+) -> Result<Written, FlashError> {
+    let size = file
+        .metadata()
+        .map_err(|source| FlashError::ReadImage { offset: 0, source })?
+        .len();
+    readahead::advise(file, 0, 0, PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL);
+    let mut copy = Copy::new(writer, device, algorithm, control, Some(size), tuner);
+    while copy.length < size {
+        control.checkpoint(copy.length)?;
+        let chunk_size = copy.tuner.size() as u64;
+        let (offset, length) = (copy.length, (size - copy.length).min(chunk_size));
+        let chunk = Mapping::new(file, offset, length as usize)
+            .map(|mapping| Chunk::new(mapping, length as usize))
+            .map_err(|source| FlashError::ReadImage { offset, source })?;
+        let checksum = crc32c::crc32c(&chunk);
+        match method {
+            CopyMethod::Sendfile => copy.transfer(&chunk, checksum, |writer| {
+                send(writer, file, offset, chunk.len())
+            })?,
+            _ => copy.write(&chunk, checksum)?,
+        }
+        // Unmapped first, mapped pages aren't dropped.
+        drop(chunk);
+        readahead::advise(
+            file,
+            offset,
+            length,
+            PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+        );
+    }
+    Ok(copy.finish())
+}
+
+/// Sends `length` bytes of `file` at `offset` to where `destination` is at.
+fn send(destination: &File, file: &File, offset: u64, length: usize) -> io::Result<()> {
+    let mut position = offset as i64;
+    let end = position + length as i64;
+    while position < end {
+        let remaining = (end - position) as usize;
+        let sent = sendfile64(destination, file, Some(&mut position), remaining)?;
+        if sent == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Image ended while it was sent",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The result of a thread that was joined, passing its panic on.
+fn joined<T>(result: thread::Result<T>) -> T {
+    result.unwrap_or_else(|panic| panic::resume_unwind(panic))
+}
+
+/// Reads back the `selected` chunks of what [`copy_chunks`] wrote, each as large as it was
+/// written, into `buffer`, which holds the largest. A chunk that doesn't match goes to `mismatch` with its offset, what was read and the hash it
+/// should have, which returns whether the chunk matches now. Returns the SHA-256 of all chunks
+/// read back.
+fn read_back(
+    reader: &mut (impl Read + Seek),
+    device: &Path,
+    buffer: &mut [u8],
+    written: &Written,
+    selected: &[bool],
+    control: &Control,
+    mut mismatch: impl FnMut(u64, &mut [u8], &[u8]) -> Result<bool, FlashError>,
+) -> Result<String, FlashError> {
+    let mut digest = Sha256::new();
+    for ((index, expected), verify) in written.hashes.iter().enumerate().zip(selected) {
+        if !verify {
+            continue;
+        }
+        let offset = written.offsets[index];
+        let end = written
+            .offsets
+            .get(index + 1)
+            .copied()
+            .unwrap_or(written.length);
+        control.checkpoint(offset)?;
+        reader
+            .seek(SeekFrom::Start(offset))
+            .map_err(DeviceError::io(device, "Seeking in"))?;
+        let chunk = &mut buffer[..(end - offset) as usize];
+        let read = read_full(reader, chunk).map_err(|source| VerifyError::Read {
+            device: device.to_path_buf(),
+            offset,
+            source,
+        })?;
+        // Only at the end of the card, `read_full` doesn't stop short otherwise.
+        if read < chunk.len() {
+            return Err(VerifyError::Truncated {
+                device: device.to_path_buf(),
+                offset: offset + read as u64,
+            }
+            .into());
+        }
+        if written.algorithm.hash(chunk) != *expected && !mismatch(offset, chunk, expected)? {
+            return Err(VerifyError::Mismatch {
+                device: device.to_path_buf(),
+                offset,
+            }
+            .into());
+        }
+        debug!("Chunk at {offset} matches");
+        digest.update(&*chunk);
+    }
+    Ok(hex(&digest.finalize()))
+}
+
+/// Which of `count` chunks the verify pass reads back. A sample always includes the first chunk,
+/// with the partition table and boot partition, and the last one.
+fn chunks_to_verify(config: &Config, count: usize) -> Vec<bool> {
+    if config.verify_mode == VerifyMode::Full {
+        return vec![true; count];
+    }
+    // xorshift64, seeded from the clock so every card gets a different sample
+    let mut state = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+        | 1;
+    (0..count)
+        .map(|index| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            index == 0 || index + 1 == count || state % 100 < config.verify_sample_percent as u64
+        })
+        .collect()
+}
+
+/// Writes the chunk at `offset` again, from a freshly opened image, until it reads back as
+/// `expected` or the configured attempts run out. `buffer` ends up holding what was read back.
+fn rewrite_chunk(
+    config: &Config,
+    cancel: &CancellationToken,
+    device: &Path,
+    destination: &File,
+    offset: u64,
+    buffer: &mut [u8],
+    expected: &[u8],
+) -> Result<ChunkRewrite, FlashError> {
+    for attempt in 1..=config.verify_rewrite_attempts {
+        if cancel.is_cancelled() {
+            return Err(FlashError::Cancelled { offset });
+        }
+        // Streams can't seek, so decompress up to the chunk again.
+        let mut image = image::open(config).map_err(FlashError::OpenImage)?;
+        let read = io::copy(&mut (&mut image.reader).take(offset), &mut io::sink())
+            .and_then(|_| read_full(&mut image.reader, buffer))
+            .map_err(|source| FlashError::ReadImage { offset, source })?;
+        if read != buffer.len() || config.verify_hash.hash(buffer) != expected {
+            return Err(VerifyError::ImageChanged { offset }.into());
+        }
+        destination
+            .write_all_at(buffer, offset)
+            .map_err(|error| FlashError::write(device, offset, error))?;
+        devices::drop_cache(destination)
+            .map_err(DeviceError::io(device, "Dropping the cache of"))?;
+        destination
+            .read_exact_at(buffer, offset)
+            .map_err(|source| VerifyError::Read {
+                device: device.to_path_buf(),
+                offset,
+                source,
+            })?;
+        if config.verify_hash.hash(buffer) == expected {
+            println!("Chunk at {offset} matches after {attempt} rewrite(s)");
+            return Ok(ChunkRewrite {
+                offset,
+                attempts: attempt,
+                matched: true,
+            });
+        }
+        println!("Chunk at {offset} still doesn't match after rewrite {attempt}");
+    }
+    Ok(ChunkRewrite {
+        offset,
+        attempts: config.verify_rewrite_attempts,
+        matched: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Hands out at most the next of `limits` bytes per read, the way decompressors and USB
+    /// readers return data in uneven pieces.
+    struct ShortReads<R> {
+        inner: R,
+        limits: Vec<usize>,
+        next: usize,
+    }
+
+    impl<R> ShortReads<R> {
+        fn new(inner: R, limits: &[usize]) -> Self {
+            Self {
+                inner,
+                limits: limits.to_vec(),
+                next: 0,
+            }
+        }
+    }
+
+    impl<R: Read> Read for ShortReads<R> {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let limit = self.limits[self.next % self.limits.len()].min(buffer.len());
+            self.next += 1;
+            self.inner.read(&mut buffer[..limit])
+        }
+    }
+
+    impl<R: Seek> Seek for ShortReads<R> {
+        fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(position)
+        }
+    }
+
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Xxh3;
+
+    fn with_control<T>(run: impl FnOnce(&Control) -> T) -> T {
+        let (pause, cancel, events) = (
+            PauseControl::default(),
+            CancellationToken::new(),
+            EventBus::new(),
+        );
+        run(&Control {
+            pause: &pause,
+            cancel: &cancel,
+            events: &events,
+        })
+    }
+
+    /// Copies `image` to an in-memory card, returning what was written and the card.
+    fn copy(image: &[u8], chunk_size: usize, limits: &[usize]) -> (Written, Vec<u8>) {
+        let mut image = Image {
+            reader: Box::new(ShortReads::new(Cursor::new(image.to_vec()), limits)),
+            size: Some(image.len() as u64),
+            file: None,
+        };
+        let mut card = vec![];
+        let written = with_control(|control| {
+            copy_chunks(
+                &mut image,
+                &mut card,
+                Path::new("card"),
+                &BufferPool::new(2, chunk_size),
+                ChunkTuner::fixed(chunk_size),
+                ALGORITHM,
+                control,
+            )
+        })
+        .unwrap();
+        (written, card)
+    }
+
+    /// Reads `card` back, collecting the offsets `mismatch` was called with.
+    fn verify(
+        card: Vec<u8>,
+        written: &Written,
+        chunk_size: usize,
+        limits: &[usize],
+        selected: &[bool],
+        mut mismatch: impl FnMut(u64, &mut [u8], &[u8]) -> bool,
+    ) -> (Result<String, FlashError>, Vec<u64>) {
+        let mut reader = ShortReads::new(Cursor::new(card), limits);
+        let mut mismatched = vec![];
+        let result = with_control(|control| {
+            read_back(
+                &mut reader,
+                Path::new("card"),
+                &mut vec![0; chunk_size],
+                written,
+                selected,
+                control,
+                |offset, chunk, expected| {
+                    mismatched.push(offset);
+                    Ok(mismatch(offset, chunk, expected))
+                },
+            )
+        });
+        (result, mismatched)
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        hex(&Sha256::digest(data))
+    }
+
+    proptest! {
+        #[test]
+        fn copies_every_byte_once(
+            image in vec(any::<u8>(), 0..4096),
+            chunk_size in 1usize..512,
+            limits in vec(1usize..700, 1..8),
+        ) {
+            let (written, card) = copy(&image, chunk_size, &limits);
+
+            prop_assert!(card == image);
+            prop_assert_eq!(written.length, image.len() as u64);
+            prop_assert_eq!(written.hashes.len(), image.len().div_ceil(chunk_size));
+            for (chunk, hash) in image.chunks(chunk_size).zip(&written.hashes) {
+                prop_assert_eq!(hash, &ALGORITHM.hash(chunk));
+            }
+            prop_assert_eq!(written.sha256, sha256(&image));
+        }
+
+        #[test]
+        fn reads_back_an_intact_card(
+            image in vec(any::<u8>(), 0..4096),
+            chunk_size in 1usize..512,
+            limits in vec(1usize..700, 1..8),
+            // Cards are usually larger than the image.
+            slack in vec(any::<u8>(), 0..64),
+        ) {
+            let (written, mut card) = copy(&image, chunk_size, &limits);
+            card.extend(slack);
+            let selected = vec![true; written.hashes.len()];
+            let (result, mismatched) =
+                verify(card, &written, chunk_size, &limits, &selected, |_, _, _| false);
+
+            prop_assert_eq!(result.unwrap(), sha256(&image));
+            prop_assert!(mismatched.is_empty());
+        }
+
+        #[test]
+        fn finds_the_corrupted_chunk(
+            image in vec(any::<u8>(), 1..4096),
+            chunk_size in 1usize..512,
+            limits in vec(1usize..700, 1..8),
+            position in any::<prop::sample::Index>(),
+            repaired: bool,
+        ) {
+            let (written, mut card) = copy(&image, chunk_size, &limits);
+            let position = position.index(image.len());
+            card[position] ^= 0xFF;
+            let offset = (position / chunk_size * chunk_size) as u64;
+            let selected = vec![true; written.hashes.len()];
+            let (result, mismatched) = verify(
+                card.clone(),
+                &written,
+                chunk_size,
+                &limits,
+                &selected,
+                |at, chunk, _| {
+                    assert!(chunk == &card[at as usize..at as usize + chunk.len()]);
+                    assert_eq!(chunk.len(), chunk_size.min(image.len() - at as usize));
+                    if repaired {
+                        chunk.copy_from_slice(&image[at as usize..at as usize + chunk.len()]);
+                    }
+                    repaired
+                },
+            );
+
+            prop_assert_eq!(mismatched, vec![offset]);
+            match result {
+                Ok(digest) => {
+                    prop_assert!(repaired);
+                    prop_assert_eq!(digest, sha256(&image));
+                }
+                Err(FlashError::Verify(VerifyError::Mismatch { offset: at, .. })) => {
+                    prop_assert!(!repaired);
+                    prop_assert_eq!(at, offset);
+                }
+                Err(error) => prop_assert!(false, "{error}"),
+            }
+        }
+
+        #[test]
+        fn skips_chunks_not_selected(
+            image in vec(any::<u8>(), 1..4096),
+            chunk_size in 1usize..512,
+            limits in vec(1usize..700, 1..8),
+            position in any::<prop::sample::Index>(),
+            selection in vec(any::<bool>(), 4096),
+        ) {
+            let (written, mut card) = copy(&image, chunk_size, &limits);
+            let position = position.index(image.len());
+            card[position] ^= 0xFF;
+            let selected = &selection[..written.hashes.len()];
+            let (result, mismatched) =
+                verify(card.clone(), &written, chunk_size, &limits, selected, |_, _, _| false);
+
+            let corrupted = position / chunk_size;
+            if selected[corrupted] {
+                prop_assert_eq!(mismatched, vec![(corrupted * chunk_size) as u64]);
+                prop_assert!(result.is_err());
+            } else {
+                let read: Vec<u8> = card[..image.len()]
+                    .chunks(chunk_size)
+                    .zip(selected)
+                    .filter(|(_, verify)| **verify)
+                    .flat_map(|(chunk, _)| chunk.to_vec())
+                    .collect();
+                prop_assert!(mismatched.is_empty());
+                prop_assert_eq!(result.unwrap(), sha256(&read));
+            }
+        }
+
+        #[test]
+        fn notices_a_card_ending_early(
+            image in vec(any::<u8>(), 1..4096),
+            chunk_size in 1usize..512,
+            limits in vec(1usize..700, 1..8),
+            end in any::<prop::sample::Index>(),
+        ) {
+            let (written, mut card) = copy(&image, chunk_size, &limits);
+            let end = end.index(image.len());
+            card.truncate(end);
+            let selected = vec![true; written.hashes.len()];
+            let (result, mismatched) =
+                verify(card, &written, chunk_size, &limits, &selected, |_, _, _| false);
+
+            prop_assert!(mismatched.is_empty());
+            match result {
+                Err(FlashError::Verify(VerifyError::Truncated { offset, .. })) => {
+                    prop_assert_eq!(offset, end as u64)
+                }
+                other => prop_assert!(false, "{other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn writes_nothing_of_a_chunk_changed_since_it_was_read() {
+        let chunk = Chunk::new(vec![0x5A; 512], 512);
+        let mut card = vec![];
+        let result = with_control(|control| {
+            let tuner = ChunkTuner::fixed(chunk.len());
+            let mut copy = Copy::new(
+                &mut card,
+                Path::new("card"),
+                ALGORITHM,
+                control,
+                None,
+                tuner,
+            );
+            let checksum = crc32c::crc32c(&chunk);
+            copy.write(&chunk, checksum)?;
+            copy.write(&chunk, checksum ^ 1)
+        });
+
+        assert!(matches!(result, Err(FlashError::Corrupted { offset: 512 })));
+        assert_eq!(card.len(), 512);
+    }
+
+    #[test]
+    fn mapped_copies_match_the_buffered_one() {
+        let image: Vec<u8> = (0..10_000u32).map(|index| (index * 7) as u8).collect();
+        let scratch = |name: &str| {
+            std::env::temp_dir().join(format!("flash-{name}-{}.img", std::process::id()))
+        };
+        let (path, card_path) = (scratch("mapped"), scratch("card"));
+        std::fs::write(&path, &image).unwrap();
+        let (buffered, _) = copy(&image, 4096, &[4096]);
+        for method in [CopyMethod::Mmap, CopyMethod::Sendfile] {
+            let card = File::create(&card_path).unwrap();
+            let mapped = with_control(|control| {
+                copy_mapped(
+                    &File::open(&path).unwrap(),
+                    method,
+                    &card,
+                    Path::new("card"),
+                    ChunkTuner::fixed(4096),
+                    ALGORITHM,
+                    control,
+                )
+            })
+            .unwrap();
+
+            assert!(std::fs::read(&card_path).unwrap() == image, "{method:?}");
+            assert_eq!(mapped.length, buffered.length);
+            assert_eq!(mapped.hashes, buffered.hashes);
+            assert_eq!(mapped.offsets, buffered.offsets);
+            assert_eq!(mapped.sha256, buffered.sha256);
+        }
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&card_path).unwrap();
+    }
+}
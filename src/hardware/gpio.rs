@@ -0,0 +1,31 @@
+use rppal::gpio::{Gpio, InputPin, OutputPin};
+
+use super::{Button, Hardware, Led};
+use crate::config::HardwareConfig;
+use crate::error::HardwareError;
+
+impl Led for OutputPin {
+    fn set(&mut self, lit: bool) {
+        if lit {
+            self.set_low();
+        } else {
+            self.set_high();
+        }
+    }
+}
+
+impl Button for InputPin {
+    fn is_pressed(&mut self) -> bool {
+        self.is_low()
+    }
+}
+
+// Gpio uses BCM pin numbering.
+pub fn open(config: &HardwareConfig) -> Result<Hardware, HardwareError> {
+    let gpio = Gpio::new()?;
+    Ok(Hardware {
+        red: Box::new(gpio.get(config.led_red)?.into_output()),
+        yellow: Box::new(gpio.get(config.led_yellow)?.into_output()),
+        button: Box::new(gpio.get(config.button)?.into_input_pullup()),
+    })
+}
@@ -0,0 +1,151 @@
+//! MCP23017 16-bit I2C I/O expander, for rigs that have run out of header pins.
+
+use std::sync::{Arc, Mutex};
+
+use rppal::i2c::I2c;
+
+use super::{Button, Hardware, Led};
+use crate::config::HardwareConfig;
+use crate::error::HardwareError;
+
+// Register addresses with IOCON.BANK = 0 (the power-on default). Port B is always at +1.
+const IODIRA: u8 = 0x00;
+const GPPUA: u8 = 0x0C;
+const GPIOA: u8 = 0x12;
+const OLATA: u8 = 0x14;
+
+struct Mcp23017 {
+    i2c: I2c,
+    /// Shadow copies of the registers we modify, indexed by port
+    iodir: [u8; 2],
+    gppu: [u8; 2],
+    olat: [u8; 2],
+}
+
+impl Mcp23017 {
+    fn new(bus: u8, address: u16) -> Result<Self, HardwareError> {
+        let mut i2c = I2c::with_bus(bus)?;
+        i2c.set_slave_address(address)?;
+        let mut chip = Self {
+            i2c,
+            iodir: [0xFF; 2],
+            gppu: [0x00; 2],
+            olat: [0x00; 2],
+        };
+        // Bring the chip into a known state, it keeps its registers across our restarts.
+        for port in 0..2 {
+            chip.write(OLATA, port, 0xFF)?;
+            chip.write(GPPUA, port, 0x00)?;
+            chip.write(IODIRA, port, 0xFF)?;
+        }
+        chip.olat = [0xFF; 2];
+        Ok(chip)
+    }
+
+    fn write(&mut self, register: u8, port: usize, value: u8) -> rppal::i2c::Result<()> {
+        self.i2c.smbus_write_byte(register + port as u8, value)
+    }
+
+    fn configure_output(&mut self, pin: u8) -> rppal::i2c::Result<()> {
+        let (port, mask) = split_pin(pin);
+        self.iodir[port] &= !mask;
+        self.write(IODIRA, port, self.iodir[port])
+    }
+
+    fn configure_input_pullup(&mut self, pin: u8) -> rppal::i2c::Result<()> {
+        let (port, mask) = split_pin(pin);
+        self.gppu[port] |= mask;
+        self.write(GPPUA, port, self.gppu[port])?;
+        self.iodir[port] |= mask;
+        self.write(IODIRA, port, self.iodir[port])
+    }
+
+    fn set_output(&mut self, pin: u8, high: bool) -> rppal::i2c::Result<()> {
+        let (port, mask) = split_pin(pin);
+        if high {
+            self.olat[port] |= mask;
+        } else {
+            self.olat[port] &= !mask;
+        }
+        self.write(OLATA, port, self.olat[port])
+    }
+
+    fn read_input(&mut self, pin: u8) -> rppal::i2c::Result<bool> {
+        let (port, mask) = split_pin(pin);
+        let value = self.i2c.smbus_read_byte(GPIOA + port as u8)?;
+        Ok(value & mask != 0)
+    }
+}
+
+/// Pins 0-7 are GPA0-GPA7, pins 8-15 are GPB0-GPB7.
+fn split_pin(pin: u8) -> (usize, u8) {
+    ((pin / 8) as usize, 1 << (pin % 8))
+}
+
+struct Mcp23017Led {
+    chip: Arc<Mutex<Mcp23017>>,
+    pin: u8,
+}
+
+impl Led for Mcp23017Led {
+    fn set(&mut self, lit: bool) {
+        let mut chip = self.chip.lock().unwrap();
+        if let Err(error) = chip.set_output(self.pin, !lit) {
+            println!(
+                "Got error when setting MCP23017 pin {}: {error:?}",
+                self.pin
+            );
+        }
+    }
+}
+
+struct Mcp23017Button {
+    chip: Arc<Mutex<Mcp23017>>,
+    pin: u8,
+}
+
+impl Button for Mcp23017Button {
+    fn is_pressed(&mut self) -> bool {
+        let mut chip = self.chip.lock().unwrap();
+        match chip.read_input(self.pin) {
+            Ok(high) => !high,
+            Err(error) => {
+                println!(
+                    "Got error when reading MCP23017 pin {}: {error:?}",
+                    self.pin
+                );
+                false
+            }
+        }
+    }
+}
+
+pub fn open(config: &HardwareConfig) -> Result<Hardware, HardwareError> {
+    for pin in [config.led_red, config.led_yellow, config.button] {
+        if pin > 15 {
+            return Err(HardwareError::Unsupported(format!(
+                "MCP23017 only has pins 0-15, got {pin}"
+            )));
+        }
+    }
+    let mut chip = Mcp23017::new(config.mcp23017.bus, config.mcp23017.address)?;
+    chip.configure_output(config.led_red)?;
+    chip.configure_output(config.led_yellow)?;
+    chip.configure_input_pullup(config.button)?;
+    let chip = Arc::new(Mutex::new(chip));
+
+    Ok(Hardware {
+        red: Box::new(Mcp23017Led {
+            chip: chip.clone(),
+            pin: config.led_red,
+        }),
+        yellow: Box::new(Mcp23017Led {
+            chip: chip.clone(),
+            pin: config.led_yellow,
+        }),
+        button: Box::new(Mcp23017Button {
+            chip,
+            pin: config.button,
+        }),
+    })
+}
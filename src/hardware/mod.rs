@@ -0,0 +1,34 @@
+//! Status LEDs and buttons, independent of how they are wired to the Pi.
+
+use crate::config::{HardwareBackend, HardwareConfig};
+use crate::error::HardwareError;
+
+mod gpio;
+mod mcp23017;
+mod simulated;
+
+/// A status LED. LEDs are wired active-low on every backend.
+pub trait Led: Send {
+    fn set(&mut self, lit: bool);
+}
+
+/// A push button. Buttons pull the line to ground when pressed.
+pub trait Button: Send {
+    fn is_pressed(&mut self) -> bool;
+}
+
+pub struct Hardware {
+    pub red: Box<dyn Led>,
+    pub yellow: Box<dyn Led>,
+    pub button: Box<dyn Button>,
+}
+
+impl Hardware {
+    pub fn new(config: &HardwareConfig) -> Result<Self, HardwareError> {
+        match config.backend {
+            HardwareBackend::Gpio => gpio::open(config),
+            HardwareBackend::Mcp23017 => mcp23017::open(config),
+            HardwareBackend::Simulated => Ok(simulated::open()),
+        }
+    }
+}
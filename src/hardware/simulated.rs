@@ -0,0 +1,100 @@
+//! LEDs and the button on the terminal, for running the cloner without a Pi. Enter presses the
+//! button, `h` and Enter holds it. The LEDs are summarized in one line whenever their pattern
+//! changes, printing every blink would drown the log.
+
+use std::io::{self, BufRead};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Button, Hardware, Led};
+use crate::LONG_PRESS;
+
+/// Longer than the slowest blink, so a blinking LED isn't mistaken for a solid one in between.
+const BLINK_WINDOW: Duration = Duration::from_secs(4);
+const PRESS: Duration = Duration::from_millis(200);
+
+#[derive(Default)]
+struct LedState {
+    lit: bool,
+    /// When the LED last turned on or off, newest last, within `BLINK_WINDOW`
+    changes: Vec<Instant>,
+}
+
+impl LedState {
+    fn describe(&mut self) -> &'static str {
+        self.changes
+            .retain(|changed| changed.elapsed() < BLINK_WINDOW);
+        match (self.changes.len() > 1, self.lit) {
+            (true, _) => "blinking",
+            (false, true) => "on",
+            (false, false) => "off",
+        }
+    }
+}
+
+struct TerminalLed(Arc<Mutex<LedState>>);
+
+impl Led for TerminalLed {
+    fn set(&mut self, lit: bool) {
+        let mut state = self.0.lock().unwrap();
+        if state.lit != lit {
+            state.lit = lit;
+            state.changes.push(Instant::now());
+        }
+    }
+}
+
+struct TerminalButton {
+    pressed_until: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Button for TerminalButton {
+    fn is_pressed(&mut self) -> bool {
+        self.pressed_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+}
+
+pub fn open() -> Hardware {
+    let (red, yellow): (Arc<Mutex<LedState>>, _) = (Arc::default(), Arc::default());
+    let leds = [("red", Arc::clone(&red)), ("yellow", Arc::clone(&yellow))];
+    thread::spawn(move || {
+        let mut shown = String::new();
+        loop {
+            thread::sleep(Duration::from_millis(250));
+            let summary = leds
+                .iter()
+                .map(|(name, led)| format!("{name} {}", led.lock().unwrap().describe()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            if summary != shown {
+                println!("LEDs: {summary}");
+                shown = summary;
+            }
+        }
+    });
+
+    let pressed_until = Arc::new(Mutex::new(None));
+    let stdin_pressed = Arc::clone(&pressed_until);
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            let length = match line.trim() {
+                "h" => LONG_PRESS + PRESS,
+                _ => PRESS,
+            };
+            *stdin_pressed.lock().unwrap() = Some(Instant::now() + length);
+        }
+    });
+
+    Hardware {
+        red: Box::new(TerminalLed(red)),
+        yellow: Box::new(TerminalLed(yellow)),
+        button: Box::new(TerminalButton { pressed_until }),
+    }
+}
@@ -0,0 +1,31 @@
+//! Hash algorithms used to compare what was written with what was read back.
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+    /// Fast enough to keep up with the card even on a Pi Zero
+    #[default]
+    Xxh3,
+    /// Hardware accelerated on the Pi 3 and later
+    Crc32c,
+}
+
+impl HashAlgorithm {
+    pub fn hash(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+            HashAlgorithm::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+            HashAlgorithm::Xxh3 => xxhash_rust::xxh3::xxh3_128(data).to_be_bytes().to_vec(),
+            HashAlgorithm::Crc32c => crc32c::crc32c(data).to_be_bytes().to_vec(),
+        }
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
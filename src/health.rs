@@ -0,0 +1,126 @@
+//! Wear and lifetime information of the card, where the card or its reader exposes any.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Cards that used at least this much of their life get a warning.
+const WEAR_WARNING_PERCENT: u8 = 90;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthSource {
+    /// EXT_CSD lifetime estimates, as exposed by the kernel for eMMC
+    ExtCsd,
+    /// SMART data through `smartctl`, for USB bridges that pass it on
+    Smart,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CardHealth {
+    pub source: HealthSource,
+    /// Upper bound of the used up lifetime, in percent
+    pub life_used_percent: Option<u8>,
+    /// eMMC pre-EOL info: 1 normal, 2 warning, 3 urgent
+    pub pre_eol: Option<u8>,
+    /// Overall SMART assessment
+    pub smart_passed: Option<bool>,
+}
+
+impl CardHealth {
+    pub fn near_end_of_life(&self) -> bool {
+        self.life_used_percent
+            .is_some_and(|percent| percent >= WEAR_WARNING_PERCENT)
+            || self.pre_eol.is_some_and(|pre_eol| pre_eol >= 2)
+            || self.smart_passed == Some(false)
+    }
+}
+
+fn parse_hex(value: &str) -> Option<u8> {
+    u8::from_str_radix(value.trim().trim_start_matches("0x"), 16).ok()
+}
+
+/// The kernel shows the two EXT_CSD estimates (type A and B cells) as e.g. `0x01 0x02`, each step
+/// being 10% of the life used.
+fn ext_csd_health(device: &Path) -> Option<CardHealth> {
+    let sys_device = Path::new("/sys/block")
+        .join(device.file_name()?)
+        .join("device");
+    let life_time = fs::read_to_string(sys_device.join("life_time")).ok();
+    let pre_eol = fs::read_to_string(sys_device.join("pre_eol_info"))
+        .ok()
+        .and_then(|value| parse_hex(&value))
+        .filter(|value| *value != 0);
+    let life_used_percent = life_time.and_then(|life_time| {
+        life_time
+            .split_whitespace()
+            .filter_map(parse_hex)
+            .filter(|estimate| *estimate != 0)
+            .max()
+            .map(|estimate| (estimate.min(11) * 10).min(100))
+    });
+    if life_used_percent.is_none() && pre_eol.is_none() {
+        return None;
+    }
+    Some(CardHealth {
+        source: HealthSource::ExtCsd,
+        life_used_percent,
+        pre_eol,
+        smart_passed: None,
+    })
+}
+
+/// Normalized values of the wear attributes count down from 100 as the flash wears.
+const SMART_WEAR_ATTRIBUTES: &[u64] = &[
+    169, // Remaining_Lifetime_Perc
+    173, // Wear_Leveling_Count on some controllers
+    177, // Wear_Leveling_Count
+    202, // Percent_Lifetime_Remain
+    231, // SSD_Life_Left
+    233, // Media_Wearout_Indicator
+];
+
+fn smart_health(device: &Path) -> Option<CardHealth> {
+    // The exit code is a bit mask that is non-zero for plenty of usable results.
+    let output = Command::new("smartctl")
+        .args(["--json", "--all"])
+        .arg(device)
+        .output()
+        .ok()?;
+    let report: Value = serde_json::from_slice(&output.stdout).ok()?;
+    let smart_passed = report["smart_status"]["passed"].as_bool();
+    let life_used_percent = report["ata_smart_attributes"]["table"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|attribute| {
+            attribute["id"]
+                .as_u64()
+                .is_some_and(|id| SMART_WEAR_ATTRIBUTES.contains(&id))
+        })
+        .filter_map(|attribute| attribute["value"].as_u64())
+        .map(|remaining| 100 - remaining.min(100) as u8)
+        .max()
+        .or_else(|| {
+            report["nvme_smart_health_information_log"]["percentage_used"]
+                .as_u64()
+                .map(|used| used.min(100) as u8)
+        });
+    if smart_passed.is_none() && life_used_percent.is_none() {
+        return None;
+    }
+    Some(CardHealth {
+        source: HealthSource::Smart,
+        life_used_percent,
+        pre_eol: None,
+        smart_passed,
+    })
+}
+
+/// Whatever health data `device` exposes, `None` for most SD cards.
+pub fn read(device: &Path) -> Option<CardHealth> {
+    ext_csd_health(device).or_else(|| smart_health(device))
+}
@@ -0,0 +1,443 @@
+//! Record of every flash attempt and every backup, appended to a JSON lines file.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::backup::Source;
+use crate::catalog;
+use crate::config::VerifyMode;
+use crate::devices::{self, DeviceInfo};
+use crate::error::FlashError;
+use crate::health::CardHealth;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlashResult {
+    Succeeded,
+    Failed,
+    /// The card was pulled out before the flash was done
+    Removed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FsckResult {
+    pub partition: PathBuf,
+    pub clean: bool,
+    pub exit_code: Option<i32>,
+    pub output: String,
+}
+
+/// A chunk that didn't read back as written, and was written again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRewrite {
+    pub offset: u64,
+    pub attempts: u32,
+    /// Whether it read back as written in the end
+    pub matched: bool,
+}
+
+/// How long each step of a flash took, in seconds. Steps that didn't run are left out.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Timings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capacity_check: Option<f64>,
+    /// Discard or secure erase
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub erase: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_flash: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlashRecord {
+    /// Seconds since the unix epoch
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub image: PathBuf,
+    pub image_version: Option<String>,
+    pub device: PathBuf,
+    /// For cards in USB readers this is the serial of the reader, see [`devices::card_serial`]
+    pub serial: Option<String>,
+    /// CID of cards on the MMC bus, the only thing telling cards apart for sure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cid: Option<String>,
+    #[serde(default)]
+    pub device_info: DeviceInfo,
+    /// Whether the card honored a secure erase, when one was requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secure_erased: Option<bool>,
+    /// Whether the card held markers written across its claimed capacity, when it was checked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity_genuine: Option<bool>,
+    /// Wear of the card before flashing, if it reports any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<CardHealth>,
+    pub bytes_written: Option<u64>,
+    /// Size the chunks written and verified ended up with. `rewrites` are of chunks that may have
+    /// been written smaller, before it settled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_size: Option<u64>,
+    /// Digest of the decompressed image that was written. Known before writing for images hashed
+    /// ahead, see [`crate::image::digest`].
+    pub image_sha256: Option<String>,
+    /// Digest of the written region as read back from the card
+    pub device_sha256: Option<String>,
+    /// How much of the card was read back
+    #[serde(default)]
+    pub verify_mode: VerifyMode,
+    /// Whether the card didn't hold what was written, as opposed to failing for another reason
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub verify_failed: bool,
+    /// Chunks that failed verification and were written again
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rewrites: Vec<ChunkRewrite>,
+    #[serde(default)]
+    pub timings: Timings,
+    pub result: FlashResult,
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fsck: Vec<FsckResult>,
+    /// Public half of the WireGuard key pair generated for the card
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wireguard_public_key: Option<String>,
+}
+
+/// A backup of a card, by the card and the cloner that made it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupRecord {
+    /// Name in the backup store, or path of the image file
+    pub backup: String,
+    pub created_at: u64,
+    #[serde(flatten)]
+    pub source: Source,
+    /// Bytes backed up, before compression
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// A line of the history. Backups come first: only they have a `backup` field, flash records
+/// have most of the others.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Backup(BackupRecord),
+    Flash(Box<FlashRecord>),
+}
+
+impl FlashRecord {
+    pub fn start(image: &Path, device: &Path, verify_mode: VerifyMode) -> Self {
+        Self {
+            started_at: unix_time(),
+            finished_at: 0,
+            image: image.to_path_buf(),
+            image_version: catalog::image_version(image),
+            device: device.to_path_buf(),
+            serial: devices::card_serial(device),
+            cid: devices::card_cid(device),
+            device_info: devices::device_info(device),
+            secure_erased: None,
+            capacity_genuine: None,
+            health: None,
+            bytes_written: None,
+            chunk_size: None,
+            image_sha256: None,
+            device_sha256: None,
+            verify_mode,
+            verify_failed: false,
+            rewrites: vec![],
+            timings: Timings::default(),
+            result: FlashResult::Failed,
+            error: None,
+            fsck: vec![],
+            wireguard_public_key: None,
+        }
+    }
+
+    pub fn finish<T>(&mut self, result: &Result<T, FlashError>) {
+        self.finished_at = unix_time();
+        match result {
+            Ok(_) => {
+                self.result = FlashResult::Succeeded;
+                self.error = None;
+            }
+            Err(error) => {
+                self.result = if error.card_removed() {
+                    FlashResult::Removed
+                } else {
+                    FlashResult::Failed
+                };
+                self.error = Some(error.to_string());
+            }
+        }
+    }
+}
+
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn append(&self, record: &FlashRecord) -> io::Result<()> {
+        self.append_line(record)
+    }
+
+    pub fn append_backup(&self, record: &BackupRecord) -> io::Result<()> {
+        self.append_line(record)
+    }
+
+    fn append_line(&self, record: &impl Serialize) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+
+    /// Every line so far, skipping those that don't parse.
+    fn entries(&self) -> io::Result<Vec<Entry>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => return Err(error),
+        };
+        let mut entries = vec![];
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(entry) => entries.push(entry),
+                // Not on standard output, which an export may be writing to.
+                Err(error) => eprintln!("Got error when parsing flash history: {error:?}"),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// All flash records so far.
+    pub fn records(&self) -> io::Result<Vec<FlashRecord>> {
+        let entries = self.entries()?.into_iter();
+        Ok(entries
+            .filter_map(|entry| match entry {
+                Entry::Flash(record) => Some(*record),
+                Entry::Backup(_) => None,
+            })
+            .collect())
+    }
+
+    /// All backups so far, of a card taken to the store or to a file.
+    pub fn backups(&self) -> io::Result<Vec<BackupRecord>> {
+        let entries = self.entries()?.into_iter();
+        Ok(entries
+            .filter_map(|entry| match entry {
+                Entry::Backup(record) => Some(record),
+                Entry::Flash(_) => None,
+            })
+            .collect())
+    }
+
+    /// The last flash of the card with `cid` that succeeded and knows the digest of its image.
+    pub fn last_flash(&self, cid: &str) -> io::Result<Option<FlashRecord>> {
+        Ok(self.records()?.into_iter().rev().find(|record| {
+            record.cid.as_deref() == Some(cid)
+                && record.result == FlashResult::Succeeded
+                && record.image_sha256.is_some()
+        }))
+    }
+
+    /// Whether the card with `cid` already passed a capacity check. By CID, as a reader serial
+    /// would let every card going through that reader skip it.
+    pub fn capacity_verified(&self, cid: &str) -> io::Result<bool> {
+        Ok(self.records()?.iter().any(|record| {
+            record.cid.as_deref() == Some(cid) && record.capacity_genuine == Some(true)
+        }))
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// `YYYY-MM-DD HH:MM` in UTC, for things people read.
+pub fn format_time(unix_time: u64) -> String {
+    let days = (unix_time / 86400) as i64;
+    let minutes = unix_time % 86400 / 60;
+    // Civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}",
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+/// `YYYY-MM-DD HH:MM:SS` in UTC, for logs and exports.
+pub fn format_time_with_seconds(unix_time: u64) -> String {
+    format!("{}:{:02}", format_time(unix_time), unix_time % 60)
+}
+
+/// Midnight UTC at the start of `date`, given as `YYYY-MM-DD`.
+pub fn parse_date(date: &str) -> Option<u64> {
+    let mut parts = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    // Days from civil, the inverse of the conversion in `format_time`
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let unix_time = u64::try_from((era * 146097 + day_of_era - 719468) * 86400).ok()?;
+    // Days past the end of the month would roll over into the next one.
+    (format_time(unix_time).get(..10) == Some(date)).then_some(unix_time)
+}
+
+/// Which records an export includes.
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Seconds since the unix epoch, inclusive
+    pub since: Option<u64>,
+    /// Seconds since the unix epoch, exclusive
+    pub until: Option<u64>,
+    /// Part of the image's path or version
+    pub image: Option<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, record: &FlashRecord) -> bool {
+        self.since.is_none_or(|since| record.started_at >= since)
+            && self.until.is_none_or(|until| record.started_at < until)
+            && self.image.as_deref().is_none_or(|image| {
+                record.image.to_string_lossy().contains(image)
+                    || record
+                        .image_version
+                        .as_deref()
+                        .is_some_and(|version| version.contains(image))
+            })
+    }
+}
+
+const CSV_HEADER: &str = "started_at,finished_at,image,image_version,device,serial,result,\
+    bytes_written,image_sha256,device_sha256,verify_mode,verify_failed,rewrites,error";
+
+/// Quotes `field` if it holds anything CSV gives a meaning to.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Writes `records` as CSV with a header, one row each. Times are in UTC.
+pub fn write_csv<'a>(
+    records: impl IntoIterator<Item = &'a FlashRecord>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    writeln!(out, "{CSV_HEADER}")?;
+    for record in records {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        let fields = [
+            format_time_with_seconds(record.started_at),
+            format_time_with_seconds(record.finished_at),
+            record.image.to_string_lossy().into_owned(),
+            optional(record.image_version.clone()),
+            record.device.to_string_lossy().into_owned(),
+            optional(record.serial.clone()),
+            format!("{:?}", record.result).to_lowercase(),
+            optional(record.bytes_written.map(|bytes| bytes.to_string())),
+            optional(record.image_sha256.clone()),
+            optional(record.device_sha256.clone()),
+            format!("{:?}", record.verify_mode).to_lowercase(),
+            record.verify_failed.to_string(),
+            record.rewrites.len().to_string(),
+            optional(record.error.clone()),
+        ];
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        writeln!(out, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn dates_round_trip(days in 0u64..200_000) {
+            let date = format_time(days * 86400)[..10].to_string();
+            prop_assert_eq!(parse_date(&date), Some(days * 86400));
+        }
+    }
+
+    #[test]
+    fn rejects_days_past_the_month() {
+        assert_eq!(parse_date("2024-02-29"), Some(1709164800));
+        assert_eq!(parse_date("2023-02-29"), None);
+        assert_eq!(parse_date("2024-13-01"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn only_cards_with_a_cid_skip_the_capacity_check() {
+        let path = std::env::temp_dir().join(format!("history-{}-capacity", std::process::id()));
+        let history = History::new(&path);
+        let mut record = FlashRecord::start(
+            Path::new("image.img"),
+            Path::new("/dev/null"),
+            VerifyMode::Full,
+        );
+        record.serial = Some("reader".to_string());
+        record.capacity_genuine = Some(true);
+        history.append(&record).unwrap();
+        record.cid = Some("9f544930".to_string());
+        history.append(&record).unwrap();
+
+        let by_reader = history.capacity_verified("reader").unwrap();
+        let by_cid = history.capacity_verified("9f544930").unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(!by_reader);
+        assert!(by_cid);
+    }
+
+    #[test]
+    fn quotes_fields_csv_cares_about() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
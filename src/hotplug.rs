@@ -0,0 +1,62 @@
+//! Kernel uevents for block devices, so an idle appliance can wait for a card instead of polling.
+
+use std::io;
+use std::os::fd::AsRawFd;
+use std::thread;
+
+use nix::sys::socket::{
+    bind, recv, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType,
+};
+
+use crate::events::{Event, EventBus};
+use crate::logging::debug;
+
+/// Multicast group the kernel sends uevents to
+const KERNEL_UEVENTS: u32 = 1;
+
+/// Whether the uevent announces a block device that came or changed (e.g. media inserted into a
+/// reader that was already there).
+fn is_block_arrival(event: &[u8]) -> bool {
+    let fields: Vec<&[u8]> = event.split(|byte| *byte == 0).collect();
+    let has = |field: &[u8]| fields.contains(&field);
+    has(b"SUBSYSTEM=block") && (has(b"ACTION=add") || has(b"ACTION=change"))
+}
+
+/// Starts listening for uevents on a background thread, publishing `DeviceAdded` whenever a block
+/// device appears.
+pub fn listen(events: &EventBus) -> io::Result<()> {
+    let socket = socket(
+        AddressFamily::Netlink,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::NetlinkKObjectUEvent,
+    )?;
+    bind(socket.as_raw_fd(), &NetlinkAddr::new(0, KERNEL_UEVENTS))?;
+
+    let events = events.clone();
+    thread::spawn(move || {
+        let mut buffer = vec![0; 8192];
+        loop {
+            match recv(socket.as_raw_fd(), &mut buffer, MsgFlags::empty()) {
+                Ok(read) => {
+                    debug!(
+                        "Uevent {}",
+                        String::from_utf8_lossy(&buffer[..read]).replace('\0', " ")
+                    );
+                    if is_block_arrival(&buffer[..read]) {
+                        events.publish(Event::DeviceAdded);
+                    }
+                }
+                Err(nix::errno::Errno::EINTR) => {}
+                // Events were dropped, one of them might have been a card.
+                Err(nix::errno::Errno::ENOBUFS) => events.publish(Event::DeviceAdded),
+                Err(error) => {
+                    println!("Got error when reading uevents: {error:?}");
+                    events.publish(Event::HotplugStopped);
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,242 @@
+//! Local cache of decompressed images, so repeated flashes of a compressed image skip the
+//! decompression.
+//!
+//! Entries are keyed by the SHA-256 of the compressed source, so a changed source never hits a
+//! stale entry. To avoid hashing the source on every flash, the path, size and mtime it had when
+//! it was last hashed are remembered next to the entry.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::readahead::Sequential;
+use super::Image;
+use crate::config::CacheConfig;
+use crate::hashing::hex;
+use crate::history::unix_time;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct SourceIdentity {
+    pub(super) path: PathBuf,
+    size: u64,
+    modified: u64,
+}
+
+impl SourceIdentity {
+    pub(super) fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Ok(Self {
+            path: fs::canonicalize(path)?,
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    source: SourceIdentity,
+    /// Size of the decompressed image
+    size: u64,
+    last_used: u64,
+}
+
+pub struct Cache<'a> {
+    config: &'a CacheConfig,
+}
+
+impl<'a> Cache<'a> {
+    pub fn new(config: &'a CacheConfig) -> Self {
+        Self { config }
+    }
+
+    fn image_path(&self, key: &str) -> PathBuf {
+        self.config.directory.join(format!("{key}.img"))
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.config.directory.join(format!("{key}.json"))
+    }
+
+    fn entries(&self) -> io::Result<Vec<(String, Entry)>> {
+        let mut entries = vec![];
+        for file in fs::read_dir(&self.config.directory)? {
+            let path = file?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let Some(key) = path
+                .file_stem()
+                .map(|key| key.to_string_lossy().to_string())
+            else {
+                continue;
+            };
+            match fs::read(&path).map(|contents| serde_json::from_slice::<Entry>(&contents)) {
+                Ok(Ok(entry)) if self.image_path(&key).exists() => entries.push((key, entry)),
+                _ => println!("Ignoring broken cache entry {path:?}"),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn write_entry(&self, key: &str, entry: &Entry) -> io::Result<()> {
+        fs::write(self.entry_path(key), serde_json::to_vec(entry)?)
+    }
+
+    /// Returns the cached image for `source`, or the key a new entry has to be stored under.
+    pub fn lookup(&self, source: &Path) -> io::Result<Result<Image, String>> {
+        fs::create_dir_all(&self.config.directory)?;
+        let identity = SourceIdentity::of(source)?;
+        let entries = self.entries()?;
+
+        let key = match entries.iter().find(|(_, entry)| entry.source == identity) {
+            Some((key, _)) => key.clone(),
+            None => {
+                println!("Hashing {source:?} for the image cache");
+                sha256_file(source)?
+            }
+        };
+        let Some((_, entry)) = entries.into_iter().find(|(entry_key, _)| *entry_key == key) else {
+            return Ok(Err(key));
+        };
+
+        let entry = Entry {
+            source: identity,
+            last_used: unix_time(),
+            ..entry
+        };
+        self.write_entry(&key, &entry)?;
+        println!("Using cached decompressed image {key}");
+        let file = File::open(self.image_path(&key))?;
+        Ok(Ok(Image {
+            reader: Box::new(BufReader::new(Sequential::new(file.try_clone()?))),
+            size: Some(entry.size),
+            file: Some(file),
+        }))
+    }
+
+    /// Wraps `image` so the decompressed data is stored under `key` as it is read.
+    pub fn store(&self, source: &Path, key: String, image: Image) -> io::Result<Image> {
+        let temporary = self.config.directory.join(format!("{key}.partial"));
+        let file = BufWriter::new(File::create(&temporary)?);
+        let size = image.size;
+        Ok(Image {
+            reader: Box::new(CachingReader {
+                inner: image.reader,
+                file: Some(file),
+                temporary,
+                written: 0,
+                key,
+                source: SourceIdentity::of(source)?,
+                cache: self.config.clone(),
+            }),
+            size,
+            file: None,
+        })
+    }
+
+    /// Removes the least recently used entries until the cache fits its size limit.
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = self.entries()?;
+        entries.sort_by_key(|(_, entry)| entry.last_used);
+        let mut total: u64 = entries.iter().map(|(_, entry)| entry.size).sum();
+        for (key, entry) in entries {
+            if total <= self.config.max_size {
+                break;
+            }
+            println!("Evicting cached image {key}");
+            fs::remove_file(self.image_path(&key))?;
+            fs::remove_file(self.entry_path(&key))?;
+            total -= entry.size;
+        }
+        Ok(())
+    }
+}
+
+/// Tees the decompressed image into the cache, committing the entry only once the whole image
+/// was read. Failing to cache never fails the flash.
+struct CachingReader {
+    inner: Box<dyn Read + Send>,
+    file: Option<BufWriter<File>>,
+    temporary: PathBuf,
+    written: u64,
+    key: String,
+    source: SourceIdentity,
+    cache: CacheConfig,
+}
+
+impl CachingReader {
+    fn commit(&mut self) -> io::Result<()> {
+        let Some(file) = self.file.take() else {
+            return Ok(());
+        };
+        file.into_inner()?.sync_all()?;
+        let cache = Cache::new(&self.cache);
+        fs::rename(&self.temporary, cache.image_path(&self.key))?;
+        cache.write_entry(
+            &self.key,
+            &Entry {
+                source: self.source.clone(),
+                size: self.written,
+                last_used: unix_time(),
+            },
+        )?;
+        println!("Cached decompressed image {}", self.key);
+        cache.evict()
+    }
+
+    fn abandon(&mut self, error: io::Error) {
+        println!("Got error when caching image, not caching: {error:?}");
+        self.file = None;
+        let _ = fs::remove_file(&self.temporary);
+    }
+}
+
+impl Read for CachingReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        if read == 0 && !buffer.is_empty() {
+            if let Err(error) = self.commit() {
+                self.abandon(error);
+            }
+        } else if let Some(file) = &mut self.file {
+            match file.write_all(&buffer[..read]) {
+                Ok(()) => self.written += read as u64,
+                Err(error) => self.abandon(error),
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl Drop for CachingReader {
+    fn drop(&mut self) {
+        // The image was not read to the end, e.g. because the flash failed.
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.temporary);
+        }
+    }
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex(&hasher.finalize()))
+}
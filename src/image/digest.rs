@@ -0,0 +1,139 @@
+//! SHA-256 of the decompressed image, hashed once per version of the source file rather than on
+//! every flash. A flash then knows what it should write before it starts: the digest goes into
+//! its record even if it fails, and an image that reads differently than when it was hashed is
+//! caught.
+//!
+//! Digests are kept in a JSON file, keyed by the path, size and mtime of the source like the
+//! image cache does.
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::cache::SourceIdentity;
+use super::info::ImageInfo;
+use crate::config::Config;
+use crate::hashing::hex;
+
+/// Held while the file is read and written again, the hashing thread and flashes both store.
+static FILE: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    source: SourceIdentity,
+    sha256: String,
+}
+
+fn entries(path: &Path) -> io::Result<Vec<Entry>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(error) => Err(error),
+    }
+}
+
+/// What is known about the configured image as it is now.
+pub struct Known {
+    identity: SourceIdentity,
+    /// `None` until the current version of the source was hashed
+    pub sha256: Option<String>,
+}
+
+impl Known {
+    /// Remembers `sha256` as the digest of the image, unless its source changed since it was
+    /// looked up, in which case `sha256` may belong to neither version.
+    pub fn remember(self, config: &Config, sha256: &str) -> io::Result<()> {
+        let Some(now) = identity(config) else {
+            return Ok(());
+        };
+        if now != self.identity {
+            return Ok(());
+        }
+        let _file = FILE.lock().unwrap();
+        // Older versions of the same file are of no use anymore.
+        let mut entries: Vec<Entry> = entries(&config.image_digests)?
+            .into_iter()
+            .filter(|entry| entry.source.path != now.path)
+            .collect();
+        entries.push(Entry {
+            source: now,
+            sha256: sha256.to_string(),
+        });
+        let temporary = config.image_digests.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(&entries)?)?;
+        fs::rename(&temporary, &config.image_digests)
+    }
+}
+
+/// Only local files can be told apart from their next version.
+fn identity(config: &Config) -> Option<SourceIdentity> {
+    let source = super::source::from_config(config).ok()?;
+    SourceIdentity::of(source.local_path()?).ok()
+}
+
+/// Looks up the digest of the configured image. `None` for images that aren't local files.
+pub fn lookup(config: &Config) -> Option<Known> {
+    let identity = identity(config)?;
+    let sha256 = match entries(&config.image_digests) {
+        Ok(entries) => entries
+            .into_iter()
+            .find(|entry| entry.source == identity)
+            .map(|entry| entry.sha256),
+        Err(error) => {
+            println!("Got error when reading the image digests: {error:?}");
+            None
+        }
+    };
+    // Images that come with their digest, like backups, don't need hashing.
+    let sha256 = sha256.or_else(|| Some(ImageInfo::lookup(&config.image)?.sha256));
+    Some(Known { identity, sha256 })
+}
+
+/// Reads the configured image through to hash it.
+pub fn hash(config: &Config) -> io::Result<String> {
+    // The cache is left to flashes, which would otherwise fill the same entry at the same time.
+    let config = Config {
+        cache: None,
+        ..config.clone()
+    };
+    let mut image = super::open(&config)?;
+    let mut digest = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        match image.reader.read(&mut buffer) {
+            Ok(0) => return Ok(hex(&digest.finalize())),
+            Ok(read) => digest.update(&buffer[..read]),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Hashes the configured image on a background thread, unless the current version of it was
+/// hashed before.
+pub fn prehash(config: &Config) {
+    let Some(known) = lookup(config) else {
+        return;
+    };
+    if known.sha256.is_some() {
+        return;
+    }
+    let config = config.clone();
+    thread::spawn(move || {
+        println!("Hashing {:?} ahead of flashing it", config.image);
+        match hash(&config) {
+            Ok(sha256) => {
+                println!("Image {:?} has SHA-256 {sha256}", config.image);
+                if let Err(error) = known.remember(&config, &sha256) {
+                    println!("Got error when storing the image digest: {error:?}");
+                }
+            }
+            Err(error) => println!("Got error when hashing {:?}: {error:?}", config.image),
+        }
+    });
+}
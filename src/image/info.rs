@@ -0,0 +1,53 @@
+//! What an image decompresses to, kept in `<image>.info.json` next to it. Backups write one:
+//! zstd compressing on the fly can't put the size into the frame header, as it isn't known until
+//! the end. Flashing such an image then knows its size and digest before it starts.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::backup::Source;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageInfo {
+    /// Bytes the image decompresses to
+    pub size: u64,
+    /// SHA-256 of the decompressed image
+    pub sha256: String,
+    /// The card the image is a backup of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+}
+
+fn path(image: &Path) -> PathBuf {
+    let mut name = image.file_name().unwrap_or_default().to_os_string();
+    name.push(".info.json");
+    image.with_file_name(name)
+}
+
+impl ImageInfo {
+    /// `None` for images without one.
+    pub fn read(image: &Path) -> io::Result<Option<Self>> {
+        match fs::read(path(image)) {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Like [`ImageInfo::read`], logging errors instead of returning them.
+    pub fn lookup(image: &Path) -> Option<Self> {
+        Self::read(image).unwrap_or_else(|error| {
+            println!("Got error when reading the info of {image:?}: {error:?}");
+            None
+        })
+    }
+
+    pub fn write(&self, image: &Path) -> io::Result<PathBuf> {
+        let path = path(image);
+        fs::write(&path, serde_json::to_vec_pretty(self)?)?;
+        Ok(path)
+    }
+}
@@ -0,0 +1,105 @@
+//! Raw local images mapped into memory a chunk at a time, so cards are written straight from the
+//! page cache instead of through a buffer. Mapping the whole image at once doesn't fit the
+//! address space of a 32-bit Pi for images of more than a few GB.
+
+use std::ffi::c_void;
+use std::fs::File;
+use std::io;
+use std::num::NonZeroUsize;
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::slice;
+
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+
+/// `length` bytes of a file, read-only.
+pub struct Mapping {
+    pointer: NonNull<c_void>,
+    /// Mappings start on a page, `start` is where the requested part starts in it
+    start: usize,
+    length: usize,
+}
+
+// The mapping is private and read-only, nothing writes to it.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Maps `length` bytes of `file` at `offset`. The file mustn't shrink while it is mapped:
+    /// reading what is gone kills the process.
+    pub fn new(file: &File, offset: u64, length: usize) -> io::Result<Self> {
+        // 16K on the Pi 5, chunks may not start on one.
+        let page = unsafe { nix::libc::sysconf(nix::libc::_SC_PAGESIZE) } as u64;
+        let start = (offset % page) as usize;
+        let Some(mapped) = NonZeroUsize::new(length).and_then(|length| length.checked_add(start))
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't map nothing",
+            ));
+        };
+        let offset = (offset - start as u64)
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Offset too large to map"))?;
+        let pointer = unsafe {
+            mmap(
+                None,
+                mapped,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_PRIVATE,
+                file,
+                offset,
+            )
+        }?;
+        Ok(Self {
+            pointer,
+            start,
+            length,
+        })
+    }
+}
+
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(
+                self.pointer.as_ptr().cast::<u8>().add(self.start),
+                self.length,
+            )
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if let Err(error) = unsafe { munmap(self.pointer, self.start + self.length) } {
+            println!("Got error when unmapping the image: {error:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::buffers::ALIGNMENT;
+
+    #[test]
+    fn maps_the_requested_part() {
+        let path = std::env::temp_dir().join(format!("mapped-{}.img", std::process::id()));
+        let data: Vec<u8> = (0..3 * ALIGNMENT).map(|index| index as u8).collect();
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        let file = File::open(&path).unwrap();
+
+        let mapping = Mapping::new(&file, ALIGNMENT as u64, 2 * ALIGNMENT - 10).unwrap();
+        assert!(*mapping == data[ALIGNMENT..3 * ALIGNMENT - 10]);
+        // Off a page as well.
+        let mapping = Mapping::new(&file, 100, ALIGNMENT).unwrap();
+        assert!(*mapping == data[100..100 + ALIGNMENT]);
+        assert!(Mapping::new(&file, 0, 0).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
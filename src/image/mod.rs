@@ -0,0 +1,275 @@
+//! Opening the source image, transparently decompressing it.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::config::Config;
+
+mod cache;
+pub mod digest;
+pub mod info;
+pub mod mapped;
+pub mod readahead;
+mod size;
+pub mod source;
+
+use cache::Cache;
+use source::{ImageSource, Metadata};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Raw,
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+    /// A zip archive with a single image inside, as many vendors ship them
+    Zip,
+}
+
+impl Compression {
+    /// Sniffs the format from the first bytes of the file. Anything unrecognized is a raw image.
+    pub fn detect(header: &[u8]) -> Self {
+        const MAGICS: &[(&[u8], Compression)] = &[
+            (&[0x1F, 0x8B], Compression::Gzip),
+            (&[0xFD, b'7', b'z', b'X', b'Z', 0x00], Compression::Xz),
+            (&[0x28, 0xB5, 0x2F, 0xFD], Compression::Zstd),
+            (b"BZh", Compression::Bzip2),
+            (b"PK\x03\x04", Compression::Zip),
+        ];
+        MAGICS
+            .iter()
+            .find(|(magic, _)| header.starts_with(magic))
+            .map_or(Compression::Raw, |(_, compression)| *compression)
+    }
+
+    /// What the file name claims the format is, if it claims anything.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_string_lossy().to_ascii_lowercase();
+        match extension.as_str() {
+            "img" | "iso" | "raw" | "bin" => Some(Compression::Raw),
+            "gz" => Some(Compression::Gzip),
+            "xz" => Some(Compression::Xz),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            "bz2" => Some(Compression::Bzip2),
+            "zip" => Some(Compression::Zip),
+            _ => None,
+        }
+    }
+}
+
+pub struct Image {
+    pub reader: Box<dyn Read + Send>,
+    /// Size of the decompressed image, if known up-front
+    pub size: Option<u64>,
+    /// The file `reader` reads as it is, for raw images stored locally. Published checksums are
+    /// checked by `reader`, those images don't have one.
+    pub file: Option<File>,
+}
+
+/// Opens the configured image, from the cache if it was decompressed before.
+pub fn open(config: &Config) -> io::Result<Image> {
+    let source = source::from_config(config)?;
+    let threads = config.decompression_threads();
+    let Some(path) = source.local_path() else {
+        return open_source(source.as_ref(), threads);
+    };
+    let Some(cache_config) = &config.cache else {
+        return open_source(source.as_ref(), threads);
+    };
+    if Compression::detect(BufReader::new(File::open(path)?).fill_buf()?) == Compression::Raw {
+        return open_source(source.as_ref(), threads);
+    }
+
+    let cache = Cache::new(cache_config);
+    match cache.lookup(path) {
+        Ok(Ok(image)) => Ok(image),
+        Ok(Err(key)) => cache.store(path, key, open_source(source.as_ref(), threads)?),
+        Err(error) => {
+            println!("Got error when looking up image cache: {error:?}");
+            open_source(source.as_ref(), threads)
+        }
+    }
+}
+
+/// Opens the image from `source`. `threads` is the number of threads used to decode xz images.
+fn open_source(source: &dyn ImageSource, threads: u32) -> io::Result<Image> {
+    let opened = source.open()?;
+    let Metadata { name, size, sha256 } = opened.metadata.clone();
+    let mut reader = BufReader::new(source::verified(opened));
+    let compression = Compression::detect(reader.fill_buf()?);
+    match Compression::from_extension(Path::new(&name)) {
+        Some(claimed) if claimed != compression => {
+            println!(
+                "Warning: {name:?} looks like {claimed:?} by its name, but its contents are {compression:?}. Using {compression:?}"
+            );
+        }
+        _ => {}
+    }
+
+    if compression == Compression::Zip {
+        // The central directory is at the end, so archives have to be read from a file.
+        let Some(path) = source.local_path() else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Zip archive {name:?} can only be flashed from a local file"),
+            ));
+        };
+        return open_zip(File::open(path)?);
+    }
+    let size = match (compression, source.local_path()) {
+        (Compression::Raw, _) => size,
+        (Compression::Xz, Some(path)) => size::xz_uncompressed_size(&mut File::open(path)?)?,
+        (Compression::Zstd, _) => size::zstd_content_size(reader.fill_buf()?),
+        _ => None,
+    };
+    let size = size.or_else(|| Some(info::ImageInfo::lookup(source.local_path()?)?.size));
+    let file = match (compression, source.local_path(), &sha256) {
+        (Compression::Raw, Some(path), None) => Some(File::open(path)?),
+        _ => None,
+    };
+    println!("Opened {name:?} as {compression:?}, size {size:?}");
+    Ok(Image {
+        reader: decode(reader, compression, threads)?,
+        size,
+        file,
+    })
+}
+
+fn decode<R: BufRead + Send + 'static>(
+    reader: R,
+    compression: Compression,
+    threads: u32,
+) -> io::Result<Box<dyn Read + Send>> {
+    let decoder: Box<dyn Read + Send> = match compression {
+        Compression::Raw => return Ok(Box::new(reader)),
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Compression::Xz => Box::new(XzStreams::new(reader, threads)?),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        Compression::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(reader)),
+        Compression::Zip => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Zip archives have to be opened with open_zip",
+            ))
+        }
+    };
+    Ok(decoder)
+}
+
+/// Streams the single image out of a zip archive. Only the central directory is parsed by the
+/// zip crate, the entry itself is decoded with the same decoders as plain compressed images.
+fn open_zip(file: File) -> io::Result<Image> {
+    use zip::CompressionMethod;
+
+    let mut archive = zip::ZipArchive::new(file).map_err(io::Error::other)?;
+    let mut images = vec![];
+    for index in 0..archive.len() {
+        let entry = archive.by_index_raw(index).map_err(io::Error::other)?;
+        if !entry.is_dir() {
+            images.push(index);
+        }
+    }
+    let [index] = images[..] else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Zip archive must contain exactly one file, found {}",
+                images.len()
+            ),
+        ));
+    };
+
+    let entry = archive.by_index_raw(index).map_err(io::Error::other)?;
+    let name = entry.name().map_err(io::Error::other)?.to_string();
+    let method = entry.compression();
+    let size = entry.size();
+    let compressed_size = entry.compressed_size();
+    let data_start = entry
+        .data_start()
+        .ok_or(io::Error::other("Zip entry has no data offset"))?;
+    drop(entry);
+
+    let mut file = archive.into_inner();
+    file.seek(SeekFrom::Start(data_start))?;
+    let data = BufReader::new(file.take(compressed_size));
+    let reader: Box<dyn Read + Send> = if method == CompressionMethod::STORE {
+        Box::new(data)
+    } else if method == CompressionMethod::DEFLATE {
+        Box::new(flate2::bufread::DeflateDecoder::new(data))
+    } else if method == CompressionMethod::BZIP2 {
+        Box::new(bzip2::bufread::BzDecoder::new(data))
+    } else if method == CompressionMethod::ZSTD {
+        Box::new(zstd::Decoder::with_buffer(data)?)
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unsupported zip compression method {method:?} for {name}"),
+        ));
+    };
+    println!("Streaming {name} ({method:?}, {size} bytes) out of the zip archive");
+
+    Ok(Image {
+        reader,
+        size: Some(size),
+        file: None,
+    })
+}
+
+/// xz files compressed with `xz -T` consist of independent blocks that liblzma decodes in
+/// parallel. Single-block files (plain `xz`) are decoded on one thread regardless.
+fn xz_decoder<R: BufRead>(file: R, threads: u32) -> io::Result<liblzma::bufread::XzDecoder<R>> {
+    let stream = liblzma::stream::MtStreamBuilder::new()
+        .threads(threads.max(1))
+        // Without these liblzma refuses to allocate anything.
+        .memlimit_threading(u64::MAX)
+        .memlimit_stop(u64::MAX)
+        .decoder()
+        .map_err(io::Error::other)?;
+    Ok(liblzma::bufread::XzDecoder::new_stream(file, stream))
+}
+
+/// The multi-threaded decoder stops after one xz stream, this continues with the next one for
+/// concatenated files.
+struct XzStreams<R: BufRead> {
+    decoder: Option<liblzma::bufread::XzDecoder<R>>,
+    threads: u32,
+}
+
+impl<R: BufRead> XzStreams<R> {
+    fn new(file: R, threads: u32) -> io::Result<Self> {
+        Ok(Self {
+            decoder: Some(xz_decoder(file, threads)?),
+            threads,
+        })
+    }
+}
+
+impl<R: BufRead> Read for XzStreams<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let Some(decoder) = self.decoder.as_mut() else {
+                return Ok(0);
+            };
+            let read = decoder.read(buffer)?;
+            if read > 0 || buffer.is_empty() {
+                return Ok(read);
+            }
+
+            let mut file = self.decoder.take().unwrap().into_inner();
+            // Streams may be followed by null padding.
+            loop {
+                let input = file.fill_buf()?;
+                let padding = input.iter().take_while(|byte| **byte == 0).count();
+                if padding == 0 {
+                    break;
+                }
+                file.consume(padding);
+            }
+            if !file.fill_buf()?.is_empty() {
+                self.decoder = Some(xz_decoder(file, self.threads)?);
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Page cache hints for image files, which are read once from start to end. Without them a
+//! 16 GB image pushes everything else out of the page cache during a flash, and the pages it
+//! leaves behind are of no use to anyone.
+
+use std::fs::File;
+use std::io::{self, Read};
+
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+
+use crate::logging::debug;
+
+/// Pages are dropped once the read position is this far past them, and as much is asked to be
+/// read ahead.
+const WINDOW: u64 = 32 * 1024 * 1024;
+
+/// Passes `advice` for `length` bytes at `offset` (0 for the rest of the file) on to the kernel.
+/// Only a hint, failing to is logged and otherwise ignored.
+pub fn advise(file: &File, offset: u64, length: u64, advice: PosixFadviseAdvice) {
+    let (Ok(offset), Ok(length)) = (offset.try_into(), length.try_into()) else {
+        return;
+    };
+    if let Err(error) = posix_fadvise(file, offset, length, advice) {
+        debug!("Got error when advising the kernel on reading the image: {error:?}");
+    }
+}
+
+/// A file read from start to end, dropping what was read from the page cache as it goes.
+pub struct Sequential {
+    file: File,
+    position: u64,
+    /// Everything before it was dropped already
+    dropped: u64,
+}
+
+impl Sequential {
+    pub fn new(file: File) -> Self {
+        advise(&file, 0, 0, PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL);
+        advise(&file, 0, WINDOW, PosixFadviseAdvice::POSIX_FADV_WILLNEED);
+        Self {
+            file,
+            position: 0,
+            dropped: 0,
+        }
+    }
+}
+
+impl Read for Sequential {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read(buffer)?;
+        self.position += read as u64;
+        if self.position - self.dropped >= WINDOW {
+            let behind = self.position - self.dropped;
+            advise(
+                &self.file,
+                self.dropped,
+                behind,
+                PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+            );
+            advise(
+                &self.file,
+                self.position,
+                WINDOW,
+                PosixFadviseAdvice::POSIX_FADV_WILLNEED,
+            );
+            self.dropped = self.position;
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_past_the_dropped_pages() {
+        let path = std::env::temp_dir().join(format!("readahead-{}.img", std::process::id()));
+        let data: Vec<u8> = (0..WINDOW + 4096)
+            .map(|index| (index / 4096) as u8)
+            .collect();
+        std::fs::write(&path, &data).unwrap();
+        let mut read = vec![];
+        Sequential::new(File::open(&path).unwrap())
+            .read_to_end(&mut read)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(read == data);
+    }
+}
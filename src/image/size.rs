@@ -0,0 +1,98 @@
+//! Decompressed sizes read from the container metadata, so the size check before flashing also
+//! works for compressed images.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+const XZ_HEADER_MAGIC: &[u8] = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const XZ_FOOTER_MAGIC: &[u8] = b"YZ";
+
+/// Sums the uncompressed sizes from the index of every stream, walking backwards from the end of
+/// the file. `None` if the file doesn't look like we expect.
+pub fn xz_uncompressed_size(file: &mut File) -> io::Result<Option<u64>> {
+    let mut position = file.seek(SeekFrom::End(0))?;
+    let mut total = 0;
+    loop {
+        // Stream padding is a multiple of four null bytes.
+        let mut word = [0; 4];
+        while position >= 4 {
+            read_at(file, position - 4, &mut word)?;
+            if word != [0; 4] {
+                break;
+            }
+            position -= 4;
+        }
+        if position == 0 {
+            return Ok(Some(total));
+        }
+        if position < 24 {
+            return Ok(None);
+        }
+
+        let mut footer = [0; 12];
+        read_at(file, position - 12, &mut footer)?;
+        if &footer[10..] != XZ_FOOTER_MAGIC {
+            return Ok(None);
+        }
+        let index_size = (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as u64 + 1) * 4;
+        let Some(index_start) = (position - 12).checked_sub(index_size) else {
+            return Ok(None);
+        };
+        let mut index = vec![0; index_size as usize];
+        read_at(file, index_start, &mut index)?;
+        let Some((blocks_size, uncompressed)) = parse_xz_index(&index) else {
+            return Ok(None);
+        };
+
+        let Some(stream_start) = index_start.checked_sub(blocks_size + 12) else {
+            return Ok(None);
+        };
+        let mut header = [0; 6];
+        read_at(file, stream_start, &mut header)?;
+        if header != XZ_HEADER_MAGIC {
+            return Ok(None);
+        }
+        total += uncompressed;
+        position = stream_start;
+    }
+}
+
+/// Returns the total size of the blocks in the stream and their uncompressed size.
+fn parse_xz_index(index: &[u8]) -> Option<(u64, u64)> {
+    let mut bytes = index.iter().copied();
+    if bytes.next()? != 0 {
+        return None;
+    }
+    let records = read_varint(&mut bytes)?;
+    let (mut blocks_size, mut uncompressed) = (0u64, 0u64);
+    for _ in 0..records {
+        let unpadded = read_varint(&mut bytes)?;
+        blocks_size = blocks_size.checked_add(unpadded.next_multiple_of(4))?;
+        uncompressed = uncompressed.checked_add(read_varint(&mut bytes)?)?;
+    }
+    Some((blocks_size, uncompressed))
+}
+
+fn read_varint(bytes: &mut impl Iterator<Item = u8>) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..63).step_by(7) {
+        let byte = bytes.next()?;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn read_at(file: &mut File, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buffer)
+}
+
+/// The content size from the header of the first zstd frame, if the encoder wrote it.
+pub fn zstd_content_size(header: &[u8]) -> Option<u64> {
+    zstd::zstd_safe::get_frame_content_size(header)
+        .ok()
+        .flatten()
+}
@@ -0,0 +1,396 @@
+//! Where the image's bytes come from. Each source hands out a reader of the raw, possibly still
+//! compressed, bytes; decompression and caching happen on top of it, whatever the source.
+//!
+//! The configured image picks the source: `-` is stdin, `http://` and `https://` URLs are
+//! downloaded, `s3://<bucket>/<key>` is fetched from S3 (or anything speaking its API), and
+//! everything else is a local file. Sources other than stdin may publish the SHA-256 of the raw
+//! bytes in a `<image>.sha256` file next to them, which the download is then checked against.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use super::readahead::Sequential;
+use crate::config::{Config, S3Config};
+use crate::hashing::hex;
+use crate::history::{format_time, unix_time};
+
+/// Image path that makes the image stream from stdin.
+pub const STDIN_PATH: &str = "-";
+
+/// Stdin can only be flashed once, there is no way to rewind it.
+static STDIN_CONSUMED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// File name of the image, its extension hints at the compression
+    pub name: String,
+    /// Size of the raw bytes, if known up-front
+    pub size: Option<u64>,
+    /// SHA-256 the raw bytes are expected to have, as published next to the image
+    pub sha256: Option<String>,
+}
+
+pub struct Source {
+    pub reader: Box<dyn Read + Send>,
+    pub metadata: Metadata,
+}
+
+pub trait ImageSource: Send + Sync {
+    fn open(&self) -> io::Result<Source>;
+
+    /// The file behind the source, for sources that are one. Only those can be cached, or read
+    /// as zip archives, which needs seeking.
+    fn local_path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// The source for the configured image.
+pub fn from_config(config: &Config) -> io::Result<Box<dyn ImageSource>> {
+    let path = &config.image;
+    let timeout = Duration::from_secs(config.image_source.timeout_secs);
+    let text = path.to_string_lossy();
+    if path == Path::new(STDIN_PATH) {
+        Ok(Box::new(StdinSource))
+    } else if text.starts_with("http://") || text.starts_with("https://") {
+        Ok(Box::new(HttpSource {
+            url: text.to_string(),
+            timeout,
+        }))
+    } else if let Some(location) = text.strip_prefix("s3://") {
+        let (bucket, key) = location
+            .split_once('/')
+            .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("S3 image {text:?} must look like s3://<bucket>/<key>"),
+                )
+            })?;
+        Ok(Box::new(S3Source {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            config: config.image_source.s3.clone(),
+            timeout,
+        }))
+    } else {
+        Ok(Box::new(FileSource { path: path.clone() }))
+    }
+}
+
+/// Checks the raw bytes against the published SHA-256 once they run out.
+pub fn verified(source: Source) -> Box<dyn Read + Send> {
+    match source.metadata.sha256 {
+        Some(expected) => Box::new(Checked {
+            reader: source.reader,
+            digest: Some(Sha256::new()),
+            expected: expected.to_ascii_lowercase(),
+            name: source.metadata.name,
+        }),
+        None => source.reader,
+    }
+}
+
+struct Checked {
+    reader: Box<dyn Read + Send>,
+    /// Taken once the end was reached and checked, decoders may read past the end more than once
+    digest: Option<Sha256>,
+    expected: String,
+    name: String,
+}
+
+impl Read for Checked {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buffer)?;
+        if read == 0 && !buffer.is_empty() {
+            let Some(digest) = self.digest.take() else {
+                return Ok(0);
+            };
+            let actual = hex(&digest.finalize());
+            if actual != self.expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} has SHA-256 {actual}, but {} was published",
+                        self.name, self.expected
+                    ),
+                ));
+            }
+        }
+        if let Some(digest) = &mut self.digest {
+            digest.update(&buffer[..read]);
+        }
+        Ok(read)
+    }
+}
+
+/// The hash out of a `sha256sum` style line, `<hash>  <name>`.
+fn parse_checksum(text: &str) -> Option<String> {
+    let hash = text.split_whitespace().next()?;
+    (hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_hexdigit())).then(|| hash.into())
+}
+
+fn file_name(location: &str) -> String {
+    location.rsplit('/').next().unwrap_or(location).to_string()
+}
+
+struct FileSource {
+    path: PathBuf,
+}
+
+impl ImageSource for FileSource {
+    fn open(&self) -> io::Result<Source> {
+        let file = File::open(&self.path)?;
+        let mut checksum_path = self.path.clone().into_os_string();
+        checksum_path.push(".sha256");
+        let sha256 = match std::fs::read_to_string(checksum_path) {
+            Ok(text) => parse_checksum(&text),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => return Err(error),
+        };
+        Ok(Source {
+            metadata: Metadata {
+                name: self.path.to_string_lossy().to_string(),
+                size: Some(file.metadata()?.len()),
+                sha256,
+            },
+            reader: Box::new(Sequential::new(file)),
+        })
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+/// Streams the image from stdin, e.g. `generate-image | rpi-sd-cloner --image -`. The size is
+/// never known up-front.
+struct StdinSource;
+
+impl ImageSource for StdinSource {
+    fn open(&self) -> io::Result<Source> {
+        if STDIN_CONSUMED.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::other(
+                "The image from stdin was already flashed, restart to flash another one",
+            ));
+        }
+        Ok(Source {
+            reader: Box::new(io::stdin()),
+            metadata: Metadata {
+                name: "stdin".to_string(),
+                ..Metadata::default()
+            },
+        })
+    }
+}
+
+/// Streams the image from a web server. Every flash downloads it again.
+struct HttpSource {
+    url: String,
+    /// For connecting and for each read, the download as a whole may take as long as it takes
+    timeout: Duration,
+}
+
+impl HttpSource {
+    /// `None` if there is nothing at `url`.
+    fn get(&self, url: &str) -> io::Result<Option<ureq::Response>> {
+        let result = ureq::AgentBuilder::new()
+            .timeout_connect(self.timeout)
+            .timeout_read(self.timeout)
+            .build()
+            .get(url)
+            .call();
+        match result {
+            Ok(response) => Ok(Some(response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(error) => Err(io::Error::other(format!("Fetching {url} failed: {error}"))),
+        }
+    }
+}
+
+impl ImageSource for HttpSource {
+    fn open(&self) -> io::Result<Source> {
+        let sha256 = match self.get(&format!("{}.sha256", self.url))? {
+            Some(response) => parse_checksum(&response.into_string()?),
+            None => None,
+        };
+        let response = self.get(&self.url)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} doesn't exist", self.url),
+            )
+        })?;
+        Ok(Source {
+            metadata: Metadata {
+                name: file_name(&self.url),
+                size: response
+                    .header("Content-Length")
+                    .and_then(|length| length.parse().ok()),
+                sha256,
+            },
+            reader: response.into_reader(),
+        })
+    }
+}
+
+/// Fetches the image from an S3 bucket, signing requests with AWS Signature Version 4 when
+/// there are credentials and fetching anonymously from public buckets otherwise.
+struct S3Source {
+    bucket: String,
+    key: String,
+    config: S3Config,
+    timeout: Duration,
+}
+
+impl S3Source {
+    /// `None` if there is no object at `key`.
+    fn get(&self, key: &str) -> io::Result<Option<ureq::Response>> {
+        let endpoint = self.config.endpoint();
+        let path = format!("/{}/{}", uri_encode(&self.bucket), uri_encode(key));
+        let request = ureq::AgentBuilder::new()
+            .timeout_connect(self.timeout)
+            .timeout_read(self.timeout)
+            .build()
+            .get(&format!("{}{path}", endpoint.trim_end_matches('/')));
+        let Some((access_key_id, secret_access_key)) = self.config.credentials() else {
+            return self.response(key, request.call());
+        };
+
+        let host = endpoint
+            .split_once("://")
+            .map_or(endpoint.as_str(), |(_, rest)| rest)
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let now = unix_time();
+        let date: String = format_time(now)
+            .chars()
+            .filter(char::is_ascii_digit)
+            .collect();
+        let timestamp = format!("{}T{}{:02}Z", &date[..8], &date[8..], now % 60);
+        let scope = format!("{}/{}/s3/aws4_request", &date[..8], self.config.region);
+        let session_token = self.config.session_token();
+
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", "UNSIGNED-PAYLOAD".to_string()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{value}\n"))
+            .collect();
+        let canonical_request =
+            format!("GET\n{path}\n\n{canonical_headers}\n{signed_headers}\nUNSIGNED-PAYLOAD");
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut signing_key = format!("AWS4{secret_access_key}").into_bytes();
+        for part in [&date[..8], &self.config.region, "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part.as_bytes());
+        }
+        let signature = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+        let mut request = request
+            .set("x-amz-content-sha256", "UNSIGNED-PAYLOAD")
+            .set("x-amz-date", &timestamp)
+            .set(
+                "Authorization",
+                &format!(
+                    "AWS4-HMAC-SHA256 Credential={access_key_id}/{scope}, SignedHeaders={signed_headers}, Signature={signature}"
+                ),
+            );
+        if let Some(token) = &session_token {
+            request = request.set("x-amz-security-token", token);
+        }
+        self.response(key, request.call())
+    }
+
+    fn response(
+        &self,
+        key: &str,
+        result: Result<ureq::Response, ureq::Error>,
+    ) -> io::Result<Option<ureq::Response>> {
+        match result {
+            Ok(response) => Ok(Some(response)),
+            // S3 answers 403 rather than 404 for missing objects without the ListBucket right.
+            Err(ureq::Error::Status(403 | 404, _)) => Ok(None),
+            Err(error) => Err(io::Error::other(format!(
+                "Fetching s3://{}/{key} failed: {error}",
+                self.bucket
+            ))),
+        }
+    }
+}
+
+impl ImageSource for S3Source {
+    fn open(&self) -> io::Result<Source> {
+        let sha256 = match self.get(&format!("{}.sha256", self.key))? {
+            Some(response) => parse_checksum(&response.into_string()?),
+            None => None,
+        };
+        let response = self.get(&self.key)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "s3://{}/{} doesn't exist or we may not read it",
+                    self.bucket, self.key
+                ),
+            )
+        })?;
+        Ok(Source {
+            metadata: Metadata {
+                name: file_name(&self.key),
+                size: response
+                    .header("Content-Length")
+                    .and_then(|length| length.parse().ok()),
+                sha256,
+            },
+            reader: response.into_reader(),
+        })
+    }
+}
+
+/// Percent-encodes everything but unreserved characters and slashes, as SigV4 wants the path.
+fn uri_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5C));
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
@@ -0,0 +1,157 @@
+//! Keyboard-like USB devices, read straight from their evdev node so no console or X is needed.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::thread;
+
+use nix::libc::input_event;
+use tokio::sync::mpsc;
+
+// From linux/input.h
+nix::ioctl_write_int!(eviocgrab, b'E', 0x90);
+
+const EV_KEY: u16 = 0x01;
+
+const KEY_ESC: u16 = 1;
+const KEY_ENTER: u16 = 28;
+const KEY_KPENTER: u16 = 96;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+
+/// The keys a numpad controls the cloner with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Digit(u8),
+    Enter,
+    Escape,
+}
+
+struct Keyboard {
+    file: File,
+    shift: bool,
+}
+
+impl Keyboard {
+    fn open(device: &Path) -> io::Result<Self> {
+        let file = File::open(device)?;
+        // Keep the keystrokes away from the console and anything else reading the device.
+        // SAFETY: EVIOCGRAB only takes the flag, the fd is open for the duration of the call.
+        unsafe { eviocgrab(file.as_raw_fd(), 1) }?;
+        Ok(Self { file, shift: false })
+    }
+
+    /// Blocks until the next key goes down (or auto-repeats) and returns its code.
+    fn next_press(&mut self) -> io::Result<u16> {
+        let mut buffer = [0; mem::size_of::<input_event>()];
+        loop {
+            self.file.read_exact(&mut buffer)?;
+            // SAFETY: input_event is plain old data, every bit pattern is valid.
+            let event: input_event = unsafe { mem::transmute(buffer) };
+            if event.type_ != EV_KEY {
+                continue;
+            }
+            let pressed = event.value != 0;
+            match event.code {
+                KEY_LEFTSHIFT | KEY_RIGHTSHIFT => self.shift = pressed,
+                code if pressed => return Ok(code),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// What a key types on a US layout, which is what scanners emulate unless told otherwise.
+fn character(code: u16, shift: bool) -> Option<char> {
+    const ROWS: [(u16, &str, &str); 4] = [
+        (2, "1234567890-=", "!@#$%^&*()_+"),
+        (16, "qwertyuiop[]", "QWERTYUIOP{}"),
+        (30, "asdfghjkl;'`", "ASDFGHJKL:\"~"),
+        (43, "\\zxcvbnm,./", "|ZXCVBNM<>?"),
+    ];
+    // The keypad, for scanners set to type digits there.
+    const KEYPAD: [(u16, char); 15] = [
+        (55, '*'),
+        (71, '7'),
+        (72, '8'),
+        (73, '9'),
+        (74, '-'),
+        (75, '4'),
+        (76, '5'),
+        (77, '6'),
+        (78, '+'),
+        (79, '1'),
+        (80, '2'),
+        (81, '3'),
+        (82, '0'),
+        (83, '.'),
+        (98, '/'),
+    ];
+    if code == 57 {
+        return Some(' ');
+    }
+    for (first, plain, shifted) in ROWS {
+        let row = if shift { shifted } else { plain };
+        if let Some(character) = code
+            .checked_sub(first)
+            .and_then(|index| row.chars().nth(index as usize))
+        {
+            return Some(character);
+        }
+    }
+    KEYPAD
+        .iter()
+        .find(|(keypad_code, _)| *keypad_code == code)
+        .map(|(_, character)| *character)
+}
+
+/// Reads `device` on a background thread and sends every line typed into it to `sender`, as a
+/// barcode scanner does for each code it reads.
+pub fn lines(device: &Path, sender: mpsc::UnboundedSender<String>) -> io::Result<()> {
+    let mut keyboard = Keyboard::open(device)?;
+    thread::spawn(move || {
+        let mut line = String::new();
+        loop {
+            match keyboard.next_press() {
+                Ok(KEY_ENTER | KEY_KPENTER) => {
+                    if sender.send(mem::take(&mut line)).is_err() {
+                        return;
+                    }
+                }
+                Ok(code) => line.extend(character(code, keyboard.shift)),
+                Err(error) => {
+                    println!("Got error when reading input device: {error:?}");
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reads `device` on a background thread and sends the digits, Enter and Esc typed on it. Both
+/// the keypad and the top row count as digits, whatever the state of Num Lock.
+pub fn keys(device: &Path) -> io::Result<mpsc::UnboundedReceiver<Key>> {
+    let mut keyboard = Keyboard::open(device)?;
+    let (sender, receiver) = mpsc::unbounded_channel();
+    thread::spawn(move || loop {
+        let key = match keyboard.next_press() {
+            Ok(KEY_ENTER | KEY_KPENTER) => Key::Enter,
+            Ok(KEY_ESC) => Key::Escape,
+            Ok(code) => match character(code, false).and_then(|key| key.to_digit(10)) {
+                Some(digit) => Key::Digit(digit as u8),
+                None => continue,
+            },
+            Err(error) => {
+                println!("Got error when reading input device: {error:?}");
+                return;
+            }
+        };
+        if sender.send(key).is_err() {
+            return;
+        }
+    });
+    Ok(receiver)
+}
@@ -0,0 +1,98 @@
+//! Printing a label for every flashed card on an ESC/POS or Brother QL printer.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::config::{LabelConfig, LabelProtocol};
+use crate::history::{format_time, FlashRecord};
+use crate::template;
+
+pub const DEFAULT_TEMPLATE: &str = "{{image}} {{version}}\n{{date}}\nSN {{serial}}\nSHA {{sha256}}";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn variables(record: &FlashRecord) -> Vec<(&'static str, String)> {
+    let unknown = || "unknown".to_string();
+    vec![
+        (
+            "image",
+            record
+                .image
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(unknown),
+        ),
+        (
+            "version",
+            record.image_version.clone().unwrap_or_else(unknown),
+        ),
+        ("date", format_time(record.finished_at)),
+        ("serial", record.serial.clone().unwrap_or_else(unknown)),
+        (
+            "sha256",
+            record
+                .image_sha256
+                .as_deref()
+                .map(|digest| digest[..digest.len().min(12)].to_string())
+                .unwrap_or_else(unknown),
+        ),
+        ("device", record.device.to_string_lossy().to_string()),
+    ]
+}
+
+/// Label printers only know ASCII, anything else would print as garbage.
+fn ascii(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|char| if char.is_ascii() { char as u8 } else { b'?' })
+        .collect()
+}
+
+fn esc_pos(text: &str) -> Vec<u8> {
+    let mut data = b"\x1b@".to_vec();
+    data.extend(ascii(text));
+    // Feed past the cutter, then a partial cut.
+    data.extend(b"\n\n\n\x1dV\x42\x00");
+    data
+}
+
+/// Brother QL printers take raster data by default, in ESC/P mode they print text themselves.
+fn brother_ql(text: &str) -> Vec<u8> {
+    let mut data = vec![0; 200];
+    data.extend(b"\x1bia\x00\x1b@");
+    data.extend(ascii(text));
+    data.push(b'\x0c');
+    data
+}
+
+fn send(printer: &str, data: &[u8]) -> io::Result<()> {
+    match printer.strip_prefix("tcp://") {
+        Some(address) => {
+            let address = address
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| io::Error::other(format!("Can't resolve {address}")))?;
+            let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+            stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+            stream.write_all(data)
+        }
+        None => OpenOptions::new()
+            .write(true)
+            .open(printer)?
+            .write_all(data),
+    }
+}
+
+pub fn print(config: &LabelConfig, record: &FlashRecord) -> io::Result<()> {
+    let text = template::render(
+        config.template.as_deref().unwrap_or(DEFAULT_TEMPLATE),
+        &variables(record),
+    );
+    let data = match config.protocol {
+        LabelProtocol::EscPos => esc_pos(&text),
+        LabelProtocol::BrotherQl => brother_ql(&text),
+    };
+    send(&config.printer, &data)?;
+    println!("Printed label for {:?}", record.serial);
+    Ok(())
+}
@@ -0,0 +1,258 @@
+use std::time::Duration;
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::Instant;
+
+use crate::config::{IdlePattern, LedPatternConfig};
+use crate::events::{Event, EventBus};
+use crate::hardware::Led;
+use crate::SystemState;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedState {
+    Off,
+    SolidBoth,
+    FlashingGreen,
+    FlashingRed,
+    FlashingGreenRed,
+    SolidGreen,
+    SolidRed,
+    SolidRedFlashingGreen,
+    FlashingBoth,
+    FlashingRedSolidGreen,
+    /// Like `FlashingRed`, but slow enough to let the Pi sleep in between
+    SlowFlashingRed,
+    SlowFlashingGreenRed,
+    SlowFlashingGreen,
+    FastFlashingRed,
+    /// Both LEDs flash quickly, in turns
+    FastFlashingGreenRed,
+    /// The red LED blinks this many times, then pauses
+    BlinkCode(u8),
+    /// The green LED blinks twice, then pauses
+    Heartbeat,
+    SlowFlashingBoth,
+}
+
+impl LedState {
+    fn period(self) -> Duration {
+        match self {
+            LedState::SlowFlashingRed => Duration::from_secs(3),
+            LedState::FastFlashingRed | LedState::FastFlashingGreenRed => {
+                Duration::from_millis(100)
+            }
+            LedState::BlinkCode(_) => Duration::from_millis(250),
+            LedState::Heartbeat => Duration::from_millis(100),
+            LedState::SlowFlashingGreenRed
+            | LedState::SlowFlashingGreen
+            | LedState::SlowFlashingBoth => Duration::from_secs(1),
+            _ => Duration::from_millis(300),
+        }
+    }
+
+    /// How `state` is shown, with the waiting states shown as configured in `patterns`.
+    pub fn for_state(state: SystemState, patterns: &LedPatternConfig) -> Self {
+        match state {
+            SystemState::Initializing => LedState::SolidBoth,
+            SystemState::NoSdCard => patterns.no_card.into(),
+            SystemState::Idle => patterns.idle.into(),
+            SystemState::SdCardFound => LedState::FlashingGreen,
+            SystemState::AwaitingConfirmation => LedState::FlashingBoth,
+            SystemState::Flashing => LedState::FlashingGreenRed,
+            SystemState::Paused => LedState::SlowFlashingGreenRed,
+            SystemState::FlashingSuceeded => LedState::SolidGreen,
+            SystemState::FlashedUnverified => LedState::SlowFlashingGreen,
+            SystemState::FlashingFailed => LedState::SolidRed,
+            SystemState::CardTooSmall => LedState::SolidRedFlashingGreen,
+            SystemState::DeviceBusy => LedState::FlashingRedSolidGreen,
+            SystemState::BadCard => LedState::FastFlashingRed,
+            SystemState::CardRemoved => LedState::FastFlashingGreenRed,
+            SystemState::ConfigError(fault) => LedState::BlinkCode(fault.blink_code()),
+            SystemState::ShuttingDown => LedState::SlowFlashingBoth,
+        }
+    }
+}
+
+impl From<IdlePattern> for LedState {
+    fn from(pattern: IdlePattern) -> Self {
+        match pattern {
+            IdlePattern::Heartbeat => LedState::Heartbeat,
+            IdlePattern::FlashingRed => LedState::FlashingRed,
+            IdlePattern::SlowFlashingRed => LedState::SlowFlashingRed,
+            IdlePattern::Off => LedState::Off,
+        }
+    }
+}
+
+/// Played once over the steady state, which shows again once it is over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Animation {
+    /// Both LEDs blink three times, quicker than any steady state, when a card is inserted
+    TripleBlink,
+}
+
+impl Animation {
+    /// How long each frame is shown, and whether the red and green LED are lit in it.
+    fn frames(self) -> &'static [(Duration, bool, bool)] {
+        const BLINK: Duration = Duration::from_millis(80);
+        match self {
+            Animation::TripleBlink => &[
+                (BLINK, true, true),
+                (BLINK, false, false),
+                (BLINK, true, true),
+                (BLINK, false, false),
+                (BLINK, true, true),
+                (BLINK, false, false),
+            ],
+        }
+    }
+}
+
+pub struct LedDriver {
+    red: Box<dyn Led>,
+    yellow: Box<dyn Led>,
+    patterns: LedPatternConfig,
+    events: EventBus,
+    receiver: broadcast::Receiver<Event>,
+}
+
+impl LedDriver {
+    pub fn new(
+        red: Box<dyn Led>,
+        yellow: Box<dyn Led>,
+        patterns: LedPatternConfig,
+        events: &EventBus,
+    ) -> Self {
+        Self {
+            red,
+            yellow,
+            patterns,
+            events: events.clone(),
+            receiver: events.subscribe(),
+        }
+    }
+
+    pub async fn update_loop(self) {
+        let LedDriver {
+            mut red,
+            mut yellow,
+            patterns,
+            events,
+            mut receiver,
+        } = self;
+        let mut flash_state = false;
+        // Ticks since the state changed, for blink codes
+        let mut ticks: u32 = 0;
+        let mut led_state = LedState::SolidBoth;
+        let mut timer = tokio::time::interval(led_state.period());
+        // The frames left of the animation playing, and when the current one ends.
+        let mut animation: &[(Duration, bool, bool)] = &[];
+        let mut frame_end = Instant::now();
+
+        loop {
+            tokio::select! {
+                event = receiver.recv() => {
+                    let state = match event {
+                        Ok(Event::StateChanged(state)) => state,
+                        Ok(Event::CardDetected) => {
+                            animation = Animation::TripleBlink.frames();
+                            frame_end = Instant::now() + animation[0].0;
+                            events.state()
+                        }
+                        Ok(_) => continue,
+                        // Only the latest state matters.
+                        Err(RecvError::Lagged(_)) => events.state(),
+                        Err(RecvError::Closed) => return,
+                    };
+                    let new_led_state = LedState::for_state(state, &patterns);
+                    if new_led_state != led_state {
+                        if new_led_state.period() != led_state.period() {
+                            timer = tokio::time::interval(new_led_state.period());
+                        }
+                        led_state = new_led_state;
+                        flash_state = false;
+                        ticks = 0;
+                    }
+                }
+                _ = timer.tick() => {
+                    flash_state = !flash_state;
+                    ticks = ticks.wrapping_add(1);
+                }
+                _ = tokio::time::sleep_until(frame_end), if !animation.is_empty() => {
+                    animation = &animation[1..];
+                    if let Some((duration, _, _)) = animation.first() {
+                        frame_end += *duration;
+                    }
+                }
+            }
+            // The steady state carries on underneath, and shows where it got to afterwards.
+            if let Some((_, red_lit, green_lit)) = animation.first() {
+                red.set(*red_lit);
+                yellow.set(*green_lit);
+                continue;
+            }
+            match (led_state, flash_state) {
+                (LedState::Off, _) => {
+                    red.set(false);
+                    yellow.set(false);
+                }
+                (LedState::SolidBoth, _) => {
+                    red.set(true);
+                    yellow.set(true);
+                }
+                (LedState::SolidRed, _) => {
+                    red.set(true);
+                    yellow.set(false);
+                }
+                (LedState::SolidGreen, _) => {
+                    red.set(false);
+                    yellow.set(true);
+                }
+                (
+                    LedState::FlashingGreenRed
+                    | LedState::SlowFlashingGreenRed
+                    | LedState::FastFlashingGreenRed,
+                    flash_state,
+                ) => {
+                    red.set(flash_state);
+                    yellow.set(!flash_state);
+                }
+                (LedState::FlashingGreen | LedState::SlowFlashingGreen, flash_state) => {
+                    yellow.set(flash_state);
+                    red.set(false);
+                }
+                (
+                    LedState::FlashingRed | LedState::SlowFlashingRed | LedState::FastFlashingRed,
+                    flash_state,
+                ) => {
+                    red.set(flash_state);
+                    yellow.set(false);
+                }
+                (LedState::SolidRedFlashingGreen, flash_state) => {
+                    red.set(true);
+                    yellow.set(flash_state);
+                }
+                (LedState::FlashingBoth | LedState::SlowFlashingBoth, flash_state) => {
+                    red.set(flash_state);
+                    yellow.set(flash_state);
+                }
+                (LedState::FlashingRedSolidGreen, flash_state) => {
+                    red.set(flash_state);
+                    yellow.set(true);
+                }
+                // On every other tick for the code, then dark for as long as two blinks.
+                (LedState::BlinkCode(code), _) => {
+                    let position = ticks % (2 * u32::from(code) + 4);
+                    red.set(position < 2 * u32::from(code) && position % 2 == 1);
+                    yellow.set(false);
+                }
+                // Lit for a tick twice, then dark for the rest of the beat.
+                (LedState::Heartbeat, _) => {
+                    red.set(false);
+                    yellow.set(matches!(ticks % 15, 0 | 2));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Making sure only one instance writes to cards at a time.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use nix::fcntl::{Flock, FlockArg};
+
+use crate::error::DeviceError;
+
+/// Holds the instance lock until dropped.
+pub struct InstanceLock {
+    _lock: Flock<File>,
+}
+
+impl InstanceLock {
+    /// Takes the lock at `path`, failing right away if another instance holds it.
+    pub fn acquire(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        let mut lock =
+            Flock::lock(file, FlockArg::LockExclusiveNonblock).map_err(|(_, errno)| {
+                io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    format!("Another instance holds {path:?}: {errno}"),
+                )
+            })?;
+        lock.set_len(0)?;
+        writeln!(lock, "{}", std::process::id())?;
+        Ok(Self { _lock: lock })
+    }
+}
+
+/// Opens the target for writing with `O_EXCL`, which for block devices fails with `EBUSY` while
+/// anything else has it mounted or exclusively open.
+pub fn open_device_exclusive(device: &Path) -> Result<File, DeviceError> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(nix::libc::O_EXCL)
+        .open(device)
+        .map_err(|error| DeviceError::open(device, error))
+}
@@ -0,0 +1,83 @@
+//! A log file rotated by size and age.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use super::Sink;
+use crate::config::LogFileConfig;
+use crate::history::format_time_with_seconds;
+
+pub struct RotatingFile {
+    config: LogFileConfig,
+    file: File,
+    size: u64,
+    started: SystemTime,
+}
+
+impl RotatingFile {
+    pub fn open(config: LogFileConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let metadata = file.metadata()?;
+        // A file left by an earlier run is carried on with, and keeps its age.
+        let started = metadata
+            .created()
+            .or_else(|_| metadata.modified())
+            .unwrap_or_else(|_| SystemTime::now());
+        Ok(Self {
+            size: metadata.len(),
+            started,
+            file,
+            config,
+        })
+    }
+
+    fn rotated(&self, number: u32) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{number}"));
+        path.into()
+    }
+
+    fn is_due(&self) -> bool {
+        let too_old = self.config.max_age().is_some_and(|max_age| {
+            self.started
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= max_age)
+        });
+        self.size > 0 && (self.size >= self.config.max_size || too_old)
+    }
+
+    /// Shifts the rotated files up by one, the oldest one falls off the end.
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.config.keep == 0 {
+            fs::remove_file(&self.config.path)?;
+        } else {
+            for number in (1..self.config.keep).rev() {
+                match fs::rename(self.rotated(number), self.rotated(number + 1)) {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.config.path, self.rotated(1))?;
+        }
+        *self = Self::open(self.config.clone())?;
+        Ok(())
+    }
+}
+
+impl Sink for RotatingFile {
+    fn write_line(&mut self, line: &[u8], unix_time: u64) -> io::Result<()> {
+        if self.is_due() {
+            self.rotate()?;
+        }
+        let mut stamped = format!("{} ", format_time_with_seconds(unix_time)).into_bytes();
+        stamped.extend_from_slice(line);
+        self.file.write_all(&stamped)?;
+        self.size += stamped.len() as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+//! Copies of everything the cloner prints, for images without journald and for fleets whose logs
+//! are collected centrally. Standard output and error are redirected into a pipe, so every
+//! `println!` (and every hook we run) reaches the sinks without knowing about them. The lines are
+//! still passed on to the terminal, on standard output.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::OwnedFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use nix::fcntl::OFlag;
+use nix::unistd::{dup, dup2_stderr, dup2_stdout, pipe2};
+
+use crate::config::{Config, LogLevel};
+use crate::history::unix_time;
+
+mod file;
+mod syslog;
+
+/// How long lines still in the pipe get to reach the sinks when the cloner exits
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+pub fn level() -> LogLevel {
+    match DEBUG.load(Ordering::Relaxed) {
+        true => LogLevel::Debug,
+        false => LogLevel::Info,
+    }
+}
+
+pub fn set_level(level: LogLevel) {
+    if DEBUG.swap(level == LogLevel::Debug, Ordering::Relaxed) != (level == LogLevel::Debug) {
+        println!("Log level is now {level:?}");
+    }
+}
+
+/// Like `println!`, at the debug level only. The lines start with `Debug:`, which is how syslog
+/// tells them apart.
+macro_rules! debug {
+    ($($argument:tt)*) => {
+        if $crate::logging::level() == $crate::config::LogLevel::Debug {
+            println!("Debug: {}", format_args!($($argument)*));
+        }
+    };
+}
+pub(crate) use debug;
+
+/// Somewhere the lines go besides the terminal.
+trait Sink: Send {
+    /// `line` ends in a newline, unless the output ended without one.
+    fn write_line(&mut self, line: &[u8], unix_time: u64) -> io::Result<()>;
+}
+
+/// Output goes to the sinks until this is dropped, then back to the terminal only.
+pub struct Logging {
+    stdout: OwnedFd,
+    stderr: OwnedFd,
+    done: mpsc::Receiver<()>,
+}
+
+impl Drop for Logging {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        // Closes our ends of the pipe. Hooks still running keep it open, so we don't wait on
+        // them forever.
+        let _ = dup2_stdout(&self.stdout);
+        let _ = dup2_stderr(&self.stderr);
+        let _ = self.done.recv_timeout(FLUSH_TIMEOUT);
+    }
+}
+
+/// Starts copying standard output and error to the sinks in `config`, if there are any.
+pub fn start(config: &Config) -> io::Result<Option<Logging>> {
+    let mut sinks: Vec<(&str, Box<dyn Sink>)> = vec![];
+    if let Some(log_file) = &config.log_file {
+        sinks.push((
+            "log file",
+            Box::new(file::RotatingFile::open(log_file.clone())?),
+        ));
+    }
+    if let Some(syslog) = &config.syslog {
+        sinks.push(("syslog", Box::new(syslog::Syslog::new(syslog.clone()))));
+    }
+    if sinks.is_empty() {
+        return Ok(None);
+    }
+
+    let (stdout, stderr) = (dup(io::stdout())?, dup(io::stderr())?);
+    let (reader, writer) = pipe2(OFlag::O_CLOEXEC)?;
+    let mut terminal = File::from(stdout.try_clone()?);
+    let (finished, done) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(File::from(reader));
+        let mut line = vec![];
+        let mut failing = vec![false; sinks.len()];
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+            let _ = terminal.write_all(&line);
+            let now = unix_time();
+            // Reported once rather than after every line, the terminal still has them.
+            for ((name, sink), failing) in sinks.iter_mut().zip(&mut failing) {
+                match sink.write_line(&line, now) {
+                    Ok(()) => *failing = false,
+                    Err(error) if !*failing => {
+                        let _ =
+                            writeln!(terminal, "Got error when writing to the {name}: {error:?}");
+                        *failing = true;
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+        let _ = finished.send(());
+    });
+    // The duplicates lose close-on-exec, so hooks we run write into the pipe too.
+    dup2_stdout(&writer)?;
+    dup2_stderr(&writer)?;
+    Ok(Some(Logging {
+        stdout,
+        stderr,
+        done,
+    }))
+}
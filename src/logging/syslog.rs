@@ -0,0 +1,153 @@
+//! Lines sent to a syslog server as RFC 5424 messages. They are sent from a thread of their own
+//! behind a bounded queue, so a server that is slow or gone drops lines rather than holding up
+//! whoever printed them.
+
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::process;
+use std::sync::mpsc::{self, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::Sink;
+use crate::config::{SyslogConfig, SyslogProtocol};
+use crate::history::format_time_with_seconds;
+use crate::peer;
+
+const APP_NAME: &str = "rpi-sd-cloner";
+/// Lines waiting for the server, newer ones are dropped beyond this
+const QUEUE_LENGTH: usize = 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Lines arriving while the server is unreachable are dropped until the next attempt
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+const SEVERITY_ERROR: u8 = 3;
+const SEVERITY_INFORMATIONAL: u8 = 6;
+const SEVERITY_DEBUG: u8 = 7;
+
+pub struct Syslog {
+    queue: mpsc::SyncSender<Vec<u8>>,
+    facility: u8,
+    hostname: String,
+}
+
+impl Syslog {
+    pub fn new(config: SyslogConfig) -> Self {
+        let (queue, messages) = mpsc::sync_channel(QUEUE_LENGTH);
+        let (facility, hostname) = (
+            config.facility,
+            config.hostname.clone().unwrap_or_else(peer::hostname),
+        );
+        thread::spawn(move || send_all(&config, messages));
+        Self {
+            queue,
+            facility,
+            hostname,
+        }
+    }
+
+    fn message(&self, line: &str, unix_time: u64) -> Vec<u8> {
+        // Lines carry no level, but how they start tells.
+        let severity = if line.starts_with("Got error") || line.starts_with("Error") {
+            SEVERITY_ERROR
+        } else if line.starts_with("Debug:") {
+            SEVERITY_DEBUG
+        } else {
+            SEVERITY_INFORMATIONAL
+        };
+        let time = format_time_with_seconds(unix_time).replacen(' ', "T", 1);
+        format!(
+            "<{}>1 {time}Z {} {APP_NAME} {} - - {line}",
+            u16::from(self.facility) * 8 + u16::from(severity),
+            self.hostname,
+            process::id(),
+        )
+        .into_bytes()
+    }
+}
+
+impl Sink for Syslog {
+    fn write_line(&mut self, line: &[u8], unix_time: u64) -> io::Result<()> {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(());
+        }
+        match self.queue.try_send(self.message(line, unix_time)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(io::Error::other(
+                "the server isn't keeping up, dropping lines",
+            )),
+            Err(TrySendError::Disconnected(_)) => Err(io::Error::other("the sender stopped")),
+        }
+    }
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+impl Connection {
+    fn open(config: &SyslogConfig) -> io::Result<Self> {
+        let address = (config.host.as_str(), config.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::other(format!("{} has no address", config.host)))?;
+        match config.protocol {
+            SyslogProtocol::Udp => {
+                let any: SocketAddr = match address {
+                    SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                    SocketAddr::V6(_) => ([0; 16], 0).into(),
+                };
+                let socket = UdpSocket::bind(any)?;
+                socket.connect(address)?;
+                Ok(Self::Udp(socket))
+            }
+            SyslogProtocol::Tcp => Ok(Self::Tcp(TcpStream::connect_timeout(
+                &address,
+                CONNECT_TIMEOUT,
+            )?)),
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.send(message).map(drop),
+            // Octet counting, so messages may contain newlines.
+            Self::Tcp(stream) => {
+                let mut framed = format!("{} ", message.len()).into_bytes();
+                framed.extend_from_slice(message);
+                stream.write_all(&framed)
+            }
+        }
+    }
+}
+
+fn send_all(config: &SyslogConfig, messages: mpsc::Receiver<Vec<u8>>) {
+    let mut connection = None;
+    let mut last_attempt: Option<Instant> = None;
+    for message in messages {
+        if connection.is_none() {
+            if last_attempt.is_some_and(|attempt| attempt.elapsed() < RECONNECT_INTERVAL) {
+                continue;
+            }
+            last_attempt = Some(Instant::now());
+            match Connection::open(config) {
+                Ok(opened) => connection = Some(opened),
+                Err(error) => {
+                    println!(
+                        "Got error when connecting to syslog server {}:{}: {error:?}",
+                        config.host, config.port
+                    );
+                    continue;
+                }
+            }
+        }
+        if let Some(open) = &mut connection {
+            if open.send(&message).is_err() {
+                connection = None;
+            }
+        }
+    }
+}
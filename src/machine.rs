@@ -0,0 +1,352 @@
+//! The state machine deciding what the cloner does next, from the inputs it was given, its
+//! timeouts and the cards around. It neither waits nor touches hardware itself: the main loop
+//! steps it, hands it the time and runs the flashes it asks for, and tests do the same with a
+//! made-up clock and cards.
+
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+use crate::config::TimeoutConfig;
+use crate::events::{ButtonEvent, Event, EventBus};
+use crate::logging::debug;
+use crate::timers::TimerWheel;
+use crate::{SystemState, POLL_INTERVAL};
+
+/// What the state machine needs to know about the cards.
+pub trait Cards {
+    /// The card to work on, if one is inserted.
+    fn find(&mut self) -> io::Result<Option<PathBuf>>;
+
+    fn present(&mut self, device: &Path) -> bool;
+
+    /// Large devices, and ones that look like hard drives rather than cards.
+    fn needs_confirmation(&mut self, device: &Path) -> bool;
+
+    /// Cards that failed verification too often are refused.
+    fn quarantined(&mut self, device: &Path) -> bool;
+}
+
+/// What the caller has to do after a step.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Step again after the polling interval
+    Continue,
+    /// Flash `device`, then report back with [`Machine::flash_finished`]
+    Flash(PathBuf),
+    /// Nothing to do until an event wakes the machine, see [`Machine::note`]
+    Sleep,
+    /// Exit, so systemd starts the updated binary
+    Restart,
+    /// Power off the Pi, the state shows it is shutting down
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Timeout {
+    /// The card has been gone long enough to give up on it
+    CardRemoved,
+    /// A result has been shown long enough
+    AutoReset,
+    /// No second press or long hold came
+    Confirmation,
+    /// Nothing happened for long enough to save power
+    Idle,
+}
+
+/// What the state machine keeps from the events it receives.
+#[derive(Debug, Default)]
+struct Inputs {
+    /// Since the last step
+    button_pressed: bool,
+    /// Since entering idle
+    woken: bool,
+    update_ready: bool,
+    hotplug_stopped: bool,
+    /// Since the last step
+    shutdown_held: bool,
+    /// Running on the battery of the UPS, no new flash may start
+    power_failing: bool,
+}
+
+pub struct Machine {
+    events: EventBus,
+    timeouts: TimeoutConfig,
+    /// Whether cards arriving are announced, so polling for them can stop while idle
+    hotplug: bool,
+    inputs: Inputs,
+    timers: TimerWheel<Timeout>,
+    previous_state: SystemState,
+    device: Option<PathBuf>,
+}
+
+impl Machine {
+    pub fn new(events: &EventBus, timeouts: TimeoutConfig, hotplug: bool, now: Instant) -> Self {
+        Self {
+            events: events.clone(),
+            timeouts,
+            hotplug,
+            inputs: Inputs::default(),
+            timers: TimerWheel::new(POLL_INTERVAL, now),
+            previous_state: SystemState::Initializing,
+            device: None,
+        }
+    }
+
+    /// Takes note of `event`. Returns whether it wakes the machine from idle.
+    pub fn note(&mut self, event: &Event) -> bool {
+        match event {
+            Event::ButtonPressed(ButtonEvent::ShutdownHold) => self.inputs.shutdown_held = true,
+            Event::PowerFailing(failing) => {
+                self.inputs.power_failing = *failing;
+                return false;
+            }
+            Event::ButtonPressed(_) => self.inputs.button_pressed = true,
+            Event::DeviceAdded => {}
+            Event::UpdateInstalled => self.inputs.update_ready = true,
+            Event::HotplugStopped => {
+                println!("Hotplug listener stopped, polling again");
+                self.inputs.hotplug_stopped = true;
+            }
+            _ => return false,
+        }
+        self.inputs.woken = true;
+        true
+    }
+
+    /// Takes note of everything received since the last call.
+    pub fn drain(&mut self, receiver: &mut broadcast::Receiver<Event>) {
+        loop {
+            match receiver.try_recv() {
+                Ok(event) => {
+                    self.note(&event);
+                }
+                Err(TryRecvError::Lagged(_)) => {}
+                Err(TryRecvError::Empty | TryRecvError::Closed) => return,
+            }
+        }
+    }
+
+    /// A press that didn't come from the button, like Enter on the keypad.
+    pub fn press(&mut self) {
+        self.inputs.button_pressed = true;
+    }
+
+    /// Backs out of a pending confirmation. Returns whether there was one.
+    pub fn cancel_confirmation(&mut self) -> bool {
+        if self.events.state() != SystemState::AwaitingConfirmation {
+            return false;
+        }
+        println!("Cancelled");
+        self.events.set_state(SystemState::SdCardFound);
+        true
+    }
+
+    /// Moves on to `state` once the flash asked for ended. Presses during the flash paused it,
+    /// they don't dismiss the result.
+    pub fn flash_finished(&mut self, state: SystemState) {
+        self.inputs.button_pressed = false;
+        self.events.set_state(state);
+    }
+
+    /// The card being flashed came back as `device` after its reader dropped off the bus.
+    pub fn reenumerated(&mut self, device: PathBuf) {
+        self.device = Some(device);
+    }
+
+    /// Runs the state machine once. `auto_start` flashes cards without waiting for a press, for
+    /// the cards of a batch.
+    pub fn step(&mut self, now: Instant, cards: &mut impl Cards, auto_start: bool) -> Action {
+        let current_state = self.events.state();
+        let pressed = mem::take(&mut self.inputs.button_pressed);
+        // Only without a card, a hold during a flash cancels it and one on a result dismisses it.
+        if mem::take(&mut self.inputs.shutdown_held)
+            && matches!(
+                current_state,
+                SystemState::NoSdCard | SystemState::Idle | SystemState::ConfigError(_)
+            )
+        {
+            println!("Button was held, shutting down");
+            self.events.set_state(SystemState::ShuttingDown);
+            return Action::Shutdown;
+        }
+        // Only between cards, systemd starts the new binary.
+        if self.inputs.update_ready
+            && matches!(
+                current_state,
+                SystemState::NoSdCard | SystemState::Idle | SystemState::ConfigError(_)
+            )
+        {
+            println!("Exiting to restart into the updated cloner");
+            return Action::Restart;
+        }
+
+        if current_state != self.previous_state {
+            self.timers.clear();
+            if current_state.shows_result() {
+                if let Some(reset) = self.timeouts.result_reset() {
+                    self.timers.schedule(Timeout::AutoReset, reset, now);
+                }
+            }
+            if current_state == SystemState::AwaitingConfirmation {
+                self.timers
+                    .schedule(Timeout::Confirmation, self.timeouts.confirmation(), now);
+            }
+            if current_state == SystemState::NoSdCard && self.can_sleep() {
+                if let Some(idle) = self.timeouts.idle() {
+                    self.timers.schedule(Timeout::Idle, idle, now);
+                }
+            }
+            if current_state == SystemState::Idle {
+                self.inputs.woken = false;
+            }
+            self.previous_state = current_state;
+        }
+        let card_present = match self.device.as_deref() {
+            Some(device) => cards.present(device),
+            None => false,
+        };
+        if current_state.needs_card() {
+            if card_present {
+                self.timers.cancel(Timeout::CardRemoved);
+            } else if !self.timers.is_scheduled(Timeout::CardRemoved) {
+                self.timers
+                    .schedule(Timeout::CardRemoved, self.timeouts.card_removed(), now);
+            }
+        }
+        let fired = self.timers.advance(now);
+        for timeout in &fired {
+            debug!("{timeout:?} timed out in {current_state:?}");
+            match timeout {
+                Timeout::CardRemoved => {
+                    println!("Card was removed");
+                    self.events.set_state(SystemState::NoSdCard);
+                }
+                Timeout::AutoReset => {
+                    self.events.set_state(SystemState::NoSdCard);
+                }
+                Timeout::Confirmation => {
+                    println!("Not confirmed in time");
+                    self.events.set_state(SystemState::SdCardFound);
+                }
+                Timeout::Idle => {
+                    println!("No card for a while, going idle");
+                    self.events.set_state(SystemState::Idle);
+                }
+            }
+        }
+        if !fired.is_empty() {
+            return Action::Continue;
+        }
+
+        match current_state {
+            SystemState::NoSdCard => {
+                self.device = match cards.find() {
+                    Ok(device) => device,
+                    Err(error) => {
+                        println!("Got error when querying devices: {error:?}");
+                        return Action::Continue;
+                    }
+                };
+                match self.device.as_deref() {
+                    None => self.events.set_state(SystemState::NoSdCard),
+                    Some(device) if cards.quarantined(device) => {
+                        println!("Card in {device:?} is quarantined");
+                        self.events.set_state(SystemState::BadCard);
+                    }
+                    Some(device) => {
+                        println!("Have device! {device:?}");
+                        // A result reset with the card still in finds the same card again.
+                        if !card_present {
+                            self.events.publish(Event::CardDetected);
+                        }
+                        self.events.set_state(SystemState::SdCardFound);
+                    }
+                }
+            }
+            SystemState::Idle => {
+                // Sleeps until a card, a press or an update comes along, rather than polling.
+                if self.can_sleep() && !mem::take(&mut self.inputs.woken) {
+                    return Action::Sleep;
+                }
+                self.events.set_state(SystemState::NoSdCard);
+            }
+            SystemState::SdCardFound => {
+                let Some(device) = self.device.as_deref() else {
+                    self.events.set_state(SystemState::NoSdCard);
+                    return Action::Continue;
+                };
+                // The work order was the go-ahead for every card of a batch.
+                if card_present && (auto_start || pressed) {
+                    if self.inputs.power_failing {
+                        if pressed {
+                            println!("Power is failing, not starting a flash");
+                        }
+                    } else if cards.needs_confirmation(device) {
+                        println!(
+                            "{device:?} is large or not a card, press again or hold to confirm"
+                        );
+                        self.events.set_state(SystemState::AwaitingConfirmation);
+                    } else {
+                        self.events.set_state(SystemState::Flashing);
+                    }
+                }
+            }
+            SystemState::AwaitingConfirmation => {
+                // Either event confirms: a second press, or the first press turning into a hold.
+                if card_present && pressed && self.inputs.power_failing {
+                    println!("Power is failing, not starting a flash");
+                } else if card_present && pressed {
+                    println!("Confirmed, flashing");
+                    self.events.set_state(SystemState::Flashing);
+                }
+            }
+            SystemState::Flashing => {
+                let Some(device) = &self.device else {
+                    self.events.set_state(SystemState::FlashingFailed);
+                    return Action::Continue;
+                };
+                return Action::Flash(device.clone());
+            }
+            SystemState::FlashingFailed
+            | SystemState::FlashingSuceeded
+            | SystemState::FlashedUnverified
+            | SystemState::CardTooSmall
+            | SystemState::DeviceBusy
+            | SystemState::BadCard => {
+                if pressed {
+                    self.events.set_state(SystemState::NoSdCard);
+                }
+            }
+            // The card is gone already, the next one inserted is flashed from scratch.
+            SystemState::CardRemoved => {
+                let inserted = match cards.find() {
+                    Ok(device) => device.is_some(),
+                    Err(error) => {
+                        println!("Got error when querying devices: {error:?}");
+                        false
+                    }
+                };
+                if pressed || inserted {
+                    self.events.set_state(SystemState::NoSdCard);
+                }
+            }
+            // Only while a flash runs, which the caller waits for.
+            SystemState::Paused => {}
+            // Only a restart gets out of it, once whatever failed the self-test is fixed.
+            SystemState::ConfigError(_) => {}
+            SystemState::ShuttingDown => {}
+            SystemState::Initializing => {
+                self.events.set_state(SystemState::NoSdCard);
+            }
+        }
+        Action::Continue
+    }
+
+    fn can_sleep(&self) -> bool {
+        self.hotplug && !self.inputs.hotplug_stopped
+    }
+}
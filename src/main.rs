@@ -9,349 +9,1142 @@
 // handle incoming signals to prevent an abnormal termination.
 
 use std::error::Error;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use clap::{Parser, Subcommand};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use agent::UnitStatus;
+use batch::Batch;
+use buffers::BufferPool;
+use config::{Config, DeviceBackend, LogLevel, VerifyMode};
+use devices::{card_present, device_size, looks_like_hard_drive};
+use error::{DeviceError, FlashError};
+use events::{ButtonEvent, Event, EventBus};
+use hardware::Hardware;
+use history::{FlashRecord, FlashResult, History};
+use input::Key;
+use leds::LedDriver;
+use machine::{Action, Cards, Machine};
+use pause::PauseControl;
+use quarantine::QuarantineList;
+
+mod agent;
+mod backup;
+mod batch;
+mod bmap;
+mod buffers;
+mod capacity;
+mod catalog;
+mod config;
+mod coordinator;
+mod devices;
+mod display;
+mod erase;
+mod error;
+mod events;
+mod ext4;
+mod flash;
+mod hardware;
+mod hashing;
+mod health;
+mod history;
+mod hotplug;
+mod image;
+mod input;
+mod label;
+mod leds;
+mod lock;
+mod logging;
+mod machine;
+mod monitoring;
+mod network;
+mod nfc;
+mod notify;
+mod partition_table;
+mod pause;
+mod peer;
+mod post_flash;
+mod power;
+mod preflight;
+mod priority;
+mod privileges;
+mod quarantine;
+mod reload;
+mod report;
+mod rtc;
+mod sd_mux;
+mod selftest;
+mod share;
+mod simulation;
+mod sync;
+mod template;
+#[cfg(test)]
+mod tests;
+mod timers;
+mod tuning;
+mod udisks;
+mod update;
+mod usb_power;
+
+/// Holding the button this long counts as a long press.
+pub(crate) const LONG_PRESS: Duration = Duration::from_millis(1500);
+/// Holding it this long without a card asks to power off.
+const SHUTDOWN_HOLD: Duration = Duration::from_secs(5);
+/// How often the state machine runs, and the resolution of its timeouts
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// A card whose reader keeps dropping off the bus is given up on after this many new starts
+const REENUMERATION_RETRIES: u32 = 2;
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// Path to the config file
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Image to flash, overriding the config. `-` streams the image from stdin.
+    #[arg(long)]
+    image: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Write the block manifest used by image sync next to a raw image
+    Manifest {
+        image: PathBuf,
+        #[arg(long, default_value_t = sync::DEFAULT_BLOCK_SIZE)]
+        block_size: u64,
+        /// Defaults to the version in the image's file name
+        #[arg(long)]
+        image_version: Option<String>,
+    },
+    /// Dump a card into an image file, or into the backup store without one
+    Backup {
+        device: PathBuf,
+        /// Image file, or a directory to give it its default name in
+        output: Option<PathBuf>,
+        /// Name of the backup in the store, defaults to the cloner, the card CID, its capacity and
+        /// the time
+        #[arg(long, conflicts_with = "output")]
+        name: Option<String>,
+        /// Only store what differs from the image the card was last flashed with
+        #[arg(long, conflicts_with = "output")]
+        incremental: bool,
+        /// Keep the whole card instead of stopping after the last partition
+        #[arg(long)]
+        no_shrink: bool,
+        /// Compress the backup with zstd
+        #[arg(long)]
+        compress: bool,
+        /// Don't read the card again to check the backup
+        #[arg(long)]
+        no_verify: bool,
+        /// zstd level, from 1 to 19. Higher ones take a lot longer for a little less.
+        #[arg(long, requires = "compress", default_value_t = 3)]
+        level: i32,
+    },
+    /// List, restore and clean up the backups in the backup store
+    Backups {
+        #[command(subcommand)]
+        action: BackupsAction,
+    },
+    /// Write `.bmap` files listing the used blocks of raw images, for bmaptool and friends
+    Bmap {
+        /// Defaults to every image in the catalog
+        images: Vec<PathBuf>,
+    },
+    /// Show or clear cards that are refused after failing verification
+    Quarantine {
+        #[command(subcommand)]
+        action: QuarantineAction,
+    },
+    /// Run the dashboard for a lab of cloners, and dispatch batches to them
+    Coordinator,
+    /// Check the config, the image, the LEDs and button, and what the device filter picks up,
+    /// exiting with an error on problems. For provisioning scripts, before enabling the service.
+    Check,
+    /// Connect the card of the configured SD mux to the cloner or to the device under test
+    Mux { side: sd_mux::Side },
+    /// Export the flash history as CSV
+    Export {
+        /// First day to include, as `YYYY-MM-DD` in UTC
+        #[arg(long, value_parser = parse_day)]
+        since: Option<u64>,
+        /// Last day to include, as `YYYY-MM-DD` in UTC
+        #[arg(long, value_parser = parse_day)]
+        until: Option<u64>,
+        /// Only flashes of images whose path or version contains this
+        #[arg(long)]
+        image: Option<String>,
+        /// Defaults to standard output
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
 
-use rppal::gpio::Gpio;
+fn parse_day(date: &str) -> Result<u64, String> {
+    history::parse_date(date).ok_or_else(|| format!("{date:?} isn't a YYYY-MM-DD date"))
+}
 
-type WhateverResult = Result<(), Box<dyn Error + Send>>;
+#[derive(Debug, Subcommand)]
+enum BackupsAction {
+    List,
+    /// Write a backup out as a raw image, which can be flashed
+    Restore {
+        name: String,
+        output: PathBuf,
+        /// Image of an incremental backup, if it isn't where it was anymore
+        #[arg(long)]
+        image: Option<PathBuf>,
+    },
+    /// Remove a backup. The space it took is only freed by `gc`.
+    Remove {
+        name: String,
+    },
+    /// Delete the chunks no backup needs anymore
+    Gc,
+    /// Remove the backups the retention config says go, and their chunks
+    Prune,
+    /// Search the history for backups, in the store or in files, whose name, cloner, CID, card
+    /// serial or reader serial contains `query`. Works without a store too.
+    Find {
+        query: String,
+    },
+}
 
-// Gpio uses BCM pin numbering. BCM GPIO 23 is tied to physical pin 16.
-const LED_YELLOW: u8 = 23;
-const LED_RED: u8 = 27;
-const BUTTON_GPIO: u8 = 26;
+#[derive(Debug, Subcommand)]
+enum QuarantineAction {
+    /// List the cards with verify failures
+    List,
+    /// Allow a card to be flashed again
+    Clear { cid: String },
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum SystemState {
+pub enum SystemState {
     /// Initializing
     Initializing,
     /// An SD card needs to be inserted
     NoSdCard,
+    /// No card for a long time, waiting for hotplug events instead of polling
+    Idle,
     /// We found an SD card
     SdCardFound,
+    /// The target is large or looks like a hard drive, waiting for a second press or long hold
+    AwaitingConfirmation,
     /// Flashing in progress
     Flashing,
+    /// Flashing is paused until the button is pressed again
+    Paused,
     /// Flashing is nominal (image checksum matches)
     FlashingSuceeded,
+    /// Written, but verification is turned off
+    FlashedUnverified,
     /// Flashing failed (image checksum doesn't match)
     FlashingFailed,
+    /// The image doesn't fit on the card
+    CardTooSmall,
+    /// Something else has the card mounted or open
+    DeviceBusy,
+    /// The card failed verification too often and is refused
+    BadCard,
+    /// The card was pulled out while it was being flashed
+    CardRemoved,
+    /// The self-test failed, nothing is flashed until the cloner is fixed and restarted
+    ConfigError(selftest::Fault),
+    /// Powering off, after the button was held without a card
+    ShuttingDown,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum LedState {
-    Off,
-    SolidBoth,
-    FlashingGreen,
-    FlashingRed,
-    FlashingGreenRed,
-    SolidGreen,
-    SolidRed,
+impl SystemState {
+    /// States that show how a flash went, until the card is taken out
+    fn shows_result(self) -> bool {
+        matches!(
+            self,
+            SystemState::FlashingSuceeded
+                | SystemState::FlashedUnverified
+                | SystemState::FlashingFailed
+                | SystemState::CardTooSmall
+                | SystemState::DeviceBusy
+                | SystemState::BadCard
+                | SystemState::CardRemoved
+        )
+    }
+
+    /// States that are about a particular card, and end when it is removed
+    fn needs_card(self) -> bool {
+        (self.shows_result() && self != SystemState::CardRemoved)
+            || matches!(
+                self,
+                SystemState::SdCardFound | SystemState::AwaitingConfirmation
+            )
+    }
 }
 
-impl Into<LedState> for SystemState {
-    fn into(self) -> LedState {
-        match self {
-            Self::Initializing => LedState::SolidBoth,
-            Self::NoSdCard => LedState::FlashingRed,
-            Self::SdCardFound => LedState::FlashingGreen,
-            Self::Flashing => LedState::FlashingGreenRed,
-            Self::FlashingSuceeded => LedState::SolidGreen,
-            Self::FlashingFailed => LedState::SolidRed,
-        }
+/// The cards on this machine, found through the configured backend.
+struct SystemCards<'a> {
+    config: &'a Config,
+    quarantine: &'a QuarantineList,
+}
+
+impl Cards for SystemCards<'_> {
+    fn find(&mut self) -> io::Result<Option<PathBuf>> {
+        Ok(devices::candidates(self.config)?.into_iter().next())
+    }
+
+    fn present(&mut self, device: &Path) -> bool {
+        card_present(self.config.device_backend, device)
+    }
+
+    fn needs_confirmation(&mut self, device: &Path) -> bool {
+        let (size, hard_drive) = match self.config.device_backend {
+            DeviceBackend::Sysfs | DeviceBackend::Udisks2 => {
+                (device_size(device), looks_like_hard_drive(device))
+            }
+            DeviceBackend::Simulated => (simulation::card_size(device), false),
+        };
+        size.is_some_and(|size| size > self.config.confirm_larger_than) || hard_drive
+    }
+
+    /// Cards without a CID are never quarantined, see `quarantine`.
+    fn quarantined(&mut self, device: &Path) -> bool {
+        devices::card_cid(device).is_some_and(|cid| {
+            self.quarantine
+                .is_quarantined(&cid)
+                .unwrap_or_else(|error| {
+                    println!("Got error when reading the quarantine list: {error:?}");
+                    false
+                })
+        })
     }
 }
 
-use rppal::gpio::OutputPin;
-use tokio::sync::watch;
+/// Where the state machine goes once flashing `device` ended with `result`.
+fn finished_state(config: &Config, device: &Path, result: &Result<(), FlashError>) -> SystemState {
+    match result {
+        Ok(()) if config.verify_mode == VerifyMode::Skip => {
+            println!("WARNING: flashed {device:?} without verifying it");
+            SystemState::FlashedUnverified
+        }
+        Ok(()) => SystemState::FlashingSuceeded,
+        Err(
+            error @ FlashError::Device(DeviceError::TooSmall { .. } | DeviceError::Full { .. }),
+        ) => {
+            println!("Card is too small: {error}");
+            SystemState::CardTooSmall
+        }
+        Err(error @ FlashError::Device(DeviceError::Busy { .. } | DeviceError::Held { .. })) => {
+            println!("Card is busy: {error}");
+            SystemState::DeviceBusy
+        }
+        Err(error) if error.card_removed() => {
+            println!("{error}");
+            SystemState::CardRemoved
+        }
+        Err(error @ FlashError::Cancelled { .. }) => {
+            println!("{error}");
+            // A card that is still there can be flashed again right away.
+            if card_present(config.device_backend, device) {
+                SystemState::SdCardFound
+            } else {
+                SystemState::NoSdCard
+            }
+        }
+        Err(error) => {
+            println!("Got error when flashing: {error:?}");
+            SystemState::FlashingFailed
+        }
+    }
+}
 
-struct LedDriver {
-    red: OutputPin,
-    yellow: OutputPin,
-    receiver: watch::Receiver<SystemState>,
+/// The next key pressed on the keypad, never if there is none.
+async fn next_key(keys: &mut Option<mpsc::UnboundedReceiver<Key>>) -> Key {
+    if let Some(keys) = keys {
+        if let Some(key) = keys.recv().await {
+            return key;
+        }
+    }
+    std::future::pending().await
 }
 
-impl LedDriver {
-    fn new(red: OutputPin, yellow: OutputPin, receiver: watch::Receiver<SystemState>) -> Self {
-        Self {
-            red,
-            yellow,
-            receiver,
+/// Cancels a running flash when its card is pulled, when the button is held or when Esc is
+/// pressed. Only a hold that started during the flash counts, the one confirming it may still be
+/// going on. Other keys are dropped rather than left for after the flash, where Enter would
+/// dismiss a result nobody saw. Runs until `cancel`, and hands the keypad back then.
+async fn watch_flash(
+    backend: DeviceBackend,
+    device: PathBuf,
+    mut receiver: broadcast::Receiver<Event>,
+    mut keys: Option<mpsc::UnboundedReceiver<Key>>,
+    cancel: CancellationToken,
+) -> Option<mpsc::UnboundedReceiver<Key>> {
+    let mut poll = tokio::time::interval(Duration::from_millis(250));
+    let mut pressed = false;
+    loop {
+        tokio::select! {
+            _ = poll.tick() => {
+                if !card_present(backend, &device) {
+                    println!("Card was removed, cancelling the flash");
+                    break;
+                }
+            }
+            event = receiver.recv() => match event {
+                Ok(Event::ButtonPressed(ButtonEvent::Press)) => pressed = true,
+                Ok(Event::ButtonPressed(ButtonEvent::LongPress)) if pressed => {
+                    println!("Button is held, cancelling the flash");
+                    break;
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return keys,
+            },
+            key = next_key(&mut keys) => {
+                if key == Key::Escape {
+                    println!("Esc was pressed, cancelling the flash");
+                    break;
+                }
+            }
+            _ = cancel.cancelled() => return keys,
         }
     }
+    cancel.cancel();
+    keys
+}
 
-    async fn update_loop(mut self) -> WhateverResult {
-        let LedDriver {
-            ref mut red,
-            ref mut yellow,
-            mut receiver,
-        } = self;
-        let mut flash_state = false;
-        let mut led_state = LedState::SolidBoth;
-        let mut timer = tokio::time::interval(Duration::from_millis(300));
-
-        let set_output = |led: &mut OutputPin, state: bool| {
-            if state {
-                led.set_low();
-            } else {
-                led.set_high();
+/// Waits up to `grace` for the card known as `stable_id` to show up again, after its reader
+/// dropped off the bus. Returns the device it came back as.
+async fn wait_for_reenumeration(
+    config: &Config,
+    stable_id: &str,
+    grace: Duration,
+    shutdown: &CancellationToken,
+) -> Option<PathBuf> {
+    println!("Card was removed, waiting {grace:?} for it to come back");
+    let deadline = Instant::now() + grace;
+    let mut poll = tokio::time::interval(Duration::from_millis(250));
+    while Instant::now() < deadline {
+        tokio::select! {
+            _ = poll.tick() => {}
+            _ = shutdown.cancelled() => return None,
+        }
+        let candidates = match devices::candidates(config) {
+            Ok(candidates) => candidates,
+            Err(error) => {
+                println!("Got error when querying devices: {error:?}");
+                continue;
             }
         };
+        let found = candidates
+            .into_iter()
+            .find(|device| devices::stable_id(device).as_deref() == Some(stable_id));
+        if found.is_some() {
+            return found;
+        }
+    }
+    None
+}
 
-        loop {
-            tokio::select! {
-                _ = receiver.changed() => {
-                    let new_led_state = receiver.borrow_and_update().clone().into();
-                    if new_led_state != led_state {
-                        println!("Got new led state: {new_led_state:?}");
-                        led_state = new_led_state;
-                        flash_state = false;
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    match &args.command {
+        Some(Command::Manifest {
+            image,
+            block_size,
+            image_version,
+        }) => {
+            let path = sync::write_manifest(image, *block_size, image_version.clone())?;
+            println!("Wrote {path:?}");
+            return Ok(());
+        }
+        Some(Command::Backup {
+            device,
+            output,
+            name,
+            incremental,
+            no_shrink,
+            compress,
+            level,
+            no_verify,
+        }) => {
+            let config = Config::load(args.config.as_deref())?;
+            let options = backup::BackupOptions {
+                shrink: !no_shrink,
+                compression_level: compress.then_some(*level),
+                verify: (!no_verify).then_some(config.verify_hash),
+            };
+            if let Some(output) = output {
+                let summary = backup::backup(&config, device, output, options)?;
+                println!(
+                    "Backed up {} of {} bytes of {device:?} to {:?}, SHA-256 {}",
+                    summary.image_size, summary.device_size, summary.output, summary.sha256
+                );
+                return Ok(());
+            }
+            let Some(store_config) = &config.backup_store else {
+                return Err("Give a file to back up to, or configure a backup store".into());
+            };
+            let store = backup::store::Store::new(store_config);
+            let manifest = backup::backup_to_store(
+                &config,
+                device,
+                &store,
+                name.as_deref(),
+                options,
+                *incremental,
+            )?;
+            println!(
+                "Backed up {} bytes of {device:?} as {:?}, SHA-256 {}",
+                manifest.size, manifest.name, manifest.sha256
+            );
+            return Ok(());
+        }
+        Some(Command::Backups { action }) => {
+            let config = Config::load(args.config.as_deref())?;
+            let store = config.backup_store.as_ref().map(backup::store::Store::new);
+            let store = || store.as_ref().ok_or("No backup store is configured");
+            match action {
+                BackupsAction::List => {
+                    for manifest in store()?.list()? {
+                        println!(
+                            "{}\t{}\t{}\t{}\t{} bytes\t{}",
+                            manifest.name,
+                            history::format_time(manifest.created_at),
+                            manifest.source.host,
+                            manifest.source.card(),
+                            manifest.size,
+                            manifest.sha256
+                        );
                     }
                 }
-                _ = timer.tick() => {
-                    flash_state = !flash_state;
+                BackupsAction::Restore {
+                    name,
+                    output,
+                    image,
+                } => {
+                    backup::restore(&config, store()?, name, output, image.as_deref())?;
+                    println!("Restored {name:?} to {output:?}");
                 }
-            }
-            match (led_state, flash_state) {
-                (LedState::Off, _) => {
-                    set_output(red, false);
-                    set_output(yellow, false);
-                }
-                (LedState::SolidBoth, _) => {
-                    set_output(red, true);
-                    set_output(yellow, true);
+                BackupsAction::Remove { name } => store()?.remove(name)?,
+                BackupsAction::Gc => {
+                    let garbage = store()?.collect_garbage()?;
+                    println!(
+                        "Deleted {} chunks, freeing {} bytes",
+                        garbage.chunks, garbage.bytes
+                    );
                 }
-                (LedState::SolidRed, _) => {
-                    set_output(red, true);
-                    set_output(yellow, false);
+                BackupsAction::Prune => {
+                    let pruned = backup::retention::prune(store()?)?;
+                    println!("Pruned {} backups", pruned.len());
                 }
-                (LedState::SolidGreen, _) => {
-                    set_output(red, false);
-                    set_output(yellow, true);
+                BackupsAction::Find { query } => {
+                    for record in History::new(&config.history).backups()? {
+                        if record.source.matches(query) || record.backup.contains(query) {
+                            println!(
+                                "{}\t{}\t{}\t{}\t{} bytes\t{}",
+                                record.backup,
+                                history::format_time(record.created_at),
+                                record.source.host,
+                                record.source.card(),
+                                record.size,
+                                record.sha256
+                            );
+                        }
+                    }
                 }
-                (LedState::FlashingGreenRed, flash_state) => {
-                    set_output(red, flash_state);
-                    set_output(yellow, !flash_state);
+            }
+            return Ok(());
+        }
+        Some(Command::Bmap { images }) if !images.is_empty() => {
+            for image in images {
+                println!("Wrote {:?}", bmap::write_bmap(image)?);
+            }
+            return Ok(());
+        }
+        Some(Command::Bmap { .. }) => {
+            let config = Config::load(args.config.as_deref())?;
+            for path in bmap::write_catalog_bmaps(&config.catalog)? {
+                println!("Wrote {path:?}");
+            }
+            return Ok(());
+        }
+        Some(Command::Quarantine { action }) => {
+            let config = Config::load(args.config.as_deref())?;
+            let quarantine =
+                QuarantineList::new(&config.quarantine, config.quarantine_after_failures);
+            match action {
+                QuarantineAction::List => {
+                    for (cid, entry) in quarantine.load()?.cards {
+                        let status = if entry.quarantined_at.is_some() {
+                            "quarantined"
+                        } else {
+                            "ok"
+                        };
+                        println!("{cid}: {} failures, {status}", entry.failures);
+                    }
                 }
-                (LedState::FlashingGreen, flash_state) => {
-                    set_output(yellow, flash_state);
-                    set_output(red, false);
+                QuarantineAction::Clear { cid } => {
+                    if quarantine.clear(cid)? {
+                        println!("Cleared {cid}");
+                    } else {
+                        println!("{cid} wasn't in the quarantine list");
+                    }
                 }
-                (LedState::FlashingRed, flash_state) => {
-                    set_output(red, flash_state);
-                    set_output(yellow, false);
+            }
+            return Ok(());
+        }
+        Some(Command::Export {
+            since,
+            until,
+            image,
+            output,
+        }) => {
+            let config = Config::load(args.config.as_deref())?;
+            let filter = history::Filter {
+                since: *since,
+                until: until.map(|until| until + 24 * 60 * 60),
+                image: image.clone(),
+            };
+            let records = History::new(&config.history).records()?;
+            let matching = records.iter().filter(|record| filter.matches(record));
+            match output {
+                Some(path) => {
+                    let mut file = BufWriter::new(File::create(path)?);
+                    history::write_csv(matching, &mut file)?;
+                    file.flush()?;
                 }
+                None => history::write_csv(matching, &mut io::stdout().lock())?,
             }
+            return Ok(());
         }
+        Some(Command::Mux { side }) => {
+            let config = Config::load(args.config.as_deref())?;
+            let Some(sd_mux) = &config.sd_mux else {
+                return Err("No SD mux is configured".into());
+            };
+            sd_mux::switch(sd_mux, *side)?;
+            return Ok(());
+        }
+        Some(Command::Check) => {
+            if !preflight::run(args.config.as_deref(), args.image.as_deref()) {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Coordinator) => {
+            let config = Config::load(args.config.as_deref())?;
+            tokio::task::spawn_blocking(move || {
+                coordinator::run(&config.coordinator).map_err(|error| error.to_string())
+            })
+            .await??;
+            return Ok(());
+        }
+        None => {}
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let source_path = "disk_image.img";
-    let source_file = File::open(&source_path)?;
+    let mut config = Config::load(args.config.as_deref())?;
+    if let Some(image) = &args.image {
+        config.image = image.clone();
+    }
+    // First, so the copies have everything the cloner prints.
+    let _logging = logging::start(&config)?;
+    logging::set_level(config.log_level);
+    // Before anything is timestamped, and while we may still set the clock.
+    if let Some(rtc) = &config.rtc {
+        if let Err(error) = rtc::sync(rtc) {
+            println!("Got error when reading the RTC: {error:?}");
+        }
+    }
+    let _instance_lock = lock::InstanceLock::acquire(&config.lock_file)?;
+    if config.verify_mode == VerifyMode::Skip {
+        println!("WARNING: verification is disabled, cards are not read back after flashing");
+    }
 
-    let red = Gpio::new()?.get(LED_RED)?.into_output();
-    let yellow = Gpio::new()?.get(LED_YELLOW)?.into_output();
+    if let Some(sync_config) = &config.sync {
+        // The first sync has to finish before we can check the image exists.
+        let (first_config, image, catalog) = (
+            sync_config.clone(),
+            config.image.clone(),
+            config.catalog.clone(),
+        );
+        let first_sync =
+            tokio::task::spawn_blocking(move || sync::sync_once(&first_config, &image, &catalog))
+                .await?;
+        if let Err(error) = first_sync {
+            println!("Got error when syncing image: {error:?}");
+        }
+    }
 
-    let (state_sender, system_state) = watch::channel(SystemState::Initializing);
-    let driver = LedDriver::new(red, yellow, system_state.clone());
-    let _led_jh = tokio::spawn(async move { driver.update_loop().await });
+    // Fail early rather than on the first card.
+    if let Some(share) = &config.share {
+        share::Share::new(share).ensure_healthy()?;
+    }
+    if let Some(peer_server) = &config.peer_server {
+        peer::serve(peer_server, config.image.clone())?;
+    }
+    // A card left with the device under test by the last run isn't seen otherwise.
+    if let Some(sd_mux) = &config.sd_mux {
+        if let Err(error) = sd_mux::switch(sd_mux, sd_mux::Side::Host) {
+            println!("Got error when switching the SD mux to the cloner: {error:?}");
+        }
+    }
+    let history = History::new(&config.history);
+    let quarantine = QuarantineList::new(&config.quarantine, config.quarantine_after_failures);
 
-    let source_bytes = {
-        let mut reader = BufReader::new(source_file);
-        reader.seek(SeekFrom::End(0))? as usize
+    let Hardware {
+        mut red,
+        mut yellow,
+        mut button,
+    } = Hardware::new(&config.hardware)?;
+    let events = EventBus::new();
+    // Subscribed before anything is published, so no input is missed.
+    let mut receiver = events.subscribe();
+    tokio::spawn(events::log(events.subscribe()));
+    display::start(&config.display, &events)?;
+    if config.device_backend == DeviceBackend::Simulated {
+        simulation::start(&config.simulation, &events)?;
+    }
+    let notifiers = notify::Notifiers::from_config(&config.notify)?.listen(events.subscribe());
+    let nfc = match &config.nfc {
+        Some(nfc_config) => Some(Arc::new(Mutex::new(nfc::Pn532::new(nfc_config)?))),
+        None => None,
     };
+    if let Some(privileges) = &config.privileges {
+        privileges::drop_privileges(privileges)?;
+    }
+    // Started here, so it keeps what capabilities dropping privileges left this thread.
+    let flasher = flash::Flasher::start()?;
+
+    let liveness = monitoring::Liveness::default();
+    tokio::spawn(monitoring::track(liveness.clone(), events.clone()));
 
-    let button_gpio = Gpio::new()?.get(BUTTON_GPIO)?.into_input_pullup();
+    // Before the LEDs and the button are handed out, the self-test uses them.
+    let self_test = tokio::task::block_in_place(|| {
+        selftest::run(&config, red.as_mut(), yellow.as_mut(), button.as_mut())
+    });
+    let driver = LedDriver::new(red, yellow, config.hardware.leds.clone(), &events);
+    let _led_jh = tokio::spawn(async move { driver.update_loop().await });
 
-    let (sender, mut button_receiver) = watch::channel(());
-    button_receiver.mark_unchanged();
+    // The state machine waits for the flash, pausing it is handled right where the press lands.
+    let pause = PauseControl::default();
+    let (button_pause, button_events, button_beat) =
+        (pause.clone(), events.clone(), liveness.button.clone());
     let _button_jh = tokio::spawn(async move {
-        let mut last_state = button_gpio.is_low();
+        let mut last_state = button.is_pressed();
+        let mut pressed_since = None;
+        let mut long_pressed = false;
         loop {
             tokio::time::sleep(Duration::from_millis(25)).await;
             // Button is pressed.
-            let current_state = button_gpio.is_low();
+            let current_state = button.is_pressed();
+            button_beat.beat();
 
             if [last_state, current_state] == [false, true] {
-                println!("Button is pressed");
-                sender.send_replace(());
+                button_pause.toggle(&button_events);
+                button_events.publish(Event::ButtonPressed(ButtonEvent::Press));
+                pressed_since = Some(Instant::now());
+                long_pressed = false;
+            }
+            if !current_state {
+                pressed_since = None;
+            } else if let Some(since) = pressed_since {
+                if since.elapsed() >= LONG_PRESS && !long_pressed {
+                    button_events.publish(Event::ButtonPressed(ButtonEvent::LongPress));
+                    long_pressed = true;
+                }
+                if since.elapsed() >= SHUTDOWN_HOLD {
+                    button_events.publish(Event::ButtonPressed(ButtonEvent::ShutdownHold));
+                    pressed_since = None;
+                }
             }
             last_state = current_state;
         }
     });
 
-    let mut device_path = None;
+    // For scripts, `kill -USR1` does the same as a press during a flash.
+    let (signal_pause, signal_events) = (pause.clone(), events.clone());
+    let mut pause_signal = signal(SignalKind::user_defined1())?;
+    let _signal_jh = tokio::spawn(async move {
+        while pause_signal.recv().await.is_some() {
+            signal_pause.toggle(&signal_events);
+        }
+    });
+
+    if let Some(power_fail) = &config.power_fail {
+        power::watch(
+            power_fail,
+            config.shutdown_command.clone(),
+            pause.clone(),
+            &events,
+        )?;
+    }
+
+    // `kill -USR2` switches between the info and debug levels, without losing the state.
+    let mut level_signal = signal(SignalKind::user_defined2())?;
+    let _level_jh = tokio::spawn(async move {
+        while level_signal.recv().await.is_some() {
+            logging::set_level(match logging::level() {
+                LogLevel::Info => LogLevel::Debug,
+                LogLevel::Debug => LogLevel::Info,
+            });
+        }
+    });
+
+    // Stops the state machine between steps, and a running flash at its next chunk.
+    let shutdown = CancellationToken::new();
+    let (mut terminate, mut interrupt) = (
+        signal(SignalKind::terminate())?,
+        signal(SignalKind::interrupt())?,
+    );
+    let signal_shutdown = shutdown.clone();
+    let _shutdown_jh = tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+        println!("Shutting down");
+        signal_shutdown.cancel();
+    });
+
+    // Without it we can't tell when a card arrives, and keep polling.
+    let hotplug = match hotplug::listen(&events) {
+        Ok(()) => true,
+        Err(error) => {
+            println!("Got error when listening for hotplug events: {error:?}");
+            false
+        }
+    };
+    liveness.hotplug_listening.store(hotplug, Ordering::Relaxed);
+    // Scanned work orders and those from a coordinator alike.
+    let (order_sender, mut work_orders) = mpsc::unbounded_channel();
+    if let Some(barcode) = &config.barcode {
+        input::lines(&barcode.device, order_sender.clone())?;
+    }
+    let unit_status = Arc::new(Mutex::new(UnitStatus::default()));
+    tokio::spawn(agent::track(unit_status.clone(), events.clone()));
+    if let Some(agent) = &config.agent {
+        agent::serve(
+            agent,
+            unit_status.clone(),
+            History::new(&config.history),
+            monitoring::Health::new(&config, liveness.clone(), events.clone()),
+            config.profiles.clone(),
+            order_sender,
+        )?;
+    }
+    let mut keypad = match &config.keypad {
+        Some(keypad) => Some(input::keys(&keypad.device)?),
+        None => None,
+    };
+    let (reload_sender, mut reloads) = mpsc::unbounded_channel();
+    reload::watch(args.config.clone(), reload_sender)?;
+    let mut typed_slot: Option<usize> = None;
+    let mut default_image = config.image.clone();
+    // Whichever image comes next is hashed ahead, before the first card of it arrives.
+    let mut prehashed: Option<PathBuf> = None;
+    let mut batch: Option<Batch> = None;
+    // Allocated once, every flash goes through the same buffers.
+    let buffers = BufferPool::from_config(&config.buffers);
+    if let Some(update) = config.update.clone() {
+        tokio::spawn(update::run(update, events.clone()));
+    }
+    // Started once the display and the agent listen, it first publishes what the first sync found.
+    if let Some(sync_config) = config.sync.clone() {
+        tokio::spawn(sync::run(
+            sync_config,
+            config.image.clone(),
+            config.catalog.clone(),
+            events.clone(),
+        ));
+    }
+    let mut machine = Machine::new(&events, config.timeouts.clone(), hotplug, Instant::now());
+    if let Err(fault) = self_test {
+        events.set_state(SystemState::ConfigError(fault));
+    }
 
     loop {
-        tokio::time::sleep(Duration::from_millis(50)).await;
-        let current_state: SystemState = system_state.borrow().clone();
-        //Get all devices that are at least 128 GB
-        match current_state {
-            SystemState::NoSdCard => {
-                let devices = get_block_devices_with_size(128 * 1000 * 1000 * 1000);
-                let Ok(devices) = devices else {
+        tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+        liveness.machine.beat();
+        machine.drain(&mut receiver);
+        {
+            let mut status = unit_status.lock().unwrap();
+            status.image = config.image.clone();
+            status.batch = batch.clone();
+        }
+        // Only picked up between cards, a running flash keeps its image.
+        while let Ok(code) = work_orders.try_recv() {
+            match Batch::from_work_order(&code, &config.profiles) {
+                Ok(new_batch) => {
                     println!(
-                        "Got error when querying devices: {:?}",
-                        devices.unwrap_err()
+                        "Starting batch of {} cards with profile {}",
+                        new_batch.remaining, new_batch.profile
                     );
-                    continue;
-                };
-
-                device_path = devices.get(0).cloned();
-                device_path = device_path
-                    .and_then(|path| path.to_str().map(|inner| inner.to_string()))
-                    .map(|path_string| PathBuf::from(path_string.replace("/sys/block/", "/dev/")));
-
-                if device_path.is_none() {
-                    state_sender.send_replace(SystemState::NoSdCard);
-                } else {
-                    println!("Have device! {device_path:?}");
-                    state_sender.send_replace(SystemState::SdCardFound);
-                    button_receiver.mark_unchanged();
+                    config.image = new_batch.image.clone();
+                    batch = Some(new_batch);
                 }
+                Err(error) => println!("Ignoring work order: {error}"),
             }
-            SystemState::SdCardFound => {
-                let Some(ref device_path) = device_path else {
-                    state_sender.send_replace(SystemState::NoSdCard);
-                    continue;
-                };
-                if !block_device_valid(device_path.to_string_lossy().to_string()) {
-                    state_sender.send_replace(SystemState::NoSdCard);
-                }
-
-                if button_receiver.has_changed()? {
-                    button_receiver.mark_unchanged();
-                    state_sender.send_replace(SystemState::Flashing);
-                }
+        }
+        // Like work orders, a reloaded config waits for the card being flashed.
+        while let Ok(reloaded) = reloads.try_recv() {
+            config.reload(reloaded);
+            logging::set_level(config.log_level);
+            if let Some(image) = &args.image {
+                config.image = image.clone();
             }
-            SystemState::Flashing => {
-                let Some(ref device_path) = device_path else {
-                    state_sender.send_replace(SystemState::FlashingFailed);
-                    continue;
-                };
-                println!("Have device! {device_path:?}. Flashing");
-                let destination_file = File::options()
-                    .write(true)
-                    .truncate(true)
-                    .read(true)
-                    .open(device_path);
-
-                match destination_file {
-                    Ok(destination_file) => {
-                        let source_file = File::open(source_path)?;
-                        let mut reader = BufReader::new(source_file.try_clone()?);
-                        let mut writer = BufWriter::new(destination_file.try_clone()?);
-
-                        const BUFFER_SIZE: usize = 128 * 1024 * 1024;
-
-                        // Copy in chunks of 64M
-                        let mut copy_buffer: Box<[u8]> = vec![0; BUFFER_SIZE].into_boxed_slice();
-
-                        let mut hasher = DefaultHasher::new();
-                        let copy_func = || {
-                            let mut hashes = vec![];
-                            let mut read_bytes = 0;
-                            loop {
-                                let read = reader.read(copy_buffer.as_mut())?;
-                                if read_bytes == source_bytes {
-                                    break;
-                                }
-                                read_bytes += read;
-                                println!("Read {read_bytes}/{source_bytes}");
-                                let copied_buffer = &copy_buffer[..read];
-                                let hash = copied_buffer.hash(&mut hasher);
-                                hashes.push(hash);
-                                writer.write_all(copied_buffer)?;
-                                writer.flush()?;
-                            }
-                            println!("Written bytes, reading back to verify. Bytes written = {read_bytes}");
-                            let mut hashes = hashes.into_iter();
-                            let mut reader = BufReader::new(writer.into_inner()?);
-                            let mut bytes_remaining = read_bytes;
-                            loop {
-                                let bytes_to_read = BUFFER_SIZE.min(bytes_remaining);
-                                if bytes_to_read == 0 {
-                                    break;
-                                }
-                                let read =
-                                    reader.read(&mut copy_buffer.as_mut()[..bytes_to_read])?;
-                                if read == 0 {
-                                    println!("Somehow read 0 bytes, with bytes remaining");
-                                }
-                                bytes_remaining = bytes_remaining.checked_sub(read).ok_or(
-                                    std::io::Error::new(
-                                        ErrorKind::Other,
-                                        "Somehow read more bytes than we could",
-                                    ),
-                                )?;
-                                let copied_buffer = &copy_buffer[..read];
-                                let hash = copied_buffer.hash(&mut hasher);
-                                if hash
-                                    != hashes.next().ok_or(std::io::Error::new(
-                                        ErrorKind::Other,
-                                        "Read more bytes than wrote",
-                                    ))?
-                                {
-                                    return Err(std::io::Error::new(
-                                        ErrorKind::Other,
-                                        "Hashes don't match",
-                                    ));
-                                }
-                            }
-                            println!("All hashes checked, and matched");
-                            Ok(())
-                        };
-
-                        let clone_result: std::io::Result<()> = copy_func();
-
-                        match clone_result {
-                            Ok(()) => {
-                                state_sender.send_replace(SystemState::FlashingSuceeded);
-                            }
-                            Err(error) => {
-                                println!("Got error when copying files: {error:?}");
-                                state_sender.send_replace(SystemState::FlashingFailed);
-                            }
+            default_image = config.image.clone();
+            // A batch keeps the image of its profile.
+            if let Some(batch) = &batch {
+                config.image = batch.image.clone();
+            }
+            if let Err(error) = notifiers.reconfigure(&config.notify) {
+                println!("Got error when setting up the reloaded notifiers: {error:?}");
+            }
+            println!("Reloaded the config, flashing {:?}", config.image);
+        }
+        // Enter stands in for the button, after selecting the profile typed before it.
+        if let Some(keys) = &mut keypad {
+            while let Ok(key) = keys.try_recv() {
+                match key {
+                    Key::Digit(digit) => {
+                        let slot = typed_slot.unwrap_or(0);
+                        typed_slot = Some(slot.saturating_mul(10).saturating_add(digit.into()));
+                    }
+                    Key::Enter => {
+                        if let Some(slot) = typed_slot.take() {
+                            let Some(profile) = slot
+                                .checked_sub(1)
+                                .and_then(|index| config.profiles.get(index))
+                            else {
+                                println!("No profile in slot {slot}");
+                                continue;
+                            };
+                            println!("Selected profile {slot}: {}", profile.name);
+                            config.image = profile.image.clone();
+                            batch = None;
                         }
+                        machine.press();
                     }
-                    Err(file_opening_error) => {
-                        println!("Got error when opening file: {file_opening_error:?}");
-                        state_sender.send_replace(SystemState::FlashingFailed);
+                    Key::Escape => {
+                        typed_slot = None;
+                        // A pending confirmation is backed out of first, the batch after it.
+                        if machine.cancel_confirmation() {
+                            continue;
+                        }
+                        if let Some(cancelled) = batch.take() {
+                            println!("Cancelled batch for profile {}", cancelled.profile);
+                            config.image = default_image.clone();
+                        }
                     }
                 }
-                button_receiver.mark_unchanged();
             }
-            SystemState::FlashingFailed | SystemState::FlashingSuceeded => {
-                if device_path.as_ref().is_none_or(|device_path| {
-                    !block_device_valid(device_path.to_string_lossy().to_string())
-                }) {
-                    state_sender.send_replace(SystemState::NoSdCard);
+        }
+
+        if prehashed.as_ref() != Some(&config.image) {
+            image::digest::prehash(&config);
+            prehashed = Some(config.image.clone());
+        }
+        let mut cards = SystemCards {
+            config: &config,
+            quarantine: &quarantine,
+        };
+        let device_path = match machine.step(Instant::now(), &mut cards, batch.is_some()) {
+            Action::Continue => continue,
+            Action::Restart => return Ok(()),
+            Action::Shutdown => {
+                // Stopped by systemd as part of the shutdown, until then the LEDs show it.
+                if let Err(error) = power::power_off(&config.shutdown_command) {
+                    println!("Got error when powering off: {error:?}");
+                    events.set_state(SystemState::NoSdCard);
                 }
-                if button_receiver.has_changed()? {
-                    button_receiver.mark_unchanged();
-                    state_sender.send_replace(SystemState::NoSdCard);
+                continue;
+            }
+            Action::Sleep => {
+                // Only asked for while hotplug events announce cards.
+                loop {
+                    let event = tokio::select! {
+                        event = receiver.recv() => event,
+                        _ = shutdown.cancelled() => break,
+                    };
+                    match event {
+                        Ok(event) if machine.note(&event) => break,
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
                 }
+                continue;
+            }
+            Action::Flash(device_path) => device_path,
+        };
+        let target_port = config
+            .usb_power
+            .as_ref()
+            .and_then(|usb_power| Some((usb_power, usb_power.target_port?)));
+        if let Some((usb_power, port)) = target_port {
+            if let Err(error) = usb_power::switch(usb_power, port, usb_power::Action::Off) {
+                println!("Got error when powering off the device under test: {error:?}");
             }
-            SystemState::Initializing => {
-                state_sender.send_replace(SystemState::NoSdCard);
+        }
+        // Read up-front, once the reader dropped off the bus there is nothing left to read.
+        let stable_id = devices::stable_id(&device_path);
+        let mut device_path = device_path;
+        let mut retries = 0;
+        let (record, flash_result) = loop {
+            println!("Have device! {device_path:?}. Flashing");
+            let record = FlashRecord::start(&config.image, &device_path, config.verify_mode);
+            pause.reset();
+            events.publish(Event::FlashStarted(record.clone()));
+            let cancel = shutdown.child_token();
+            let watcher = tokio::spawn(watch_flash(
+                config.device_backend,
+                device_path.clone(),
+                events.subscribe(),
+                keypad.take(),
+                cancel.clone(),
+            ));
+            let (mut record, flash_result) = flash::spawn(
+                &flasher,
+                config.clone(),
+                device_path.clone(),
+                buffers.clone(),
+                record,
+                pause.clone(),
+                events.clone(),
+                cancel.clone(),
+            )
+            .await;
+            // The flash is over, cancelling only stops the watcher.
+            cancel.cancel();
+            keypad = watcher.await.unwrap_or_else(|error| {
+                println!("Flash watcher failed: {error:?}");
+                None
+            });
+            record.finish(&flash_result);
+            let grace = config.timeouts.reenumeration();
+            let reenumerated = match (&flash_result, &stable_id, grace) {
+                (Err(error), Some(stable_id), Some(grace))
+                    if error.card_removed() && retries < REENUMERATION_RETRIES =>
+                {
+                    wait_for_reenumeration(&config, stable_id, grace, &shutdown).await
+                }
+                _ => None,
+            };
+            let Some(reenumerated) = reenumerated else {
+                break (record, flash_result);
+            };
+            println!("Card came back as {reenumerated:?}, flashing it again");
+            // The attempt that was cut short is kept, the next one starts from scratch.
+            if let Err(error) = history.append(&record) {
+                println!("Got error when writing flash history: {error:?}");
             }
+            retries += 1;
+            device_path = reenumerated;
+            machine.reenumerated(device_path.clone());
         };
+        let device_path = &device_path;
+        events.publish(Event::FlashFinished(record.clone()));
+        // Presses during the flash paused it, they don't dismiss the result.
+        machine.drain(&mut receiver);
+        machine.flash_finished(finished_state(&config, device_path, &flash_result));
+        if let Err(error) = history.append(&record) {
+            println!("Got error when writing flash history: {error:?}");
+        }
+        if let Some(reports) = &config.reports {
+            match report::write(reports, &record) {
+                Ok(path) => println!("Wrote report {path:?}"),
+                Err(error) => println!("Got error when writing the flash report: {error:?}"),
+            }
+        }
+        if let (Some(sd_mux), FlashResult::Succeeded) = (&config.sd_mux, record.result) {
+            if let Err(error) = sd_mux::switch(sd_mux, sd_mux::Side::Dut) {
+                println!("Got error when handing the card over to the device: {error:?}");
+            }
+        }
+        if let (Some((usb_power, port)), FlashResult::Succeeded) = (target_port, record.result) {
+            if let Err(error) = usb_power::switch(usb_power, port, usb_power::Action::On) {
+                println!("Got error when powering on the device under test: {error:?}");
+            }
+        }
+        let reader_port = config
+            .usb_power
+            .as_ref()
+            .and_then(|usb_power| Some((usb_power.clone(), usb_power.reader_port?)));
+        if let (Some((usb_power, port)), Err(error)) = (reader_port, &flash_result) {
+            if usb_power::reader_may_hang(error) {
+                // Waiting for the port to come back mustn't hold up the LEDs and the button.
+                thread::spawn(move || {
+                    if let Err(error) =
+                        usb_power::switch(&usb_power, port, usb_power::Action::Cycle)
+                    {
+                        println!("Got error when power-cycling the card reader: {error:?}");
+                    }
+                });
+            }
+        }
+        if let (Some(label), FlashResult::Succeeded) = (&config.label, record.result) {
+            if let Err(error) = label::print(label, &record) {
+                println!("Got error when printing label: {error:?}");
+            }
+        }
+        if let (Some(nfc), Some(nfc_config), FlashResult::Succeeded) =
+            (&nfc, &config.nfc, record.result)
+        {
+            // Waiting for the tap mustn't hold up the next card.
+            let (nfc, message, timeout) = (
+                nfc.clone(),
+                nfc::record_message(&record),
+                nfc_config.tap_timeout(),
+            );
+            thread::spawn(move || {
+                println!("Tap an NFC sticker to tag the card");
+                match nfc.lock().unwrap().write_ndef(&message, timeout) {
+                    Ok(()) => println!("Wrote NFC sticker"),
+                    Err(error) => println!("Got error when writing NFC sticker: {error:?}"),
+                }
+            });
+        }
+        if let (Some(current), FlashResult::Succeeded) = (&mut batch, record.result) {
+            current.remaining -= 1;
+            if current.remaining == 0 {
+                println!("Batch for profile {} is done", current.profile);
+                config.image = default_image.clone();
+                batch = None;
+            } else {
+                println!("{} cards left in the batch", current.remaining);
+            }
+        }
+        if let Some(cid) = &record.cid {
+            let updated = match record.result {
+                FlashResult::Succeeded => quarantine.record_success(cid),
+                FlashResult::Failed if record.verify_failed => {
+                    quarantine.record_failure(cid).map(|quarantined| {
+                        if quarantined {
+                            println!("Card {cid} failed verification too often, quarantining it");
+                        }
+                    })
+                }
+                FlashResult::Failed | FlashResult::Removed => Ok(()),
+            };
+            if let Err(error) = updated {
+                println!("Got error when updating the quarantine list: {error:?}");
+            }
+        }
     }
 }
 
-fn block_device_valid(path: String) -> bool {
-    let mut path = path.replace("/dev/", "/sys/block/");
-    path.push_str("/size");
-    std::fs::read_to_string(path)
-        .ok()
-        .and_then(|string| string.trim().parse::<u64>().ok())
-        .is_some_and(|sectors| sectors > 0)
-}
-
 /*
 fn main() -> Result<(), Box<dyn Error>> {
     let input = File::open("disk.img")?;
@@ -371,36 +1164,3 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
 }
 */
-use std::fs;
-use std::hash::{DefaultHasher, Hash};
-use std::path::{Path, PathBuf};
-
-fn get_block_devices_with_size(min_size_bytes: u64) -> io::Result<Vec<PathBuf>> {
-    let block_path = Path::new("/sys/block");
-
-    Ok(fs::read_dir(block_path)?
-        .filter_map(|entry| entry.ok())
-        .filter_map(|entry| {
-            let path = entry.path().join("size");
-            if path.exists() {
-                let size = fs::read_to_string(&path).ok()?.trim().to_string();
-                match size.parse::<u64>() {
-                    Ok(size_blocks) => Some((entry, size_blocks * 512)),
-                    Err(error) => {
-                        println!("Got error when parsing path: {entry:?}. Error={error:?}");
-                        None
-                    }
-                }
-            } else {
-                None
-            }
-        })
-        .filter_map(|(entry, size)| {
-            if size < min_size_bytes {
-                None
-            } else {
-                Some(entry.path())
-            }
-        })
-        .collect())
-}
@@ -0,0 +1,273 @@
+//! What external monitoring sees of the cloner at the agent's `/healthz`: a check per subsystem,
+//! so a cloner waiting for cards can be told apart from one that is wedged.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use nix::sys::statvfs::statvfs;
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::Config;
+use crate::events::{Event, EventBus};
+use crate::history;
+use crate::image;
+use crate::rtc;
+use crate::SystemState;
+
+/// The button is read every 25 ms, going this long without means its task is stuck.
+const BUTTON_STALL: Duration = Duration::from_secs(2);
+/// The state machine steps every 50 ms unless it waits on purpose, see [`waits_on_purpose`].
+const MACHINE_STALL: Duration = Duration::from_secs(10);
+/// Less free space than this where the logs go fails the check, before writes start failing
+const MIN_FREE_SPACE: u64 = 100 * 1024 * 1024;
+
+/// When something last showed it is alive.
+#[derive(Debug, Clone, Default)]
+pub struct Heartbeat(Arc<Mutex<Option<Instant>>>);
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.0.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// `None` until the first beat.
+    pub fn age(&self) -> Option<Duration> {
+        self.0.lock().unwrap().map(|beat| beat.elapsed())
+    }
+}
+
+/// Kept up to date by the parts of the cloner the checks watch.
+#[derive(Debug, Clone, Default)]
+pub struct Liveness {
+    /// Beats whenever the button is read
+    pub button: Heartbeat,
+    /// Beats whenever the state machine steps
+    pub machine: Heartbeat,
+    /// Beats whenever a hotplug event announces a block device
+    pub hotplug: Heartbeat,
+    pub hotplug_listening: Arc<AtomicBool>,
+}
+
+/// Keeps the hotplug part of `liveness` up to date.
+pub async fn track(liveness: Liveness, events: EventBus) {
+    let mut receiver = events.subscribe();
+    loop {
+        match receiver.recv().await {
+            Ok(Event::DeviceAdded) => liveness.hotplug.beat(),
+            Ok(Event::HotplugStopped) => liveness.hotplug_listening.store(false, Ordering::Relaxed),
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    pub detail: String,
+    /// Seconds since the subsystem last showed it is alive, for those that do
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_secs: Option<f64>,
+}
+
+impl Check {
+    fn new(ok: bool, detail: impl Into<String>) -> Self {
+        Self {
+            ok,
+            detail: detail.into(),
+            age_secs: None,
+        }
+    }
+
+    fn aged(mut self, age: Option<Duration>) -> Self {
+        self.age_secs = age.map(|age| age.as_secs_f64());
+        self
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    /// Whether every check is ok
+    pub healthy: bool,
+    pub state: String,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+/// States the state machine stays in without stepping: waiting for a hotplug event, or for the
+/// flash, which takes as long as it takes.
+fn waits_on_purpose(state: SystemState) -> bool {
+    matches!(
+        state,
+        SystemState::Idle | SystemState::Flashing | SystemState::Paused
+    )
+}
+
+/// The closest existing directory `path` is in, or is, for files and directories yet to be
+/// created.
+fn existing_directory(path: &Path) -> Option<&Path> {
+    let mut directory = match path.parent() {
+        Some(parent) if !path.is_dir() => parent,
+        _ => path,
+    };
+    while !directory.as_os_str().is_empty() && !directory.is_dir() {
+        directory = directory.parent()?;
+    }
+    match directory.as_os_str().is_empty() {
+        true => Some(Path::new(".")),
+        false => Some(directory),
+    }
+}
+
+/// Whether `image` can be opened, with the rest of `config` telling where it comes from.
+pub fn check_image(config: &Config, image: &Path) -> Check {
+    let mut config = config.clone();
+    config.image = image.to_path_buf();
+    let source = match image::source::from_config(&config) {
+        Ok(source) => source,
+        Err(error) => return Check::new(false, format!("{image:?} is invalid: {error}")),
+    };
+    // Remote images are only fetched when flashing, there is nothing to look at before.
+    let Some(path) = source.local_path() else {
+        return Check::new(true, format!("{image:?} isn't a local file"));
+    };
+    match File::open(path).and_then(|file| file.metadata()) {
+        Ok(metadata) => Check::new(true, format!("{path:?} has {} bytes", metadata.len())),
+        Err(error) => Check::new(false, format!("{path:?} can't be read: {error}")),
+    }
+}
+
+/// Whether there is room left for the history, the reports and the log file.
+pub fn check_log_space(config: &Config) -> Check {
+    let mut paths: Vec<&PathBuf> = vec![&config.history];
+    paths.extend(&config.reports);
+    paths.extend(config.log_file.iter().map(|log_file| &log_file.path));
+    let mut details = vec![];
+    let mut ok = true;
+    for path in paths {
+        let free = existing_directory(path)
+            .ok_or(nix::errno::Errno::ENOENT)
+            .and_then(statvfs)
+            // The counts are 32 bits on 32-bit Pis.
+            .map(|stat| {
+                #[allow(clippy::unnecessary_cast)]
+                let free = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+                free
+            });
+        match free {
+            Ok(free) => {
+                ok &= free >= MIN_FREE_SPACE;
+                details.push(format!("{free} bytes free for {path:?}"));
+            }
+            Err(error) => {
+                ok = false;
+                details.push(format!("{path:?} can't be checked: {error}"));
+            }
+        }
+    }
+    Check::new(ok, details.join(", "))
+}
+
+pub struct Health {
+    config: Config,
+    liveness: Liveness,
+    events: EventBus,
+}
+
+impl Health {
+    pub fn new(config: &Config, liveness: Liveness, events: EventBus) -> Self {
+        Self {
+            config: config.clone(),
+            liveness,
+            events,
+        }
+    }
+
+    /// Runs every check, with `image` as the image being flashed now.
+    pub fn check(&self, image: &Path) -> HealthReport {
+        let state = self.events.state();
+        let checks = BTreeMap::from([
+            ("clock", self.check_clock()),
+            ("hardware", self.check_hardware()),
+            ("image", check_image(&self.config, image)),
+            ("log_space", check_log_space(&self.config)),
+            ("hotplug", self.check_hotplug()),
+            ("state_machine", self.check_machine(state)),
+        ]);
+        HealthReport {
+            healthy: checks.values().all(|check| check.ok),
+            state: format!("{state:?}"),
+            checks,
+        }
+    }
+
+    /// Records are only dated right with a clock that was set, by NTP or from the RTC.
+    fn check_clock(&self) -> Check {
+        let now = history::unix_time();
+        let time = history::format_time_with_seconds(now);
+        if !rtc::clock_sane(now) {
+            return Check::new(false, format!("Clock reads {time}, it was never set"));
+        }
+        if rtc::clock_synchronized() {
+            return Check::new(true, format!("Clock is synchronized, {time}"));
+        }
+        let detail = match self.config.rtc {
+            Some(_) => format!("Clock isn't synchronized, {time} is from the RTC"),
+            None => format!("Clock isn't synchronized, {time} may be off"),
+        };
+        Check::new(true, detail)
+    }
+
+    fn check_hardware(&self) -> Check {
+        let age = self.liveness.button.age();
+        let check = match age {
+            Some(age) if age < BUTTON_STALL => Check::new(true, "Button is being read"),
+            Some(_) => Check::new(false, "Button stopped being read"),
+            None => Check::new(false, "Button wasn't read yet"),
+        };
+        check.aged(age)
+    }
+
+    /// Never fails, without hotplug events cards are polled for instead.
+    fn check_hotplug(&self) -> Check {
+        let check = match self.liveness.hotplug_listening.load(Ordering::Relaxed) {
+            true => Check::new(true, "Listening for hotplug events"),
+            false => Check::new(true, "Not listening for hotplug events, polling for cards"),
+        };
+        check.aged(self.liveness.hotplug.age())
+    }
+
+    fn check_machine(&self, state: SystemState) -> Check {
+        let age = self.liveness.machine.age();
+        let check = match age {
+            _ if waits_on_purpose(state) => Check::new(true, format!("Waiting while {state:?}")),
+            Some(age) if age < MACHINE_STALL => Check::new(true, "Stepping"),
+            Some(_) => Check::new(false, format!("Stopped stepping while {state:?}")),
+            None => Check::new(false, "Hasn't started yet"),
+        };
+        check.aged(age)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_directory_of_files_yet_to_be_created() {
+        let directory = std::env::temp_dir();
+        assert_eq!(
+            existing_directory(&directory.join("reports/2024/flash.json")),
+            Some(directory.as_path())
+        );
+        assert_eq!(existing_directory(&directory), Some(directory.as_path()));
+        assert_eq!(
+            existing_directory(Path::new("history.jsonl")),
+            Some(Path::new("."))
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! The addresses a headless unit can be reached at, shown on its display so nobody has to scan
+//! the network for it.
+
+use std::io;
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+
+use nix::ifaddrs::getifaddrs;
+use nix::net::if_::InterfaceFlags;
+
+use crate::events::{Event, EventBus};
+use crate::peer;
+
+/// How often addresses are looked at, DHCP leases come and go at any time
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Addresses of the interfaces that are up, other than loopback and IPv6 link-local ones, which
+/// nobody can type into a browser.
+pub fn addresses() -> io::Result<Vec<IpAddr>> {
+    let mut addresses: Vec<IpAddr> = getifaddrs()?
+        .filter(|interface| {
+            interface.flags.contains(InterfaceFlags::IFF_UP)
+                && !interface.flags.contains(InterfaceFlags::IFF_LOOPBACK)
+        })
+        .filter_map(|interface| {
+            let address = interface.address?;
+            if let Some(v4) = address.as_sockaddr_in() {
+                return Some(IpAddr::V4(v4.ip()));
+            }
+            let v6 = address.as_sockaddr_in6()?.ip();
+            (!v6.is_unicast_link_local()).then_some(IpAddr::V6(v6))
+        })
+        .collect();
+    // IPv4 first, it is the shorter one to type.
+    addresses.sort_by_key(|address| address.is_ipv6());
+    addresses.dedup();
+    Ok(addresses)
+}
+
+/// Publishes `NetworkChanged` on a background thread once the network is up, and again whenever
+/// the addresses change.
+pub fn watch(events: &EventBus) {
+    let events = events.clone();
+    thread::spawn(move || {
+        let mut known = vec![];
+        loop {
+            match addresses() {
+                Ok(addresses) if addresses != known => {
+                    let hostname = peer::hostname();
+                    println!("Reachable as {hostname} at {addresses:?}");
+                    known = addresses.clone();
+                    events.publish(Event::NetworkChanged {
+                        hostname,
+                        addresses,
+                    });
+                }
+                Ok(_) => {}
+                Err(error) => println!("Got error when listing network addresses: {error:?}"),
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_out_loopback() {
+        let addresses = addresses().unwrap();
+        assert!(!addresses.iter().any(IpAddr::is_loopback), "{addresses:?}");
+        let first_v6 = addresses.iter().position(IpAddr::is_ipv6);
+        assert!(first_v6.is_none_or(|first| addresses[first..].iter().all(IpAddr::is_ipv6)));
+    }
+}
@@ -0,0 +1,202 @@
+//! PN532 NFC reader on I2C, writing flash metadata to NTAG21x stickers as NDEF text records.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rppal::i2c::I2c;
+
+use crate::config::NfcConfig;
+use crate::error::HardwareError;
+use crate::history::FlashRecord;
+
+const HOST_TO_PN532: u8 = 0xD4;
+const PN532_TO_HOST: u8 = 0xD5;
+
+const SAM_CONFIGURATION: u8 = 0x14;
+const IN_LIST_PASSIVE_TARGET: u8 = 0x4A;
+const IN_DATA_EXCHANGE: u8 = 0x40;
+
+/// Type 2 tag command writing one 4-byte page.
+const NTAG_WRITE: u8 = 0xA2;
+/// NDEF data starts right after the capability container.
+const FIRST_USER_PAGE: u8 = 4;
+/// User memory of the smallest NTAG21x (213), larger ones just keep the rest blank.
+const USER_MEMORY: usize = 144;
+
+const ACK: [u8; 6] = [0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00];
+
+pub struct Pn532 {
+    i2c: I2c,
+}
+
+impl Pn532 {
+    pub fn new(config: &NfcConfig) -> Result<Self, HardwareError> {
+        let mut i2c = I2c::with_bus(config.bus)?;
+        i2c.set_slave_address(config.address)?;
+        let mut pn532 = Self { i2c };
+        // Normal mode, the SAM isn't fitted on the common breakout boards.
+        pn532.call(
+            SAM_CONFIGURATION,
+            &[0x01, 0x14, 0x01],
+            Duration::from_secs(1),
+        )?;
+        Ok(pn532)
+    }
+
+    /// Sends a command frame and returns the payload of the response, without the command code.
+    fn call(
+        &mut self,
+        command: u8,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, HardwareError> {
+        let mut body = vec![HOST_TO_PN532, command];
+        body.extend_from_slice(data);
+        let length = body.len() as u8;
+        let checksum = body.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let mut frame = vec![0x00, 0x00, 0xFF, length, length.wrapping_neg()];
+        frame.extend_from_slice(&body);
+        frame.extend_from_slice(&[checksum.wrapping_neg(), 0x00]);
+        self.i2c.write(&frame)?;
+
+        let ack = self.read_ready(ACK.len(), Duration::from_millis(100))?;
+        if ack.as_deref() != Some(&ACK[..]) {
+            return Err(protocol(format!(
+                "No acknowledgement of command {command:#04x}"
+            )));
+        }
+        let Some(response) = self.read_ready(64, timeout)? else {
+            // Any frame from the host aborts the pending command.
+            self.i2c.write(&ACK)?;
+            return Err(protocol(format!(
+                "No answer to command {command:#04x} in time"
+            )));
+        };
+        parse_response(command, &response)
+    }
+
+    /// Polls until the PN532 sets its ready bit, then returns `length` bytes after it.
+    fn read_ready(
+        &mut self,
+        length: usize,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, HardwareError> {
+        let deadline = Instant::now() + timeout;
+        let mut buffer = vec![0; length + 1];
+        loop {
+            self.i2c.read(&mut buffer)?;
+            if buffer[0] & 0x01 != 0 {
+                return Ok(Some(buffer.split_off(1)));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Waits for a tag in the field and writes `message` to it.
+    pub fn write_ndef(&mut self, message: &[u8], timeout: Duration) -> Result<(), HardwareError> {
+        let tlv = ndef_tlv(message)?;
+        let targets = self.call(IN_LIST_PASSIVE_TARGET, &[0x01, 0x00], timeout)?;
+        if targets.first() != Some(&1) {
+            return Err(protocol("No NFC tag found".to_string()));
+        }
+        for (page, chunk) in (FIRST_USER_PAGE..).zip(tlv.chunks(4)) {
+            let mut data = [0; 4];
+            data[..chunk.len()].copy_from_slice(chunk);
+            let mut exchange = vec![1, NTAG_WRITE, page];
+            exchange.extend_from_slice(&data);
+            let status = self.call(IN_DATA_EXCHANGE, &exchange, Duration::from_secs(1))?;
+            if status.first() != Some(&0x00) {
+                return Err(protocol(format!(
+                    "Writing NFC tag page {page} failed with status {status:02x?}"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn protocol(message: String) -> HardwareError {
+    HardwareError::Protocol {
+        chip: "PN532",
+        message,
+    }
+}
+
+/// Checks the framing of a normal information frame and strips it down to the payload.
+fn parse_response(command: u8, frame: &[u8]) -> Result<Vec<u8>, HardwareError> {
+    let invalid = || protocol(format!("Invalid response to {command:#04x}: {frame:02x?}"));
+    let start = frame
+        .windows(3)
+        .position(|window| window == [0x00, 0x00, 0xFF])
+        .ok_or_else(invalid)?
+        + 3;
+    let (&length, rest) = frame[start..].split_first().ok_or_else(invalid)?;
+    let length = length as usize;
+    if rest.first().map(|lcs| lcs.wrapping_add(length as u8)) != Some(0) || rest.len() < length + 2
+    {
+        return Err(invalid());
+    }
+    let body = &rest[1..1 + length];
+    let checksum = body
+        .iter()
+        .fold(rest[1 + length], |sum, byte| sum.wrapping_add(*byte));
+    if checksum != 0 || body.len() < 2 || body[0] != PN532_TO_HOST || body[1] != command + 1 {
+        return Err(invalid());
+    }
+    Ok(body[2..].to_vec())
+}
+
+/// Wraps an NDEF message in the TLV block Type 2 tags store it in.
+fn ndef_tlv(message: &[u8]) -> Result<Vec<u8>, HardwareError> {
+    let mut tlv = vec![0x03];
+    if message.len() < 0xFF {
+        tlv.push(message.len() as u8);
+    } else {
+        tlv.extend_from_slice(&[0xFF, (message.len() >> 8) as u8, message.len() as u8]);
+    }
+    tlv.extend_from_slice(message);
+    tlv.push(0xFE);
+    if tlv.len() > USER_MEMORY {
+        return Err(HardwareError::Unsupported(format!(
+            "NDEF message of {} bytes doesn't fit an NTAG213",
+            message.len()
+        )));
+    }
+    Ok(tlv)
+}
+
+/// NDEF message with one well-known text record per line of `texts`.
+fn text_records(texts: &[String]) -> Vec<u8> {
+    let mut message = Vec::new();
+    for (index, text) in texts.iter().enumerate() {
+        let mut payload = vec![2];
+        payload.extend_from_slice(b"en");
+        payload.extend_from_slice(text.as_bytes());
+        // Short records (SR) with the well-known type name format.
+        let mut header = 0x10 | 0x01;
+        if index == 0 {
+            header |= 0x80;
+        }
+        if index == texts.len() - 1 {
+            header |= 0x40;
+        }
+        message.extend_from_slice(&[header, 1, payload.len().min(0xFF) as u8, b'T']);
+        message.extend_from_slice(&payload[..payload.len().min(0xFF)]);
+    }
+    message
+}
+
+/// Records the image version and card serial, the two fields asset tracking keys on.
+pub fn record_message(record: &FlashRecord) -> Vec<u8> {
+    let unknown = || "unknown".to_string();
+    text_records(&[
+        format!(
+            "image: {}",
+            record.image_version.clone().unwrap_or_else(unknown)
+        ),
+        format!("serial: {}", record.serial.clone().unwrap_or_else(unknown)),
+    ])
+}
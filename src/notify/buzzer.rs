@@ -0,0 +1,49 @@
+//! An active buzzer on a GPIO pin, for rigs where nobody watches the LEDs.
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use rppal::gpio::{Gpio, OutputPin};
+
+use super::Notifier;
+use crate::config::BuzzerConfig;
+use crate::error::HardwareError;
+use crate::history::{FlashRecord, FlashResult};
+
+pub struct Buzzer {
+    pin: OutputPin,
+}
+
+impl Buzzer {
+    pub fn new(config: &BuzzerConfig) -> Result<Self, HardwareError> {
+        Ok(Self {
+            pin: Gpio::new()?.get(config.pin)?.into_output_low(),
+        })
+    }
+
+    fn beep(&mut self, count: u32, length: Duration) {
+        for _ in 0..count {
+            self.pin.set_high();
+            thread::sleep(length);
+            self.pin.set_low();
+            thread::sleep(Duration::from_millis(150));
+        }
+    }
+}
+
+impl Notifier for Buzzer {
+    fn started(&mut self, _record: &FlashRecord) -> io::Result<()> {
+        self.beep(1, Duration::from_millis(50));
+        Ok(())
+    }
+
+    /// One long beep when the card is good, three short ones when it isn't.
+    fn finished(&mut self, record: &FlashRecord) -> io::Result<()> {
+        match record.result {
+            FlashResult::Succeeded => self.beep(1, Duration::from_millis(600)),
+            FlashResult::Failed | FlashResult::Removed => self.beep(3, Duration::from_millis(150)),
+        }
+        Ok(())
+    }
+}
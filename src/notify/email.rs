@@ -0,0 +1,67 @@
+//! Mail about finished flashes, handed to the local `sendmail` so relaying and TLS are the MTA's
+//! business.
+
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+use super::Notifier;
+use crate::config::EmailConfig;
+use crate::history::{format_time, FlashRecord, FlashResult};
+
+pub struct Email {
+    config: EmailConfig,
+}
+
+impl Email {
+    pub fn new(config: &EmailConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+}
+
+impl Notifier for Email {
+    fn finished(&mut self, record: &FlashRecord) -> io::Result<()> {
+        let result = match record.result {
+            FlashResult::Succeeded if self.config.failures_only => return Ok(()),
+            FlashResult::Succeeded => "succeeded",
+            FlashResult::Failed => "failed",
+            FlashResult::Removed => "was cut short, the card was removed",
+        };
+        let unknown = || "unknown".to_string();
+        let mut message = format!("To: {}\n", self.config.to.join(", "));
+        if let Some(from) = &self.config.from {
+            message.push_str(&format!("From: {from}\n"));
+        }
+        message.push_str(&format!(
+            "Subject: Flashing {} {result}\n\n",
+            record.device.display()
+        ));
+        message.push_str(&format!(
+            "Image: {} ({})\nCard: {}\nFinished: {} UTC\nSHA-256: {}\n",
+            record.image.display(),
+            record.image_version.clone().unwrap_or_else(unknown),
+            record.serial.clone().unwrap_or_else(unknown),
+            format_time(record.finished_at),
+            record.image_sha256.clone().unwrap_or_else(unknown),
+        ));
+        if let Some(error) = &record.error {
+            message.push_str(&format!("Error: {error}\n"));
+        }
+
+        let mut sendmail = Command::new(&self.config.sendmail)
+            .args(["-t", "-i"])
+            .stdin(Stdio::piped())
+            .spawn()?;
+        sendmail
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(message.as_bytes())?;
+        let status = sendmail.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("sendmail failed with {status}")));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! Notifications about flashes. Every channel implements `Notifier` and is registered from the
+//! config; each runs on its own thread, so a slow server never holds up a flash.
+//!
+//! The notifiers follow flashes on the event bus.
+
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::config::NotifyConfig;
+use crate::error::HardwareError;
+use crate::events;
+use crate::history::FlashRecord;
+
+mod buzzer;
+mod email;
+mod mqtt;
+mod webhook;
+
+/// Progress is passed on at most this often.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+pub trait Notifier: Send {
+    fn started(&mut self, _record: &FlashRecord) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// `total` is unknown for compressed images without a size in their header.
+    fn progress(&mut self, _device: &Path, _written: u64, _total: Option<u64>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finished(&mut self, record: &FlashRecord) -> io::Result<()>;
+}
+
+enum Event {
+    Started(FlashRecord),
+    Progress {
+        device: PathBuf,
+        written: u64,
+        total: Option<u64>,
+    },
+    Finished(FlashRecord),
+}
+
+struct Worker {
+    sender: mpsc::Sender<Event>,
+    thread: JoinHandle<()>,
+}
+
+#[derive(Default)]
+pub struct Notifiers {
+    workers: Mutex<Vec<Worker>>,
+    last_progress: Mutex<Option<Instant>>,
+}
+
+impl Notifiers {
+    pub fn from_config(config: &NotifyConfig) -> Result<Self, HardwareError> {
+        let notifiers = Self::default();
+        notifiers.register_all(config)?;
+        Ok(notifiers)
+    }
+
+    fn register_all(&self, config: &NotifyConfig) -> Result<(), HardwareError> {
+        for webhook in &config.webhooks {
+            self.register("webhook", Box::new(webhook::Webhook::new(webhook)));
+        }
+        if let Some(mqtt) = &config.mqtt {
+            self.register("MQTT", Box::new(mqtt::Mqtt::new(mqtt)));
+        }
+        if let Some(email) = &config.email {
+            self.register("email", Box::new(email::Email::new(email)));
+        }
+        if let Some(buzzer) = &config.buzzer {
+            self.register("buzzer", Box::new(buzzer::Buzzer::new(buzzer)?));
+        }
+        Ok(())
+    }
+
+    /// Replaces the notifiers with the ones in `config`, for a reloaded config. The old ones send
+    /// what they have queued first, and let go of their pins before the buzzer takes them again.
+    pub fn reconfigure(&self, config: &NotifyConfig) -> Result<(), HardwareError> {
+        for worker in mem::take(&mut *self.workers.lock().unwrap()) {
+            drop(worker.sender);
+            let _ = worker.thread.join();
+        }
+        self.register_all(config)
+    }
+
+    pub fn register(&self, name: &'static str, mut notifier: Box<dyn Notifier>) {
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for event in receiver {
+                let result = match &event {
+                    Event::Started(record) => notifier.started(record),
+                    Event::Progress {
+                        device,
+                        written,
+                        total,
+                    } => notifier.progress(device, *written, *total),
+                    Event::Finished(record) => notifier.finished(record),
+                };
+                if let Err(error) = result {
+                    println!("Got error when notifying via {name}: {error:?}");
+                }
+            }
+        });
+        self.workers.lock().unwrap().push(Worker { sender, thread });
+    }
+
+    fn send(&self, event: impl Fn() -> Event) {
+        for worker in self.workers.lock().unwrap().iter() {
+            let _ = worker.sender.send(event());
+        }
+    }
+
+    /// Passes flashes from `events` on to the notifiers, from a background thread. The notifiers
+    /// returned can still be reconfigured.
+    pub fn listen(self, mut events: broadcast::Receiver<events::Event>) -> Arc<Self> {
+        let notifiers = Arc::new(self);
+        let listening = Arc::clone(&notifiers);
+        thread::spawn(move || loop {
+            match events.blocking_recv() {
+                Ok(events::Event::FlashStarted(record)) => listening.started(&record),
+                Ok(events::Event::ProgressTick {
+                    device,
+                    written,
+                    total,
+                }) => listening.progress(&device, written, total),
+                Ok(events::Event::FlashFinished(record)) => listening.finished(&record),
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    println!("Notifications fell behind, missed {missed} events")
+                }
+                Err(RecvError::Closed) => return,
+            }
+        });
+        notifiers
+    }
+
+    fn started(&self, record: &FlashRecord) {
+        *self.last_progress.lock().unwrap() = None;
+        self.send(|| Event::Started(record.clone()));
+    }
+
+    fn progress(&self, device: &Path, written: u64, total: Option<u64>) {
+        let mut last_progress = self.last_progress.lock().unwrap();
+        if last_progress.is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL) {
+            return;
+        }
+        *last_progress = Some(Instant::now());
+        self.send(|| Event::Progress {
+            device: device.to_path_buf(),
+            written,
+            total,
+        });
+    }
+
+    fn finished(&self, record: &FlashRecord) {
+        self.send(|| Event::Finished(record.clone()));
+    }
+}
+
+/// The JSON body webhooks and MQTT messages carry for `started` and `finished`.
+fn record_payload(event: &str, record: &FlashRecord) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({ "event": event, "record": record })).unwrap_or_default()
+}
+
+fn progress_payload(device: &Path, written: u64, total: Option<u64>) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({
+        "event": "progress",
+        "device": device,
+        "written": written,
+        "total": total,
+    }))
+    .unwrap_or_default()
+}
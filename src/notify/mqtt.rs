@@ -0,0 +1,125 @@
+//! MQTT 3.1.1 publisher, just enough of the protocol to send QoS 0 messages.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::Duration;
+
+use super::{progress_payload, record_payload, Notifier};
+use crate::config::MqttConfig;
+use crate::history::FlashRecord;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+
+pub struct Mqtt {
+    config: MqttConfig,
+    connection: Option<TcpStream>,
+}
+
+/// Remaining length, seven bits per byte with the top bit marking that more follow.
+fn encode_length(mut length: usize, packet: &mut Vec<u8>) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            return;
+        }
+    }
+}
+
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    encode_length(body.len(), &mut packet);
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn push_string(text: &str, body: &mut Vec<u8>) {
+    body.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    body.extend_from_slice(text.as_bytes());
+}
+
+impl Mqtt {
+    pub fn new(config: &MqttConfig) -> Self {
+        Self {
+            config: config.clone(),
+            connection: None,
+        }
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.config.host.as_str(), self.config.port))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+
+        let mut body = vec![];
+        push_string("MQTT", &mut body);
+        body.push(4);
+        // Clean session, plus the user name and password flags when we have them.
+        let mut flags = 0x02;
+        if self.config.username.is_some() {
+            flags |= 0x80;
+        }
+        if self.config.password.is_some() {
+            flags |= 0x40;
+        }
+        body.push(flags);
+        // No keep alive, we may stay quiet for hours between cards.
+        body.extend_from_slice(&0u16.to_be_bytes());
+        push_string(&self.config.client_id, &mut body);
+        for credential in [&self.config.username, &self.config.password]
+            .into_iter()
+            .flatten()
+        {
+            push_string(credential, &mut body);
+        }
+        stream.write_all(&packet(CONNECT, &body))?;
+
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack)?;
+        if connack[0] != CONNACK || connack[3] != 0 {
+            return Err(io::Error::other(format!(
+                "MQTT broker refused the connection with code {}",
+                connack[3]
+            )));
+        }
+        Ok(stream)
+    }
+
+    fn publish(&mut self, event: &str, payload: &[u8]) -> io::Result<()> {
+        let mut body = vec![];
+        push_string(&format!("{}/{event}", self.config.topic), &mut body);
+        body.extend_from_slice(payload);
+        let message = packet(PUBLISH, &body);
+        // The broker may have dropped an old connection, reconnect once before giving up.
+        if let Some(connection) = &mut self.connection {
+            if connection.write_all(&message).is_ok() {
+                return Ok(());
+            }
+        }
+        let mut connection = self.connect()?;
+        connection.write_all(&message)?;
+        self.connection = Some(connection);
+        Ok(())
+    }
+}
+
+impl Notifier for Mqtt {
+    fn started(&mut self, record: &FlashRecord) -> io::Result<()> {
+        self.publish("started", &record_payload("started", record))
+    }
+
+    fn progress(&mut self, device: &Path, written: u64, total: Option<u64>) -> io::Result<()> {
+        self.publish("progress", &progress_payload(device, written, total))
+    }
+
+    fn finished(&mut self, record: &FlashRecord) -> io::Result<()> {
+        self.publish("finished", &record_payload("finished", record))
+    }
+}
@@ -0,0 +1,49 @@
+//! JSON POSTs to an HTTP endpoint, e.g. a chat integration or a line's MES.
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use super::{progress_payload, record_payload, Notifier};
+use crate::config::WebhookConfig;
+use crate::history::FlashRecord;
+
+pub struct Webhook {
+    config: WebhookConfig,
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    fn post(&self, body: &[u8]) -> io::Result<()> {
+        ureq::post(&self.config.url)
+            .set("Content-Type", "application/json")
+            .timeout(Duration::from_secs(10))
+            .send_bytes(body)
+            .map_err(|error| {
+                io::Error::other(format!("POST to {} failed: {error}", self.config.url))
+            })?;
+        Ok(())
+    }
+}
+
+impl Notifier for Webhook {
+    fn started(&mut self, record: &FlashRecord) -> io::Result<()> {
+        self.post(&record_payload("started", record))
+    }
+
+    fn progress(&mut self, device: &Path, written: u64, total: Option<u64>) -> io::Result<()> {
+        if !self.config.progress {
+            return Ok(());
+        }
+        self.post(&progress_payload(device, written, total))
+    }
+
+    fn finished(&mut self, record: &FlashRecord) -> io::Result<()> {
+        self.post(&record_payload("finished", record))
+    }
+}
@@ -0,0 +1,185 @@
+//! Just enough MBR and GPT parsing to find where the partitions are.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// What images are laid out for, cards may use larger sectors
+pub const IMAGE_SECTOR_SIZE: u64 = 512;
+pub const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+pub const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+pub const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// Entries are 128 bytes in practice, anything much bigger is a corrupt header.
+const GPT_MAX_ENTRY_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// Byte offsets on the device
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionTable {
+    pub partitions: Vec<Partition>,
+    /// End of the table itself, everything before it has to be kept
+    pub table_end: u64,
+}
+
+/// The primary partitions (extended partitions as a whole), or `None` if there is no partition
+/// table we understand. Block addresses count in `sector_size` bytes, the logical sector size of
+/// the device the table was written for.
+pub fn read(
+    device: &mut (impl Read + Seek),
+    sector_size: u64,
+) -> io::Result<Option<PartitionTable>> {
+    // The MBR fills the first 512 bytes, whatever the sector size.
+    let mut mbr = [0; 512];
+    device.seek(SeekFrom::Start(0))?;
+    device.read_exact(&mut mbr)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(None);
+    }
+
+    let mut partitions = vec![];
+    for entry in mbr[446..510].chunks_exact(16) {
+        let partition_type = entry[4];
+        if partition_type == 0 {
+            continue;
+        }
+        if partition_type == MBR_TYPE_GPT_PROTECTIVE {
+            return read_gpt(device, sector_size);
+        }
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        partitions.push(Partition {
+            start: start * sector_size,
+            end: (start + sectors) * sector_size,
+        });
+    }
+    Ok(Some(PartitionTable {
+        partitions,
+        table_end: sector_size,
+    }))
+}
+
+fn read_gpt(
+    device: &mut (impl Read + Seek),
+    sector_size: u64,
+) -> io::Result<Option<PartitionTable>> {
+    let mut header = [0; 92];
+    device.seek(SeekFrom::Start(sector_size))?;
+    device.read_exact(&mut header)?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entry_count = u32::from_le_bytes(header[80..84].try_into().unwrap()) as usize;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    if !(48..=GPT_MAX_ENTRY_SIZE).contains(&entry_size) || entry_count > 1024 {
+        return Ok(None);
+    }
+    let Some(entries_start) = entries_lba.checked_mul(sector_size) else {
+        return Ok(None);
+    };
+
+    let mut entries = vec![0; entry_count * entry_size];
+    device.seek(SeekFrom::Start(entries_start))?;
+    device.read_exact(&mut entries)?;
+    // Any partition beyond what a disk can address, or ending before it starts, means we don't
+    // understand the table.
+    let partitions: Option<Vec<Partition>> = entries
+        .chunks_exact(entry_size)
+        .filter(|entry| entry[..16].iter().any(|byte| *byte != 0))
+        .map(|entry| {
+            let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+            let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+            let start = first_lba.checked_mul(sector_size)?;
+            let end = last_lba.checked_add(1)?.checked_mul(sector_size)?;
+            (start < end).then_some(Partition { start, end })
+        })
+        .collect();
+    let Some(partitions) = partitions else {
+        return Ok(None);
+    };
+    Ok(Some(PartitionTable {
+        partitions,
+        table_end: entries_start + entries.len() as u64,
+    }))
+}
+
+/// Byte offset where the last partition ends, or `None` if there is no partition table we
+/// understand. `sector_size` as for [`read`].
+///
+/// Note the backup GPT at the end of the disk is not included, it has to be recreated (e.g. by
+/// `sgdisk -e`) when restoring to a differently sized card anyway.
+pub fn used_end(device: &mut (impl Read + Seek), sector_size: u64) -> io::Result<Option<u64>> {
+    let Some(table) = read(device, sector_size)? else {
+        return Ok(None);
+    };
+    let end = table
+        .partitions
+        .iter()
+        .map(|partition| partition.end)
+        .fold(table.table_end, u64::max);
+    Ok(Some(end))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A GPT with a single partition over blocks 10 to 19.
+    fn gpt(sector_size: u64) -> Vec<u8> {
+        let sector_size = sector_size as usize;
+        let mut disk = vec![0; 20 * sector_size];
+        disk[446 + 4] = MBR_TYPE_GPT_PROTECTIVE;
+        disk[510..512].copy_from_slice(&MBR_SIGNATURE);
+        let header = &mut disk[sector_size..];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&1u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        let entry = &mut disk[2 * sector_size..];
+        entry[0] = 1;
+        entry[32..40].copy_from_slice(&10u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&19u64.to_le_bytes());
+        disk
+    }
+
+    #[test]
+    fn counts_in_the_sector_size_of_the_device() {
+        for sector_size in [512, 4096] {
+            let table = read(&mut Cursor::new(gpt(sector_size)), sector_size)
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                table.partitions,
+                vec![Partition {
+                    start: 10 * sector_size,
+                    end: 20 * sector_size,
+                }]
+            );
+        }
+        // Read with the wrong sector size the header isn't found.
+        assert_eq!(read(&mut Cursor::new(gpt(4096)), 512).unwrap(), None);
+    }
+
+    #[test]
+    fn doesnt_understand_corrupt_gpts() {
+        let corrupted = |offset: usize, value: &[u8]| {
+            let mut disk = gpt(512);
+            disk[offset..offset + value.len()].copy_from_slice(value);
+            read(&mut Cursor::new(disk), 512)
+        };
+        // Entry size, entries LBA, then the partition ending before it starts and at the end of
+        // what 64 bits address.
+        assert_eq!(corrupted(512 + 84, &u32::MAX.to_le_bytes()).unwrap(), None);
+        assert_eq!(corrupted(512 + 72, &u64::MAX.to_le_bytes()).unwrap(), None);
+        assert_eq!(corrupted(1024 + 40, &5u64.to_le_bytes()).unwrap(), None);
+        assert_eq!(corrupted(1024 + 40, &u64::MAX.to_le_bytes()).unwrap(), None);
+        // Entries past the end of the disk can't be read.
+        assert!(corrupted(512 + 72, &1000u64.to_le_bytes()).is_err());
+        assert!(read(&mut Cursor::new(vec![0; 100]), 512).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+//! Pausing a flash between chunks, e.g. to free the USB bus for a while.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+
+use crate::events::EventBus;
+use crate::SystemState;
+
+#[derive(Debug, Clone, Default)]
+pub struct PauseControl {
+    paused: Arc<AtomicBool>,
+}
+
+impl PauseControl {
+    /// Pauses a running flash or resumes a paused one, showing it in the state. Does nothing
+    /// when no flash is running.
+    pub fn toggle(&self, events: &EventBus) {
+        events.update_state(|state| {
+            let next = match state {
+                SystemState::Flashing => SystemState::Paused,
+                SystemState::Paused => SystemState::Flashing,
+                _ => return None,
+            };
+            self.paused
+                .store(next == SystemState::Paused, Ordering::SeqCst);
+            println!(
+                "{} flashing",
+                if next == SystemState::Paused {
+                    "Pausing"
+                } else {
+                    "Resuming"
+                }
+            );
+            Some(next)
+        });
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Forgets a pause left over from an earlier flash.
+    pub fn reset(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Blocks for as long as the flash is paused, or until it is cancelled.
+    pub fn wait_while_paused(&self, cancel: &CancellationToken) {
+        while self.is_paused() && !cancel.is_cancelled() {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
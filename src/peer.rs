@@ -0,0 +1,240 @@
+//! Peer mode: one cloner serves its image over HTTP and announces itself over mDNS, and the others
+//! sync from it (see `sync`) without any server of their own.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+use crate::catalog;
+use crate::config::PeerServerConfig;
+use crate::sync::{Manifest, DEFAULT_BLOCK_SIZE};
+
+const SERVICE_TYPE: &str = "_rpi-sd-cloner._tcp.local.";
+
+/// How long peers listen for a primary before giving up on this sync.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Body = Box<dyn Read + Send>;
+
+/// The manifest of the image as it was at `modified`, hashing a whole image takes a while.
+struct CachedManifest {
+    modified: Option<SystemTime>,
+    json: Vec<u8>,
+}
+
+struct ImageServer {
+    image: PathBuf,
+    manifest: Mutex<Option<CachedManifest>>,
+}
+
+impl ImageServer {
+    /// The manifest for the image currently on disk, regenerated after it was swapped.
+    fn manifest(&self) -> io::Result<Vec<u8>> {
+        let modified = fs::metadata(&self.image)?.modified().ok();
+        let mut cached = self.manifest.lock().unwrap();
+        if let Some(cached) = cached.as_ref().filter(|cached| cached.modified == modified) {
+            return Ok(cached.json.clone());
+        }
+        let version = catalog::image_version(&self.image);
+        let manifest = Manifest::generate(&self.image, DEFAULT_BLOCK_SIZE, version)?;
+        let json = serde_json::to_vec(&manifest)?;
+        *cached = Some(CachedManifest {
+            modified,
+            json: json.clone(),
+        });
+        Ok(json)
+    }
+
+    fn path(&self) -> String {
+        format!(
+            "/{}",
+            self.image.file_name().unwrap_or_default().to_string_lossy()
+        )
+    }
+
+    fn handle(&self, request: &Request) -> io::Result<Response<Body>> {
+        if *request.method() != Method::Get {
+            return Ok(status(405));
+        }
+        let path = self.path();
+        if request.url() == format!("{path}.manifest.json") {
+            let json = self.manifest()?;
+            let length = json.len();
+            return Ok(Response::new(
+                StatusCode(200),
+                vec![header("Content-Type", "application/json")],
+                Box::new(io::Cursor::new(json)) as Body,
+                Some(length),
+                None,
+            ));
+        }
+        if request.url() != path {
+            return Ok(status(404));
+        }
+
+        let mut file = File::open(&self.image)?;
+        let size = file.metadata()?.len();
+        let range = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Range"))
+            .map(|header| parse_range(header.value.as_str(), size));
+        let accept_ranges = header("Accept-Ranges", "bytes");
+        match range {
+            None => Ok(Response::new(
+                StatusCode(200),
+                vec![accept_ranges],
+                Box::new(file) as Body,
+                Some(size as usize),
+                None,
+            )),
+            Some(Some((start, end))) => {
+                file.seek(SeekFrom::Start(start))?;
+                let length = end - start;
+                let content_range = format!("bytes {start}-{}/{size}", end - 1);
+                Ok(Response::new(
+                    StatusCode(206),
+                    vec![accept_ranges, header("Content-Range", &content_range)],
+                    Box::new(file.take(length)) as Body,
+                    Some(length as usize),
+                    None,
+                ))
+            }
+            Some(None) => {
+                Ok(status(416).with_header(header("Content-Range", &format!("bytes */{size}"))))
+            }
+        }
+    }
+}
+
+fn status(code: u16) -> Response<Body> {
+    Response::new(
+        StatusCode(code),
+        vec![],
+        Box::new(io::empty()),
+        Some(0),
+        None,
+    )
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// Parses a single `bytes=` range into a half-open range, `None` if it is unsatisfiable.
+fn parse_range(value: &str, size: u64) -> Option<(u64, u64)> {
+    let (first, last) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (first.trim(), last.trim()) {
+        // The last n bytes
+        ("", suffix) => (size.saturating_sub(suffix.parse().ok()?), size),
+        (first, "") => (first.parse().ok()?, size),
+        (first, last) => (
+            first.parse().ok()?,
+            last.parse::<u64>().ok()?.saturating_add(1).min(size),
+        ),
+    };
+    (start < end).then_some((start, end))
+}
+
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| "rpi-sd-cloner".to_string())
+}
+
+/// Announces a service of ours at `path` on `port` under this host's name. It stays announced
+/// until the returned daemon is dropped.
+pub fn announce(
+    service_type: &str,
+    port: u16,
+    path: &str,
+) -> Result<ServiceDaemon, Box<dyn Error>> {
+    let host = hostname();
+    let mdns = ServiceDaemon::new()?;
+    let service = ServiceInfo::new(
+        service_type,
+        &host,
+        &format!("{host}.local."),
+        "",
+        port,
+        &[("path", path)][..],
+    )?
+    .enable_addr_auto();
+    mdns.register(service)?;
+    Ok(mdns)
+}
+
+/// Serves `image` and its manifest on a background thread, and announces it over mDNS.
+pub fn serve(config: &PeerServerConfig, image: PathBuf) -> Result<(), Box<dyn Error>> {
+    let listener = Server::http(("0.0.0.0", config.port))
+        .map_err(|error| format!("Listening on port {} failed: {error}", config.port))?;
+    let server = ImageServer {
+        image,
+        manifest: Mutex::new(None),
+    };
+
+    let mdns = announce(SERVICE_TYPE, config.port, &server.path())?;
+
+    thread::spawn(move || {
+        // Peers would time out waiting for the first manifest otherwise.
+        if let Err(error) = server.manifest() {
+            println!("Got error when generating the image manifest: {error:?}");
+        }
+        let _mdns = mdns;
+        for request in listener.incoming_requests() {
+            let response = server.handle(&request).unwrap_or_else(|error| {
+                println!("Got error when serving {}: {error:?}", request.url());
+                status(500)
+            });
+            if let Err(error) = request.respond(response) {
+                println!("Got error when sending the response: {error:?}");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// URLs of the services of `service_type` that answer within `timeout`, or just the first one
+/// with `first_only`.
+pub fn browse(service_type: &str, timeout: Duration, first_only: bool) -> io::Result<Vec<String>> {
+    let mdns = ServiceDaemon::new().map_err(io::Error::other)?;
+    let events = mdns.browse(service_type).map_err(io::Error::other)?;
+    let deadline = Instant::now() + timeout;
+    let mut found: Vec<(String, String)> = vec![];
+    while let Ok(event) = events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        let ServiceEvent::ServiceResolved(service) = event else {
+            continue;
+        };
+        // Services are resolved again on every interface they are seen on.
+        if found.iter().any(|(name, _)| *name == service.fullname) {
+            continue;
+        }
+        let (Some(address), Some(path)) = (
+            service.get_addresses_v4().into_iter().next(),
+            service.get_property_val_str("path"),
+        ) else {
+            continue;
+        };
+        let url = format!("http://{address}:{}{path}", service.port);
+        found.push((service.fullname.clone(), url));
+        if first_only {
+            break;
+        }
+    }
+    let _ = mdns.shutdown();
+    Ok(found.into_iter().map(|(_, url)| url).collect())
+}
+
+/// Finds a primary on the local network and returns the URL of its image.
+pub fn discover() -> io::Result<String> {
+    browse(SERVICE_TYPE, DISCOVERY_TIMEOUT, true)?
+        .pop()
+        .ok_or_else(|| io::Error::other("No primary cloner found over mDNS"))
+}
@@ -0,0 +1,195 @@
+//! Edits to `config.txt` and `cmdline.txt` on the boot partition, so one image can be tuned per
+//! product. Every edit is a line of `config.txt` or a parameter of `cmdline.txt`, with template
+//! variables substituted:
+//!
+//! - `name=value` sets it, replacing what is set for `name` already. `dtoverlay`, `dtparam` and
+//!   `include` in `config.txt` and `console` in `cmdline.txt` are added next to the others
+//!   instead, as they can be given more than once.
+//! - `-name` removes everything set for `name`, `-name=value` only that.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::config::BootFilesConfig;
+use crate::template;
+
+const CONFIG_TXT_REPEATED: &[&str] = &["dtoverlay", "dtparam", "include"];
+const CMDLINE_REPEATED: &[&str] = &["console"];
+
+pub fn apply(
+    config: &BootFilesConfig,
+    boot: &Path,
+    variables: &[(&str, String)],
+) -> io::Result<()> {
+    let render = |edits: &[String]| -> io::Result<Vec<String>> {
+        edits
+            .iter()
+            .map(|edit| template::render_for_card(edit, variables))
+            .collect()
+    };
+    if !config.config_txt.is_empty() {
+        let path = boot.join("config.txt");
+        // Images without one boot with the defaults, which an empty file doesn't change.
+        let text = match fs::read_to_string(&path) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            text => text?,
+        };
+        fs::write(&path, edit_config_txt(&text, &render(&config.config_txt)?))?;
+        println!("Applied {} edits to config.txt", config.config_txt.len());
+    }
+    if !config.cmdline.is_empty() {
+        let path = boot.join("cmdline.txt");
+        let text = fs::read_to_string(&path)?;
+        fs::write(&path, edit_cmdline(&text, &render(&config.cmdline)?))?;
+        println!("Applied {} edits to cmdline.txt", config.cmdline.len());
+    }
+    Ok(())
+}
+
+/// An edit, split into what it removes or sets.
+enum Edit<'a> {
+    Remove {
+        name: &'a str,
+        value: Option<&'a str>,
+    },
+    Set {
+        name: &'a str,
+        line: &'a str,
+    },
+}
+
+impl<'a> Edit<'a> {
+    fn parse(edit: &'a str) -> Self {
+        let edit = edit.trim();
+        match edit.strip_prefix('-') {
+            Some(removed) => {
+                let (name, value) = split(removed);
+                Edit::Remove { name, value }
+            }
+            None => Edit::Set {
+                name: split(edit).0,
+                line: edit,
+            },
+        }
+    }
+
+    /// Whether `line`, a line of `config.txt` or a parameter of `cmdline.txt`, goes.
+    fn removes(&self, line: &str) -> bool {
+        let (name, value) = split(line.trim());
+        match *self {
+            Edit::Remove {
+                name: removed,
+                value: removed_value,
+            } => name == removed && (removed_value.is_none() || value == removed_value),
+            Edit::Set { .. } => false,
+        }
+    }
+}
+
+fn split(setting: &str) -> (&str, Option<&str>) {
+    match setting.split_once('=') {
+        Some((name, value)) => (name.trim(), Some(value.trim())),
+        None => (setting, None),
+    }
+}
+
+/// Applies `edits` to `lines` in place. Returns the lines that were added at the end.
+fn edit_lines(lines: &mut Vec<String>, edits: &[String], repeated: &[&str]) -> Vec<String> {
+    let mut added: Vec<String> = vec![];
+    for edit in edits {
+        let edit = Edit::parse(edit);
+        lines.retain(|line| !edit.removes(line));
+        added.retain(|line| !edit.removes(line));
+        let Edit::Set { name, line: set } = edit else {
+            continue;
+        };
+        if lines.iter().any(|line| line.trim() == set) {
+            continue;
+        }
+        let existing = (!repeated.contains(&name))
+            .then(|| lines.iter_mut().find(|line| split(line.trim()).0 == name))
+            .flatten();
+        match existing {
+            Some(line) => *line = set.to_string(),
+            None => {
+                lines.push(set.to_string());
+                added.push(set.to_string());
+            }
+        }
+    }
+    added
+}
+
+fn edit_config_txt(text: &str, edits: &[String]) -> String {
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    let added = edit_lines(&mut lines, edits, CONFIG_TXT_REPEATED);
+    // Added lines would only apply to the models of the section they end up in.
+    let in_section = lines[..lines.len() - added.len()]
+        .iter()
+        .rev()
+        .find(|line| line.trim_start().starts_with('['))
+        .is_some_and(|section| section.trim() != "[all]");
+    if in_section && !added.is_empty() {
+        let at = lines.len() - added.len();
+        lines.insert(at, "[all]".to_string());
+    }
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+fn edit_cmdline(text: &str, edits: &[String]) -> String {
+    // The kernel reads a single line, anything after it is ignored.
+    let line = text.lines().next().unwrap_or_default();
+    let mut parameters: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+    edit_lines(&mut parameters, edits, CMDLINE_REPEATED);
+    format!("{}\n", parameters.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edits(edits: &[&str]) -> Vec<String> {
+        edits.iter().map(|edit| edit.to_string()).collect()
+    }
+
+    #[test]
+    fn edits_config_txt() {
+        let text = "dtparam=audio=on\ngpu_mem=64\n\n[pi4]\narm_boost=1\n";
+        let edited = edit_config_txt(
+            text,
+            &edits(&[
+                "gpu_mem=128",
+                "-dtparam=audio=on",
+                "dtoverlay=vc4-kms-v3d",
+                "enable_uart=1",
+            ]),
+        );
+        assert_eq!(
+            edited,
+            "gpu_mem=128\n\n[pi4]\narm_boost=1\n[all]\ndtoverlay=vc4-kms-v3d\nenable_uart=1\n"
+        );
+    }
+
+    #[test]
+    fn edits_cmdline_txt() {
+        let text = "console=serial0,115200 console=tty1 root=PARTUUID=1234-02 quiet splash\n";
+        let edited = edit_cmdline(
+            text,
+            &edits(&[
+                "-quiet",
+                "-splash",
+                "-console=tty1",
+                "console=ttyAMA0",
+                "root=/dev/mmcblk0p2",
+                "cgroup_enable=memory",
+            ]),
+        );
+        assert_eq!(
+            edited,
+            "console=serial0,115200 root=/dev/mmcblk0p2 console=ttyAMA0 cgroup_enable=memory\n"
+        );
+    }
+}
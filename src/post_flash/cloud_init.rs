@@ -0,0 +1,60 @@
+//! Writes cloud-init NoCloud seed files into the boot partition, as used by Ubuntu Server for Pi.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::config::CloudInitConfig;
+use crate::template;
+
+/// cloud-init only runs again when the instance id changes, so every card gets its own. Cards
+/// without a CID are told apart by the time alone.
+const DEFAULT_META_DATA: &str = "instance-id: rpi-sd-cloner-{{flashed_at}}\n";
+const DEFAULT_META_DATA_WITH_CID: &str = "instance-id: rpi-sd-cloner-{{cid}}-{{flashed_at}}\n";
+
+pub fn install(
+    config: &CloudInitConfig,
+    boot: &Path,
+    variables: &[(&str, String)],
+) -> io::Result<()> {
+    let meta_data = match &config.meta_data {
+        Some(path) => read_template(path)?,
+        None if variables.iter().any(|(name, _)| *name == template::CARD_ID) => {
+            DEFAULT_META_DATA_WITH_CID.to_string()
+        }
+        None => DEFAULT_META_DATA.to_string(),
+    };
+    let files = [
+        ("user-data", Some(read_template(&config.user_data)?)),
+        ("meta-data", Some(meta_data)),
+        (
+            "network-config",
+            config
+                .network_config
+                .as_deref()
+                .map(read_template)
+                .transpose()?,
+        ),
+    ];
+
+    for (name, contents) in files {
+        let Some(contents) = contents else {
+            continue;
+        };
+        fs::write(
+            boot.join(name),
+            template::render_for_card(&contents, variables)?,
+        )?;
+        println!("Wrote cloud-init {name}");
+    }
+    Ok(())
+}
+
+fn read_template(path: &Path) -> io::Result<String> {
+    fs::read_to_string(path).map_err(|error| {
+        io::Error::new(
+            error.kind(),
+            format!("Could not read cloud-init template {path:?}: {error}"),
+        )
+    })
+}
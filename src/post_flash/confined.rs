@@ -0,0 +1,275 @@
+//! Writing into a mounted partition of the card without leaving it. The image decides what its
+//! symbolic links point at, and an absolute one resolves against the cloner while the card is
+//! mounted. Paths are resolved with `openat2` and `RESOLVE_IN_ROOT` instead, which treats the
+//! partition as `/`: links are followed as the card would follow them once it boots, and can't
+//! get out. The last component of a path is never followed, what is written replaces a link
+//! rather than going through it.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use nix::dir::Dir;
+use nix::errno::Errno;
+use nix::fcntl::{self, AtFlags, OFlag, OpenHow, ResolveFlag};
+use nix::sys::stat::{self, FileStat, Mode, SFlag};
+use nix::unistd::{self, UnlinkatFlags};
+
+/// Directories created on the way are created like `mkdir -p` would.
+const DIRECTORY_MODE: u32 = 0o755;
+
+fn mode(mode: u32) -> Mode {
+    Mode::from_bits_truncate(mode)
+}
+
+/// Whether `stat` is of a directory, not following links.
+pub fn is_dir(stat: &FileStat) -> bool {
+    SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFDIR
+}
+
+/// Whether `stat` is of a regular file, not following links.
+pub fn is_file(stat: &FileStat) -> bool {
+    SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFREG
+}
+
+/// `path` relative to the root whether or not it starts with `/`, refusing `..`.
+fn relative(path: &Path) -> io::Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Refusing {path:?}, it leads out of the partition"),
+                ))
+            }
+        }
+    }
+    Ok(relative)
+}
+
+/// A mounted partition, which the paths given to it are resolved in.
+pub struct Root {
+    fd: OwnedFd,
+}
+
+impl Root {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let fd = fcntl::open(
+            path,
+            OFlag::O_DIRECTORY | OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        Ok(Self { fd })
+    }
+
+    /// Opens the directory at `relative`, following links within the root.
+    fn resolve(&self, relative: &Path) -> nix::Result<OwnedFd> {
+        let path = match relative.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => relative,
+        };
+        let how = OpenHow::new()
+            .flags(OFlag::O_DIRECTORY | OFlag::O_RDONLY | OFlag::O_CLOEXEC)
+            .resolve(ResolveFlag::RESOLVE_IN_ROOT);
+        fcntl::openat2(&self.fd, path, how)
+    }
+
+    /// The directory at `path`, `None` if it doesn't exist. With `create` the directories
+    /// missing on the way are created.
+    pub fn dir(&self, path: &Path, create: bool) -> io::Result<Option<OwnedFd>> {
+        let mut resolved = PathBuf::new();
+        let mut dir = self.resolve(&resolved)?;
+        for name in relative(path)?.iter() {
+            resolved.push(name);
+            dir = match self.resolve(&resolved) {
+                Ok(next) => next,
+                Err(Errno::ENOENT) if create => {
+                    stat::mkdirat(&dir, name, mode(DIRECTORY_MODE))?;
+                    self.resolve(&resolved)?
+                }
+                Err(Errno::ENOENT) => return Ok(None),
+                Err(errno) => return Err(errno.into()),
+            };
+        }
+        Ok(Some(dir))
+    }
+
+    /// The directory `path` is in and its name there.
+    fn parent(&self, path: &Path, create: bool) -> io::Result<Option<(OwnedFd, OsString)>> {
+        let relative = relative(path)?;
+        let Some(name) = relative.file_name() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{path:?} names no file"),
+            ));
+        };
+        let parent = relative.parent().unwrap_or(Path::new(""));
+        Ok(self
+            .dir(parent, create)?
+            .map(|dir| (dir, name.to_os_string())))
+    }
+
+    /// What is at `path`, without following it if it is a link. `None` if nothing is.
+    pub fn lstat(&self, path: &Path) -> io::Result<Option<FileStat>> {
+        let Some((dir, name)) = self.parent(path, false)? else {
+            return Ok(None);
+        };
+        match stat::fstatat(&dir, name.as_os_str(), AtFlags::AT_SYMLINK_NOFOLLOW) {
+            Ok(stat) => Ok(Some(stat)),
+            Err(Errno::ENOENT) => Ok(None),
+            Err(errno) => Err(errno.into()),
+        }
+    }
+
+    /// Opens the file at `path` to read it, `None` if there is none. Links are followed, within
+    /// the root.
+    pub fn open_file(&self, path: &Path) -> io::Result<Option<File>> {
+        let how = OpenHow::new()
+            .flags(OFlag::O_RDONLY | OFlag::O_CLOEXEC)
+            .resolve(ResolveFlag::RESOLVE_IN_ROOT);
+        match fcntl::openat2(&self.fd, &relative(path)?, how) {
+            Ok(fd) => Ok(Some(File::from(fd))),
+            Err(Errno::ENOENT) => Ok(None),
+            Err(errno) => Err(errno.into()),
+        }
+    }
+
+    /// Creates the file at `path` with `permissions`, replacing whatever file or link is there.
+    /// Created with them rather than changed after, so secrets are never readable by others.
+    pub fn create(&self, path: &Path, permissions: u32) -> io::Result<File> {
+        let (dir, name) = self
+            .parent(path, true)?
+            .expect("missing directories are created");
+        unlink(&dir, &name)?;
+        let fd = fcntl::openat(
+            &dir,
+            name.as_os_str(),
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            mode(permissions),
+        )?;
+        // The umask may have taken some away.
+        stat::fchmod(&fd, mode(permissions))?;
+        Ok(File::from(fd))
+    }
+
+    pub fn write(&self, path: &Path, contents: &[u8], permissions: u32) -> io::Result<()> {
+        self.create(path, permissions)?.write_all(contents)
+    }
+
+    /// Makes `path` a link to `target`, replacing whatever file or link is there.
+    pub fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        let (dir, name) = self
+            .parent(path, true)?
+            .expect("missing directories are created");
+        unlink(&dir, &name)?;
+        unistd::symlinkat(target, &dir, name.as_os_str())?;
+        Ok(())
+    }
+
+    /// Removes what is at `path`, directories with everything in them. Links are removed
+    /// rather than what they point at.
+    pub fn remove(&self, path: &Path) -> io::Result<()> {
+        let Some((dir, name)) = self.parent(path, false)? else {
+            return Ok(());
+        };
+        match unistd::unlinkat(&dir, name.as_os_str(), UnlinkatFlags::NoRemoveDir) {
+            Ok(()) | Err(Errno::ENOENT) => Ok(()),
+            Err(Errno::EISDIR) => remove_tree(&dir, &name),
+            Err(errno) => Err(errno.into()),
+        }
+    }
+
+    /// Names in the directory at `path`, none if there is no such directory.
+    pub fn entries(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let Some(dir) = self.dir(path, false)? else {
+            return Ok(vec![]);
+        };
+        names(Dir::from_fd(dir)?)
+    }
+}
+
+fn names(mut dir: Dir) -> io::Result<Vec<OsString>> {
+    let mut names = vec![];
+    for entry in dir.iter() {
+        let name = OsStr::from_bytes(entry?.file_name().to_bytes()).to_os_string();
+        if name != "." && name != ".." {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// Removes the file or link `name` in `dir` if there is one.
+fn unlink(dir: &OwnedFd, name: &OsStr) -> io::Result<()> {
+    match unistd::unlinkat(dir, name, UnlinkatFlags::NoRemoveDir) {
+        Ok(()) | Err(Errno::ENOENT) => Ok(()),
+        Err(errno) => Err(errno.into()),
+    }
+}
+
+/// Removes the directory `name` in `parent` with everything in it, following no links.
+fn remove_tree(parent: &OwnedFd, name: &OsStr) -> io::Result<()> {
+    let flags = OFlag::O_DIRECTORY | OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let dir = fcntl::openat(parent, name, flags, Mode::empty())?;
+    for entry in names(Dir::from_fd(dir.try_clone()?)?)? {
+        match unistd::unlinkat(&dir, entry.as_os_str(), UnlinkatFlags::NoRemoveDir) {
+            Ok(()) => {}
+            Err(Errno::EISDIR) => remove_tree(&dir, &entry)?,
+            Err(errno) => return Err(errno.into()),
+        }
+    }
+    unistd::unlinkat(parent, name, UnlinkatFlags::RemoveDir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    use super::*;
+
+    #[test]
+    fn keeps_links_inside_the_partition() {
+        let scratch = std::env::temp_dir().join(format!("confined-{}", std::process::id()));
+        let (partition, host) = (scratch.join("partition"), scratch.join("host"));
+        fs::create_dir_all(partition.join("usr/lib")).unwrap();
+        fs::create_dir_all(&host).unwrap();
+        // Absolute links resolve against the host while the card is mounted, on the card this
+        // one leads to a directory of its own.
+        let on_card = partition.join(host.strip_prefix("/").unwrap());
+        fs::create_dir_all(&on_card).unwrap();
+        symlink(&host, partition.join("etc")).unwrap();
+        symlink("/usr/lib", partition.join("lib")).unwrap();
+        symlink(host.join("token"), partition.join("token")).unwrap();
+
+        let root = Root::open(&partition).unwrap();
+        root.write(Path::new("/etc/secret"), b"key", 0o600).unwrap();
+        root.write(Path::new("lib/module"), b"", 0o644).unwrap();
+        root.write(Path::new("/token"), b"token", 0o600).unwrap();
+        let escaped = root.write(Path::new("../secret"), b"key", 0o600);
+        let host_files = fs::read_dir(&host).unwrap().count();
+        let secret_mode = fs::metadata(on_card.join("secret"))
+            .unwrap()
+            .permissions()
+            .mode();
+        let through_lib = partition.join("usr/lib/module").exists();
+        let token = root.lstat(Path::new("token")).unwrap().unwrap();
+        root.remove(Path::new("usr")).unwrap();
+        let usr_gone = root.lstat(Path::new("usr")).unwrap().is_none();
+        fs::remove_dir_all(&scratch).unwrap();
+
+        assert_eq!(host_files, 0);
+        assert!(escaped.is_err());
+        assert_eq!(secret_mode & 0o777, 0o600);
+        assert!(through_lib);
+        assert!(is_file(&token));
+        assert!(usr_gone);
+    }
+}
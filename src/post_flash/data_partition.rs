@@ -0,0 +1,236 @@
+//! An extra partition in the space behind the last partition of the image, e.g. for the data
+//! volume of an appliance. Created in the partition table, formatted and seeded with files.
+
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::process::Command;
+
+use super::confined::Root;
+use super::disk_ids::random_guid;
+use super::gpt::{parse_guid, Gpt};
+use super::mount::{self, Mount};
+use super::overlay;
+use crate::config::{DataFilesystem, DataPartitionConfig};
+use crate::devices;
+use crate::flash;
+use crate::partition_table;
+
+/// Partitions start on a multiple of this, as partitioning tools do
+const ALIGNMENT: u64 = 4 * 1024 * 1024;
+/// Cylinder/head/sector addresses too large to express, telling to use the LBA ones instead
+const MBR_CHS_UNUSED: [u8; 3] = [0xFE, 0xFF, 0xFF];
+const MBR_TYPE_LINUX: u8 = 0x83;
+const MBR_TYPE_FAT32_LBA: u8 = 0x0C;
+const GPT_TYPE_LINUX: &str = "0fc63daf-8483-4772-8e79-3d69d8477de4";
+const GPT_TYPE_BASIC_DATA: &str = "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7";
+/// UTF-16 code units in the name of a GPT partition
+const GPT_NAME_LENGTH: usize = 36;
+
+/// Blocks a new partition spans, inclusive.
+struct Extent {
+    first: u64,
+    last: u64,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+pub fn create(config: &DataPartitionConfig, device: &Path) -> io::Result<()> {
+    let number = add_partition(config, device)?;
+    devices::settle(flash::UDEV_SETTLE_TIMEOUT);
+    let partition = mount::wait_for_partition(device, number)?;
+    format(config, &partition)?;
+    println!("Created data partition {partition:?}");
+
+    // mkfs.ext4 copies the seed itself.
+    if let (Some(seed), DataFilesystem::Vfat) = (&config.seed, config.filesystem) {
+        let mount = Mount::new(&partition, "vfat")?;
+        overlay::copy_tree(seed, &Root::open(mount.path())?, Path::new(""), false)?;
+        mount.unmount()?;
+    }
+    Ok(())
+}
+
+/// Adds the partition to the table and has the kernel pick it up. Returns its number.
+fn add_partition(config: &DataPartitionConfig, device: &Path) -> io::Result<u32> {
+    let mut card = File::options().read(true).write(true).open(device)?;
+    let sector_size = devices::block_sizes(device).logical;
+    let blocks = card.seek(SeekFrom::End(0))? / sector_size;
+    let used_end = partition_table::used_end(&mut card, sector_size)?
+        .ok_or_else(|| io::Error::other(format!("{device:?} has no partition table")))?;
+    let first = used_end.next_multiple_of(ALIGNMENT) / sector_size;
+    let extent = |last_usable: u64| {
+        let last = match config.size {
+            Some(size) => first + size / sector_size - 1,
+            None => last_usable,
+        };
+        if first > last || last > last_usable {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("No room for the data partition on {device:?}"),
+            ));
+        }
+        Ok(Extent { first, last })
+    };
+
+    let number = match Gpt::read(&card, sector_size)? {
+        Some(mut gpt) => {
+            gpt.move_backup_to_end(blocks);
+            let extent = extent(gpt.last_usable_lba())?;
+            let number = add_gpt_entry(config, &mut gpt, &extent)?;
+            gpt.write(&card)?;
+            number
+        }
+        None => add_mbr_entry(config, &card, &extent(blocks - 1)?)?,
+    };
+    card.sync_all()?;
+    flash::reread_partitions(device, &card)?;
+    Ok(number)
+}
+
+fn add_gpt_entry(config: &DataPartitionConfig, gpt: &mut Gpt, extent: &Extent) -> io::Result<u32> {
+    let partition_type = match &config.partition_type {
+        Some(partition_type) => partition_type.as_str(),
+        None => match config.filesystem {
+            DataFilesystem::Ext4 => GPT_TYPE_LINUX,
+            DataFilesystem::Vfat => GPT_TYPE_BASIC_DATA,
+        },
+    };
+    let partition_type = parse_guid(partition_type)
+        .ok_or_else(|| invalid(format!("{partition_type:?} is no GPT partition type")))?;
+    let guid = random_guid()?;
+    let (index, entry) = gpt
+        .entries_mut()
+        .enumerate()
+        .find(|(_, entry)| entry[..16].iter().all(|byte| *byte == 0))
+        .ok_or_else(|| io::Error::other("The GPT has no free entry"))?;
+    entry.fill(0);
+    entry[..16].copy_from_slice(&partition_type);
+    entry[16..32].copy_from_slice(&guid);
+    entry[32..40].copy_from_slice(&extent.first.to_le_bytes());
+    entry[40..48].copy_from_slice(&extent.last.to_le_bytes());
+    let name = config.label.encode_utf16().take(GPT_NAME_LENGTH);
+    for (unit, bytes) in name.zip(entry[56..].chunks_exact_mut(2)) {
+        bytes.copy_from_slice(&unit.to_le_bytes());
+    }
+    Ok(index as u32 + 1)
+}
+
+fn add_mbr_entry(config: &DataPartitionConfig, card: &File, extent: &Extent) -> io::Result<u32> {
+    let partition_type = match &config.partition_type {
+        Some(partition_type) => u8::from_str_radix(partition_type, 16)
+            .map_err(|_| invalid(format!("{partition_type:?} is no MBR partition type")))?,
+        None => match config.filesystem {
+            DataFilesystem::Ext4 => MBR_TYPE_LINUX,
+            DataFilesystem::Vfat => MBR_TYPE_FAT32_LBA,
+        },
+    };
+    let (Ok(first), Ok(blocks)) = (
+        u32::try_from(extent.first),
+        u32::try_from(extent.last - extent.first + 1),
+    ) else {
+        return Err(invalid(
+            "MBR partitions end at 2 TiB, set a smaller size".to_string(),
+        ));
+    };
+
+    let mut mbr = [0; 512];
+    card.read_exact_at(&mut mbr, 0)?;
+    let entries = &mut mbr[446..510];
+    if entries
+        .chunks_exact(16)
+        .any(|entry| entry[4] == partition_table::MBR_TYPE_GPT_PROTECTIVE)
+    {
+        return Err(io::Error::other("The GPT of the card isn't understood"));
+    }
+    let (index, entry) = entries
+        .chunks_exact_mut(16)
+        .enumerate()
+        .find(|(_, entry)| entry[4] == 0)
+        .ok_or_else(|| io::Error::other("All four primary partitions are in use"))?;
+    entry.fill(0);
+    entry[1..4].copy_from_slice(&MBR_CHS_UNUSED);
+    entry[4] = partition_type;
+    entry[5..8].copy_from_slice(&MBR_CHS_UNUSED);
+    entry[8..12].copy_from_slice(&first.to_le_bytes());
+    entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+    card.write_all_at(&mbr, 0)?;
+    Ok(index as u32 + 1)
+}
+
+fn format(config: &DataPartitionConfig, partition: &Path) -> io::Result<()> {
+    let mut command = match config.filesystem {
+        DataFilesystem::Ext4 => {
+            let mut command = Command::new("mkfs.ext4");
+            command.arg("-F").arg("-q").arg("-L").arg(&config.label);
+            if let Some(seed) = &config.seed {
+                command.arg("-d").arg(seed);
+            }
+            command
+        }
+        DataFilesystem::Vfat => {
+            let mut command = Command::new("mkfs.vfat");
+            // FAT labels are upper case, mkfs.vfat warns about others.
+            command.arg("-n").arg(config.label.to_uppercase());
+            command
+        }
+    };
+    let status = command
+        .arg(partition)
+        .env("PATH", "/usr/sbin:/sbin:/usr/bin:/bin")
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "Formatting {partition:?} failed with {status}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition_table::{Partition, MBR_SIGNATURE};
+
+    #[test]
+    fn adds_an_mbr_partition_behind_the_others() {
+        // One partition over blocks 8192 to 16383.
+        let mut disk = vec![0; 512];
+        disk[446 + 4] = MBR_TYPE_LINUX;
+        disk[446 + 8..446 + 12].copy_from_slice(&8192u32.to_le_bytes());
+        disk[446 + 12..446 + 16].copy_from_slice(&8192u32.to_le_bytes());
+        disk[510..512].copy_from_slice(&MBR_SIGNATURE);
+        let path = std::env::temp_dir().join(format!("data-partition-{}.img", std::process::id()));
+        std::fs::write(&path, &disk).unwrap();
+        let card = File::options().read(true).write(true).open(&path).unwrap();
+        let config = DataPartitionConfig {
+            size: None,
+            filesystem: DataFilesystem::Vfat,
+            partition_type: None,
+            label: "data".to_string(),
+            seed: None,
+        };
+
+        let extent = Extent {
+            first: 16384,
+            last: 32767,
+        };
+        assert_eq!(add_mbr_entry(&config, &card, &extent).unwrap(), 2);
+        let table = partition_table::read(&mut File::open(&path).unwrap(), 512)
+            .unwrap()
+            .unwrap();
+        let disk = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            table.partitions[1],
+            Partition {
+                start: 16384 * 512,
+                end: 32768 * 512,
+            }
+        );
+        assert_eq!(disk[446 + 16 + 4], MBR_TYPE_FAT32_LBA);
+    }
+}
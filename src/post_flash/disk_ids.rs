@@ -0,0 +1,118 @@
+//! New disk and partition identifiers for every card. Clones of one image otherwise share them,
+//! and a host with two clones attached can't tell their partitions apart by PARTUUID.
+//!
+//! On an MBR card the PARTUUIDs are the disk identifier and the partition number, on a GPT card
+//! every partition has a GUID of its own. The backup GPT is updated along with the primary one.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+
+use super::gpt::{guid_text, Gpt};
+use super::references::Renamed;
+use crate::partition_table::{MBR_SIGNATURE, MBR_TYPE_GPT_PROTECTIVE};
+
+/// Where the disk identifier is in the MBR
+const MBR_DISK_ID: usize = 440;
+
+pub(super) fn random<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// A random (version 4) GUID, in the byte order GPT stores them.
+pub(super) fn random_guid() -> io::Result<[u8; 16]> {
+    let mut guid = random::<16>()?;
+    // The version is in the high bits of the third field, which is stored little-endian.
+    guid[7] = (guid[7] & 0x0F) | 0x40;
+    guid[8] = (guid[8] & 0x3F) | 0x80;
+    Ok(guid)
+}
+
+/// Gives the partition table of `device` new random identifiers, with block addresses counting
+/// in `sector_size` bytes. Returns what changed, nothing if there is no partition table.
+pub fn randomize(device: &File, sector_size: u64) -> io::Result<Vec<Renamed>> {
+    let mut mbr = [0; 512];
+    device.read_exact_at(&mut mbr, 0)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(vec![]);
+    }
+    let protective = mbr[446..510]
+        .chunks_exact(16)
+        .any(|entry| entry[4] == MBR_TYPE_GPT_PROTECTIVE);
+    if protective {
+        return randomize_gpt(device, sector_size);
+    }
+
+    let old = u32::from_le_bytes(mbr[MBR_DISK_ID..MBR_DISK_ID + 4].try_into().unwrap());
+    let new = loop {
+        let new = u32::from_le_bytes(random()?);
+        // 0 means no identifier at all.
+        if new != 0 && new != old {
+            break new;
+        }
+    };
+    device.write_all_at(&new.to_le_bytes(), MBR_DISK_ID as u64)?;
+    Ok(vec![Renamed {
+        kind: "PARTUUID",
+        old: format!("{old:08x}-"),
+        new: format!("{new:08x}-"),
+    }])
+}
+
+fn randomize_gpt(device: &File, sector_size: u64) -> io::Result<Vec<Renamed>> {
+    let Some(mut gpt) = Gpt::read(device, sector_size)? else {
+        return Ok(vec![]);
+    };
+    let mut renamed = vec![];
+    for entry in gpt.entries_mut() {
+        if entry[..16].iter().all(|byte| *byte == 0) {
+            continue;
+        }
+        let guid = random_guid()?;
+        renamed.push(Renamed {
+            kind: "PARTUUID",
+            old: guid_text(&entry[16..32]),
+            new: guid_text(&guid),
+        });
+        entry[16..32].copy_from_slice(&guid);
+    }
+    gpt.set_disk_guid(&random_guid()?);
+    gpt.write(device)?;
+    Ok(renamed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_flash::gpt;
+
+    #[test]
+    fn makes_version_4_guids() {
+        let random = guid_text(&random_guid().unwrap());
+        assert_eq!(random.as_bytes()[14], b'4');
+        assert!(matches!(random.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+    }
+
+    #[test]
+    fn gives_gpt_partitions_new_guids() {
+        let path = std::env::temp_dir().join(format!("disk-ids-{}.img", std::process::id()));
+        std::fs::write(&path, gpt::tests::disk()).unwrap();
+        let device = File::options().read(true).write(true).open(&path).unwrap();
+
+        let renamed = randomize(&device, 512).unwrap();
+        let disk = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Only the one used entry.
+        assert_eq!(renamed.len(), 1);
+        assert_eq!(renamed[0].old, "11111111-1111-1111-1111-111111111111");
+        assert_eq!(renamed[0].new, guid_text(&disk[2 * 512 + 16..2 * 512 + 32]));
+        assert_eq!(disk[2 * 512..3 * 512], disk[28 * 512..29 * 512]);
+        assert_ne!(disk[512 + 56..512 + 72], [0xAA; 16]);
+        assert_eq!(disk[512 + 56..512 + 72], disk[29 * 512 + 56..29 * 512 + 72]);
+        assert!(gpt::tests::checksums_match(&disk, 1));
+        assert!(gpt::tests::checksums_match(&disk, 29));
+    }
+}
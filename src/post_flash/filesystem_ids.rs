@@ -0,0 +1,197 @@
+//! New filesystem UUIDs for every card, on top of the partition identifiers: `UUID=` references
+//! and tools like blkid go by these. ext filesystems get theirs from tune2fs, which also rewrites
+//! the metadata checksums seeded by the UUID. FAT only has a volume serial in its boot sector.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::process::Command;
+
+use super::disk_ids::random;
+use super::references::Renamed;
+use crate::devices;
+use crate::ext4;
+
+const BOOT_SECTOR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// The extended boot signature, saying a volume serial follows it
+const EXTENDED_BOOT_SIGNATURE: u8 = 0x29;
+
+/// Where the boot sector of a FAT filesystem keeps its volume serial.
+enum Fat {
+    /// FAT32, which keeps a backup of its boot sector at this byte offset
+    Fat32 {
+        backup: Option<u64>,
+    },
+    Fat16,
+}
+
+impl Fat {
+    fn detect(boot_sector: &[u8; 512]) -> Option<Self> {
+        if boot_sector[510..512] != BOOT_SECTOR_SIGNATURE {
+            return None;
+        }
+        if boot_sector[0x42] == EXTENDED_BOOT_SIGNATURE && &boot_sector[0x52..0x57] == b"FAT32" {
+            let sector_size = u16::from_le_bytes([boot_sector[0x0B], boot_sector[0x0C]]) as u64;
+            let backup = u16::from_le_bytes([boot_sector[0x32], boot_sector[0x33]]) as u64;
+            return Some(Fat::Fat32 {
+                backup: (backup != 0).then_some(backup * sector_size),
+            });
+        }
+        // FAT12 as well, as far as the serial goes.
+        if boot_sector[0x26] == EXTENDED_BOOT_SIGNATURE && &boot_sector[0x36..0x39] == b"FAT" {
+            return Some(Fat::Fat16);
+        }
+        None
+    }
+
+    fn serial_offset(&self) -> usize {
+        match self {
+            Fat::Fat32 { .. } => 0x43,
+            Fat::Fat16 => 0x27,
+        }
+    }
+}
+
+/// A FAT volume serial as blkid shows it.
+fn serial_text(serial: u32) -> String {
+    format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF)
+}
+
+/// Gives every filesystem on `device` a new UUID or volume serial, skipping partitions that
+/// hold neither ext nor FAT. Returns what changed.
+pub fn randomize_all(device: &Path) -> io::Result<Vec<Renamed>> {
+    let mut renamed = vec![];
+    for partition in devices::partitions(device)? {
+        if let Some(changed) = randomize(&partition)? {
+            println!(
+                "Replaced UUID {} with {} on {partition:?}",
+                changed.old, changed.new
+            );
+            renamed.push(changed);
+        }
+    }
+    Ok(renamed)
+}
+
+fn randomize(partition: &Path) -> io::Result<Option<Renamed>> {
+    let mut file = File::options().read(true).write(true).open(partition)?;
+    if let Some(old) = ext4::uuid(&mut file, 0)? {
+        drop(file);
+        return randomize_ext(partition, old).map(Some);
+    }
+
+    let mut boot_sector = [0; 512];
+    file.read_exact_at(&mut boot_sector, 0)?;
+    let Some(fat) = Fat::detect(&boot_sector) else {
+        return Ok(None);
+    };
+    let offset = fat.serial_offset();
+    let old = u32::from_le_bytes(boot_sector[offset..offset + 4].try_into().unwrap());
+    let new = loop {
+        let new = u32::from_le_bytes(random()?);
+        if new != 0 && new != old {
+            break new;
+        }
+    };
+    file.write_all_at(&new.to_le_bytes(), offset as u64)?;
+    if let Fat::Fat32 {
+        backup: Some(backup),
+    } = fat
+    {
+        let mut backup_sector = [0; 512];
+        file.read_exact_at(&mut backup_sector, backup)?;
+        // Only if it is one, a corrupt backup isn't made any worse by leaving it.
+        if Fat::detect(&backup_sector).is_some() {
+            file.write_all_at(&new.to_le_bytes(), backup + offset as u64)?;
+        }
+    }
+    file.sync_all()?;
+    Ok(Some(Renamed {
+        kind: "UUID",
+        old: serial_text(old),
+        new: serial_text(new),
+    }))
+}
+
+fn randomize_ext(partition: &Path, old: String) -> io::Result<Renamed> {
+    // tune2fs only changes the UUID of a freshly checked filesystem, and images are usually made
+    // from one that was mounted since. -p only fixes what is safe to fix unattended.
+    let status = Command::new("e2fsck")
+        .arg("-f")
+        .arg("-p")
+        .arg(partition)
+        .env("PATH", "/usr/sbin:/sbin:/usr/bin:/bin")
+        .status()?;
+    // 1 means errors were corrected.
+    if !matches!(status.code(), Some(0 | 1)) {
+        return Err(io::Error::other(format!(
+            "e2fsck of {partition:?} failed with {status}"
+        )));
+    }
+    let status = Command::new("tune2fs")
+        .arg("-U")
+        .arg("random")
+        .arg(partition)
+        .env("PATH", "/usr/sbin:/sbin:/usr/bin:/bin")
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "tune2fs of {partition:?} failed with {status}"
+        )));
+    }
+    let new = ext4::uuid(&mut File::open(partition)?, 0)?
+        .ok_or_else(|| io::Error::other(format!("{partition:?} is no ext filesystem anymore")))?;
+    Ok(Renamed {
+        kind: "UUID",
+        old,
+        new,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_the_uuid_of_ext_filesystems() {
+        let path = std::env::temp_dir().join(format!("filesystem-ids-{}.img", std::process::id()));
+        File::create(&path)
+            .unwrap()
+            .set_len(8 * 1024 * 1024)
+            .unwrap();
+        let created = Command::new("mkfs.ext4")
+            .arg("-q")
+            .arg("-U")
+            .arg("01234567-89ab-cdef-0123-456789abcdef")
+            .arg(&path)
+            .env("PATH", "/usr/sbin:/sbin:/usr/bin:/bin")
+            .status();
+        if !created.is_ok_and(|status| status.success()) {
+            std::fs::remove_file(&path).unwrap();
+            println!("mkfs.ext4 isn't available, skipping");
+            return;
+        }
+
+        let renamed = randomize(&path).unwrap().unwrap();
+        let uuid = ext4::uuid(&mut File::open(&path).unwrap(), 0).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(renamed.old, "01234567-89ab-cdef-0123-456789abcdef");
+        assert_eq!(Some(renamed.new), uuid);
+        assert_ne!(uuid.unwrap(), renamed.old);
+    }
+
+    #[test]
+    fn finds_the_serial_of_fat32() {
+        let mut boot_sector = [0; 512];
+        boot_sector[0x0B..0x0D].copy_from_slice(&512u16.to_le_bytes());
+        boot_sector[0x32..0x34].copy_from_slice(&6u16.to_le_bytes());
+        boot_sector[0x42] = EXTENDED_BOOT_SIGNATURE;
+        boot_sector[0x52..0x5A].copy_from_slice(b"FAT32   ");
+        boot_sector[510..512].copy_from_slice(&BOOT_SECTOR_SIGNATURE);
+        let fat = Fat::detect(&boot_sector).unwrap();
+        assert!(matches!(fat, Fat::Fat32 { backup: Some(3072) }));
+        assert_eq!(fat.serial_offset(), 0x43);
+        assert_eq!(serial_text(0x1A2B3C4D), "1A2B-3C4D");
+    }
+}
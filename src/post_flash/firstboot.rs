@@ -0,0 +1,61 @@
+//! Installs a one-shot systemd unit that runs a script on the first boot of the card, and then
+//! removes itself.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::confined::Root;
+use crate::config::FirstbootConfig;
+use crate::template;
+
+const SCRIPT_PATH: &str = "usr/local/sbin/rpi-sd-cloner-firstboot";
+const UNIT_NAME: &str = "rpi-sd-cloner-firstboot.service";
+
+const UNIT: &str = "\
+[Unit]
+Description=rpi-sd-cloner first boot script
+Wants=network-online.target
+After=network-online.target
+ConditionPathExists=/usr/local/sbin/rpi-sd-cloner-firstboot
+
+[Service]
+Type=oneshot
+ExecStart=/usr/local/sbin/rpi-sd-cloner-firstboot
+ExecStartPost=/bin/rm -f /usr/local/sbin/rpi-sd-cloner-firstboot
+ExecStartPost=/bin/systemctl disable rpi-sd-cloner-firstboot.service
+
+[Install]
+WantedBy=multi-user.target
+";
+
+pub fn install(
+    config: &FirstbootConfig,
+    rootfs: &Path,
+    variables: &[(&str, String)],
+) -> io::Result<()> {
+    let script = fs::read_to_string(&config.script).map_err(|error| {
+        io::Error::new(
+            error.kind(),
+            format!(
+                "Could not read firstboot script {:?}: {error}",
+                config.script
+            ),
+        )
+    })?;
+    let script = template::render_for_card(&script, variables)?;
+
+    let root = Root::open(rootfs)?;
+    root.write(Path::new(SCRIPT_PATH), script.as_bytes(), 0o755)?;
+    let unit_dir = Path::new("etc/systemd/system");
+    root.write(&unit_dir.join(UNIT_NAME), UNIT.as_bytes(), 0o644)?;
+
+    // Equivalent to `systemctl enable`, the link has to be absolute within the target rootfs.
+    root.symlink(
+        &Path::new("/etc/systemd/system").join(UNIT_NAME),
+        &unit_dir.join("multi-user.target.wants").join(UNIT_NAME),
+    )?;
+
+    println!("Installed firstboot script from {:?}", config.script);
+    Ok(())
+}
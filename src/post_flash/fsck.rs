@@ -0,0 +1,39 @@
+//! Read-only filesystem check of every partition on the card, as a sanity layer on top of the
+//! byte-level verification.
+
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use crate::devices;
+use crate::history::FsckResult;
+
+pub fn check_all(device: &Path) -> io::Result<Vec<FsckResult>> {
+    devices::partitions(device)?
+        .iter()
+        .map(|partition| check(partition))
+        .collect()
+}
+
+fn check(partition: &Path) -> io::Result<FsckResult> {
+    // -n opens the filesystem read-only and answers "no" to every repair question.
+    let output = Command::new("fsck")
+        .arg("-n")
+        .arg(partition)
+        .env("PATH", "/usr/sbin:/sbin:/usr/bin:/bin")
+        .output()?;
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+
+    let result = FsckResult {
+        partition: partition.to_path_buf(),
+        clean: output.status.success(),
+        exit_code: output.status.code(),
+        output: text.trim().to_string(),
+    };
+    println!(
+        "fsck of {partition:?}: clean={} exit_code={:?}",
+        result.clean, result.exit_code
+    );
+    Ok(result)
+}
@@ -0,0 +1,296 @@
+//! Changing a GPT in place. Both copies of it are rewritten with valid checksums, or firmware and
+//! partitioning tools take the table for corrupt.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use flate2::Crc;
+
+use crate::partition_table::GPT_SIGNATURE;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// A GUID as Linux shows it in PARTUUIDs. The first three fields are stored little-endian.
+pub fn guid_text(guid: &[u8]) -> String {
+    let hex = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>()
+    };
+    let reversed = |bytes: &[u8]| hex(&bytes.iter().rev().copied().collect::<Vec<u8>>());
+    format!(
+        "{}-{}-{}-{}-{}",
+        reversed(&guid[0..4]),
+        reversed(&guid[4..6]),
+        reversed(&guid[6..8]),
+        hex(&guid[8..10]),
+        hex(&guid[10..16])
+    )
+}
+
+/// The reverse of [`guid_text`], `None` unless `text` is a GUID.
+pub fn parse_guid(text: &str) -> Option<[u8; 16]> {
+    let fields: Vec<&str> = text.split('-').collect();
+    let lengths: Vec<usize> = fields.iter().map(|field| field.len()).collect();
+    if lengths != [8, 4, 4, 4, 12]
+        || !text
+            .chars()
+            .all(|char| char == '-' || char.is_ascii_hexdigit())
+    {
+        return None;
+    }
+    let mut guid = Vec::with_capacity(16);
+    for (index, field) in fields.iter().enumerate() {
+        let mut bytes = (0..field.len())
+            .step_by(2)
+            .map(|start| u8::from_str_radix(&field[start..start + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        if index < 3 {
+            bytes.reverse();
+        }
+        guid.extend(bytes);
+    }
+    guid.try_into().ok()
+}
+
+/// One of the two headers, the block it is in read whole.
+struct Header {
+    bytes: Vec<u8>,
+    /// Bytes the header checksum covers
+    size: usize,
+    lba: u64,
+}
+
+impl Header {
+    fn read(device: &File, lba: u64, sector_size: u64) -> io::Result<Option<Self>> {
+        let mut bytes = vec![0; sector_size as usize];
+        device.read_exact_at(&mut bytes, lba * sector_size)?;
+        if &bytes[..8] != GPT_SIGNATURE {
+            return Ok(None);
+        }
+        let size = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+        if !(92..=bytes.len()).contains(&size) {
+            return Ok(None);
+        }
+        Ok(Some(Self { bytes, size, lba }))
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn set_u64(&mut self, offset: usize, value: u64) {
+        self.bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn entries_lba(&self) -> u64 {
+        self.u64(72)
+    }
+
+    /// Writes the entries and then the header, with the checksums of both updated.
+    fn write(&mut self, device: &File, sector_size: u64, entries: &[u8]) -> io::Result<()> {
+        device.write_all_at(entries, self.entries_lba() * sector_size)?;
+        self.bytes[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+        // The header checksum covers the header with the checksum itself zeroed.
+        self.bytes[16..20].fill(0);
+        let checksum = crc32(&self.bytes[..self.size]);
+        self.bytes[16..20].copy_from_slice(&checksum.to_le_bytes());
+        device.write_all_at(&self.bytes, self.lba * sector_size)
+    }
+}
+
+/// The primary GPT of a device, and the backup if the primary one points to a valid one.
+pub struct Gpt {
+    primary: Header,
+    backup: Option<Header>,
+    entries: Vec<u8>,
+    entry_size: usize,
+    sector_size: u64,
+    /// Where the backup was before it was moved, to be wiped
+    moved_from: Option<u64>,
+}
+
+impl Gpt {
+    /// `None` if there is no GPT we understand. Block addresses count in `sector_size` bytes.
+    pub fn read(device: &File, sector_size: u64) -> io::Result<Option<Self>> {
+        let Some(primary) = Header::read(device, 1, sector_size)? else {
+            return Ok(None);
+        };
+        let count = primary.u32(80) as usize;
+        let entry_size = primary.u32(84) as usize;
+        if entry_size < 128 || count > 1024 {
+            return Ok(None);
+        }
+        let mut entries = vec![0; count * entry_size];
+        device.read_exact_at(&mut entries, primary.entries_lba() * sector_size)?;
+
+        let backup_lba = primary.u64(32);
+        let backup = match Header::read(device, backup_lba, sector_size) {
+            Ok(backup) => backup,
+            Err(error) => {
+                println!("Got error when reading the backup GPT: {error:?}");
+                None
+            }
+        };
+        if backup.is_none() {
+            println!("No backup GPT at block {backup_lba}, leaving it");
+        }
+        Ok(Some(Self {
+            primary,
+            backup,
+            entries,
+            entry_size,
+            sector_size,
+            moved_from: None,
+        }))
+    }
+
+    /// All entries, the unused ones with an all-zero type GUID.
+    pub fn entries_mut(&mut self) -> impl Iterator<Item = &mut [u8]> {
+        self.entries.chunks_exact_mut(self.entry_size)
+    }
+
+    pub fn set_disk_guid(&mut self, guid: &[u8; 16]) {
+        for header in [Some(&mut self.primary), self.backup.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            header.bytes[56..72].copy_from_slice(guid);
+        }
+    }
+
+    /// Last block partitions may use.
+    pub fn last_usable_lba(&self) -> u64 {
+        self.primary.u64(48)
+    }
+
+    /// Moves the backup to the last block of a device of `blocks` blocks, where it belongs. An
+    /// image written to a larger card has it where the image ended, and the space behind it can't
+    /// be partitioned until it is moved.
+    pub fn move_backup_to_end(&mut self, blocks: u64) {
+        let last_lba = blocks - 1;
+        let entries_blocks = (self.entries.len() as u64).div_ceil(self.sector_size);
+        let old_lba = self.primary.u64(32);
+        if old_lba == last_lba {
+            return;
+        }
+        let mut backup = Header {
+            bytes: self.primary.bytes.clone(),
+            size: self.primary.size,
+            lba: last_lba,
+        };
+        backup.set_u64(24, last_lba);
+        backup.set_u64(32, 1);
+        backup.set_u64(72, last_lba - entries_blocks);
+        for header in [&mut self.primary, &mut backup] {
+            header.set_u64(48, last_lba - entries_blocks - 1);
+        }
+        self.primary.set_u64(32, last_lba);
+        self.backup = Some(backup);
+        self.moved_from = Some(old_lba);
+    }
+
+    /// Writes the backup first, so a card pulled halfway has at least one valid copy.
+    pub fn write(&mut self, device: &File) -> io::Result<()> {
+        if let Some(backup) = &mut self.backup {
+            backup.write(device, self.sector_size, &self.entries)?;
+        }
+        if let Some(old_lba) = self.moved_from.take() {
+            // A stale header later in the disk confuses tools looking for the backup.
+            device.write_all_at(
+                &vec![0; self.sector_size as usize],
+                old_lba * self.sector_size,
+            )?;
+        }
+        self.primary.write(device, self.sector_size, &self.entries)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::partition_table::{MBR_SIGNATURE, MBR_TYPE_GPT_PROTECTIVE};
+
+    /// A disk of 40 blocks with the GPT of a 30 block image: primary header in block 1 with
+    /// entries in 2, backup in 29 with entries in 28, one partition.
+    pub(in crate::post_flash) fn disk() -> Vec<u8> {
+        let mut disk = vec![0; 40 * 512];
+        disk[446 + 4] = MBR_TYPE_GPT_PROTECTIVE;
+        disk[510..512].copy_from_slice(&MBR_SIGNATURE);
+        for (lba, alternate, entries_lba) in [(1usize, 29u64, 2u64), (29, 1, 28)] {
+            let header = &mut disk[lba * 512..(lba + 1) * 512];
+            header[..8].copy_from_slice(GPT_SIGNATURE);
+            header[12..16].copy_from_slice(&92u32.to_le_bytes());
+            header[24..32].copy_from_slice(&(lba as u64).to_le_bytes());
+            header[32..40].copy_from_slice(&alternate.to_le_bytes());
+            header[48..56].copy_from_slice(&27u64.to_le_bytes());
+            header[56..72].fill(0xAA);
+            header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+            header[80..84].copy_from_slice(&4u32.to_le_bytes());
+            header[84..88].copy_from_slice(&128u32.to_le_bytes());
+            let entry = &mut disk[entries_lba as usize * 512..];
+            entry[0] = 1;
+            entry[16..32].fill(0x11);
+            entry[32..40].copy_from_slice(&3u64.to_le_bytes());
+            entry[40..48].copy_from_slice(&20u64.to_le_bytes());
+        }
+        disk
+    }
+
+    /// Whether the header at `lba` and its entries have valid checksums.
+    pub(in crate::post_flash) fn checksums_match(disk: &[u8], lba: usize) -> bool {
+        let mut header = disk[lba * 512..lba * 512 + 92].to_vec();
+        let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap()) as usize;
+        let entries = &disk[entries_lba * 512..entries_lba * 512 + 4 * 128];
+        let checksum = header[16..20].to_vec();
+        header[16..20].fill(0);
+        crc32(&header).to_le_bytes() == checksum[..]
+            && crc32(entries).to_le_bytes() == header[88..92]
+    }
+
+    #[test]
+    fn shows_guids_like_linux() {
+        // The EFI system partition type, as stored on disk.
+        let guid = [
+            0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E,
+            0xC9, 0x3B,
+        ];
+        assert_eq!(guid_text(&guid), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+        assert_eq!(
+            parse_guid("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"),
+            Some(guid)
+        );
+        assert_eq!(parse_guid("c12a7328-f81f-11d2-ba4b"), None);
+        assert_eq!(parse_guid("c12a7328-f81f-11d2-ba4b-00a0c93ec9+b"), None);
+    }
+
+    #[test]
+    fn moves_the_backup_to_the_end() {
+        let path = std::env::temp_dir().join(format!("gpt-{}.img", std::process::id()));
+        std::fs::write(&path, disk()).unwrap();
+        let device = File::options().read(true).write(true).open(&path).unwrap();
+
+        let mut gpt = Gpt::read(&device, 512).unwrap().unwrap();
+        gpt.move_backup_to_end(40);
+        assert_eq!(gpt.last_usable_lba(), 37);
+        gpt.write(&device).unwrap();
+        let disk = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(checksums_match(&disk, 1));
+        assert!(checksums_match(&disk, 39));
+        assert_eq!(disk[38 * 512..39 * 512], disk[2 * 512..3 * 512]);
+        assert!(disk[29 * 512..30 * 512].iter().all(|byte| *byte == 0));
+        assert_eq!(disk[512 + 32..512 + 40], 39u64.to_le_bytes());
+    }
+}
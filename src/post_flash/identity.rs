@@ -0,0 +1,37 @@
+//! Strips the per-machine identities out of the golden image, so every card generates its own on
+//! first boot.
+
+use std::io;
+use std::path::Path;
+
+use super::confined::{self, Root};
+
+pub fn reset(rootfs: &Path) -> io::Result<()> {
+    let root = Root::open(rootfs)?;
+    // An empty (rather than missing) machine-id makes systemd generate a new one on boot.
+    let machine_id = Path::new("/etc/machine-id");
+    if root.lstat(machine_id)?.is_some() {
+        root.write(machine_id, b"", 0o444)?;
+        println!("Truncated {machine_id:?}");
+    }
+
+    // Debian links this to /etc/machine-id, but older images have a separate copy.
+    let dbus_machine_id = Path::new("/var/lib/dbus/machine-id");
+    if root
+        .lstat(dbus_machine_id)?
+        .is_some_and(|stat| confined::is_file(&stat))
+    {
+        root.remove(dbus_machine_id)?;
+        println!("Removed {dbus_machine_id:?}");
+    }
+
+    let ssh_dir = Path::new("/etc/ssh");
+    for name in root.entries(ssh_dir)? {
+        if name.to_string_lossy().starts_with("ssh_host_") {
+            let path = ssh_dir.join(name);
+            root.remove(&path)?;
+            println!("Removed {path:?}");
+        }
+    }
+    Ok(())
+}
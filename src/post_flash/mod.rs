@@ -0,0 +1,198 @@
+//! Customization of the card after the image was written and verified.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+
+use crate::config::{Config, PostFlashConfig};
+use crate::devices;
+use crate::flash;
+use crate::history::{self, FlashRecord};
+use crate::template;
+
+mod boot_files;
+mod cloud_init;
+mod confined;
+mod data_partition;
+mod disk_ids;
+mod filesystem_ids;
+mod firstboot;
+mod fsck;
+mod gpt;
+mod identity;
+mod mount;
+mod overlay;
+mod provision;
+mod references;
+mod wireguard;
+
+use mount::Mount;
+
+/// Runs all configured post-flash steps against `device`, which must no longer be open for
+/// writing. Results worth keeping are added to `record`.
+pub fn run(config: &Config, device: &Path, record: &mut FlashRecord) -> io::Result<()> {
+    customize(config, device, record)?;
+    if config.post_flash.fsck {
+        record.fsck = fsck::check_all(device)?;
+        if let Some(dirty) = record.fsck.iter().find(|result| !result.clean) {
+            return Err(io::Error::other(format!(
+                "fsck found problems on {:?}",
+                dirty.partition
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn customize(config: &Config, device: &Path, record: &mut FlashRecord) -> io::Result<()> {
+    let post_flash = &config.post_flash;
+    if post_flash.firstboot.is_none()
+        && !post_flash.reset_identity
+        && !post_flash.randomize_disk_ids
+        && !post_flash.randomize_filesystem_ids
+        && post_flash.provision.is_none()
+        && post_flash.cloud_init.is_none()
+        && post_flash.data_partition.is_none()
+        && post_flash.overlay.is_none()
+        && post_flash.wireguard.is_none()
+        && post_flash.boot_files.is_none()
+    {
+        return Ok(());
+    }
+
+    // Before anything is mounted: the kernel has to forget the old partitions, and filesystems
+    // are changed underneath.
+    let mut renamed = vec![];
+    if post_flash.randomize_disk_ids {
+        renamed.extend(randomize_disk_ids(device)?);
+    }
+    if let Some(data_partition) = &post_flash.data_partition {
+        data_partition::create(data_partition, device)?;
+    }
+    if post_flash.randomize_filesystem_ids {
+        renamed.extend(filesystem_ids::randomize_all(device)?);
+    }
+
+    let variables = variables(config, device);
+    let mut partitions = Partitions::new(device, post_flash);
+
+    if !renamed.is_empty() {
+        references::fix_boot(partitions.boot()?, &renamed)?;
+        references::fix_rootfs(partitions.rootfs()?, &renamed)?;
+    }
+    if post_flash.reset_identity {
+        identity::reset(partitions.rootfs()?)?;
+    }
+    if let Some(overlay) = &post_flash.overlay {
+        overlay::apply(overlay, &mut partitions)?;
+    }
+    if let Some(boot_files) = &post_flash.boot_files {
+        boot_files::apply(boot_files, partitions.boot()?, &variables)?;
+    }
+    if let Some(wireguard) = &post_flash.wireguard {
+        record.wireguard_public_key = Some(wireguard::install(
+            wireguard,
+            partitions.rootfs()?,
+            &variables,
+        )?);
+    }
+    if let Some(firstboot) = &post_flash.firstboot {
+        firstboot::install(firstboot, partitions.rootfs()?, &variables)?;
+    }
+    if let Some(cloud_init) = &post_flash.cloud_init {
+        cloud_init::install(cloud_init, partitions.boot()?, &variables)?;
+    }
+    if let Some(provision) = &post_flash.provision {
+        provision::run(provision, &mut partitions, &variables)?;
+    }
+    partitions.unmount()
+}
+
+fn randomize_disk_ids(device: &Path) -> io::Result<Vec<references::Renamed>> {
+    let card = OpenOptions::new().read(true).write(true).open(device)?;
+    let renamed = disk_ids::randomize(&card, devices::block_sizes(device).logical)?;
+    for renamed in &renamed {
+        println!("Replaced PARTUUID {} with {}", renamed.old, renamed.new);
+    }
+    card.sync_all()?;
+    flash::reread_partitions(device, &card)?;
+    drop(card);
+    devices::settle(flash::UDEV_SETTLE_TIMEOUT);
+    Ok(renamed)
+}
+
+/// Values available to every template as `{{name}}`. `serial` is the reader's for cards in USB
+/// readers, what is per card goes by `cid`, which only cards with a CID have.
+fn variables(config: &Config, device: &Path) -> Vec<(&'static str, String)> {
+    let flashed_at = history::unix_time();
+    let mut variables = vec![
+        ("image", config.image.to_string_lossy().to_string()),
+        ("device", device.to_string_lossy().to_string()),
+        ("flashed_at", flashed_at.to_string()),
+        (
+            "serial",
+            devices::card_serial(device).unwrap_or_else(|| "unknown".to_string()),
+        ),
+    ];
+    if let Some(cid) = devices::card_cid(device) {
+        variables.push((template::CARD_ID, cid));
+    }
+    variables
+}
+
+/// The partitions of the flashed card, mounted on first use.
+pub struct Partitions<'a> {
+    device: &'a Path,
+    config: &'a PostFlashConfig,
+    boot: Option<Mount>,
+    rootfs: Option<Mount>,
+}
+
+impl<'a> Partitions<'a> {
+    fn new(device: &'a Path, config: &'a PostFlashConfig) -> Self {
+        Self {
+            device,
+            config,
+            boot: None,
+            rootfs: None,
+        }
+    }
+
+    pub fn boot(&mut self) -> io::Result<&Path> {
+        Self::mount(
+            &mut self.boot,
+            self.device,
+            self.config.boot_partition,
+            "vfat",
+        )
+    }
+
+    pub fn rootfs(&mut self) -> io::Result<&Path> {
+        Self::mount(
+            &mut self.rootfs,
+            self.device,
+            self.config.rootfs_partition,
+            "ext4",
+        )
+    }
+
+    fn mount<'m>(
+        slot: &'m mut Option<Mount>,
+        device: &Path,
+        partition: u32,
+        fstype: &str,
+    ) -> io::Result<&'m Path> {
+        if slot.is_none() {
+            let source = mount::wait_for_partition(device, partition)?;
+            *slot = Some(Mount::new(&source, fstype)?);
+        }
+        Ok(slot.as_ref().unwrap().path())
+    }
+
+    fn unmount(self) -> io::Result<()> {
+        for mount in [self.boot, self.rootfs].into_iter().flatten() {
+            mount.unmount()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use nix::mount::{mount, umount, MsFlags};
+
+/// How long to wait for udev to create the partition nodes after the device was closed.
+const PARTITION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `/dev/sda` + 2 is `/dev/sda2`, but `/dev/mmcblk0` + 2 is `/dev/mmcblk0p2`.
+pub fn partition_path(device: &Path, partition: u32) -> PathBuf {
+    let mut path = device.as_os_str().to_os_string();
+    if path
+        .to_string_lossy()
+        .ends_with(|char: char| char.is_ascii_digit())
+    {
+        path.push("p");
+    }
+    path.push(partition.to_string());
+    PathBuf::from(path)
+}
+
+/// Waits for the kernel to expose the freshly written partition.
+pub fn wait_for_partition(device: &Path, partition: u32) -> io::Result<PathBuf> {
+    let path = partition_path(device, partition);
+    let start = Instant::now();
+    while !path.exists() {
+        if start.elapsed() > PARTITION_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Partition {path:?} did not appear"),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(path)
+}
+
+/// A mounted partition, unmounted (after syncing) when dropped.
+pub struct Mount {
+    target: PathBuf,
+}
+
+impl Mount {
+    pub fn new(source: &Path, fstype: &str) -> io::Result<Self> {
+        let name = source
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let target = std::env::temp_dir().join(format!("rpi-sd-cloner-{name}"));
+        std::fs::create_dir_all(&target)?;
+        mount(
+            Some(source),
+            &target,
+            Some(fstype),
+            MsFlags::MS_NOATIME,
+            None::<&str>,
+        )?;
+        println!("Mounted {source:?} at {target:?}");
+        Ok(Self { target })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.target
+    }
+
+    /// Unmounts explicitly, so errors can be reported instead of only logged.
+    pub fn unmount(mut self) -> io::Result<()> {
+        let result = self.unmount_inner();
+        self.target = PathBuf::new();
+        result
+    }
+
+    fn unmount_inner(&mut self) -> io::Result<()> {
+        nix::unistd::sync();
+        umount(&self.target)?;
+        std::fs::remove_dir(&self.target)?;
+        println!("Unmounted {:?}", self.target);
+        Ok(())
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        if self.target.as_os_str().is_empty() {
+            return;
+        }
+        if let Err(error) = self.unmount_inner() {
+            println!("Got error when unmounting {:?}: {error:?}", self.target);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Directories copied onto the flashed partitions, for per-site changes too small to be worth a
+//! golden image of their own: `overlay/etc/hostname` ends up as `/etc/hostname` on the card.
+
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use nix::sys::stat::{fchmod, Mode};
+
+use super::confined::{self, Root};
+use super::Partitions;
+use crate::config::OverlayConfig;
+
+pub fn apply(config: &OverlayConfig, partitions: &mut Partitions) -> io::Result<()> {
+    if let Some(boot) = &config.boot {
+        let copied = copy_tree(boot, &Root::open(partitions.boot()?)?, Path::new(""), false)?;
+        println!("Copied {copied} files from {boot:?} to the boot partition");
+    }
+    if let Some(rootfs) = &config.rootfs {
+        let root = Root::open(partitions.rootfs()?)?;
+        let copied = copy_tree(rootfs, &root, Path::new(""), true)?;
+        println!("Copied {copied} files from {rootfs:?} to the root partition");
+    }
+    Ok(())
+}
+
+/// Copies what is below `source` into `target` on `root`, merging it with the directories
+/// already there and replacing files. With `metadata` the permissions of what is copied and
+/// symbolic links are kept, which FAT can't hold. Owners never are, the overlay is usually a
+/// checkout of whoever maintains it. Returns how many files and links were copied.
+pub fn copy_tree(source: &Path, root: &Root, target: &Path, metadata: bool) -> io::Result<u32> {
+    let mut copied = 0;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let from = entry.path();
+        let to = target.join(entry.file_name());
+        let permissions = entry.metadata()?.permissions().mode() & 0o7777;
+        let existing = root.lstat(&to)?;
+
+        if file_type.is_dir() {
+            // Directories that are there keep what they have, an overlay of `etc` mustn't
+            // change who can write to `/etc`. Links to directories, like `/lib` on merged-usr
+            // systems, are followed, as the card would.
+            let is_dir = matches!(root.dir(&to, false), Ok(Some(_)));
+            if !is_dir {
+                if existing.is_some() {
+                    root.remove(&to)?;
+                }
+                let created = root
+                    .dir(&to, true)?
+                    .expect("missing directories are created");
+                if metadata {
+                    fchmod(&created, Mode::from_bits_truncate(permissions))?;
+                }
+            }
+            copied += copy_tree(&from, root, &to, metadata)?;
+            continue;
+        }
+        let copyable = file_type.is_file() || (file_type.is_symlink() && metadata);
+        if !copyable {
+            println!("Skipping {from:?}, it can't be copied onto the card");
+            continue;
+        }
+
+        // Replaced rather than written through, a link on the card may point anywhere.
+        if existing.is_some_and(|stat| confined::is_dir(&stat)) {
+            root.remove(&to)?;
+        }
+        if file_type.is_symlink() {
+            root.symlink(&fs::read_link(&from)?, &to)?;
+        } else {
+            let permissions = if metadata { permissions } else { 0o644 };
+            io::copy(&mut File::open(&from)?, &mut root.create(&to, permissions)?)?;
+        }
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn merges_into_the_target_keeping_permissions() {
+        let root = std::env::temp_dir().join(format!("overlay-{}", std::process::id()));
+        let (source, target) = (root.join("source"), root.join("target"));
+        fs::create_dir_all(source.join("etc/ssh")).unwrap();
+        fs::create_dir_all(target.join("etc")).unwrap();
+        fs::write(source.join("etc/hostname"), "kiosk\n").unwrap();
+        fs::write(source.join("etc/ssh/key"), "secret").unwrap();
+        fs::set_permissions(
+            source.join("etc/ssh/key"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        symlink("hostname", source.join("etc/name")).unwrap();
+        fs::write(target.join("etc/hostname"), "raspberrypi\n").unwrap();
+        fs::write(target.join("etc/fstab"), "proc /proc proc defaults 0 0\n").unwrap();
+        // An absolute link on the card points into the host while it is mounted.
+        symlink("/nonexistent/hostname", target.join("etc/name")).unwrap();
+
+        let partition = Root::open(&target).unwrap();
+        assert_eq!(
+            copy_tree(&source, &partition, Path::new(""), true).unwrap(),
+            3
+        );
+        let hostname = fs::read_to_string(target.join("etc/hostname")).unwrap();
+        let fstab_kept = target.join("etc/fstab").exists();
+        let mode = fs::metadata(target.join("etc/ssh/key"))
+            .unwrap()
+            .permissions()
+            .mode();
+        let link = fs::read_link(target.join("etc/name")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(hostname, "kiosk\n");
+        assert!(fstab_kept);
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(link, Path::new("hostname"));
+    }
+}
@@ -0,0 +1,86 @@
+//! Fetches per-card secrets and config from a provisioning service and writes them onto the card.
+//!
+//! The service is queried with `GET <url>` after template substitution. The URL has to contain
+//! `{{cid}}`, the only thing telling cards apart, so cards without a CID aren't provisioned. The
+//! service must answer with:
+//!
+//! ```json
+//! { "files": [{ "path": "/etc/token", "contents": "...", "mode": 384, "partition": "rootfs" }] }
+//! ```
+
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use super::confined::Root;
+use super::Partitions;
+use crate::config::ProvisionConfig;
+use crate::template;
+
+#[derive(Debug, Deserialize)]
+struct ProvisionResponse {
+    files: Vec<ProvisionedFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProvisionedFile {
+    path: String,
+    contents: String,
+    /// Secrets are private unless the service says otherwise
+    #[serde(default = "default_mode")]
+    mode: u32,
+    #[serde(default)]
+    partition: Partition,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Partition {
+    Boot,
+    #[default]
+    Rootfs,
+}
+
+fn default_mode() -> u32 {
+    0o600
+}
+
+pub fn run(
+    config: &ProvisionConfig,
+    partitions: &mut Partitions,
+    variables: &[(&str, String)],
+) -> io::Result<()> {
+    if !template::uses(&config.url, template::CARD_ID) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Provisioning URL {} has no {{{{{}}}}}, every card would get the same secrets",
+                config.url,
+                template::CARD_ID
+            ),
+        ));
+    }
+    let url = template::render_for_card(&config.url, variables)?;
+    println!("Fetching provisioning data from {url}");
+
+    let mut request = ureq::get(&url).timeout(config.timeout());
+    if let Some(token) = &config.token {
+        request = request.set("Authorization", &format!("Bearer {token}"));
+    }
+    let response: ProvisionResponse = request
+        .call()
+        .map_err(|error| io::Error::other(format!("Provisioning request failed: {error}")))?
+        .into_json()?;
+
+    for file in response.files {
+        let root = match file.partition {
+            Partition::Boot => partitions.boot()?,
+            Partition::Rootfs => partitions.rootfs()?,
+        };
+        // Paths from the service stay on the partition, whatever links the image has.
+        Root::open(root)?.write(Path::new(&file.path), file.contents.as_bytes(), file.mode)?;
+        println!("Provisioned {:?} {}", file.partition, file.path);
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+//! Files on the card that refer to partitions and filesystems by identifier, kept in step when
+//! the identifiers are regenerated.
+
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use super::confined::Root;
+
+/// Files below the boot and the root partition that can refer to identifiers
+const BOOT_FILES: &[&str] = &["cmdline.txt"];
+const ROOTFS_FILES: &[&str] = &["etc/fstab"];
+
+/// An identifier as it was and as it is now, referred to as `<kind>=<identifier>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Renamed {
+    /// `PARTUUID` or `UUID`
+    pub kind: &'static str,
+    /// For the PARTUUIDs of MBR cards only the disk identifier part, which all of them start with
+    pub old: String,
+    pub new: String,
+}
+
+fn fix(root: &Path, files: &[&str], renamed: &[Renamed]) -> io::Result<()> {
+    let root = Root::open(root)?;
+    for file in files {
+        let path = Path::new(file);
+        let Some(mut opened) = root.open_file(path)? else {
+            continue;
+        };
+        let mut contents = String::new();
+        opened.read_to_string(&mut contents)?;
+        let permissions = opened.metadata()?.permissions().mode();
+        let fixed = renamed.iter().fold(contents.clone(), |text, renamed| {
+            text.replace(
+                &format!("{}={}", renamed.kind, renamed.old),
+                &format!("{}={}", renamed.kind, renamed.new),
+            )
+        });
+        if fixed != contents {
+            root.write(path, fixed.as_bytes(), permissions & 0o7777)?;
+            println!("Updated the identifiers in {path:?}");
+        }
+    }
+    Ok(())
+}
+
+pub fn fix_boot(boot: &Path, renamed: &[Renamed]) -> io::Result<()> {
+    fix(boot, BOOT_FILES, renamed)
+}
+
+pub fn fix_rootfs(rootfs: &Path, renamed: &[Renamed]) -> io::Result<()> {
+    fix(rootfs, ROOTFS_FILES, renamed)
+}
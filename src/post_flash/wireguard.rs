@@ -0,0 +1,88 @@
+//! A WireGuard key pair of its own for every card, with the interface config around it.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use curve25519_dalek::MontgomeryPoint;
+
+use super::confined::Root;
+use super::disk_ids::random;
+use crate::config::WireGuardConfig;
+use crate::template;
+
+/// Private and public key, base64 encoded as `wg` shows them.
+struct KeyPair {
+    private: String,
+    public: String,
+}
+
+impl KeyPair {
+    fn from_private(mut private: [u8; 32]) -> Self {
+        // Clamped like `wg genkey` does, the public key is the same either way.
+        private[0] &= 248;
+        private[31] = (private[31] & 127) | 64;
+        let public = MontgomeryPoint::mul_base_clamped(private).to_bytes();
+        Self {
+            private: STANDARD.encode(private),
+            public: STANDARD.encode(public),
+        }
+    }
+}
+
+/// Writes a new private key and the config to `rootfs`. Returns the public key.
+pub fn install(
+    config: &WireGuardConfig,
+    rootfs: &Path,
+    variables: &[(&str, String)],
+) -> io::Result<String> {
+    let template = fs::read_to_string(&config.template).map_err(|error| {
+        io::Error::new(
+            error.kind(),
+            format!(
+                "Could not read WireGuard template {:?}: {error}",
+                config.template
+            ),
+        )
+    })?;
+    let keys = KeyPair::from_private(random()?);
+    let mut variables = variables.to_vec();
+    variables.push(("wireguard_private_key", keys.private.clone()));
+    variables.push(("wireguard_public_key", keys.public.clone()));
+
+    // Only root may read either, the config holds the key too.
+    let root = Root::open(rootfs)?;
+    let rendered = template::render_for_card(&template, &variables)?;
+    root.write(&config.config_path, rendered.as_bytes(), 0o600)?;
+    let private = format!("{}\n", keys.private);
+    root.write(&config.private_key_path, private.as_bytes(), 0o600)?;
+    println!("Installed WireGuard key {}", keys.public);
+    Ok(keys.public)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_the_public_key_like_wg() {
+        // The key pair of Alice in RFC 7748, section 6.1.
+        let private: [u8; 32] = [
+            0x77, 0x07, 0x6d, 0x0a, 0x73, 0x18, 0xa5, 0x7d, 0x3c, 0x16, 0xc1, 0x72, 0x51, 0xb2,
+            0x66, 0x45, 0xdf, 0x4c, 0x2f, 0x87, 0xeb, 0xc0, 0x99, 0x2a, 0xb1, 0x77, 0xfb, 0xa5,
+            0x1d, 0xb9, 0x2c, 0x2a,
+        ];
+        let public: [u8; 32] = [
+            0x85, 0x20, 0xf0, 0x09, 0x89, 0x30, 0xa7, 0x54, 0x74, 0x8b, 0x7d, 0xdc, 0xb4, 0x3e,
+            0xf7, 0x5a, 0x0d, 0xbf, 0x3a, 0x0d, 0x26, 0x38, 0x1a, 0xf4, 0xeb, 0xa4, 0xa9, 0x8e,
+            0xaa, 0x9b, 0x4e, 0x6a,
+        ];
+        let keys = KeyPair::from_private(private);
+        assert_eq!(keys.public, STANDARD.encode(public));
+        let clamped = STANDARD.decode(&keys.private).unwrap();
+        assert_eq!(clamped[0] & 7, 0);
+        assert_eq!(clamped[31] & 0xC0, 0x40);
+    }
+}
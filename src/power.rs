@@ -0,0 +1,87 @@
+//! Turning the Pi off, when asked to with the button or before the battery of its UPS runs out.
+//! UPS HATs signal a failing supply on a GPIO line and keep the Pi running for a while longer.
+//! Meanwhile no new flash starts, and if the supply doesn't come back the Pi powers off cleanly.
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rppal::gpio::{Gpio, InputPin};
+
+use crate::config::PowerFailConfig;
+use crate::error::HardwareError;
+use crate::events::{Event, EventBus};
+use crate::pause::PauseControl;
+use crate::SystemState;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Writes out what is cached and runs `command`, which powers off the Pi.
+pub fn power_off(command: &[String]) -> io::Result<()> {
+    println!("Powering off");
+    nix::unistd::sync();
+    let Some((program, arguments)) = command.split_first() else {
+        return Err(io::Error::other("No shutdown_command is configured"));
+    };
+    let status = std::process::Command::new(program)
+        .args(arguments)
+        .env("PATH", "/usr/sbin:/sbin:/usr/bin:/bin")
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{program} failed with {status}")));
+    }
+    Ok(())
+}
+
+/// Watches the power-fail line on a background thread, publishing `PowerFailing` when it
+/// changes. A flash that is cut off by the shutdown ends cancelled and is recorded as such, as
+/// systemd stops the cloner before powering off.
+pub fn watch(
+    config: &PowerFailConfig,
+    shutdown_command: Vec<String>,
+    pause: PauseControl,
+    events: &EventBus,
+) -> Result<(), HardwareError> {
+    let pin = Gpio::new()?.get(config.pin)?.into_input();
+    let (config, events) = (config.clone(), events.clone());
+    let failing = move |pin: &InputPin| pin.is_high() != config.active_low;
+    thread::spawn(move || {
+        // When the supply failed, and whether the flash was paused for it.
+        let mut failed: Option<(Instant, bool)> = None;
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            match (failing(&pin), failed) {
+                (true, None) => {
+                    println!("Power is failing, running on the UPS");
+                    events.publish(Event::PowerFailing(true));
+                    let paused = config.pause_flash && events.state() == SystemState::Flashing;
+                    if paused {
+                        pause.toggle(&events);
+                    }
+                    failed = Some((Instant::now(), paused));
+                }
+                (true, Some((since, _))) if since.elapsed() >= config.shutdown_after() => {
+                    println!("Power didn't come back, shutting down");
+                    events.update_state(|state| {
+                        (!matches!(state, SystemState::Flashing | SystemState::Paused))
+                            .then_some(SystemState::ShuttingDown)
+                    });
+                    if let Err(error) = power_off(&shutdown_command) {
+                        println!("Got error when powering off: {error:?}");
+                    }
+                    return;
+                }
+                (false, Some((_, paused))) => {
+                    println!("Power is back");
+                    events.publish(Event::PowerFailing(false));
+                    if paused && events.state() == SystemState::Paused {
+                        pause.toggle(&events);
+                    }
+                    failed = None;
+                }
+                _ => {}
+            }
+        }
+    });
+    Ok(())
+}
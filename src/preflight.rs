@@ -0,0 +1,188 @@
+//! The `check` subcommand, for provisioning scripts to run before the service is enabled. Unlike
+//! the self-test it reads the whole image, and it looks at what the device filter would pick up.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, DeviceBackend};
+use crate::devices::{self, looks_like_hard_drive};
+use crate::hardware::Hardware;
+use crate::image;
+use crate::monitoring::{check_image, check_log_space};
+
+/// Collects the lines of the report, counting the problems.
+#[derive(Default)]
+struct Report {
+    problems: usize,
+}
+
+impl Report {
+    fn ok(&mut self, message: impl AsRef<str>) {
+        println!("ok    {}", message.as_ref());
+    }
+
+    /// Worth a look, but the cloner works.
+    fn warn(&mut self, message: impl AsRef<str>) {
+        println!("warn  {}", message.as_ref());
+    }
+
+    fn fail(&mut self, message: impl AsRef<str>) {
+        println!("FAIL  {}", message.as_ref());
+        self.problems += 1;
+    }
+}
+
+/// Decodes the whole image, which is the only way to know every byte of it can be flashed.
+/// Returns the decoded size.
+fn decode_image(config: &Config) -> io::Result<u64> {
+    // The cache is the service's to fill.
+    let config = Config {
+        cache: None,
+        ..config.clone()
+    };
+    let mut image = image::open(&config)?;
+    let length = io::copy(&mut image.reader, &mut io::sink())?;
+    match image.size {
+        Some(size) if size != length => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("decodes to {length} bytes, its header says {size}"),
+        )),
+        _ => Ok(length),
+    }
+}
+
+fn check_image_contents(config: &Config, report: &mut Report) -> Option<u64> {
+    let check = check_image(config, &config.image);
+    if !check.ok {
+        report.fail(format!("Image: {}", check.detail));
+        return None;
+    }
+    let local = image::source::from_config(config)
+        .ok()
+        .is_some_and(|source| source.local_path().is_some());
+    if !local {
+        report.warn(format!("Image: {}, not downloading it", check.detail));
+        return None;
+    }
+    match decode_image(config) {
+        Ok(size) => {
+            report.ok(format!("Image {:?} decodes to {size} bytes", config.image));
+            Some(size)
+        }
+        Err(error) => {
+            report.fail(format!("Image {:?} doesn't decode: {error}", config.image));
+            None
+        }
+    }
+}
+
+/// Whether something has `device` mounted or open exclusively, like the system disk.
+fn in_use(device: &Path) -> bool {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(nix::libc::O_EXCL)
+        .open(device)
+        .is_err_and(|error| error.kind() == io::ErrorKind::ResourceBusy)
+}
+
+fn check_device_filter(config: &Config, image_size: Option<u64>, report: &mut Report) {
+    if config.min_device_size == 0 {
+        report.fail("min_device_size is 0, empty devices would be taken for cards");
+    }
+    if config.confirm_larger_than < config.min_device_size {
+        report.warn(format!(
+            "confirm_larger_than ({}) is below min_device_size ({}), every card needs confirming",
+            config.confirm_larger_than, config.min_device_size
+        ));
+    }
+    if let Some(size) = image_size.filter(|size| *size > config.min_device_size) {
+        report.warn(format!(
+            "min_device_size ({}) is below the image's {size} bytes, smaller cards are picked up \
+             and fail",
+            config.min_device_size
+        ));
+    }
+
+    if let Some(target) = &config.target {
+        if !target.exists() {
+            report.warn(format!(
+                "target {target:?} doesn't exist, is the reader plugged in?"
+            ));
+        }
+    }
+    let candidates: Vec<PathBuf> = match devices::candidates(config) {
+        Ok(candidates) => candidates,
+        Err(error) => {
+            report.fail(format!("Listing devices failed: {error}"));
+            return;
+        }
+    };
+    // Anything in use now is there for good, and would be flashed the moment it comes first.
+    let real = config.device_backend != DeviceBackend::Simulated;
+    let mut clean = true;
+    for device in &candidates {
+        if real && in_use(device) {
+            report.fail(format!(
+                "{device:?} passes the device filter but is in use, is it the system disk? \
+                 Raise min_device_size above its size"
+            ));
+            clean = false;
+        } else if real && looks_like_hard_drive(device) {
+            report.warn(format!(
+                "{device:?} passes the device filter and looks like a hard drive"
+            ));
+            clean = false;
+        } else {
+            report.warn(format!(
+                "{device:?} passes the device filter, is a card inserted?"
+            ));
+        }
+    }
+    if clean {
+        report.ok(format!(
+            "Device filter passes {} device(s), none of them in use",
+            candidates.len()
+        ));
+    }
+}
+
+/// Checks the config at `path` (see [`Config::path`]), with `image` overriding its image like
+/// `--image` does. Prints a line per check, and returns whether none failed.
+pub fn run(path: Option<&Path>, image: Option<&Path>) -> bool {
+    let mut report = Report::default();
+    let mut config = match Config::load(path) {
+        Ok(config) => config,
+        Err(error) => {
+            report.fail(format!("Config doesn't load: {error}"));
+            return false;
+        }
+    };
+    match Config::path(path) {
+        Some(path) => report.ok(format!("Config {path:?} loads")),
+        None => report.ok("No config file, using the defaults"),
+    }
+    if let Some(image) = image {
+        config.image = image.to_path_buf();
+    }
+
+    let image_size = check_image_contents(&config, &mut report);
+    match Hardware::new(&config.hardware) {
+        Ok(_) => report.ok(format!("{:?} LEDs and button", config.hardware.backend)),
+        Err(error) => report.fail(format!("LEDs and button: {error}")),
+    }
+    check_device_filter(&config, image_size, &mut report);
+    let space = check_log_space(&config);
+    match space.ok {
+        true => report.ok(format!("Disk space: {}", space.detail)),
+        false => report.fail(format!("Disk space: {}", space.detail)),
+    }
+
+    match report.problems {
+        0 => println!("No problems found"),
+        1 => println!("1 problem found"),
+        problems => println!("{problems} problems found"),
+    }
+    report.problems == 0
+}
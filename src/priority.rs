@@ -0,0 +1,77 @@
+//! CPU and I/O priority of the flashing threads. Both are per thread on Linux and inherited by
+//! the threads a thread starts, so setting them on the thread running a flash covers the readers,
+//! decompressors and hashers it starts as well.
+
+use std::io;
+
+use nix::libc;
+
+use crate::config::{IoClass, PriorityConfig};
+
+/// `ioprio_set` and `ioprio_get` on a thread, 0 being the calling one
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+impl IoClass {
+    fn number(self) -> libc::c_int {
+        match self {
+            IoClass::Realtime => 1,
+            IoClass::BestEffort => 2,
+            IoClass::Idle => 3,
+        }
+    }
+}
+
+/// The I/O priority as `ioprio_set` takes it, the class above the level.
+fn io_priority(class: IoClass, level: u8) -> libc::c_int {
+    // The idle class has no levels.
+    let level = match class {
+        IoClass::Idle => 0,
+        _ => libc::c_int::from(level.min(7)),
+    };
+    (class.number() << IOPRIO_CLASS_SHIFT) | level
+}
+
+/// Sets the priority of the calling thread. Setting one fails for lack of privileges, a negative
+/// nice value needs `CAP_SYS_NICE` and the realtime I/O class `CAP_SYS_ADMIN`, and the other one
+/// is set anyway.
+pub fn apply(config: &PriorityConfig) -> io::Result<()> {
+    let nice = match config.nice {
+        // SAFETY: plain system call, `0` is the calling thread.
+        Some(nice) => match unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        },
+        None => Ok(()),
+    };
+    let io = match config.io_class {
+        Some(class) => {
+            let priority = io_priority(class, config.io_level);
+            // SAFETY: as above, libc has no wrapper for it.
+            match unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        }
+        None => Ok(()),
+    };
+    nice.map_err(|error| io::Error::new(error.kind(), format!("Setting nice failed: {error}")))?;
+    io.map_err(|error| {
+        io::Error::new(
+            error.kind(),
+            format!("Setting the I/O priority failed: {error}"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_class_and_level() {
+        assert_eq!(io_priority(IoClass::BestEffort, 4), (2 << 13) | 4);
+        assert_eq!(io_priority(IoClass::Realtime, 9), (1 << 13) | 7);
+        assert_eq!(io_priority(IoClass::Idle, 4), 3 << 13);
+    }
+}
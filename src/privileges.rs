@@ -0,0 +1,61 @@
+//! Switching to an unprivileged user once the hardware is set up, keeping only the capabilities
+//! flashing needs.
+
+use std::ffi::CString;
+use std::io;
+
+use caps::{CapSet, Capability, CapsHashSet};
+use nix::unistd::{self, Group, User};
+
+use crate::config::PrivilegesConfig;
+
+fn caps_error(error: caps::errors::CapsError) -> io::Error {
+    io::Error::other(format!("Changing capabilities failed: {error}"))
+}
+
+/// Becomes `config.user`, unless we aren't root to begin with. glibc changes the user of every
+/// thread, which clears their capabilities, and only the calling thread gets
+/// `config.capabilities` back. Threads it starts afterwards inherit them, so flashes run on a
+/// [`crate::flash::Flasher`] it starts right after.
+pub fn drop_privileges(config: &PrivilegesConfig) -> io::Result<()> {
+    if !unistd::geteuid().is_root() {
+        println!("Not running as root, keeping the current user");
+        return Ok(());
+    }
+    let user = User::from_name(&config.user)?
+        .ok_or_else(|| io::Error::other(format!("Unknown user {:?}", config.user)))?;
+    let gid = match &config.group {
+        Some(group) => {
+            Group::from_name(group)?
+                .ok_or_else(|| io::Error::other(format!("Unknown group {group:?}")))?
+                .gid
+        }
+        None => user.gid,
+    };
+    let keep = config
+        .capabilities
+        .iter()
+        .map(|name| {
+            caps::to_canonical(name)
+                .parse::<Capability>()
+                .map_err(caps_error)
+        })
+        .collect::<io::Result<CapsHashSet>>()?;
+
+    caps::securebits::set_keepcaps(true).map_err(caps_error)?;
+    let name = CString::new(user.name.as_str()).map_err(io::Error::other)?;
+    unistd::initgroups(&name, gid)?;
+    unistd::setgid(gid)?;
+    unistd::setuid(user.uid)?;
+    caps::securebits::set_keepcaps(false).map_err(caps_error)?;
+
+    caps::set(None, CapSet::Permitted, &keep).map_err(caps_error)?;
+    caps::set(None, CapSet::Effective, &keep).map_err(caps_error)?;
+    caps::set(None, CapSet::Inheritable, &keep).map_err(caps_error)?;
+    // Ambient so mount, fsck and friends run with them too.
+    for capability in &keep {
+        caps::raise(None, CapSet::Ambient, *capability).map_err(caps_error)?;
+    }
+    println!("Running as {} with {:?}", config.user, config.capabilities);
+    Ok(())
+}
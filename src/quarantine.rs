@@ -0,0 +1,104 @@
+//! Cards that keep failing verification, which we refuse to flash again until an operator clears
+//! them.
+//!
+//! Cards are told apart by their CID. Cards in USB readers have none, the serial they report is
+//! the reader's, so they are never quarantined: it would refuse every later card in the reader.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::history::unix_time;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CardEntry {
+    /// Verify failures since the last successful flash
+    pub failures: u32,
+    /// Seconds since the unix epoch, set once the card is quarantined
+    pub quarantined_at: Option<u64>,
+}
+
+/// Keyed by card CID.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Quarantine {
+    pub cards: BTreeMap<String, CardEntry>,
+}
+
+impl Quarantine {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(temporary, path)
+    }
+
+    pub fn is_quarantined(&self, cid: &str) -> bool {
+        self.cards
+            .get(cid)
+            .is_some_and(|entry| entry.quarantined_at.is_some())
+    }
+}
+
+pub struct QuarantineList {
+    path: PathBuf,
+    /// 0 never quarantines
+    max_failures: u32,
+}
+
+impl QuarantineList {
+    pub fn new(path: &Path, max_failures: u32) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            max_failures,
+        }
+    }
+
+    pub fn is_quarantined(&self, cid: &str) -> io::Result<bool> {
+        Ok(Quarantine::load(&self.path)?.is_quarantined(cid))
+    }
+
+    /// Counts a verify failure, returning whether the card is quarantined now.
+    pub fn record_failure(&self, cid: &str) -> io::Result<bool> {
+        if self.max_failures == 0 {
+            return Ok(false);
+        }
+        let mut quarantine = Quarantine::load(&self.path)?;
+        let entry = quarantine.cards.entry(cid.to_string()).or_default();
+        entry.failures += 1;
+        if entry.failures >= self.max_failures && entry.quarantined_at.is_none() {
+            entry.quarantined_at = Some(unix_time());
+        }
+        let quarantined = entry.quarantined_at.is_some();
+        quarantine.save(&self.path)?;
+        Ok(quarantined)
+    }
+
+    /// Forgets earlier failures of a card that just flashed fine.
+    pub fn record_success(&self, cid: &str) -> io::Result<()> {
+        self.clear(cid).map(|_| ())
+    }
+
+    /// Lets the card be flashed again, returning whether it was known at all.
+    pub fn clear(&self, cid: &str) -> io::Result<bool> {
+        let mut quarantine = Quarantine::load(&self.path)?;
+        let known = quarantine.cards.remove(cid).is_some();
+        if known {
+            quarantine.save(&self.path)?;
+        }
+        Ok(known)
+    }
+
+    pub fn load(&self) -> io::Result<Quarantine> {
+        Quarantine::load(&self.path)
+    }
+}
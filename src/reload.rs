@@ -0,0 +1,90 @@
+//! Reloading the config mid-shift, on `SIGHUP` or whenever its file is saved. The main loop picks
+//! reloaded configs up between cards, [`Config::reload`] decides what they change.
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+use crate::config::Config;
+
+fn load(path: Option<&Path>) -> Option<Config> {
+    match Config::load(path) {
+        Ok(config) => Some(config),
+        Err(error) => {
+            println!("Keeping the current config, got error when reloading it: {error}");
+            None
+        }
+    }
+}
+
+/// Sends the config loaded from `path` (see [`Config::path`]) to `sender` on every `SIGHUP`, and
+/// whenever the file is saved.
+pub fn watch(
+    path: Option<PathBuf>,
+    sender: mpsc::UnboundedSender<Config>,
+) -> Result<(), Box<dyn Error>> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let (signal_path, signal_sender) = (path.clone(), sender.clone());
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            println!("Got SIGHUP, reloading the config");
+            if let Some(config) = load(signal_path.as_deref()) {
+                let _ = signal_sender.send(config);
+            }
+        }
+    });
+
+    let Some(path) = Config::path(path.as_deref()).map(Path::to_path_buf) else {
+        return Ok(());
+    };
+    // Editors tend to save by renaming a new file over the old one, which only the directory
+    // sees.
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    inotify.add_watch(
+        &directory,
+        AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO,
+    )?;
+    thread::spawn(move || {
+        // One save can come as several events, the contents tell whether anything changed.
+        let mut loaded = fs::read_to_string(&path).ok();
+        loop {
+            let events = match inotify.read_events() {
+                Ok(events) => events,
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(error) => {
+                    println!(
+                        "Got error when watching the config, send SIGHUP to reload: {error:?}"
+                    );
+                    return;
+                }
+            };
+            let saved = events
+                .iter()
+                .any(|event| event.name.as_deref() == path.file_name());
+            if !saved {
+                continue;
+            }
+            let contents = fs::read_to_string(&path).ok();
+            if contents.is_none() || contents == loaded {
+                continue;
+            }
+            loaded = contents;
+            println!("Config {path:?} changed, reloading it");
+            if let Some(config) = load(Some(&path)) {
+                if sender.send(config).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
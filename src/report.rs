@@ -0,0 +1,55 @@
+//! A standalone JSON report of every flash, collected as its manufacturing record. Unlike a line
+//! of the history, a report says which cloner wrote the card and stands on its own once copied
+//! off the Pi.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::history::FlashRecord;
+use crate::peer;
+
+/// Bumped when a field changes its meaning, so collectors can tell reports apart
+const FORMAT: u32 = 1;
+
+#[derive(Serialize)]
+struct Report<'a> {
+    format: u32,
+    host: String,
+    cloner_version: &'static str,
+    #[serde(flatten)]
+    record: &'a FlashRecord,
+}
+
+/// Writes the report of `record` to `directory`, returning its path. It goes to a temporary file
+/// first, so collectors never pick up half a report.
+pub fn write(directory: &Path, record: &FlashRecord) -> io::Result<PathBuf> {
+    fs::create_dir_all(directory)?;
+    let card = record
+        .serial
+        .clone()
+        .or_else(|| {
+            record
+                .device
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_default()
+        .replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_");
+    let path = directory.join(format!("{}-{card}.json", record.started_at));
+    let temporary = path.with_extension("json.tmp");
+    let report = Report {
+        format: FORMAT,
+        host: peer::hostname(),
+        cloner_version: env!("CARGO_PKG_VERSION"),
+        record,
+    };
+    let mut file = File::create(&temporary)?;
+    serde_json::to_writer_pretty(&mut file, &report)?;
+    file.write_all(b"\n")?;
+    file.sync_all()?;
+    fs::rename(&temporary, &path)?;
+    Ok(path)
+}
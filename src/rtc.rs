@@ -0,0 +1,182 @@
+//! DS3231 real-time clock on I2C, for units without network. Their clock would start out in 1970
+//! otherwise, and every record they write would be dated then.
+
+use std::mem;
+
+use nix::sys::time::TimeSpec;
+use nix::time::{clock_settime, ClockId};
+use rppal::i2c::I2c;
+
+use crate::config::RtcConfig;
+use crate::error::HardwareError;
+use crate::history::{format_time_with_seconds, parse_date, unix_time};
+
+/// Clocks showing anything before 2024 were never set
+const MIN_SANE_TIME: u64 = 1_704_067_200;
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_STATUS: u8 = 0x0F;
+/// Set when the oscillator stopped, e.g. the battery ran out, and the time is wrong since
+const STATUS_OSCILLATOR_STOPPED: u8 = 0x80;
+/// In the hours register, the hours count to 12 with a PM bit
+const HOURS_12: u8 = 0x40;
+const HOURS_PM: u8 = 0x20;
+/// In the month register, the year is 2100 or later
+const MONTH_CENTURY: u8 = 0x80;
+
+pub fn clock_sane(unix_time: u64) -> bool {
+    unix_time >= MIN_SANE_TIME
+}
+
+/// Whether the kernel keeps the clock synchronized, as NTP daemons have it do.
+pub fn clock_synchronized() -> bool {
+    // Without modes set, this only reads the state.
+    let mut timex: nix::libc::timex = unsafe { mem::zeroed() };
+    let state = unsafe { nix::libc::adjtimex(&mut timex) };
+    state >= 0 && state != nix::libc::TIME_ERROR
+}
+
+fn from_bcd(value: u8) -> u64 {
+    u64::from(value >> 4) * 10 + u64::from(value & 0x0F)
+}
+
+fn to_bcd(value: u64) -> u8 {
+    (((value / 10) << 4) | (value % 10)) as u8
+}
+
+pub struct Ds3231 {
+    i2c: I2c,
+}
+
+impl Ds3231 {
+    pub fn new(config: &RtcConfig) -> Result<Self, HardwareError> {
+        let mut i2c = I2c::with_bus(config.bus)?;
+        i2c.set_slave_address(config.address)?;
+        Ok(Self { i2c })
+    }
+
+    /// The time it keeps, `None` if it stopped since it was last set.
+    pub fn read(&mut self) -> Result<Option<u64>, HardwareError> {
+        let mut status = [0];
+        self.i2c.write_read(&[REGISTER_STATUS], &mut status)?;
+        if status[0] & STATUS_OSCILLATOR_STOPPED != 0 {
+            return Ok(None);
+        }
+        let mut registers = [0; 7];
+        self.i2c.write_read(&[REGISTER_SECONDS], &mut registers)?;
+        decode(&registers).map(Some)
+    }
+
+    /// Sets it to `unix_time`, which also tells it the time is valid again.
+    pub fn write(&mut self, unix_time: u64) -> Result<(), HardwareError> {
+        let mut message = vec![REGISTER_SECONDS];
+        message.extend(encode(unix_time));
+        self.i2c.write(&message)?;
+        let mut status = [0];
+        self.i2c.write_read(&[REGISTER_STATUS], &mut status)?;
+        self.i2c
+            .write(&[REGISTER_STATUS, status[0] & !STATUS_OSCILLATOR_STOPPED])?;
+        Ok(())
+    }
+}
+
+/// The seconds to year registers as a unix time. The RTC keeps UTC.
+fn decode(registers: &[u8; 7]) -> Result<u64, HardwareError> {
+    let invalid = || HardwareError::Protocol {
+        chip: "DS3231",
+        message: format!("Registers {registers:02x?} don't hold a time"),
+    };
+    let hours = match registers[2] & HOURS_12 != 0 {
+        true => from_bcd(registers[2] & 0x1F) % 12 + 12 * u64::from(registers[2] & HOURS_PM != 0),
+        false => from_bcd(registers[2] & 0x3F),
+    };
+    let century = if registers[5] & MONTH_CENTURY != 0 {
+        2100
+    } else {
+        2000
+    };
+    let date = format!(
+        "{:04}-{:02}-{:02}",
+        century + from_bcd(registers[6]),
+        from_bcd(registers[5] & 0x1F),
+        from_bcd(registers[4] & 0x3F)
+    );
+    let (minutes, seconds) = (from_bcd(registers[1] & 0x7F), from_bcd(registers[0] & 0x7F));
+    if hours > 23 || minutes > 59 || seconds > 59 {
+        return Err(invalid());
+    }
+    let midnight = parse_date(&date).ok_or_else(invalid)?;
+    Ok(midnight + hours * 3600 + minutes * 60 + seconds)
+}
+
+fn encode(unix_time: u64) -> [u8; 7] {
+    // `YYYY-MM-DD HH:MM:SS`
+    let text = format_time_with_seconds(unix_time);
+    let field = |range: std::ops::Range<usize>| text[range].parse::<u64>().unwrap_or_default();
+    let year = field(0..4);
+    let days = unix_time / 86400;
+    // 1970-01-01 was a Thursday. The DS3231 leaves the numbering to us, Monday is 1.
+    let weekday = (days + 3) % 7 + 1;
+    let century = if year >= 2100 { MONTH_CENTURY } else { 0 };
+    [
+        to_bcd(field(17..19)),
+        to_bcd(field(14..16)),
+        to_bcd(field(11..13)),
+        weekday as u8,
+        to_bcd(field(8..10)),
+        to_bcd(field(5..7)) | century,
+        to_bcd(year % 100),
+    ]
+}
+
+/// Sets the system clock from the RTC when nothing else set it, and the RTC from the system clock
+/// when NTP keeps that right.
+pub fn sync(config: &RtcConfig) -> Result<(), HardwareError> {
+    let mut rtc = Ds3231::new(config)?;
+    let now = unix_time();
+    if clock_synchronized() {
+        rtc.write(now)?;
+        println!(
+            "Clock is synchronized, set the RTC to {}",
+            format_time_with_seconds(now)
+        );
+        return Ok(());
+    }
+    match rtc.read()? {
+        Some(time) if clock_sane(time) => {
+            clock_settime(ClockId::CLOCK_REALTIME, TimeSpec::new(time as i64, 0)).map_err(
+                |errno| HardwareError::Unsupported(format!("Setting the clock failed: {errno}")),
+            )?;
+            println!(
+                "Set the clock from the RTC to {}",
+                format_time_with_seconds(time)
+            );
+        }
+        Some(time) => println!(
+            "RTC reads {}, it was never set. Timestamps are wrong until the clock is synchronized",
+            format_time_with_seconds(time)
+        ),
+        None => println!(
+            "RTC stopped, its battery may be empty. Timestamps are wrong until the clock is \
+             synchronized"
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_registers_both_ways() {
+        // Friday 2024-03-15 13:45:30
+        let time = parse_date("2024-03-15").unwrap() + 13 * 3600 + 45 * 60 + 30;
+        let registers = [0x30, 0x45, 0x13, 5, 0x15, 0x03, 0x24];
+        assert_eq!(encode(time), registers);
+        assert_eq!(decode(&registers).unwrap(), time);
+        // 1:45 PM in 12 hour mode
+        let twelve_hours = [0x30, 0x45, HOURS_12 | HOURS_PM | 0x01, 5, 0x15, 0x03, 0x24];
+        assert_eq!(decode(&twelve_hours).unwrap(), time);
+        assert!(decode(&[0x30, 0x45, 0x13, 5, 0x31, 0x02, 0x24]).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+//! SD card multiplexers like the SDWire and the usbsdmux, which connect the card either to the
+//! cloner or to a device under test. After a flash the card is handed over to the device, and
+//! the device boots from it without anyone moving it: a test rig flashes and boots unattended.
+//!
+//! Both are switched through the tools of their makers, `sd-mux-ctrl` and `usbsdmux`.
+
+use std::io;
+use std::process::Command;
+
+use clap::ValueEnum;
+
+use crate::config::{SdMuxConfig, SdMuxKind};
+
+/// Where the card is connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Side {
+    /// The cloner, which sees it as a card in a reader
+    Host,
+    /// The device under test
+    Dut,
+}
+
+pub fn switch(config: &SdMuxConfig, side: Side) -> io::Result<()> {
+    let mut command = match config.kind {
+        SdMuxKind::Sdwire => {
+            let mut command = Command::new("sd-mux-ctrl");
+            command
+                .arg(format!("--device-serial={}", config.device))
+                .arg(match side {
+                    Side::Host => "--ts",
+                    Side::Dut => "--dut",
+                });
+            command
+        }
+        SdMuxKind::Usbsdmux => {
+            let mut command = Command::new("usbsdmux");
+            command.arg(&config.device).arg(match side {
+                Side::Host => "host",
+                Side::Dut => "dut",
+            });
+            command
+        }
+    };
+    let output = command
+        .env("PATH", "/usr/local/bin:/usr/sbin:/sbin:/usr/bin:/bin")
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "Switching {} to {side:?} failed with {}: {}",
+            config.device,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    println!("Switched the card of {} to {side:?}", config.device);
+    Ok(())
+}
@@ -0,0 +1,153 @@
+//! The self-test run on every start, before the first card is looked for. A unit that fails it
+//! shows which check failed as a blink code, rather than failing on the first card.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::hardware::{Button, Led};
+use crate::hashing::hex;
+use crate::image;
+use crate::monitoring::{check_image, check_log_space};
+
+/// How long each LED is lit while they are cycled, long enough to see it
+const LED_CYCLE: Duration = Duration::from_millis(400);
+/// The button is read this often while checking it is released, a press isn't that quick
+const BUTTON_READS: u32 = 10;
+const BUTTON_READ_INTERVAL: Duration = Duration::from_millis(20);
+/// Pieces of the image read for the hash, spread evenly over it
+const IMAGE_SAMPLES: u64 = 16;
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// What failed the self-test, each shown with a blink code of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The button reads as pressed with nobody pressing it, it is stuck or wired wrong
+    ButtonStuck,
+    /// The image is missing, or reading it fails
+    ImageUnreadable,
+    /// No room for the history, the reports or the log file
+    DiskFull,
+}
+
+impl Fault {
+    /// How often the red LED blinks before each pause.
+    pub fn blink_code(self) -> u8 {
+        match self {
+            Fault::ButtonStuck => 2,
+            Fault::ImageUnreadable => 3,
+            Fault::DiskFull => 4,
+        }
+    }
+
+    pub fn text(self) -> &'static str {
+        match self {
+            Fault::ButtonStuck => "Button stuck",
+            Fault::ImageUnreadable => "Image unreadable",
+            Fault::DiskFull => "Disk full",
+        }
+    }
+}
+
+fn light_briefly(led: &mut dyn Led) {
+    led.set(true);
+    thread::sleep(LED_CYCLE);
+    led.set(false);
+}
+
+fn button_released(button: &mut dyn Button) -> bool {
+    (0..BUTTON_READS).any(|_| {
+        thread::sleep(BUTTON_READ_INTERVAL);
+        !button.is_pressed()
+    })
+}
+
+/// SHA-256 of [`IMAGE_SAMPLES`] pieces spread over the file, which reads bad sectors of the stick
+/// it is on without reading all of it.
+fn sample_hash(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut digest = Sha256::new();
+    let mut sample = vec![0; SAMPLE_SIZE as usize];
+    for index in 0..IMAGE_SAMPLES {
+        let offset = size.saturating_sub(SAMPLE_SIZE) * index / (IMAGE_SAMPLES - 1);
+        file.seek(SeekFrom::Start(offset))?;
+        let length = SAMPLE_SIZE.min(size - offset) as usize;
+        file.read_exact(&mut sample[..length])?;
+        digest.update(&sample[..length]);
+    }
+    Ok(hex(&digest.finalize()))
+}
+
+/// Runs every check, printing how each went. Stops at the first failure, which is the one the
+/// LEDs show.
+pub fn run(
+    config: &Config,
+    red: &mut dyn Led,
+    yellow: &mut dyn Led,
+    button: &mut dyn Button,
+) -> Result<(), Fault> {
+    println!("Running the self-test");
+    // Each on its own in turn, so whoever watches the start sees a dead one.
+    light_briefly(red);
+    light_briefly(yellow);
+
+    if !button_released(button) {
+        println!("Self-test failed: the button is pressed, it may be stuck or wired wrong");
+        return Err(Fault::ButtonStuck);
+    }
+
+    let image_check = check_image(config, &config.image);
+    if !image_check.ok {
+        println!("Self-test failed: {}", image_check.detail);
+        return Err(Fault::ImageUnreadable);
+    }
+    let local_path = image::source::from_config(config)
+        .ok()
+        .and_then(|source| source.local_path().map(Path::to_path_buf));
+    if let Some(path) = local_path {
+        match sample_hash(&path) {
+            Ok(hash) => println!("Image {path:?} reads fine, its sample hash is {hash}"),
+            Err(error) => {
+                println!("Self-test failed: got error when reading {path:?}: {error:?}");
+                return Err(Fault::ImageUnreadable);
+            }
+        }
+    }
+
+    let space_check = check_log_space(config);
+    if !space_check.ok {
+        println!("Self-test failed: {}", space_check.detail);
+        return Err(Fault::DiskFull);
+    }
+    println!("Self-test passed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    #[test]
+    fn sample_hash_covers_the_whole_file() {
+        let path = std::env::temp_dir().join(format!("selftest-{}.img", std::process::id()));
+        let mut data = vec![0; 3 * 1024 * 1024];
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        let hash = sample_hash(&path).unwrap();
+        // The last byte is in the last sample.
+        *data.last_mut().unwrap() = 1;
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert_ne!(sample_hash(&path).unwrap(), hash);
+        // Files smaller than a sample are read whole, as often as there are samples.
+        File::create(&path).unwrap().write_all(b"tiny").unwrap();
+        assert!(sample_hash(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
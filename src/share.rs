@@ -0,0 +1,121 @@
+//! Images living on an NFS or SMB share, either mounted by us or mounted by the system.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{ShareConfig, ShareKind};
+
+/// A stale NFS mount blocks `stat` forever, anything slower than this is treated as stale.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Share<'a> {
+    config: &'a ShareConfig,
+}
+
+impl<'a> Share<'a> {
+    pub fn new(config: &'a ShareConfig) -> Self {
+        Self { config }
+    }
+
+    /// Makes sure the share is mounted and responsive, remounting it if we manage it.
+    pub fn ensure_healthy(&self) -> io::Result<()> {
+        let mountpoint = &self.config.mountpoint;
+        let error = match self.check() {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+        if self.config.kind == ShareKind::Premounted {
+            return Err(error);
+        }
+
+        println!("Share at {mountpoint:?} is unhealthy ({error}), remounting");
+        if is_mounted(mountpoint)? {
+            // Lazy, a stale mount can't be unmounted normally.
+            run(Command::new("umount").arg("-l").arg(mountpoint))?;
+        }
+        self.mount()?;
+        self.check()
+    }
+
+    fn check(&self) -> io::Result<()> {
+        let mountpoint = &self.config.mountpoint;
+        if !is_mounted(mountpoint)? {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{mountpoint:?} is not mounted"),
+            ));
+        }
+        responsive(mountpoint)
+    }
+
+    fn mount(&self) -> io::Result<()> {
+        let (fstype, source) = match self.config.kind {
+            ShareKind::Nfs => ("nfs", &self.config.source),
+            ShareKind::Smb => ("cifs", &self.config.source),
+            ShareKind::Premounted => unreachable!("Premounted shares are never mounted by us"),
+        };
+        let Some(source) = source else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Share needs a source to be mounted",
+            ));
+        };
+        fs::create_dir_all(&self.config.mountpoint)?;
+        let mut command = Command::new("mount");
+        command.arg("-t").arg(fstype);
+        // Images are only ever read.
+        let mut options = String::from("ro");
+        if let Some(extra) = &self.config.options {
+            options.push(',');
+            options.push_str(extra);
+        }
+        command.arg("-o").arg(options);
+        command.arg(source).arg(&self.config.mountpoint);
+        run(&mut command)?;
+        println!("Mounted {source} at {:?}", self.config.mountpoint);
+        Ok(())
+    }
+}
+
+fn is_mounted(mountpoint: &Path) -> io::Result<bool> {
+    let mountpoint = mountpoint.to_string_lossy();
+    let mounts = fs::read_to_string("/proc/self/mounts")?;
+    Ok(mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        // Spaces in mount points are octal escaped.
+        .any(|target| target.replace("\\040", " ") == mountpoint.trim_end_matches('/')))
+}
+
+/// Lists the mount point on another thread, so a hung server can't hang us.
+fn responsive(mountpoint: &Path) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let path = mountpoint.to_path_buf();
+    thread::spawn(move || {
+        let _ = sender.send(fs::read_dir(&path).map(|_| ()));
+    });
+    match receiver.recv_timeout(HEALTH_CHECK_TIMEOUT) {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{mountpoint:?} did not respond, the mount is stale"),
+        )),
+    }
+}
+
+fn run(command: &mut Command) -> io::Result<()> {
+    let output = command.output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{command:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
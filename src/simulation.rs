@@ -0,0 +1,81 @@
+//! Cards simulated by files, for running the whole cloner on a laptop. Cards are inserted and
+//! removed on the schedule in the config, and are sparse files so their size costs nothing.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::SimulationConfig;
+use crate::events::{Event, EventBus};
+
+/// Removes cards left over from an earlier run and plays the schedule on a background thread.
+pub fn start(config: &SimulationConfig, events: &EventBus) -> io::Result<()> {
+    fs::create_dir_all(&config.directory)?;
+    // Only the configured names, the directory may hold other files.
+    for card in &config.cards {
+        match fs::remove_file(config.directory.join(&card.name)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+    let mut steps: Vec<(Duration, PathBuf, Option<u64>)> = vec![];
+    for card in &config.cards {
+        let path = config.directory.join(&card.name);
+        steps.push((
+            Duration::from_secs(card.insert_after_secs),
+            path.clone(),
+            Some(card.size),
+        ));
+        if let Some(remove_after) = card.remove_after_secs {
+            steps.push((Duration::from_secs(remove_after), path, None));
+        }
+    }
+    steps.sort_by_key(|(at, _, _)| *at);
+
+    let (started, events) = (Instant::now(), events.clone());
+    thread::spawn(move || {
+        for (at, path, size) in steps {
+            thread::sleep(at.saturating_sub(started.elapsed()));
+            let result = match size {
+                Some(size) => File::create(&path).and_then(|card| card.set_len(size)),
+                None => fs::remove_file(&path),
+            };
+            match (result, size) {
+                (Ok(()), Some(_)) => {
+                    println!("Inserted simulated card {path:?}");
+                    events.publish(Event::DeviceAdded);
+                }
+                (Ok(()), None) => println!("Removed simulated card {path:?}"),
+                (Err(error), _) => {
+                    println!("Got error when simulating card {path:?}: {error:?}")
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Simulated cards of at least `min_size_bytes`, in name order.
+pub fn get_cards_with_size(
+    config: &SimulationConfig,
+    min_size_bytes: u64,
+) -> io::Result<Vec<PathBuf>> {
+    let mut cards: Vec<PathBuf> = config
+        .cards
+        .iter()
+        .map(|card| config.directory.join(&card.name))
+        .filter(|path| card_size(path).is_some_and(|size| size >= min_size_bytes))
+        .collect();
+    cards.sort();
+    Ok(cards)
+}
+
+/// Size of a simulated card, if it is inserted.
+pub fn card_size(card: &Path) -> Option<u64> {
+    fs::metadata(card)
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+}
@@ -0,0 +1,287 @@
+//! Keeps the local image in sync with a golden image on a server, only downloading the blocks
+//! that changed.
+//!
+//! Next to the image the server publishes `<image>.manifest.json` (generated with the `manifest`
+//! subcommand) listing the SHA-256 of every block. Disk images change in place, so blocks are
+//! compared at the same offsets and changed ranges are fetched with HTTP range requests. The new
+//! image is assembled next to the old one and swapped in with a rename, so a flash that already
+//! opened the old image is unaffected.
+//!
+//! Without a URL, the image is pulled from a primary cloner on the local network (see `peer`).
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::bmap;
+use crate::catalog::{self, compare_versions, Catalog, CatalogEntry};
+use crate::config::SyncConfig;
+use crate::events::{Event, EventBus};
+use crate::hashing::hex;
+use crate::peer;
+
+pub const DEFAULT_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub version: Option<String>,
+    pub size: u64,
+    pub block_size: u64,
+    pub sha256: String,
+    pub blocks: Vec<String>,
+}
+
+impl Manifest {
+    pub fn generate(image: &Path, block_size: u64, version: Option<String>) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(image)?);
+        let mut buffer = vec![0; block_size as usize];
+        let mut digest = Sha256::new();
+        let mut blocks = vec![];
+        let mut size = 0;
+        loop {
+            let read = read_block(&mut file, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            size += read as u64;
+            digest.update(&buffer[..read]);
+            blocks.push(hex(&Sha256::digest(&buffer[..read])));
+        }
+        Ok(Self {
+            version,
+            size,
+            block_size,
+            sha256: hex(&digest.finalize()),
+            blocks,
+        })
+    }
+}
+
+fn read_block(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+fn manifest_url(url: &str) -> String {
+    format!("{url}.manifest.json")
+}
+
+/// Brings `image` up to date, recording the versions in the catalog at `catalog_path`. Returns
+/// whether anything changed.
+pub fn sync_once(config: &SyncConfig, image: &Path, catalog_path: &Path) -> io::Result<bool> {
+    let url = match &config.url {
+        Some(url) => url.clone(),
+        None => peer::discover()?,
+    };
+    let manifest: Manifest = ureq::get(&manifest_url(&url))
+        .timeout(Duration::from_secs(30))
+        .call()
+        .map_err(|error| io::Error::other(format!("Fetching manifest failed: {error}")))?
+        .into_json()?;
+
+    let local_version = catalog::image_version(image);
+    let catalog = Catalog::update(catalog_path, |catalog| {
+        let entry = catalog.entry_mut(image);
+        entry.version = local_version.clone();
+        entry.available_version = manifest.version.clone();
+    })?;
+    if !config.auto_update {
+        if let Some(available) = catalog
+            .entry(image)
+            .and_then(CatalogEntry::update_available)
+        {
+            println!("Newer image {available} is available for {image:?} (have {local_version:?})");
+        }
+        return Ok(false);
+    }
+    if let (Some(local), Some(available)) = (&local_version, &manifest.version) {
+        if compare_versions(available, local) == std::cmp::Ordering::Less {
+            println!("Server has older image {available} than {local}, not downgrading");
+            return Ok(false);
+        }
+    }
+
+    let mut local = match File::open(image) {
+        Ok(file) => Some(BufReader::new(file)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => return Err(error),
+    };
+
+    // Find the blocks we don't have, merging neighbours into one request.
+    let mut buffer = vec![0; manifest.block_size as usize];
+    let mut missing: Vec<Range<u64>> = vec![];
+    for (index, expected) in manifest.blocks.iter().enumerate() {
+        let start = index as u64 * manifest.block_size;
+        let end = (start + manifest.block_size).min(manifest.size);
+        let matches = match &mut local {
+            Some(local) => {
+                let read = read_block(local, &mut buffer[..(end - start) as usize])?;
+                read as u64 == end - start && hex(&Sha256::digest(&buffer[..read])) == *expected
+            }
+            None => false,
+        };
+        if matches {
+            continue;
+        }
+        match missing.last_mut() {
+            Some(range) if range.end == start => range.end = end,
+            _ => missing.push(start..end),
+        }
+    }
+    let local_size = fs::metadata(image).map(|metadata| metadata.len()).ok();
+    if missing.is_empty() && local_size == Some(manifest.size) {
+        println!("Image {image:?} is up to date");
+        return Ok(false);
+    }
+    let missing_bytes: u64 = missing.iter().map(|range| range.end - range.start).sum();
+    println!(
+        "Image {image:?} is out of date, fetching {missing_bytes} of {} bytes",
+        manifest.size
+    );
+
+    let temporary = temporary_path(image);
+    let result = assemble(config, &url, image, &temporary, &manifest, &missing);
+    if let Err(error) = result {
+        let _ = fs::remove_file(&temporary);
+        return Err(error);
+    }
+    fs::rename(&temporary, image)?;
+    match &manifest.version {
+        Some(version) => fs::write(catalog::version_file(image), version)?,
+        None => {
+            let _ = fs::remove_file(catalog::version_file(image));
+        }
+    }
+    Catalog::update(catalog_path, |catalog| {
+        catalog.entry_mut(image).version = manifest.version.clone();
+    })?;
+    println!(
+        "Swapped in new image {image:?} version {:?} ({})",
+        manifest.version, manifest.sha256
+    );
+    // Keep an existing block map in step with the image it describes.
+    if bmap::bmap_file(image).exists() {
+        if let Err(error) = bmap::write_bmap(image) {
+            println!("Got error when updating bmap for {image:?}: {error}");
+        }
+    }
+    Ok(true)
+}
+
+fn temporary_path(image: &Path) -> PathBuf {
+    let mut name = image.file_name().unwrap_or_default().to_os_string();
+    name.push(".sync");
+    image.with_file_name(name)
+}
+
+/// Writes the new image to `temporary`: unchanged blocks from the local image, the rest from the
+/// server. Verifies the whole result before returning.
+fn assemble(
+    config: &SyncConfig,
+    url: &str,
+    image: &Path,
+    temporary: &Path,
+    manifest: &Manifest,
+    missing: &[Range<u64>],
+) -> io::Result<()> {
+    let mut output = BufWriter::new(File::create(temporary)?);
+    if let Ok(mut local) = File::open(image) {
+        io::copy(&mut (&mut local).take(manifest.size), &mut output)?;
+    }
+    output.get_mut().set_len(manifest.size)?;
+
+    for range in missing {
+        let response = ureq::get(url)
+            .set("Range", &format!("bytes={}-{}", range.start, range.end - 1))
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .call()
+            .map_err(|error| io::Error::other(format!("Fetching {range:?} failed: {error}")))?;
+        if response.status() != 206 {
+            return Err(io::Error::other(format!(
+                "Server ignored the range request for {range:?}"
+            )));
+        }
+        output.seek(SeekFrom::Start(range.start))?;
+        let copied = io::copy(
+            &mut response.into_reader().take(range.end - range.start),
+            &mut output,
+        )?;
+        if copied != range.end - range.start {
+            return Err(io::Error::other(format!("Short read for {range:?}")));
+        }
+    }
+    let file = output.into_inner()?;
+    file.sync_all()?;
+
+    let written = Manifest::generate(temporary, manifest.block_size, None)?;
+    if written.sha256 != manifest.sha256 {
+        return Err(io::Error::other(format!(
+            "Synced image has digest {}, expected {}",
+            written.sha256, manifest.sha256
+        )));
+    }
+    Ok(())
+}
+
+/// Tells the display and the agent whether the catalog has a newer version of `image` than the
+/// local one, which is only updated by hand without `auto_update`.
+fn publish_update(events: &EventBus, image: &Path, catalog_path: &Path) {
+    match Catalog::load(catalog_path) {
+        Ok(catalog) => {
+            let available = catalog
+                .entry(image)
+                .and_then(CatalogEntry::update_available);
+            events.publish(Event::UpdateAvailable(available.map(str::to_string)));
+        }
+        Err(error) => println!("Got error when reading the catalog: {error:?}"),
+    }
+}
+
+/// Syncs every `interval_secs` forever, starting one interval from now. Whether an update is
+/// available is published right away, for the sync at startup, and after every sync.
+pub async fn run(config: SyncConfig, image: PathBuf, catalog: PathBuf, events: EventBus) {
+    publish_update(&events, &image, &catalog);
+    let period = Duration::from_secs(config.interval_secs);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let sync = {
+            let (config, image, catalog) = (config.clone(), image.clone(), catalog.clone());
+            move || sync_once(&config, &image, &catalog)
+        };
+        match tokio::task::spawn_blocking(sync).await {
+            Ok(Ok(_)) => publish_update(&events, &image, &catalog),
+            Ok(Err(error)) => println!("Got error when syncing image: {error:?}"),
+            Err(error) => println!("Image sync task failed: {error:?}"),
+        }
+    }
+}
+
+/// Writes the manifest for `image` next to it, for the server side. The version defaults to the
+/// one in the file name.
+pub fn write_manifest(
+    image: &Path,
+    block_size: u64,
+    version: Option<String>,
+) -> io::Result<PathBuf> {
+    let version = version.or_else(|| catalog::version_from_filename(image));
+    let manifest = Manifest::generate(image, block_size, version)?;
+    let mut name = image.file_name().unwrap_or_default().to_os_string();
+    name.push(".manifest.json");
+    let path = image.with_file_name(name);
+    let mut file = File::create(&path)?;
+    file.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(path)
+}
@@ -0,0 +1,54 @@
+use std::io;
+
+/// The variable identifying the card, its CID. Only set for cards that have one.
+pub const CARD_ID: &str = "cid";
+
+/// Replaces every `{{name}}` in `template` with the matching value. Unknown names are left as is,
+/// so a typo shows up in the output rather than silently disappearing.
+pub fn render(template: &str, variables: &[(&str, String)]) -> String {
+    let mut output = template.to_string();
+    for (name, value) in variables {
+        output = output.replace(&format!("{{{{{name}}}}}"), value);
+    }
+    output
+}
+
+/// Whether `template` uses `{{name}}`.
+pub fn uses(template: &str, name: &str) -> bool {
+    template.contains(&format!("{{{{{name}}}}}"))
+}
+
+/// Renders a template written onto a card. One using the card's CID is refused for cards
+/// without one, it would come out the same for every card in a USB reader.
+pub fn render_for_card(template: &str, variables: &[(&str, String)]) -> io::Result<String> {
+    let known = variables.iter().any(|(name, _)| *name == CARD_ID);
+    if uses(template, CARD_ID) && !known {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The card has no CID for {{{{{CARD_ID}}}}}, it can't be told apart"),
+        ));
+    }
+    Ok(render(template, variables))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_the_card_id_for_cards_without_one() {
+        let template = "https://factory/cards/{{cid}}?at={{flashed_at}}";
+        let flashed_at = ("flashed_at", "1760000000".to_string());
+        let cid = (CARD_ID, "1b534d4542333247".to_string());
+
+        assert_eq!(
+            render_for_card(template, &[flashed_at.clone(), cid]).unwrap(),
+            "https://factory/cards/1b534d4542333247?at=1760000000"
+        );
+        assert!(render_for_card(template, std::slice::from_ref(&flashed_at)).is_err());
+        assert_eq!(
+            render_for_card("at={{flashed_at}}", &[flashed_at]).unwrap(),
+            "at=1760000000"
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! The flash pipeline against file-backed loop devices, so it is tested without sacrificing
+//! cards. Setting up loop devices takes root, so these only run when asked to:
+//!
+//! ```sh
+//! sudo RPI_SD_CLONER_LOOP_TESTS=1 cargo test loop_device
+//! ```
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::buffers::BufferPool;
+use crate::config::{Config, VerifyMode};
+use crate::error::{DeviceError, FlashError};
+use crate::events::{Event, EventBus};
+use crate::flash;
+use crate::history::{FlashRecord, FlashResult};
+use crate::pause::PauseControl;
+use crate::{finished_state, SystemState};
+
+const ENABLE: &str = "RPI_SD_CLONER_LOOP_TESTS";
+const CARD_SIZE: u64 = 8 * 1024 * 1024;
+
+/// A loop device over a sparse file, detached and removed when dropped.
+struct LoopDevice {
+    path: PathBuf,
+    backing: PathBuf,
+}
+
+impl LoopDevice {
+    fn new(name: &str, size: u64) -> io::Result<Self> {
+        Self::with_sectors(name, size, 512)
+    }
+
+    fn with_sectors(name: &str, size: u64, sector_size: u64) -> io::Result<Self> {
+        let backing = scratch_path(name);
+        File::create(&backing)?.set_len(size)?;
+        let output = Command::new("losetup")
+            .args(["--find", "--show", "--partscan", "--sector-size"])
+            .arg(sector_size.to_string())
+            .arg(&backing)
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "losetup failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let path = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+        Ok(Self { path, backing })
+    }
+
+    /// The first `length` bytes, read through the device rather than the backing file.
+    fn contents(&self, length: usize) -> Vec<u8> {
+        let mut contents = vec![0; length];
+        File::open(&self.path)
+            .and_then(|mut device| device.read_exact(&mut contents))
+            .unwrap();
+        contents
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        let _ = Command::new("losetup").arg("-d").arg(&self.path).status();
+        let _ = fs::remove_file(&self.backing);
+    }
+}
+
+fn scratch_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rpi-sd-cloner-{}-{name}", process::id()))
+}
+
+fn enabled() -> bool {
+    if std::env::var_os(ENABLE).is_none() {
+        println!("Skipping, set {ENABLE}=1 to run against loop devices");
+        return false;
+    }
+    true
+}
+
+/// Writes `size` bytes that differ from chunk to chunk, so misplaced chunks don't verify.
+fn write_image(name: &str, size: usize) -> (PathBuf, Vec<u8>) {
+    let mut state = 0x9E37_79B9_7F4A_7C15_u64;
+    let contents: Vec<u8> = (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let path = scratch_path(name);
+    fs::write(&path, &contents).unwrap();
+    (path, contents)
+}
+
+fn config(image: &Path) -> Config {
+    Config {
+        image: image.to_path_buf(),
+        // Not the working directory, which would end up with the digests of scratch images.
+        image_digests: image.with_extension("digests.json"),
+        verify_mode: VerifyMode::Full,
+        ..Config::default()
+    }
+}
+
+struct Outcome {
+    record: FlashRecord,
+    result: Result<(), FlashError>,
+    state: SystemState,
+    events: Vec<Event>,
+}
+
+fn run(config: &Config, device: &Path, cancel: &CancellationToken) -> Outcome {
+    let events = EventBus::new();
+    let mut receiver = events.subscribe();
+    let mut record = FlashRecord::start(&config.image, device, config.verify_mode);
+    let pause = PauseControl::default();
+    let buffers = BufferPool::from_config(&config.buffers);
+    let result = flash::flash(
+        config,
+        device,
+        &buffers,
+        &mut record,
+        &pause,
+        &events,
+        cancel,
+    );
+    record.finish(&result);
+    let state = finished_state(config, device, &result);
+    let mut published = vec![];
+    loop {
+        match receiver.try_recv() {
+            Ok(event) => published.push(event),
+            Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+            Err(_) => break,
+        }
+    }
+    Outcome {
+        record,
+        result,
+        state,
+        events: published,
+    }
+}
+
+#[test]
+fn flashes_and_verifies() {
+    if !enabled() {
+        return;
+    }
+    let card = LoopDevice::new("verify.card", CARD_SIZE).unwrap();
+    // Not a multiple of any sector or chunk size.
+    let (image, contents) = write_image("verify.img", 5 * 1024 * 1024 + 123);
+    let outcome = run(&config(&image), &card.path, &CancellationToken::new());
+
+    assert!(outcome.result.is_ok(), "{:?}", outcome.result);
+    assert_eq!(outcome.state, SystemState::FlashingSuceeded);
+    assert_eq!(outcome.record.result, FlashResult::Succeeded);
+    assert_eq!(outcome.record.bytes_written, Some(contents.len() as u64));
+    assert_eq!(outcome.record.image_sha256, outcome.record.device_sha256);
+    assert!(card.contents(contents.len()) == contents);
+    let Some(Event::ProgressTick { written, total, .. }) = outcome.events.last() else {
+        panic!("No progress was published: {:?}", outcome.events);
+    };
+    assert_eq!(
+        (*written, *total),
+        (contents.len() as u64, Some(contents.len() as u64))
+    );
+    fs::remove_file(image).unwrap();
+}
+
+#[test]
+fn sampled_verification_passes() {
+    if !enabled() {
+        return;
+    }
+    let card = LoopDevice::new("sampled.card", CARD_SIZE).unwrap();
+    let (image, contents) = write_image("sampled.img", 3 * 1024 * 1024);
+    let config = Config {
+        verify_mode: VerifyMode::Sampled,
+        ..config(&image)
+    };
+    let outcome = run(&config, &card.path, &CancellationToken::new());
+
+    assert!(outcome.result.is_ok(), "{:?}", outcome.result);
+    assert_eq!(outcome.state, SystemState::FlashingSuceeded);
+    assert!(card.contents(contents.len()) == contents);
+    fs::remove_file(image).unwrap();
+}
+
+#[test]
+fn card_with_4k_sectors() {
+    if !enabled() {
+        return;
+    }
+    let card = LoopDevice::with_sectors("4kn.card", CARD_SIZE, 4096).unwrap();
+    let (image, _) = write_image("4kn.img", 1024 * 1024);
+    let outcome = run(&config(&image), &card.path, &CancellationToken::new());
+
+    assert!(
+        matches!(
+            outcome.result,
+            Err(FlashError::Device(DeviceError::SectorSize {
+                logical: 4096,
+                ..
+            }))
+        ),
+        "{:?}",
+        outcome.result
+    );
+    assert_eq!(outcome.state, SystemState::FlashingFailed);
+    assert!(card
+        .contents(CARD_SIZE as usize)
+        .iter()
+        .all(|byte| *byte == 0));
+    fs::remove_file(image).unwrap();
+}
+
+#[test]
+fn image_larger_than_card() {
+    if !enabled() {
+        return;
+    }
+    let card = LoopDevice::new("small.card", CARD_SIZE).unwrap();
+    let (image, _) = write_image("small.img", CARD_SIZE as usize + 1);
+    let outcome = run(&config(&image), &card.path, &CancellationToken::new());
+
+    assert!(
+        matches!(
+            outcome.result,
+            Err(FlashError::Device(DeviceError::TooSmall { .. }))
+        ),
+        "{:?}",
+        outcome.result
+    );
+    assert_eq!(outcome.state, SystemState::CardTooSmall);
+    assert_eq!(outcome.record.result, FlashResult::Failed);
+    assert!(card
+        .contents(CARD_SIZE as usize)
+        .iter()
+        .all(|byte| *byte == 0));
+    fs::remove_file(image).unwrap();
+}
+
+#[test]
+fn card_in_use() {
+    if !enabled() {
+        return;
+    }
+    let card = LoopDevice::new("busy.card", CARD_SIZE).unwrap();
+    let (image, _) = write_image("busy.img", 1024 * 1024);
+    // Like a mount, holds the device exclusively.
+    let _holder = OpenOptions::new()
+        .read(true)
+        .custom_flags(nix::libc::O_EXCL)
+        .open(&card.path)
+        .unwrap();
+    let outcome = run(&config(&image), &card.path, &CancellationToken::new());
+
+    assert!(
+        matches!(
+            outcome.result,
+            Err(FlashError::Device(DeviceError::Busy { .. }))
+        ),
+        "{:?}",
+        outcome.result
+    );
+    assert_eq!(outcome.state, SystemState::DeviceBusy);
+    fs::remove_file(image).unwrap();
+}
+
+#[test]
+fn cancelled_before_the_first_chunk() {
+    if !enabled() {
+        return;
+    }
+    let card = LoopDevice::new("cancel.card", CARD_SIZE).unwrap();
+    let (image, _) = write_image("cancel.img", 1024 * 1024);
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+    let outcome = run(&config(&image), &card.path, &cancel);
+
+    assert!(
+        matches!(outcome.result, Err(FlashError::Cancelled { offset: 0 })),
+        "{:?}",
+        outcome.result
+    );
+    // The card is still there, so it can be flashed again.
+    assert_eq!(outcome.state, SystemState::SdCardFound);
+    assert!(outcome.events.is_empty(), "{:?}", outcome.events);
+    fs::remove_file(image).unwrap();
+}
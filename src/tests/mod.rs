@@ -0,0 +1,4 @@
+//! Tests running whole pipelines, rather than single functions.
+
+mod loop_device;
+mod state_machine;
@@ -0,0 +1,463 @@
+//! The state machine stepped through scripted sequences, with a clock that only moves when the
+//! test says so and cards that are inserted and removed by hand.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use tokio::sync::broadcast;
+
+use crate::config::{Config, LedPatternConfig, TimeoutConfig};
+use crate::error::FlashError;
+use crate::events::{ButtonEvent, Event, EventBus};
+use crate::leds::LedState;
+use crate::machine::{Action, Cards, Machine};
+use crate::{finished_state, SystemState, POLL_INTERVAL};
+
+const CARD: &str = "/dev/sdz";
+
+#[derive(Default)]
+struct FakeCards {
+    inserted: Option<PathBuf>,
+    large: bool,
+    quarantined: bool,
+}
+
+impl Cards for FakeCards {
+    fn find(&mut self) -> io::Result<Option<PathBuf>> {
+        Ok(self.inserted.clone())
+    }
+
+    fn present(&mut self, device: &Path) -> bool {
+        self.inserted.as_deref() == Some(device)
+    }
+
+    fn needs_confirmation(&mut self, _device: &Path) -> bool {
+        self.large
+    }
+
+    fn quarantined(&mut self, _device: &Path) -> bool {
+        self.quarantined
+    }
+}
+
+struct Harness {
+    machine: Machine,
+    receiver: broadcast::Receiver<Event>,
+    cards: FakeCards,
+    start: Instant,
+    /// Time on the made-up clock since `start`
+    elapsed: Duration,
+    history: Vec<SystemState>,
+    /// Insertions acknowledged with `CardDetected`
+    detected: usize,
+}
+
+impl Harness {
+    fn new(timeouts: TimeoutConfig, hotplug: bool) -> Self {
+        let events = EventBus::new();
+        let receiver = events.subscribe();
+        let start = Instant::now();
+        Self {
+            machine: Machine::new(&events, timeouts, hotplug, start),
+            receiver,
+            cards: FakeCards::default(),
+            start,
+            elapsed: Duration::ZERO,
+            history: vec![],
+            detected: 0,
+        }
+    }
+
+    /// Without idling or automatic resets, so only the scripted inputs move the machine.
+    fn without_timeouts() -> Self {
+        let timeouts = TimeoutConfig {
+            idle_secs: None,
+            result_reset_secs: None,
+            ..TimeoutConfig::default()
+        };
+        Self::new(timeouts, false)
+    }
+
+    /// Steps once, a polling interval after the last step.
+    fn step(&mut self) -> Action {
+        self.elapsed += POLL_INTERVAL;
+        let action = self
+            .machine
+            .step(self.start + self.elapsed, &mut self.cards, false);
+        while let Ok(event) = self.receiver.try_recv() {
+            match event {
+                Event::StateChanged(state) => self.history.push(state),
+                Event::CardDetected => self.detected += 1,
+                _ => {}
+            }
+        }
+        action
+    }
+
+    /// Steps until `duration` passed, returning what the machine asked for along the way.
+    fn run_for(&mut self, duration: Duration) -> Vec<Action> {
+        let until = self.elapsed + duration;
+        let mut actions = vec![];
+        while self.elapsed < until {
+            match self.step() {
+                Action::Continue => {}
+                action => actions.push(action),
+            }
+        }
+        actions
+    }
+
+    fn insert(&mut self) {
+        self.cards.inserted = Some(PathBuf::from(CARD));
+    }
+
+    fn remove(&mut self) {
+        self.cards.inserted = None;
+    }
+
+    fn press(&mut self) {
+        assert!(self.machine.note(&Event::ButtonPressed(ButtonEvent::Press)));
+    }
+
+    fn state(&self) -> SystemState {
+        *self.history.last().unwrap_or(&SystemState::Initializing)
+    }
+
+    fn leds(&self) -> Vec<LedState> {
+        let patterns = LedPatternConfig::default();
+        self.history
+            .iter()
+            .map(|state| LedState::for_state(*state, &patterns))
+            .collect()
+    }
+}
+
+#[test]
+fn write_error_then_removal() {
+    let mut harness = Harness::without_timeouts();
+    assert_eq!(harness.step(), Action::Continue);
+    harness.insert();
+    harness.step();
+    assert_eq!(harness.state(), SystemState::SdCardFound);
+    // Nothing happens without a press.
+    assert!(harness.run_for(Duration::from_secs(5)).is_empty());
+
+    harness.press();
+    harness.step();
+    assert_eq!(harness.step(), Action::Flash(PathBuf::from(CARD)));
+    let result = Err(FlashError::Write {
+        device: PathBuf::from(CARD),
+        offset: 4 * 1024 * 1024,
+        source: io::Error::from_raw_os_error(nix::libc::EIO),
+    });
+    harness
+        .machine
+        .flash_finished(finished_state(&Config::default(), Path::new(CARD), &result));
+    // The result stays until the card is gone for a second.
+    assert!(harness.run_for(Duration::from_secs(5)).is_empty());
+    assert_eq!(harness.state(), SystemState::FlashingFailed);
+    harness.remove();
+    harness.run_for(Duration::from_millis(900));
+    assert_eq!(harness.state(), SystemState::FlashingFailed);
+    harness.run_for(Duration::from_millis(200));
+
+    assert_eq!(
+        harness.history,
+        [
+            SystemState::NoSdCard,
+            SystemState::SdCardFound,
+            SystemState::Flashing,
+            SystemState::FlashingFailed,
+            SystemState::NoSdCard,
+        ]
+    );
+    assert_eq!(
+        harness.leds(),
+        [
+            LedState::Heartbeat,
+            LedState::FlashingGreen,
+            LedState::FlashingGreenRed,
+            LedState::SolidRed,
+            LedState::Heartbeat,
+        ]
+    );
+}
+
+#[test]
+fn removed_mid_flash_until_the_next_card() {
+    let mut harness = Harness::without_timeouts();
+    harness.insert();
+    harness.run_for(Duration::from_millis(200));
+    harness.press();
+    harness.step();
+    assert_eq!(harness.step(), Action::Flash(PathBuf::from(CARD)));
+    harness.remove();
+    let result = Err(FlashError::write(
+        Path::new(CARD),
+        4 * 1024 * 1024,
+        io::Error::from_raw_os_error(nix::libc::ENODEV),
+    ));
+    harness
+        .machine
+        .flash_finished(finished_state(&Config::default(), Path::new(CARD), &result));
+    // Unlike other results, it doesn't wait for the card that is gone already.
+    harness.run_for(Duration::from_secs(5));
+    assert_eq!(harness.state(), SystemState::CardRemoved);
+    harness.insert();
+    harness.run_for(Duration::from_millis(200));
+
+    assert_eq!(
+        harness.history,
+        [
+            SystemState::NoSdCard,
+            SystemState::SdCardFound,
+            SystemState::Flashing,
+            SystemState::CardRemoved,
+            SystemState::NoSdCard,
+            SystemState::SdCardFound,
+        ]
+    );
+    assert_eq!(harness.leds()[3], LedState::FastFlashingGreenRed);
+}
+
+#[test]
+fn result_follows_a_reenumerated_card() {
+    let mut harness = Harness::without_timeouts();
+    harness.insert();
+    harness.run_for(Duration::from_millis(200));
+    harness.press();
+    harness.step();
+    assert_eq!(harness.step(), Action::Flash(PathBuf::from(CARD)));
+    // The reader came back under another name, and the card was flashed there.
+    harness.cards.inserted = Some(PathBuf::from("/dev/sdy"));
+    harness.machine.reenumerated(PathBuf::from("/dev/sdy"));
+    harness.machine.flash_finished(finished_state(
+        &Config::default(),
+        Path::new("/dev/sdy"),
+        &Ok(()),
+    ));
+    harness.run_for(Duration::from_secs(5));
+
+    assert_eq!(harness.state(), SystemState::FlashingSuceeded);
+}
+
+#[test]
+fn wobbly_reader_keeps_the_card() {
+    let mut harness = Harness::without_timeouts();
+    harness.insert();
+    harness.run_for(Duration::from_millis(100));
+    harness.remove();
+    harness.run_for(Duration::from_millis(500));
+    harness.insert();
+    harness.run_for(Duration::from_secs(2));
+
+    assert_eq!(
+        harness.history,
+        [SystemState::NoSdCard, SystemState::SdCardFound]
+    );
+}
+
+#[test]
+fn acknowledges_each_insertion_once() {
+    let timeouts = TimeoutConfig {
+        result_reset_secs: Some(30),
+        idle_secs: None,
+        ..TimeoutConfig::default()
+    };
+    let mut harness = Harness::new(timeouts, false);
+    harness.insert();
+    harness.run_for(Duration::from_millis(100));
+    assert_eq!(harness.detected, 1);
+    harness.press();
+    harness.step();
+    assert_eq!(harness.step(), Action::Flash(PathBuf::from(CARD)));
+    harness
+        .machine
+        .flash_finished(SystemState::FlashingSuceeded);
+    // Found again after the reset, but it is still the same card.
+    harness.run_for(Duration::from_secs(31));
+    assert_eq!(harness.state(), SystemState::SdCardFound);
+    assert_eq!(harness.detected, 1);
+
+    harness.remove();
+    harness.run_for(Duration::from_secs(2));
+    harness.insert();
+    harness.run_for(Duration::from_millis(100));
+    assert_eq!(harness.detected, 2);
+}
+
+#[test]
+fn large_card_needs_confirmation() {
+    let mut harness = Harness::without_timeouts();
+    harness.cards.large = true;
+    harness.insert();
+    harness.run_for(Duration::from_millis(100));
+    harness.press();
+    harness.step();
+    assert_eq!(harness.state(), SystemState::AwaitingConfirmation);
+    // Unconfirmed, it goes back to waiting for the first press.
+    harness.run_for(Duration::from_secs(3));
+    assert_eq!(harness.state(), SystemState::SdCardFound);
+
+    harness.press();
+    harness.step();
+    harness.press();
+    harness.step();
+    assert_eq!(harness.step(), Action::Flash(PathBuf::from(CARD)));
+    assert_eq!(
+        harness.history,
+        [
+            SystemState::NoSdCard,
+            SystemState::SdCardFound,
+            SystemState::AwaitingConfirmation,
+            SystemState::SdCardFound,
+            SystemState::AwaitingConfirmation,
+            SystemState::Flashing,
+        ]
+    );
+}
+
+#[test]
+fn quarantined_card_is_refused() {
+    let mut harness = Harness::without_timeouts();
+    harness.cards.quarantined = true;
+    harness.insert();
+    harness.run_for(Duration::from_millis(100));
+    assert_eq!(harness.state(), SystemState::BadCard);
+    // A press dismisses the result, but the card is still refused.
+    harness.press();
+    harness.run_for(Duration::from_millis(100));
+
+    assert_eq!(
+        harness.history,
+        [
+            SystemState::NoSdCard,
+            SystemState::BadCard,
+            SystemState::NoSdCard,
+            SystemState::BadCard,
+        ]
+    );
+    assert_eq!(harness.leds()[1], LedState::FastFlashingRed);
+}
+
+#[test]
+fn idles_until_woken() {
+    let timeouts = TimeoutConfig {
+        idle_secs: Some(60),
+        ..TimeoutConfig::default()
+    };
+    let mut harness = Harness::new(timeouts, true);
+    harness.run_for(Duration::from_secs(59));
+    assert_eq!(harness.state(), SystemState::NoSdCard);
+    let actions = harness.run_for(Duration::from_secs(2));
+    assert_eq!(harness.state(), SystemState::Idle);
+    assert!(actions.iter().all(|action| *action == Action::Sleep));
+    assert_eq!(harness.step(), Action::Sleep);
+
+    harness.insert();
+    assert!(harness.machine.note(&Event::DeviceAdded));
+    harness.run_for(Duration::from_millis(100));
+    assert_eq!(
+        harness.history,
+        [
+            SystemState::NoSdCard,
+            SystemState::Idle,
+            SystemState::NoSdCard,
+            SystemState::SdCardFound,
+        ]
+    );
+    assert_eq!(harness.leds()[1], LedState::SlowFlashingRed);
+}
+
+#[test]
+fn never_idles_without_hotplug() {
+    let timeouts = TimeoutConfig {
+        idle_secs: Some(60),
+        ..TimeoutConfig::default()
+    };
+    let mut harness = Harness::new(timeouts, false);
+    assert!(harness.run_for(Duration::from_secs(120)).is_empty());
+    assert_eq!(harness.history, [SystemState::NoSdCard]);
+}
+
+#[test]
+fn result_resets_by_itself() {
+    let timeouts = TimeoutConfig {
+        result_reset_secs: Some(30),
+        idle_secs: None,
+        ..TimeoutConfig::default()
+    };
+    let mut harness = Harness::new(timeouts, false);
+    harness.insert();
+    harness.run_for(Duration::from_millis(100));
+    harness.press();
+    harness.step();
+    assert_eq!(harness.step(), Action::Flash(PathBuf::from(CARD)));
+    harness
+        .machine
+        .flash_finished(SystemState::FlashingSuceeded);
+    harness.run_for(Duration::from_secs(29));
+    assert_eq!(harness.state(), SystemState::FlashingSuceeded);
+    harness.run_for(Duration::from_secs(2));
+
+    // The card is still in, so it is found again right away.
+    assert_eq!(
+        harness.history[3..],
+        [
+            SystemState::FlashingSuceeded,
+            SystemState::NoSdCard,
+            SystemState::SdCardFound,
+        ]
+    );
+}
+
+#[test]
+fn update_waits_for_the_card_to_go() {
+    let mut harness = Harness::without_timeouts();
+    harness.insert();
+    harness.run_for(Duration::from_millis(100));
+    assert!(harness.machine.note(&Event::UpdateInstalled));
+    assert!(harness.run_for(Duration::from_secs(5)).is_empty());
+
+    harness.remove();
+    let actions = harness.run_for(Duration::from_secs(2));
+    assert_eq!(actions.first(), Some(&Action::Restart));
+    assert_eq!(harness.state(), SystemState::NoSdCard);
+}
+
+#[test]
+fn holding_the_button_shuts_down_without_a_card() {
+    let mut harness = Harness::without_timeouts();
+    harness.insert();
+    harness.run_for(Duration::from_millis(100));
+    assert!(harness
+        .machine
+        .note(&Event::ButtonPressed(ButtonEvent::ShutdownHold)));
+    assert!(harness.run_for(Duration::from_secs(1)).is_empty());
+    assert_eq!(harness.state(), SystemState::SdCardFound);
+
+    harness.remove();
+    harness.run_for(Duration::from_secs(2));
+    harness
+        .machine
+        .note(&Event::ButtonPressed(ButtonEvent::ShutdownHold));
+    assert_eq!(harness.run_for(Duration::from_secs(1)), [Action::Shutdown]);
+    assert_eq!(harness.state(), SystemState::ShuttingDown);
+}
+
+#[test]
+fn no_flash_starts_while_power_is_failing() {
+    let mut harness = Harness::without_timeouts();
+    harness.insert();
+    harness.run_for(Duration::from_millis(100));
+    assert!(!harness.machine.note(&Event::PowerFailing(true)));
+    harness.press();
+    assert!(harness.run_for(Duration::from_secs(1)).is_empty());
+    assert_eq!(harness.state(), SystemState::SdCardFound);
+
+    harness.machine.note(&Event::PowerFailing(false));
+    harness.press();
+    let actions = harness.run_for(Duration::from_millis(200));
+    assert_eq!(actions.first(), Some(&Action::Flash(PathBuf::from(CARD))));
+}
@@ -0,0 +1,76 @@
+//! A hashed timer wheel for the state machine's timeouts. The main loop ticks it at its polling
+//! rate, so timers fire with that resolution. Callers pass the time in, tests bring their own.
+
+use std::time::{Duration, Instant};
+
+const SLOTS: usize = 64;
+
+struct Entry<K> {
+    key: K,
+    deadline: u64,
+}
+
+pub struct TimerWheel<K> {
+    slots: Vec<Vec<Entry<K>>>,
+    resolution: Duration,
+    start: Instant,
+    /// Every tick before this has been processed
+    current: u64,
+}
+
+impl<K: Copy + PartialEq> TimerWheel<K> {
+    pub fn new(resolution: Duration, now: Instant) -> Self {
+        Self {
+            slots: (0..SLOTS).map(|_| vec![]).collect(),
+            resolution,
+            start: now,
+            current: 0,
+        }
+    }
+
+    fn tick_at(&self, instant: Instant) -> u64 {
+        (instant.duration_since(self.start).as_nanos() / self.resolution.as_nanos()) as u64
+    }
+
+    /// Fires `key` once `after` has passed since `now`, replacing an earlier timer for the same
+    /// key.
+    pub fn schedule(&mut self, key: K, after: Duration, now: Instant) {
+        self.cancel(key);
+        // Rounded up, so timers never fire early.
+        let deadline = (self.tick_at(now + after) + 1).max(self.current);
+        self.slots[deadline as usize % SLOTS].push(Entry { key, deadline });
+    }
+
+    pub fn cancel(&mut self, key: K) {
+        for slot in &mut self.slots {
+            slot.retain(|entry| entry.key != key);
+        }
+    }
+
+    pub fn is_scheduled(&self, key: K) -> bool {
+        self.slots.iter().flatten().any(|entry| entry.key == key)
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.iter_mut().for_each(Vec::clear);
+    }
+
+    /// Advances to `now`, returning the keys of the timers that fired since the last call.
+    pub fn advance(&mut self, now: Instant) -> Vec<K> {
+        let now = self.tick_at(now);
+        let mut fired = vec![];
+        // A full turn visits every slot, entries further out wait for a later turn.
+        let last = now.min(self.current + SLOTS as u64 - 1);
+        for tick in self.current..=last {
+            self.slots[tick as usize % SLOTS].retain(|entry| {
+                let due = entry.deadline <= now;
+                if due {
+                    fired.push(entry.key);
+                }
+                !due
+            });
+        }
+        self.current = self.current.max(now + 1);
+        fired
+    }
+}
@@ -0,0 +1,121 @@
+//! Finding the chunk size a card takes writes fastest in while it is written. A Pi 5 with a
+//! UHS-II reader keeps getting faster with chunks of many megabytes, a Pi Zero with a cheap USB
+//! dongle is better off with small ones.
+
+use std::time::Duration;
+
+/// Bytes written in one size before its throughput is compared, so a stall or the page cache
+/// taking a burst doesn't decide on its own
+const WINDOW: u64 = 64 * 1024 * 1024;
+/// How much faster a larger size has to be to be worth trying the next one
+const MIN_GAIN: f64 = 1.05;
+
+/// Doubles the chunk size while throughput improves, then goes back to the fastest size and
+/// keeps it.
+#[derive(Debug)]
+pub struct ChunkTuner {
+    size: usize,
+    largest: usize,
+    /// Bytes written in `size` so far and how long they took
+    window: (u64, Duration),
+    /// The fastest size so far, with its throughput in bytes per second
+    best: Option<(usize, f64)>,
+    settled: bool,
+}
+
+impl ChunkTuner {
+    /// Starts with chunks of `smallest` bytes, growing up to `largest`. Both are multiples of
+    /// the block size chunks are aligned to, and so is every size in between.
+    pub fn new(smallest: usize, largest: usize) -> Self {
+        Self {
+            size: smallest.min(largest),
+            largest,
+            window: (0, Duration::ZERO),
+            best: None,
+            settled: smallest >= largest,
+        }
+    }
+
+    /// Always chunks of `size` bytes.
+    pub fn fixed(size: usize) -> Self {
+        Self::new(size, size)
+    }
+
+    /// Size of the next chunk.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Counts a chunk of `length` bytes that took `elapsed`, possibly changing the size of the
+    /// chunks after it.
+    pub fn record(&mut self, length: usize, elapsed: Duration) {
+        if self.settled {
+            return;
+        }
+        let (bytes, time) = &mut self.window;
+        *bytes += length as u64;
+        *time += elapsed;
+        if *bytes < WINDOW.max(4 * self.size as u64) || time.is_zero() {
+            return;
+        }
+        let throughput = *bytes as f64 / time.as_secs_f64();
+        self.window = (0, Duration::ZERO);
+        match self.best {
+            Some((best_size, best)) if throughput < best * MIN_GAIN => {
+                self.size = best_size;
+                self.settled = true;
+            }
+            _ => {
+                self.best = Some((self.size, throughput));
+                self.settled = self.size >= self.largest;
+                self.size = (self.size * 2).min(self.largest);
+            }
+        }
+        if self.settled {
+            println!(
+                "Writing in chunks of {} bytes, at {:.1} MB/s",
+                self.size,
+                self.best.map_or(throughput, |(_, best)| best) / 1e6
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: usize = 1024 * 1024;
+
+    /// Feeds `tuner` a window of chunks written at `throughput` MB/s.
+    fn write_window(tuner: &mut ChunkTuner, throughput: f64) {
+        let mut written = 0;
+        while written < WINDOW as usize {
+            let size = tuner.size();
+            tuner.record(
+                size,
+                Duration::from_secs_f64(size as f64 / (throughput * 1e6)),
+            );
+            written += size;
+        }
+    }
+
+    #[test]
+    fn grows_while_faster_and_goes_back_to_the_fastest() {
+        let mut tuner = ChunkTuner::new(MIB, 16 * MIB);
+        for throughput in [10.0, 20.0, 21.0] {
+            write_window(&mut tuner, throughput);
+        }
+        // 4 MiB wasn't enough faster than 2 MiB to keep growing.
+        assert_eq!(tuner.size(), 2 * MIB);
+        write_window(&mut tuner, 5.0);
+        assert_eq!(tuner.size(), 2 * MIB);
+
+        let mut tuner = ChunkTuner::new(MIB, 4 * MIB);
+        for throughput in [10.0, 20.0, 30.0] {
+            write_window(&mut tuner, throughput);
+        }
+        assert_eq!(tuner.size(), 4 * MIB);
+        assert_eq!(ChunkTuner::fixed(3 * MIB).size(), 3 * MIB);
+    }
+}
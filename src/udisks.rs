@@ -0,0 +1,137 @@
+//! Finding, unmounting and opening cards through udisks2 over D-Bus, for systems where a desktop
+//! stack manages the disks and polkit decides who may write to them.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+
+use zbus::blocking::fdo::ObjectManagerProxy;
+use zbus::blocking::{Connection, Proxy};
+use zbus::fdo::ManagedObjects;
+use zbus::zvariant::{self, OwnedObjectPath, OwnedValue, Value};
+
+const SERVICE: &str = "org.freedesktop.UDisks2";
+const ROOT: &str = "/org/freedesktop/UDisks2";
+const BLOCK: &str = "org.freedesktop.UDisks2.Block";
+const PARTITION: &str = "org.freedesktop.UDisks2.Partition";
+const FILESYSTEM: &str = "org.freedesktop.UDisks2.Filesystem";
+
+fn dbus_error(error: zbus::Error) -> io::Error {
+    io::Error::other(format!("udisks2 request failed: {error}"))
+}
+
+type Properties = HashMap<String, OwnedValue>;
+
+/// Properties of `interface` on an object, if it has that interface.
+fn interface<'a>(
+    interfaces: &'a HashMap<zbus::names::OwnedInterfaceName, Properties>,
+    name: &str,
+) -> Option<&'a Properties> {
+    interfaces
+        .iter()
+        .find(|(interface, _)| interface.as_str() == name)
+        .map(|(_, properties)| properties)
+}
+
+fn property<T>(properties: &Properties, name: &str) -> Option<T>
+where
+    T: TryFrom<OwnedValue>,
+{
+    T::try_from(properties.get(name)?.try_clone().ok()?).ok()
+}
+
+/// udisks2 sends paths as NUL terminated byte arrays.
+fn device_path(block: &Properties) -> Option<PathBuf> {
+    let mut bytes: Vec<u8> = property(block, "Device")?;
+    if bytes.last() == Some(&0) {
+        bytes.pop();
+    }
+    Some(PathBuf::from(String::from_utf8(bytes).ok()?))
+}
+
+fn managed_objects(connection: &Connection) -> io::Result<ManagedObjects> {
+    let manager = ObjectManagerProxy::builder(connection)
+        .destination(SERVICE)
+        .and_then(|builder| builder.path(ROOT))
+        .and_then(|builder| builder.build())
+        .map_err(dbus_error)?;
+    manager
+        .get_managed_objects()
+        .map_err(|error| dbus_error(error.into()))
+}
+
+fn connect() -> io::Result<Connection> {
+    Connection::system().map_err(dbus_error)
+}
+
+/// Whole disks (not partitions) of at least `min_size_bytes`, as `/dev` paths.
+pub fn get_block_devices_with_size(min_size_bytes: u64) -> io::Result<Vec<PathBuf>> {
+    let objects = managed_objects(&connect()?)?;
+    let mut devices: Vec<PathBuf> = objects
+        .values()
+        .filter(|interfaces| interface(interfaces, PARTITION).is_none())
+        .filter_map(|interfaces| interface(interfaces, BLOCK))
+        .filter(|block| !property::<bool>(block, "HintIgnore").unwrap_or(false))
+        .filter(|block| property::<u64>(block, "Size").is_some_and(|size| size >= min_size_bytes))
+        .filter_map(device_path)
+        .collect();
+    devices.sort();
+    Ok(devices)
+}
+
+/// The udisks2 object of the block device `device`.
+fn block_object(objects: &ManagedObjects, device: &Path) -> io::Result<OwnedObjectPath> {
+    objects
+        .iter()
+        .find(|(_, interfaces)| {
+            interface(interfaces, BLOCK)
+                .and_then(device_path)
+                .as_deref()
+                == Some(device)
+        })
+        .map(|(path, _)| path.clone())
+        .ok_or_else(|| io::Error::other(format!("udisks2 doesn't know {device:?}")))
+}
+
+/// Unmounts every filesystem on `device` or its partitions, e.g. ones a desktop automounted.
+pub fn unmount_all(device: &Path) -> io::Result<()> {
+    let connection = connect()?;
+    let objects = managed_objects(&connection)?;
+    let disk = block_object(&objects, device)?;
+    for (path, interfaces) in &objects {
+        let on_disk = *path == disk
+            || interface(interfaces, PARTITION)
+                .and_then(|partition| property::<OwnedObjectPath>(partition, "Table"))
+                .is_some_and(|table| table == disk);
+        let mounted = interface(interfaces, FILESYSTEM)
+            .and_then(|filesystem| property::<Vec<Vec<u8>>>(filesystem, "MountPoints"))
+            .is_some_and(|mount_points| !mount_points.is_empty());
+        if !on_disk || !mounted {
+            continue;
+        }
+        println!("Unmounting {path} through udisks2");
+        let filesystem =
+            Proxy::new(&connection, SERVICE, path.as_str(), FILESYSTEM).map_err(dbus_error)?;
+        let options: HashMap<&str, Value> = HashMap::new();
+        filesystem
+            .call::<_, _, ()>("Unmount", &(options,))
+            .map_err(dbus_error)?;
+    }
+    Ok(())
+}
+
+/// Opens `device` for reading and writing through udisks2, which asks polkit whether we may.
+pub fn open_device(device: &Path) -> io::Result<File> {
+    let connection = connect()?;
+    let objects = managed_objects(&connection)?;
+    let path = block_object(&objects, device)?;
+    let block = Proxy::new(&connection, SERVICE, path.as_str(), BLOCK).map_err(dbus_error)?;
+    let mut options: HashMap<&str, Value> = HashMap::new();
+    options.insert("flags", Value::from(nix::libc::O_EXCL));
+    let fd: zvariant::OwnedFd = block
+        .call("OpenDevice", &("rw", options))
+        .map_err(dbus_error)?;
+    Ok(File::from(OwnedFd::from(fd)))
+}
@@ -0,0 +1,121 @@
+//! Updates the cloner itself from a release server, for boxes nobody logs into.
+//!
+//! Next to the binary at `url` the server publishes `<url>.version`, the release's version, and
+//! `<url>.sig`, the raw 64-byte Ed25519 signature of the version, a newline, and the binary. The
+//! version is part of what's signed, so an old release can't be passed off as a newer one. The new
+//! binary replaces the running one with a rename; systemd starts it once the cloner exits between
+//! cards (the unit needs `Restart=always`).
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+
+use crate::catalog::compare_versions;
+use crate::config::UpdateConfig;
+use crate::events::{Event, EventBus};
+
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+fn fetch(url: &str, timeout: Duration) -> io::Result<Vec<u8>> {
+    let response = ureq::get(url)
+        .timeout(timeout)
+        .call()
+        .map_err(|error| io::Error::other(format!("Fetching {url} failed: {error}")))?;
+    let mut body = vec![];
+    response.into_reader().read_to_end(&mut body)?;
+    Ok(body)
+}
+
+fn parse_key(hex: &str) -> io::Result<VerifyingKey> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "Invalid update public key");
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut key = [0; 32];
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
+    VerifyingKey::from_bytes(&key).map_err(|_| invalid())
+}
+
+fn verify(key: &VerifyingKey, version: &str, binary: &[u8], signature: &[u8]) -> io::Result<()> {
+    let signature: [u8; 64] = signature
+        .try_into()
+        .map_err(|_| io::Error::other("Release signature isn't 64 bytes"))?;
+    let mut message = format!("{version}\n").into_bytes();
+    message.extend_from_slice(binary);
+    key.verify_strict(&message, &Signature::from_bytes(&signature))
+        .map_err(|_| io::Error::other(format!("Bad signature on release {version}")))
+}
+
+/// Writes the new binary next to the running one and renames it over it.
+fn install(binary: &[u8], executable: &Path) -> io::Result<()> {
+    let mut name = executable.file_name().unwrap_or_default().to_os_string();
+    name.push(".update");
+    let temporary = executable.with_file_name(name);
+    let result = (|| {
+        let mut file = File::create(&temporary)?;
+        file.write_all(binary)?;
+        file.set_permissions(fs::Permissions::from_mode(0o755))?;
+        file.sync_all()?;
+        fs::rename(&temporary, executable)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Installs a newer release if there is one. Returns whether it did.
+pub fn update_once(config: &UpdateConfig, executable: &Path) -> io::Result<bool> {
+    let key = parse_key(&config.public_key)?;
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let version = String::from_utf8_lossy(&fetch(&format!("{}.version", config.url), timeout)?)
+        .trim()
+        .to_string();
+    if compare_versions(&version, CURRENT_VERSION) != std::cmp::Ordering::Greater {
+        return Ok(false);
+    }
+    println!("Updating from {CURRENT_VERSION} to {version}");
+    let binary = fetch(&config.url, timeout)?;
+    let signature = fetch(&format!("{}.sig", config.url), timeout)?;
+    verify(&key, &version, &binary, &signature)?;
+    install(&binary, executable)?;
+    println!("Installed {version}, restarting once the current card is done");
+    Ok(true)
+}
+
+/// Checks for updates every `interval_secs`, starting one interval from now. Publishes
+/// `UpdateInstalled` once a new binary is in place and the cloner should exit to let systemd
+/// start it.
+pub async fn run(config: UpdateConfig, events: EventBus) {
+    let executable: PathBuf = match env::current_exe() {
+        Ok(executable) => executable,
+        Err(error) => {
+            println!("Got error when locating our executable, not updating: {error:?}");
+            return;
+        }
+    };
+    let period = Duration::from_secs(config.interval_secs);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let (config, executable) = (config.clone(), executable.clone());
+        match tokio::task::spawn_blocking(move || update_once(&config, &executable)).await {
+            Ok(Ok(true)) => {
+                events.publish(Event::UpdateInstalled);
+                return;
+            }
+            Ok(Ok(false)) => {}
+            Ok(Err(error)) => println!("Got error when updating: {error:?}"),
+            Err(error) => println!("Update task failed: {error:?}"),
+        }
+    }
+}
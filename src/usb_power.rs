@@ -0,0 +1,60 @@
+//! Per-port power of USB hubs that can switch it, through uhubctl. The port of a card reader is
+//! power-cycled when a flash failed on I/O errors, which brings back readers that hung. The port
+//! powering a device under test is switched off while its card is flashed.
+
+use std::io;
+use std::process::Command;
+
+use crate::config::UsbPowerConfig;
+use crate::error::{DeviceError, FlashError, VerifyError};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    On,
+    Off,
+    /// Off for the configured time, then on again
+    Cycle,
+}
+
+pub fn switch(config: &UsbPowerConfig, port: u32, action: Action) -> io::Result<()> {
+    let mut command = Command::new("uhubctl");
+    command
+        .arg("--location")
+        .arg(&config.hub)
+        .arg("--ports")
+        .arg(port.to_string())
+        .arg("--action")
+        .arg(match action {
+            Action::On => "on",
+            Action::Off => "off",
+            Action::Cycle => "cycle",
+        })
+        .arg("--delay")
+        .arg(config.cycle_off_secs.to_string());
+    let output = command
+        .env(
+            "PATH",
+            "/usr/local/sbin:/usr/local/bin:/usr/sbin:/sbin:/usr/bin:/bin",
+        )
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "uhubctl failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    println!("Switched port {port} of hub {} {action:?}", config.hub);
+    Ok(())
+}
+
+/// Whether a flash failed in a way a hung reader explains, rather than because of the card or
+/// the image.
+pub fn reader_may_hang(error: &FlashError) -> bool {
+    matches!(
+        error,
+        FlashError::Write { .. }
+            | FlashError::Verify(VerifyError::Read { .. } | VerifyError::Truncated { .. })
+            | FlashError::Device(DeviceError::Open { .. } | DeviceError::Io { .. })
+    )
+}